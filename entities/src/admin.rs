@@ -1,5 +1,6 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.10.6
 
+use crate::unsigned::BigUnsigned;
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
@@ -7,9 +8,9 @@ use serde::{Deserialize, Serialize};
 #[sea_orm(table_name = "admin")]
 pub struct Model {
     #[sea_orm(primary_key)]
-    pub id: u64,
+    pub id: BigUnsigned,
     #[sea_orm(unique)]
-    pub user_id: u64,
+    pub user_id: BigUnsigned,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
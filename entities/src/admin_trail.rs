@@ -1,5 +1,6 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.10.6
 
+use crate::unsigned::BigUnsigned;
 use sea_orm::entity::prelude::*;
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
@@ -7,8 +8,8 @@ use sea_orm::entity::prelude::*;
 pub struct Model {
     #[sea_orm(primary_key)]
     pub id: i32,
-    pub caller: u64,
-    pub imitating_user: Option<u64>,
+    pub caller: BigUnsigned,
+    pub imitating_user: Option<BigUnsigned>,
     pub endpoint: String,
     pub payload: String,
     pub timestamp: DateTimeUtc,
@@ -1,6 +1,7 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.10.6
 
 use crate::serialization;
+use crate::unsigned::BigUnsigned;
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
@@ -8,13 +9,23 @@ use serde::{Deserialize, Serialize};
 #[sea_orm(table_name = "login")]
 pub struct Model {
     #[sea_orm(primary_key)]
-    pub id: u64,
+    pub id: BigUnsigned,
     #[sea_orm(unique)]
-    #[serde(serialize_with = "serialization::uuid_as_ulid")]
+    #[serde(
+        serialize_with = "serialization::uuid_as_ulid",
+        deserialize_with = "serialization::ulid_as_uuid"
+    )]
     pub bearer_token: Uuid,
-    pub user_id: u64,
+    pub user_id: BigUnsigned,
     pub expires_at: DateTimeUtc,
     pub read_only: bool,
+    /// None for logins from before sessions were tracked
+    pub created_at: Option<DateTimeUtc>,
+    /// only updated about once a minute
+    pub last_used_at: Option<DateTimeUtc>,
+    /// where the login came from
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -4,6 +4,10 @@ pub mod prelude;
 
 pub mod admin;
 pub mod admin_trail;
+pub mod balance_ledger;
+pub mod deposit;
+pub mod email_verification;
+pub mod jwt_public_key;
 pub mod login;
 pub mod pending_login;
 pub mod revert_log;
@@ -12,5 +16,7 @@ pub mod rpc_key;
 pub mod sea_orm_active_enums;
 pub mod secondary_user;
 pub mod serialization;
+pub mod team_trail;
+pub mod unsigned;
 pub mod user;
 pub mod user_tier;
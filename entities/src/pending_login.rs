@@ -1,6 +1,7 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.10.5
 
 use crate::serialization;
+use crate::unsigned::BigUnsigned;
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
@@ -8,14 +9,17 @@ use serde::{Deserialize, Serialize};
 #[sea_orm(table_name = "pending_login")]
 pub struct Model {
     #[sea_orm(primary_key)]
-    pub id: u64,
+    pub id: BigUnsigned,
     #[sea_orm(unique)]
-    #[serde(serialize_with = "serialization::uuid_as_ulid")]
+    #[serde(
+        serialize_with = "serialization::uuid_as_ulid",
+        deserialize_with = "serialization::ulid_as_uuid"
+    )]
     pub nonce: Uuid,
     #[sea_orm(column_type = "Text")]
     pub message: String,
     pub expires_at: DateTimeUtc,
-    pub imitating_user: Option<u64>,
+    pub imitating_user: Option<BigUnsigned>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
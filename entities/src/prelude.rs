@@ -2,11 +2,16 @@
 
 pub use super::admin::Entity as Admin;
 pub use super::admin_trail::Entity as AdminTrail;
+pub use super::balance_ledger::Entity as BalanceLedger;
+pub use super::deposit::Entity as Deposit;
+pub use super::email_verification::Entity as EmailVerification;
+pub use super::jwt_public_key::Entity as JwtPublicKey;
 pub use super::login::Entity as Login;
 pub use super::pending_login::Entity as PendingLogin;
 pub use super::revert_log::Entity as RevertLog;
 pub use super::rpc_accounting::Entity as RpcAccounting;
 pub use super::rpc_key::Entity as RpcKey;
 pub use super::secondary_user::Entity as SecondaryUser;
+pub use super::team_trail::Entity as TeamTrail;
 pub use super::user::Entity as User;
 pub use super::user_tier::Entity as UserTier;
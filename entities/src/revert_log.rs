@@ -2,6 +2,7 @@
 
 use super::sea_orm_active_enums::Method;
 use crate::serialization;
+use crate::unsigned::BigUnsigned;
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
@@ -9,15 +10,18 @@ use serde::{Deserialize, Serialize};
 #[sea_orm(table_name = "revert_log")]
 pub struct Model {
     #[sea_orm(primary_key)]
-    pub id: u64,
-    pub rpc_key_id: u64,
+    pub id: BigUnsigned,
+    pub rpc_key_id: BigUnsigned,
     pub timestamp: DateTimeUtc,
     pub method: Method,
-    #[serde(serialize_with = "serialization::vec_as_address")]
+    #[serde(
+        serialize_with = "serialization::vec_as_address",
+        deserialize_with = "serialization::address_as_vec"
+    )]
     pub to: Vec<u8>,
     #[sea_orm(column_type = "Text", nullable)]
     pub call_data: Option<String>,
-    pub chain_id: u64,
+    pub chain_id: BigUnsigned,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
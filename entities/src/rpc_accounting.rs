@@ -1,5 +1,6 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.10.5
 
+use crate::unsigned::BigUnsigned;
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
@@ -7,37 +8,37 @@ use serde::{Deserialize, Serialize};
 #[sea_orm(table_name = "rpc_accounting")]
 pub struct Model {
     #[sea_orm(primary_key)]
-    pub id: u64,
-    pub rpc_key_id: Option<u64>,
-    pub chain_id: u64,
+    pub id: BigUnsigned,
+    pub rpc_key_id: Option<BigUnsigned>,
+    pub chain_id: BigUnsigned,
     pub method: Option<String>,
     pub error_response: bool,
     pub period_datetime: DateTimeUtc,
-    pub frontend_requests: u64,
-    pub backend_requests: u64,
-    pub cache_misses: u64,
-    pub cache_hits: u64,
-    pub sum_request_bytes: u64,
-    pub min_request_bytes: u64,
+    pub frontend_requests: BigUnsigned,
+    pub backend_requests: BigUnsigned,
+    pub cache_misses: BigUnsigned,
+    pub cache_hits: BigUnsigned,
+    pub sum_request_bytes: BigUnsigned,
+    pub min_request_bytes: BigUnsigned,
     pub mean_request_bytes: f64,
-    pub p50_request_bytes: u64,
-    pub p90_request_bytes: u64,
-    pub p99_request_bytes: u64,
-    pub max_request_bytes: u64,
-    pub sum_response_millis: u64,
-    pub min_response_millis: u64,
+    pub p50_request_bytes: BigUnsigned,
+    pub p90_request_bytes: BigUnsigned,
+    pub p99_request_bytes: BigUnsigned,
+    pub max_request_bytes: BigUnsigned,
+    pub sum_response_millis: BigUnsigned,
+    pub min_response_millis: BigUnsigned,
     pub mean_response_millis: f64,
-    pub p50_response_millis: u64,
-    pub p90_response_millis: u64,
-    pub p99_response_millis: u64,
-    pub max_response_millis: u64,
-    pub sum_response_bytes: u64,
-    pub min_response_bytes: u64,
+    pub p50_response_millis: BigUnsigned,
+    pub p90_response_millis: BigUnsigned,
+    pub p99_response_millis: BigUnsigned,
+    pub max_response_millis: BigUnsigned,
+    pub sum_response_bytes: BigUnsigned,
+    pub min_response_bytes: BigUnsigned,
     pub mean_response_bytes: f64,
-    pub p50_response_bytes: u64,
-    pub p90_response_bytes: u64,
-    pub p99_response_bytes: u64,
-    pub max_response_bytes: u64,
+    pub p50_response_bytes: BigUnsigned,
+    pub p90_response_bytes: BigUnsigned,
+    pub p99_response_bytes: BigUnsigned,
+    pub max_response_bytes: BigUnsigned,
     pub archive_request: bool,
     pub origin: Option<String>,
 }
@@ -2,6 +2,7 @@
 
 use super::sea_orm_active_enums::LogLevel;
 use crate::serialization;
+use crate::unsigned::BigUnsigned;
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
@@ -9,10 +10,13 @@ use serde::{Deserialize, Serialize};
 #[sea_orm(table_name = "rpc_key")]
 pub struct Model {
     #[sea_orm(primary_key)]
-    pub id: u64,
-    pub user_id: u64,
+    pub id: BigUnsigned,
+    pub user_id: BigUnsigned,
     #[sea_orm(unique)]
-    #[serde(serialize_with = "serialization::uuid_as_ulid")]
+    #[serde(
+        serialize_with = "serialization::uuid_as_ulid",
+        deserialize_with = "serialization::ulid_as_uuid"
+    )]
     pub secret_key: Uuid,
     pub description: Option<String>,
     pub private_txs: bool,
@@ -27,10 +31,25 @@ pub struct Model {
     pub allowed_user_agents: Option<String>,
     pub log_revert_chance: f64,
     pub log_level: LogLevel,
+    /// the secret key from before the last rotation
+    #[serde(
+        default,
+        serialize_with = "serialization::option_uuid_as_ulid",
+        deserialize_with = "serialization::option_ulid_as_uuid"
+    )]
+    pub old_secret_key: Option<Uuid>,
+    /// when `old_secret_key` stops working
+    #[serde(default)]
+    pub old_secret_key_expires_at: Option<DateTimeUtc>,
+    /// deleted keys are kept for their stats. they never work again
+    #[serde(default)]
+    pub deleted_at: Option<DateTimeUtc>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
+    #[sea_orm(has_many = "super::jwt_public_key::Entity")]
+    JwtPublicKey,
     #[sea_orm(has_many = "super::revert_log::Entity")]
     RevertLog,
     #[sea_orm(has_many = "super::rpc_accounting::Entity")]
@@ -45,6 +64,12 @@ pub enum Relation {
     User,
 }
 
+impl Related<super::jwt_public_key::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::JwtPublicKey.def()
+    }
+}
+
 impl Related<super::revert_log::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::RevertLog.def()
@@ -40,3 +40,33 @@ pub enum Role {
     #[sea_orm(string_value = "collaborator")]
     Collaborator,
 }
+
+/// Deposits are only credited once they are deep enough that a reorg is unlikely
+#[derive(Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
+#[sea_orm(rs_type = "String", db_type = "String(Some(16))")]
+#[serde(rename_all = "snake_case")]
+pub enum DepositStatus {
+    /// seen on chain, but not deep enough to credit
+    #[sea_orm(string_value = "pending")]
+    Pending,
+    /// added to the user's balance
+    #[sea_orm(string_value = "credited")]
+    Credited,
+    /// the block it was in is no longer canonical. if it was credited, the credit was reversed
+    #[sea_orm(string_value = "reorged")]
+    Reorged,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
+#[sea_orm(rs_type = "String", db_type = "String(Some(16))")]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceLedgerKind {
+    #[sea_orm(string_value = "deposit")]
+    Deposit,
+    /// undoes a deposit that was reorged out after being credited
+    #[sea_orm(string_value = "reorg")]
+    Reorg,
+    /// requests that were billed
+    #[sea_orm(string_value = "usage")]
+    Usage,
+}
@@ -1,17 +1,25 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.10.5
 
 use super::sea_orm_active_enums::Role;
+use crate::unsigned::BigUnsigned;
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
+/// A member of another user's account
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
 #[sea_orm(table_name = "secondary_user")]
 pub struct Model {
     #[sea_orm(primary_key)]
-    pub id: u64,
-    pub user_id: u64,
+    pub id: BigUnsigned,
+    /// the account that is shared
+    pub user_id: BigUnsigned,
+    /// the user that was invited
+    pub member_user_id: BigUnsigned,
     pub description: Option<String>,
     pub role: Role,
+    /// None until the member accepts the invitation
+    pub accepted_at: Option<DateTimeUtc>,
+    pub created_at: DateTimeUtc,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -24,6 +32,14 @@ pub enum Relation {
         on_delete = "NoAction"
     )]
     User,
+    #[sea_orm(
+        belongs_to = "super::user::Entity",
+        from = "Column::MemberUserId",
+        to = "super::user::Column::Id",
+        on_update = "NoAction",
+        on_delete = "NoAction"
+    )]
+    MemberUser,
 }
 
 impl Related<super::user::Entity> for Entity {
@@ -1,7 +1,7 @@
 //! sea-orm types don't always serialize how we want. this helps that, though it won't help every case.
-use ethers::prelude::Address;
+use ethers::prelude::{Address, H256};
 use sea_orm::prelude::Uuid;
-use serde::{Serialize, Serializer};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::convert::TryInto;
 use ulid::Ulid;
 
@@ -19,6 +19,15 @@ where
     x.serialize(s)
 }
 
+pub fn vec_as_h256<S>(x: &[u8], s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let x = H256::from_slice(x);
+
+    x.serialize(s)
+}
+
 pub fn uuid_as_ulid<S>(x: &Uuid, s: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -28,3 +37,57 @@ where
     // TODO: to_string shouldn't be needed, but i'm still seeing Uuid length
     x.to_string().serialize(s)
 }
+
+pub fn option_uuid_as_ulid<S>(x: &Option<Uuid>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    x.map(|x| Ulid::from(x.as_u128()).to_string()).serialize(s)
+}
+
+/// the inverse of `vec_as_address`
+pub fn address_as_vec<'de, D>(d: D) -> Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let x = Address::deserialize(d)?;
+
+    Ok(x.as_bytes().to_vec())
+}
+
+/// the inverse of `vec_as_h256`
+pub fn h256_as_vec<'de, D>(d: D) -> Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let x = H256::deserialize(d)?;
+
+    Ok(x.as_bytes().to_vec())
+}
+
+/// the inverse of `uuid_as_ulid`. UUID strings are accepted too
+pub fn ulid_as_uuid<'de, D>(d: D) -> Result<Uuid, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let x = String::deserialize(d)?;
+
+    if let Ok(x) = Ulid::from_string(&x) {
+        return Ok(Uuid::from_u128(x.0));
+    }
+
+    Uuid::parse_str(&x).map_err(de::Error::custom)
+}
+
+/// the inverse of `option_uuid_as_ulid`
+pub fn option_ulid_as_uuid<'de, D>(d: D) -> Result<Option<Uuid>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "ulid_as_uuid")] Uuid);
+
+    let x = Option::<Wrapper>::deserialize(d)?;
+
+    Ok(x.map(|x| x.0))
+}
@@ -0,0 +1,129 @@
+//! sea-orm can only decode unsigned integers on mysql. postgres and sqlite store them as signed integers.
+use sea_orm::sea_query::{ArrayType, ColumnType, Nullable, ValueType, ValueTypeErr};
+use sea_orm::{ColIdx, DbErr, QueryResult, TryFromU64, TryGetError, TryGetable, Value};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::num::NonZeroU64;
+
+macro_rules! unsigned_column {
+    ($(#[$meta:meta])* $name:ident, $unsigned:ty, $signed:ty, $value:ident, $signed_value:ident) => {
+        $(#[$meta])*
+        #[derive(
+            Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+        )]
+        #[serde(transparent)]
+        pub struct $name(pub $unsigned);
+
+        impl TryGetable for $name {
+            fn try_get_by<I: ColIdx>(res: &QueryResult, index: I) -> Result<Self, TryGetError> {
+                match <$unsigned>::try_get_by(res, index) {
+                    Ok(x) => Ok(Self(x)),
+                    Err(TryGetError::DbErr(_)) => {
+                        let x = <$signed>::try_get_by(res, index)?;
+
+                        x.try_into()
+                            .map(Self)
+                            .map_err(|err| TryGetError::DbErr(DbErr::Type(err.to_string())))
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+        }
+
+        impl ValueType for $name {
+            fn try_from(v: Value) -> Result<Self, ValueTypeErr> {
+                match v {
+                    Value::$value(Some(x)) => Ok(Self(x)),
+                    Value::$signed_value(Some(x)) => x.try_into().map(Self).map_err(|_| ValueTypeErr),
+                    _ => Err(ValueTypeErr),
+                }
+            }
+
+            fn type_name() -> String {
+                stringify!($name).to_owned()
+            }
+
+            fn array_type() -> ArrayType {
+                ArrayType::$value
+            }
+
+            fn column_type() -> ColumnType {
+                ColumnType::$value
+            }
+        }
+
+        impl Nullable for $name {
+            fn null() -> Value {
+                Value::$value(None)
+            }
+        }
+
+        impl From<$name> for Value {
+            fn from(x: $name) -> Self {
+                Value::$value(Some(x.0))
+            }
+        }
+
+        impl TryFromU64 for $name {
+            fn try_from_u64(n: u64) -> Result<Self, DbErr> {
+                n.try_into()
+                    .map(Self)
+                    .map_err(|err| DbErr::Type(format!("{:?}", err)))
+            }
+        }
+
+        impl From<$unsigned> for $name {
+            fn from(x: $unsigned) -> Self {
+                Self(x)
+            }
+        }
+
+        impl From<$name> for $unsigned {
+            fn from(x: $name) -> Self {
+                x.0
+            }
+        }
+
+        impl PartialEq<$unsigned> for $name {
+            fn eq(&self, other: &$unsigned) -> bool {
+                self.0 == *other
+            }
+        }
+
+        impl PartialEq<$name> for $unsigned {
+            fn eq(&self, other: &$name) -> bool {
+                *self == other.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+    };
+}
+
+unsigned_column!(
+    /// A u64 column that can be read from mysql, postgres, or sqlite
+    BigUnsigned,
+    u64,
+    i64,
+    BigUnsigned,
+    BigInt
+);
+
+unsigned_column!(
+    /// A u32 column that can be read from mysql, postgres, or sqlite
+    Unsigned,
+    u32,
+    i32,
+    Unsigned,
+    Int
+);
+
+impl From<NonZeroU64> for BigUnsigned {
+    fn from(x: NonZeroU64) -> Self {
+        Self(x.get())
+    }
+}
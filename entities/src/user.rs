@@ -1,6 +1,7 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.10.5
 
 use crate::serialization;
+use crate::unsigned::BigUnsigned;
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
@@ -8,17 +9,30 @@ use serde::{Deserialize, Serialize};
 #[sea_orm(table_name = "user")]
 pub struct Model {
     #[sea_orm(primary_key)]
-    pub id: u64,
+    pub id: BigUnsigned,
     #[sea_orm(unique)]
-    #[serde(serialize_with = "serialization::vec_as_address")]
+    #[serde(
+        serialize_with = "serialization::vec_as_address",
+        deserialize_with = "serialization::address_as_vec"
+    )]
     pub address: Vec<u8>,
     pub description: Option<String>,
     pub email: Option<String>,
-    pub user_tier_id: u64,
+    /// None until the current email is confirmed. only verified emails are sent notifications
+    pub email_verified_at: Option<DateTimeUtc>,
+    pub user_tier_id: BigUnsigned,
+    /// set while the user is on the free tier because their balance ran out
+    pub downgraded_from_tier_id: Option<BigUnsigned>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
+    #[sea_orm(has_many = "super::balance_ledger::Entity")]
+    BalanceLedger,
+    #[sea_orm(has_many = "super::deposit::Entity")]
+    Deposit,
+    #[sea_orm(has_many = "super::email_verification::Entity")]
+    EmailVerification,
     #[sea_orm(has_many = "super::login::Entity")]
     Login,
     #[sea_orm(has_many = "super::rpc_key::Entity")]
@@ -35,6 +49,24 @@ pub enum Relation {
     UserTier,
 }
 
+impl Related<super::balance_ledger::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::BalanceLedger.def()
+    }
+}
+
+impl Related<super::deposit::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Deposit.def()
+    }
+}
+
+impl Related<super::email_verification::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::EmailVerification.def()
+    }
+}
+
 impl Related<super::login::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::Login.def()
@@ -1,5 +1,6 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.10.5
 
+use crate::unsigned::{BigUnsigned, Unsigned};
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
@@ -7,10 +8,10 @@ use serde::{Deserialize, Serialize};
 #[sea_orm(table_name = "user_tier")]
 pub struct Model {
     #[sea_orm(primary_key)]
-    pub id: u64,
+    pub id: BigUnsigned,
     pub title: String,
-    pub max_requests_per_period: Option<u64>,
-    pub max_concurrent_requests: Option<u32>,
+    pub max_requests_per_period: Option<BigUnsigned>,
+    pub max_concurrent_requests: Option<Unsigned>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
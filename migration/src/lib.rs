@@ -17,6 +17,48 @@ mod m20230119_204135_better_free_tier;
 mod m20230130_124740_read_only_login_logic;
 mod m20230130_165144_prepare_admin_imitation_pre_login;
 mod m20230215_152254_admin_trail;
+mod m20230307_002623_portable_schema;
+mod m20230310_153527_deposits;
+mod m20230314_201846_billing;
+mod m20230318_112417_jwt_public_keys;
+mod m20230321_093512_teams;
+mod m20230323_141207_rpc_key_rotation;
+mod m20230325_083014_login_sessions;
+mod m20230327_160512_email_verification;
+
+/// Everything before `m20230307_002623_portable_schema` was written for mysql.
+/// Other backends skip those migrations and get the whole schema from that one instead.
+pub(crate) fn is_mysql(manager: &SchemaManager) -> bool {
+    manager.get_database_backend() == sea_orm::DbBackend::MySql
+}
+
+/// Primary key column that matches `user.id` and friends on every backend.
+/// sqlite only allows AUTOINCREMENT on an INTEGER primary key. its integers are 64 bits anyways
+pub(crate) fn id<T: IntoIden>(db_backend: sea_orm::DbBackend, col: T) -> ColumnDef {
+    let mut c = ColumnDef::new(col);
+
+    match db_backend {
+        sea_orm::DbBackend::MySql => c.big_unsigned(),
+        sea_orm::DbBackend::Postgres => c.big_integer(),
+        sea_orm::DbBackend::Sqlite => c.integer(),
+    };
+
+    c.not_null().auto_increment().primary_key().to_owned()
+}
+
+/// Column that holds a foreign key to one of the `id` columns.
+/// mysql needs the types to match exactly for the constraint.
+pub(crate) fn id_ref<T: IntoIden>(db_backend: sea_orm::DbBackend, col: T) -> ColumnDef {
+    let mut c = ColumnDef::new(col);
+
+    if db_backend == sea_orm::DbBackend::MySql {
+        c.big_unsigned();
+    } else {
+        c.big_integer();
+    }
+
+    c
+}
 
 pub struct Migrator;
 
@@ -41,6 +83,143 @@ impl MigratorTrait for Migrator {
             Box::new(m20230130_124740_read_only_login_logic::Migration),
             Box::new(m20230130_165144_prepare_admin_imitation_pre_login::Migration),
             Box::new(m20230215_152254_admin_trail::Migration),
+            Box::new(m20230307_002623_portable_schema::Migration),
+            Box::new(m20230310_153527_deposits::Migration),
+            Box::new(m20230314_201846_billing::Migration),
+            Box::new(m20230318_112417_jwt_public_keys::Migration),
+            Box::new(m20230321_093512_teams::Migration),
+            Box::new(m20230323_141207_rpc_key_rotation::Migration),
+            Box::new(m20230325_083014_login_sessions::Migration),
+            Box::new(m20230327_160512_email_verification::Migration),
         ]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sea_orm::{Database, DatabaseConnection};
+
+    #[tokio::test]
+    async fn sqlite_up_and_down() {
+        let db_conn = Database::connect("sqlite::memory:").await.unwrap();
+
+        Migrator::up(&db_conn, None).await.unwrap();
+
+        let manager = SchemaManager::new(&db_conn);
+        assert!(manager.has_table("rpc_accounting").await.unwrap());
+        assert!(manager.has_column("user", "user_tier_id").await.unwrap());
+        assert!(manager.has_table("balance_ledger").await.unwrap());
+        assert!(manager
+            .has_column("user", "downgraded_from_tier_id")
+            .await
+            .unwrap());
+        assert!(manager.has_table("jwt_public_key").await.unwrap());
+        assert!(manager
+            .has_column("secondary_user", "member_user_id")
+            .await
+            .unwrap());
+        assert!(manager.has_table("team_trail").await.unwrap());
+        assert!(manager
+            .has_column("rpc_key", "old_secret_key")
+            .await
+            .unwrap());
+        assert!(manager.has_column("rpc_key", "deleted_at").await.unwrap());
+        assert!(manager.has_column("login", "last_used_at").await.unwrap());
+        assert!(manager
+            .has_column("user", "email_verified_at")
+            .await
+            .unwrap());
+        assert!(manager.has_table("email_verification").await.unwrap());
+
+        Migrator::down(&db_conn, None).await.unwrap();
+
+        assert!(!manager.has_table("user").await.unwrap());
+    }
+
+    /// postgres and sqlite only have signed integers. make sure the entities' unsigned ids still round trip
+    async fn user_and_rpc_key_round_trip(db_conn: &DatabaseConnection) {
+        use entities::{rpc_key, user, user_tier};
+        use sea_orm::prelude::Uuid;
+        use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set};
+
+        let free_tier = user_tier::Entity::find()
+            .filter(user_tier::Column::Title.eq("Free"))
+            .one(db_conn)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(free_tier.max_requests_per_period, Some(6000.into()));
+
+        let new_user = user::ActiveModel {
+            address: Set(vec![1; 20]),
+            user_tier_id: Set(free_tier.id),
+            ..Default::default()
+        }
+        .insert(db_conn)
+        .await
+        .unwrap();
+
+        let secret_key = Uuid::from_u128(1);
+
+        let new_rpc_key = rpc_key::ActiveModel {
+            user_id: Set(new_user.id),
+            secret_key: Set(secret_key),
+            description: Set(Some("test".to_string())),
+            private_txs: Set(true),
+            active: Set(true),
+            log_revert_chance: Set(0.1),
+            ..Default::default()
+        }
+        .insert(db_conn)
+        .await
+        .unwrap();
+
+        let found_user = user::Entity::find_by_id(new_user.id)
+            .one(db_conn)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(found_user, new_user);
+        assert_eq!(found_user.user_tier_id, free_tier.id);
+
+        let found_rpc_key = rpc_key::Entity::find()
+            .filter(rpc_key::Column::UserId.eq(new_user.id))
+            .one(db_conn)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(found_rpc_key, new_rpc_key);
+        assert_eq!(found_rpc_key.secret_key, secret_key);
+    }
+
+    #[tokio::test]
+    async fn sqlite_user_and_rpc_key() {
+        let db_conn = Database::connect("sqlite::memory:").await.unwrap();
+
+        Migrator::up(&db_conn, None).await.unwrap();
+
+        user_and_rpc_key_round_trip(&db_conn).await;
+    }
+
+    /// POSTGRES_URL should point at an empty database. the tables are dropped again when the test passes
+    #[tokio::test]
+    #[ignore = "needs a postgres server. set POSTGRES_URL"]
+    async fn postgres_user_and_rpc_key() {
+        let db_url = std::env::var("POSTGRES_URL").expect("POSTGRES_URL is not set");
+
+        let db_conn = Database::connect(db_url).await.unwrap();
+
+        Migrator::up(&db_conn, None).await.unwrap();
+
+        user_and_rpc_key_round_trip(&db_conn).await;
+
+        Migrator::down(&db_conn, None).await.unwrap();
+
+        let manager = SchemaManager::new(&db_conn);
+        assert!(!manager.has_table("user").await.unwrap());
+    }
+}
@@ -7,6 +7,10 @@ pub struct Migration;
 #[async_trait::async_trait]
 impl MigrationTrait for Migration {
     async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if !crate::is_mysql(manager) {
+            return Ok(());
+        }
+
         // users
         manager
             .create_table(
@@ -130,6 +134,10 @@ impl MigrationTrait for Migration {
     }
 
     async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if !crate::is_mysql(manager) {
+            return Ok(());
+        }
+
         manager
             .drop_table(Table::drop().table(User::Table).to_owned())
             .await?;
@@ -7,6 +7,10 @@ pub struct Migration;
 #[async_trait::async_trait]
 impl MigrationTrait for Migration {
     async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if !crate::is_mysql(manager) {
+            return Ok(());
+        }
+
         // add some fields to the UserKeys table
         manager
             .alter_table(
@@ -79,6 +83,10 @@ impl MigrationTrait for Migration {
     }
 
     async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if !crate::is_mysql(manager) {
+            return Ok(());
+        }
+
         // drop the new table
         manager
             .drop_table(Table::drop().table(RevertLogs::Table).to_owned())
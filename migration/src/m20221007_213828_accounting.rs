@@ -7,6 +7,10 @@ pub struct Migration;
 #[async_trait::async_trait]
 impl MigrationTrait for Migration {
     async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if !crate::is_mysql(manager) {
+            return Ok(());
+        }
+
         // create a table for rpc request accounting
         manager
             .create_table(
@@ -188,6 +192,10 @@ impl MigrationTrait for Migration {
     }
 
     async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if !crate::is_mysql(manager) {
+            return Ok(());
+        }
+
         manager
             .drop_table(Table::drop().table(RpcAccounting::Table).to_owned())
             .await
@@ -7,6 +7,10 @@ pub struct Migration;
 #[async_trait::async_trait]
 impl MigrationTrait for Migration {
     async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if !crate::is_mysql(manager) {
+            return Ok(());
+        }
+
         // add a field to the UserKeys table
         manager
             .alter_table(
@@ -42,6 +46,10 @@ impl MigrationTrait for Migration {
     }
 
     async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if !crate::is_mysql(manager) {
+            return Ok(());
+        }
+
         // put the RevertLogs back to how it was before our migrations
         manager
             .alter_table(
@@ -0,0 +1,647 @@
+//! Create the current schema in one step on backends other than mysql.
+//!
+//! The older migrations lean on mysql (inline indexes, enum columns, modify_column, unsigned defaults),
+//! so postgres and sqlite skip all of them and start here. On mysql this does nothing.
+//!
+//! Ids are signed here because postgres and sqlite don't have unsigned integers.
+//! New migrations should be written to work on every backend.
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::{ConnectionTrait, DbBackend};
+use sea_orm_migration::sea_query::extension::postgres::Type;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db_backend = manager.get_database_backend();
+
+        if db_backend == DbBackend::MySql {
+            return Ok(());
+        }
+
+        // postgres needs the enum types to exist before they can be used. sqlite stores them as text
+        if db_backend == DbBackend::Postgres {
+            for (name, variants) in enums() {
+                manager
+                    .create_type(
+                        Type::create()
+                            .as_enum(Alias::new(name))
+                            .values(variants.iter().map(|x| Alias::new(x)))
+                            .to_owned(),
+                    )
+                    .await?;
+            }
+        }
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(UserTier::Table)
+                    .col(&mut crate::id(db_backend, UserTier::Id))
+                    .col(ColumnDef::new(UserTier::Title).string().not_null())
+                    .col(ColumnDef::new(UserTier::MaxRequestsPerPeriod).big_integer())
+                    .col(ColumnDef::new(UserTier::MaxConcurrentRequests).integer())
+                    .to_owned(),
+            )
+            .await?;
+
+        // same tiers and limits as mysql ends up with
+        let user_tiers = Query::insert()
+            .into_table(UserTier::Table)
+            .columns([
+                UserTier::Title,
+                UserTier::MaxRequestsPerPeriod,
+                UserTier::MaxConcurrentRequests,
+            ])
+            .values_panic(["Free".into(), Some(6000i64).into(), Some(5i32).into()])
+            .values_panic([
+                "Private Demo".into(),
+                None::<i64>.into(),
+                Some(2000i32).into(),
+            ])
+            .values_panic([
+                "Effectively Unlimited".into(),
+                Some(6_000_000i64).into(),
+                Some(10_000i32).into(),
+            ])
+            .values_panic(["Unlimited".into(), None::<i64>.into(), None::<i32>.into()])
+            .to_owned();
+
+        manager.exec_stmt(user_tiers).await?;
+
+        // sqlite can't change a column's default later, so the free tier's id is needed before the user table is made
+        let select_free_id = Query::select()
+            .column(UserTier::Id)
+            .from(UserTier::Table)
+            .and_where(Expr::col(UserTier::Title).eq("Free"))
+            .to_owned();
+
+        let free_id: i64 = manager
+            .get_connection()
+            .query_one(db_backend.build(&select_free_id))
+            .await?
+            .expect("we just created Free")
+            .try_get("", &UserTier::Id.to_string())?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(User::Table)
+                    .col(&mut crate::id(db_backend, User::Id))
+                    .col(
+                        ColumnDef::new(User::Address)
+                            .binary_len(20)
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(ColumnDef::new(User::Description).string())
+                    .col(ColumnDef::new(User::Email).string())
+                    .col(
+                        ColumnDef::new(User::UserTierId)
+                            .big_integer()
+                            .not_null()
+                            .default(free_id),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(User::Table, User::UserTierId)
+                            .to(UserTier::Table, UserTier::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(SecondaryUser::Table)
+                    .col(&mut crate::id(db_backend, SecondaryUser::Id))
+                    .col(
+                        ColumnDef::new(SecondaryUser::UserId)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(SecondaryUser::Description).string())
+                    .col(
+                        ColumnDef::new(SecondaryUser::Role)
+                            .enumeration(Alias::new("role"), enum_values("role"))
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(SecondaryUser::Table, SecondaryUser::UserId)
+                            .to(User::Table, User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(RpcKey::Table)
+                    .col(&mut crate::id(db_backend, RpcKey::Id))
+                    .col(ColumnDef::new(RpcKey::UserId).big_integer().not_null())
+                    .col(
+                        ColumnDef::new(RpcKey::SecretKey)
+                            .uuid()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(ColumnDef::new(RpcKey::Description).string())
+                    .col(
+                        ColumnDef::new(RpcKey::PrivateTxs)
+                            .boolean()
+                            .not_null()
+                            .default(true),
+                    )
+                    .col(
+                        ColumnDef::new(RpcKey::Active)
+                            .boolean()
+                            .not_null()
+                            .default(true),
+                    )
+                    .col(ColumnDef::new(RpcKey::AllowedIps).text())
+                    .col(ColumnDef::new(RpcKey::AllowedOrigins).text())
+                    .col(ColumnDef::new(RpcKey::AllowedReferers).text())
+                    .col(ColumnDef::new(RpcKey::AllowedUserAgents).text())
+                    .col(
+                        ColumnDef::new(RpcKey::LogRevertChance)
+                            .double()
+                            .not_null()
+                            .default(0.0),
+                    )
+                    .col(
+                        ColumnDef::new(RpcKey::LogLevel)
+                            .enumeration(Alias::new("log_level"), enum_values("log_level"))
+                            .not_null()
+                            .default("none"),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(RpcKey::Table, RpcKey::UserId)
+                            .to(User::Table, User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-rpc_key-active")
+                    .table(RpcKey::Table)
+                    .col(RpcKey::Active)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(RevertLog::Table)
+                    .col(&mut crate::id(db_backend, RevertLog::Id))
+                    .col(ColumnDef::new(RevertLog::RpcKeyId).big_integer().not_null())
+                    .col(
+                        ColumnDef::new(RevertLog::Timestamp)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RevertLog::Method)
+                            .enumeration(Alias::new("method"), enum_values("method"))
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(RevertLog::To).binary_len(20).not_null())
+                    .col(ColumnDef::new(RevertLog::CallData).text())
+                    .col(ColumnDef::new(RevertLog::ChainId).big_integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(RevertLog::Table, RevertLog::RpcKeyId)
+                            .to(RpcKey::Table, RpcKey::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-revert_log-to")
+                    .table(RevertLog::Table)
+                    .col(RevertLog::To)
+                    .to_owned(),
+            )
+            .await?;
+
+        let mut rpc_accounting = Table::create()
+            .table(RpcAccounting::Table)
+            .col(&mut crate::id(db_backend, RpcAccounting::Id))
+            .col(ColumnDef::new(RpcAccounting::RpcKeyId).big_integer())
+            .col(
+                ColumnDef::new(RpcAccounting::ChainId)
+                    .big_integer()
+                    .not_null(),
+            )
+            .col(ColumnDef::new(RpcAccounting::Method).string())
+            .col(
+                ColumnDef::new(RpcAccounting::ErrorResponse)
+                    .boolean()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(RpcAccounting::PeriodDatetime)
+                    .timestamp_with_time_zone()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(RpcAccounting::ArchiveRequest)
+                    .boolean()
+                    .not_null(),
+            )
+            .col(ColumnDef::new(RpcAccounting::Origin).string())
+            .foreign_key(
+                ForeignKey::create()
+                    .from(RpcAccounting::Table, RpcAccounting::RpcKeyId)
+                    .to(RpcKey::Table, RpcKey::Id),
+            )
+            .to_owned();
+
+        for col in RpcAccounting::counters() {
+            rpc_accounting.col(ColumnDef::new(col).big_integer().not_null());
+        }
+
+        for col in RpcAccounting::means() {
+            rpc_accounting.col(ColumnDef::new(col).double().not_null());
+        }
+
+        manager.create_table(rpc_accounting).await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-rpc_accounting-period_datetime")
+                    .table(RpcAccounting::Table)
+                    .col(RpcAccounting::PeriodDatetime)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-rpc_accounting-method")
+                    .table(RpcAccounting::Table)
+                    .col(RpcAccounting::Method)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(Login::Table)
+                    .col(&mut crate::id(db_backend, Login::Id))
+                    .col(
+                        ColumnDef::new(Login::BearerToken)
+                            .uuid()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(ColumnDef::new(Login::UserId).big_integer().not_null())
+                    .col(
+                        ColumnDef::new(Login::ExpiresAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(Login::ReadOnly)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(Login::Table, Login::UserId)
+                            .to(User::Table, User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(PendingLogin::Table)
+                    .col(&mut crate::id(db_backend, PendingLogin::Id))
+                    .col(
+                        ColumnDef::new(PendingLogin::Nonce)
+                            .uuid()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(ColumnDef::new(PendingLogin::Message).text().not_null())
+                    .col(
+                        ColumnDef::new(PendingLogin::ExpiresAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(PendingLogin::ImitatingUser).big_integer())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(PendingLogin::Table, PendingLogin::ImitatingUser)
+                            .to(User::Table, User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(Admin::Table)
+                    .col(&mut crate::id(db_backend, Admin::Id))
+                    .col(
+                        ColumnDef::new(Admin::UserId)
+                            .big_integer()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(Admin::Table, Admin::UserId)
+                            .to(User::Table, User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(AdminTrail::Table)
+                    .col(
+                        ColumnDef::new(AdminTrail::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(AdminTrail::Caller).big_integer().not_null())
+                    .col(ColumnDef::new(AdminTrail::ImitatingUser).big_integer())
+                    .col(ColumnDef::new(AdminTrail::Endpoint).string().not_null())
+                    .col(ColumnDef::new(AdminTrail::Payload).string().not_null())
+                    .col(
+                        ColumnDef::new(AdminTrail::Timestamp)
+                            .timestamp_with_time_zone()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(AdminTrail::Table, AdminTrail::Caller)
+                            .to(User::Table, User::Id),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(AdminTrail::Table, AdminTrail::ImitatingUser)
+                            .to(User::Table, User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db_backend = manager.get_database_backend();
+
+        if db_backend == DbBackend::MySql {
+            return Ok(());
+        }
+
+        // children before parents so that the foreign keys don't get in the way
+        for table in [
+            "admin_trail",
+            "admin",
+            "pending_login",
+            "login",
+            "rpc_accounting",
+            "revert_log",
+            "rpc_key",
+            "secondary_user",
+            "user",
+            "user_tier",
+        ] {
+            manager
+                .drop_table(Table::drop().table(Alias::new(table)).to_owned())
+                .await?;
+        }
+
+        if db_backend == DbBackend::Postgres {
+            for (name, _) in enums() {
+                manager
+                    .drop_type(Type::drop().name(Alias::new(name)).to_owned())
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// these need to match `entities::sea_orm_active_enums`
+fn enums() -> [(&'static str, &'static [&'static str]); 3] {
+    [
+        ("log_level", &["none", "aggregated", "detailed"]),
+        (
+            "method",
+            &["eth_call", "eth_estimateGas", "eth_sendRawTransaction"],
+        ),
+        ("role", &["owner", "admin", "collaborator"]),
+    ]
+}
+
+fn enum_values(name: &str) -> Vec<Alias> {
+    enums()
+        .into_iter()
+        .find(|x| x.0 == name)
+        .expect("unknown enum")
+        .1
+        .iter()
+        .map(|x| Alias::new(x))
+        .collect()
+}
+
+#[derive(Iden)]
+enum UserTier {
+    Table,
+    Id,
+    Title,
+    MaxRequestsPerPeriod,
+    MaxConcurrentRequests,
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+    Address,
+    Description,
+    Email,
+    UserTierId,
+}
+
+#[derive(Iden)]
+enum SecondaryUser {
+    Table,
+    Id,
+    UserId,
+    Description,
+    Role,
+}
+
+#[derive(Iden)]
+enum RpcKey {
+    Table,
+    Id,
+    UserId,
+    SecretKey,
+    Description,
+    PrivateTxs,
+    Active,
+    AllowedIps,
+    AllowedOrigins,
+    AllowedReferers,
+    AllowedUserAgents,
+    LogRevertChance,
+    LogLevel,
+}
+
+#[derive(Iden)]
+enum RevertLog {
+    Table,
+    Id,
+    RpcKeyId,
+    Timestamp,
+    Method,
+    To,
+    CallData,
+    ChainId,
+}
+
+#[derive(Clone, Copy, Iden)]
+enum RpcAccounting {
+    Table,
+    Id,
+    RpcKeyId,
+    ChainId,
+    Method,
+    ErrorResponse,
+    PeriodDatetime,
+    FrontendRequests,
+    BackendRequests,
+    CacheMisses,
+    CacheHits,
+    SumRequestBytes,
+    MinRequestBytes,
+    MeanRequestBytes,
+    P50RequestBytes,
+    P90RequestBytes,
+    P99RequestBytes,
+    MaxRequestBytes,
+    SumResponseMillis,
+    MinResponseMillis,
+    MeanResponseMillis,
+    P50ResponseMillis,
+    P90ResponseMillis,
+    P99ResponseMillis,
+    MaxResponseMillis,
+    SumResponseBytes,
+    MinResponseBytes,
+    MeanResponseBytes,
+    P50ResponseBytes,
+    P90ResponseBytes,
+    P99ResponseBytes,
+    MaxResponseBytes,
+    ArchiveRequest,
+    Origin,
+}
+
+impl RpcAccounting {
+    fn counters() -> [Self; 22] {
+        [
+            Self::FrontendRequests,
+            Self::BackendRequests,
+            Self::CacheMisses,
+            Self::CacheHits,
+            Self::SumRequestBytes,
+            Self::MinRequestBytes,
+            Self::P50RequestBytes,
+            Self::P90RequestBytes,
+            Self::P99RequestBytes,
+            Self::MaxRequestBytes,
+            Self::SumResponseMillis,
+            Self::MinResponseMillis,
+            Self::P50ResponseMillis,
+            Self::P90ResponseMillis,
+            Self::P99ResponseMillis,
+            Self::MaxResponseMillis,
+            Self::SumResponseBytes,
+            Self::MinResponseBytes,
+            Self::P50ResponseBytes,
+            Self::P90ResponseBytes,
+            Self::P99ResponseBytes,
+            Self::MaxResponseBytes,
+        ]
+    }
+
+    fn means() -> [Self; 3] {
+        [
+            Self::MeanRequestBytes,
+            Self::MeanResponseMillis,
+            Self::MeanResponseBytes,
+        ]
+    }
+}
+
+#[derive(Iden)]
+enum Login {
+    Table,
+    Id,
+    BearerToken,
+    UserId,
+    ExpiresAt,
+    ReadOnly,
+}
+
+#[derive(Iden)]
+enum PendingLogin {
+    Table,
+    Id,
+    Nonce,
+    Message,
+    ExpiresAt,
+    ImitatingUser,
+}
+
+#[derive(Iden)]
+enum Admin {
+    Table,
+    Id,
+    UserId,
+}
+
+#[derive(Iden)]
+enum AdminTrail {
+    Table,
+    Id,
+    Caller,
+    ImitatingUser,
+    Endpoint,
+    Payload,
+    Timestamp,
+}
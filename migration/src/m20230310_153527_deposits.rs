@@ -0,0 +1,190 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db_backend = manager.get_database_backend();
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(Deposit::Table)
+                    .col(&mut crate::id(db_backend, Deposit::Id))
+                    .col(ColumnDef::new(Deposit::ChainId).big_integer().not_null())
+                    .col(ColumnDef::new(Deposit::TxHash).binary_len(32).not_null())
+                    .col(ColumnDef::new(Deposit::LogIndex).big_integer().not_null())
+                    .col(ColumnDef::new(Deposit::BlockHash).binary_len(32).not_null())
+                    .col(
+                        ColumnDef::new(Deposit::BlockNumber)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(crate::id_ref(db_backend, Deposit::UserId).not_null())
+                    .col(ColumnDef::new(Deposit::Token).binary_len(20).not_null())
+                    .col(
+                        ColumnDef::new(Deposit::Amount)
+                            .decimal_len(38, 18)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(Deposit::Confirmations)
+                            .big_integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    .col(
+                        ColumnDef::new(Deposit::Status)
+                            .string_len(16)
+                            .not_null()
+                            .default("pending"),
+                    )
+                    .col(
+                        ColumnDef::new(Deposit::CreatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(Deposit::Table, Deposit::UserId)
+                            .to(User::Table, User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // the same log can show up again in a different block after a reorg. that is a new deposit
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-deposit-log")
+                    .table(Deposit::Table)
+                    .col(Deposit::ChainId)
+                    .col(Deposit::TxHash)
+                    .col(Deposit::LogIndex)
+                    .col(Deposit::BlockHash)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-deposit-status")
+                    .table(Deposit::Table)
+                    .col(Deposit::Status)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(BalanceLedger::Table)
+                    .col(&mut crate::id(db_backend, BalanceLedger::Id))
+                    .col(crate::id_ref(db_backend, BalanceLedger::UserId).not_null())
+                    .col(
+                        ColumnDef::new(BalanceLedger::Amount)
+                            .decimal_len(38, 18)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(BalanceLedger::Kind)
+                            .string_len(16)
+                            .not_null(),
+                    )
+                    .col(&mut crate::id_ref(db_backend, BalanceLedger::DepositId))
+                    .col(
+                        ColumnDef::new(BalanceLedger::CreatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(BalanceLedger::Table, BalanceLedger::UserId)
+                            .to(User::Table, User::Id),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(BalanceLedger::Table, BalanceLedger::DepositId)
+                            .to(Deposit::Table, Deposit::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // a deposit can be credited once and reversed once. this keeps multiple proxies from doing either twice
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-balance_ledger-deposit_id-kind")
+                    .table(BalanceLedger::Table)
+                    .col(BalanceLedger::DepositId)
+                    .col(BalanceLedger::Kind)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-balance_ledger-user_id")
+                    .table(BalanceLedger::Table)
+                    .col(BalanceLedger::UserId)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(BalanceLedger::Table).to_owned())
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(Deposit::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
+
+#[derive(Iden)]
+enum Deposit {
+    Table,
+    Id,
+    ChainId,
+    TxHash,
+    LogIndex,
+    BlockHash,
+    BlockNumber,
+    UserId,
+    Token,
+    Amount,
+    Confirmations,
+    Status,
+    CreatedAt,
+}
+
+#[derive(Iden)]
+enum BalanceLedger {
+    Table,
+    Id,
+    UserId,
+    Amount,
+    Kind,
+    DepositId,
+    CreatedAt,
+}
@@ -0,0 +1,83 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db_backend = manager.get_database_backend();
+
+        // usage is debited by every proxy. the key lets a proxy retry a debit without charging twice
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(BalanceLedger::Table)
+                    .add_column(ColumnDef::new(BalanceLedger::IdempotencyKey).string_len(128))
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-balance_ledger-idempotency_key")
+                    .table(BalanceLedger::Table)
+                    .col(BalanceLedger::IdempotencyKey)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        // users that run out of balance are moved to the free tier. this is where they go back to after a deposit
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .add_column(&mut crate::id_ref(db_backend, User::DowngradedFromTierId))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .drop_column(User::DowngradedFromTierId)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx-balance_ledger-idempotency_key")
+                    .table(BalanceLedger::Table)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(BalanceLedger::Table)
+                    .drop_column(BalanceLedger::IdempotencyKey)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    DowngradedFromTierId,
+}
+
+#[derive(Iden)]
+enum BalanceLedger {
+    Table,
+    IdempotencyKey,
+}
@@ -0,0 +1,74 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db_backend = manager.get_database_backend();
+
+        // public keys that users upload so that jwts they sign can be used in place of an rpc key
+        manager
+            .create_table(
+                Table::create()
+                    .table(JwtPublicKey::Table)
+                    .col(&mut crate::id(db_backend, JwtPublicKey::Id))
+                    .col(crate::id_ref(db_backend, JwtPublicKey::RpcKeyId).not_null())
+                    .col(
+                        ColumnDef::new(JwtPublicKey::KeyId)
+                            .uuid()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(
+                        ColumnDef::new(JwtPublicKey::Algorithm)
+                            .string_len(16)
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(JwtPublicKey::PublicKey).text().not_null())
+                    .col(ColumnDef::new(JwtPublicKey::Audience).string())
+                    .col(ColumnDef::new(JwtPublicKey::MaxLifetimeSeconds).big_integer())
+                    .col(ColumnDef::new(JwtPublicKey::Description).string())
+                    .col(
+                        ColumnDef::new(JwtPublicKey::CreatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(JwtPublicKey::Table, JwtPublicKey::RpcKeyId)
+                            .to(RpcKey::Table, RpcKey::Id),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(JwtPublicKey::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum RpcKey {
+    Table,
+    Id,
+}
+
+#[derive(Iden)]
+enum JwtPublicKey {
+    Table,
+    Id,
+    RpcKeyId,
+    KeyId,
+    Algorithm,
+    PublicKey,
+    Audience,
+    MaxLifetimeSeconds,
+    Description,
+    CreatedAt,
+}
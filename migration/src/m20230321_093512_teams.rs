@@ -0,0 +1,183 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+fn roles() -> Vec<Alias> {
+    ["owner", "admin", "collaborator"]
+        .into_iter()
+        .map(Alias::new)
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db_backend = manager.get_database_backend();
+
+        // secondary users lost their address in m20221031_211916_clean_up, so old rows can't say who the member is.
+        // nothing ever read them. start the table over with a column for the member
+        manager
+            .drop_table(Table::drop().table(SecondaryUser::Table).to_owned())
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(SecondaryUser::Table)
+                    .col(&mut crate::id(db_backend, SecondaryUser::Id))
+                    .col(crate::id_ref(db_backend, SecondaryUser::UserId).not_null())
+                    .col(crate::id_ref(db_backend, SecondaryUser::MemberUserId).not_null())
+                    .col(ColumnDef::new(SecondaryUser::Description).string())
+                    .col(
+                        ColumnDef::new(SecondaryUser::Role)
+                            .enumeration(Alias::new("role"), roles())
+                            .not_null(),
+                    )
+                    // null until the member accepts the invitation
+                    .col(ColumnDef::new(SecondaryUser::AcceptedAt).timestamp_with_time_zone())
+                    .col(
+                        ColumnDef::new(SecondaryUser::CreatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(SecondaryUser::Table, SecondaryUser::UserId)
+                            .to(User::Table, User::Id),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(SecondaryUser::Table, SecondaryUser::MemberUserId)
+                            .to(User::Table, User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-secondary_user-user_id-member_user_id")
+                    .table(SecondaryUser::Table)
+                    .col(SecondaryUser::UserId)
+                    .col(SecondaryUser::MemberUserId)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-secondary_user-member_user_id")
+                    .table(SecondaryUser::Table)
+                    .col(SecondaryUser::MemberUserId)
+                    .to_owned(),
+            )
+            .await?;
+
+        // everything that members do to a team's account
+        manager
+            .create_table(
+                Table::create()
+                    .table(TeamTrail::Table)
+                    .col(&mut crate::id(db_backend, TeamTrail::Id))
+                    .col(crate::id_ref(db_backend, TeamTrail::UserId).not_null())
+                    .col(crate::id_ref(db_backend, TeamTrail::Caller).not_null())
+                    .col(ColumnDef::new(TeamTrail::Endpoint).string().not_null())
+                    .col(ColumnDef::new(TeamTrail::Payload).text().not_null())
+                    .col(
+                        ColumnDef::new(TeamTrail::Timestamp)
+                            .timestamp_with_time_zone()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(TeamTrail::Table, TeamTrail::UserId)
+                            .to(User::Table, User::Id),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(TeamTrail::Table, TeamTrail::Caller)
+                            .to(User::Table, User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-team_trail-user_id")
+                    .table(TeamTrail::Table)
+                    .col(TeamTrail::UserId)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db_backend = manager.get_database_backend();
+
+        manager
+            .drop_table(Table::drop().table(TeamTrail::Table).to_owned())
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(SecondaryUser::Table).to_owned())
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(SecondaryUser::Table)
+                    .col(&mut crate::id(db_backend, SecondaryUser::Id))
+                    .col(crate::id_ref(db_backend, SecondaryUser::UserId).not_null())
+                    .col(ColumnDef::new(SecondaryUser::Description).string())
+                    .col(
+                        ColumnDef::new(SecondaryUser::Role)
+                            .enumeration(Alias::new("role"), roles())
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(SecondaryUser::Table, SecondaryUser::UserId)
+                            .to(User::Table, User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
+
+#[derive(Iden)]
+enum SecondaryUser {
+    Table,
+    Id,
+    UserId,
+    MemberUserId,
+    Description,
+    Role,
+    AcceptedAt,
+    CreatedAt,
+}
+
+#[derive(Iden)]
+enum TeamTrail {
+    Table,
+    Id,
+    UserId,
+    Caller,
+    Endpoint,
+    Payload,
+    Timestamp,
+}
@@ -0,0 +1,76 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // logins are listed as sessions. these help users recognize them
+        // sqlite can't add a column with a non-constant default, so created_at is set by the app. older logins don't have it
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Login::Table)
+                    .add_column(ColumnDef::new(Login::CreatedAt).timestamp_with_time_zone())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Login::Table)
+                    .add_column(ColumnDef::new(Login::LastUsedAt).timestamp_with_time_zone())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Login::Table)
+                    .add_column(ColumnDef::new(Login::Ip).string_len(64))
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Login::Table)
+                    .add_column(ColumnDef::new(Login::UserAgent).text())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for col in [
+            Login::UserAgent,
+            Login::Ip,
+            Login::LastUsedAt,
+            Login::CreatedAt,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Login::Table)
+                        .drop_column(col)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum Login {
+    Table,
+    CreatedAt,
+    LastUsedAt,
+    Ip,
+    UserAgent,
+}
@@ -0,0 +1,98 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db_backend = manager.get_database_backend();
+
+        // null until the user clicks the link that was sent to their current email
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .add_column(ColumnDef::new(User::EmailVerifiedAt).timestamp_with_time_zone())
+                    .to_owned(),
+            )
+            .await?;
+
+        // tokens that were emailed. the email is saved too so that changing it again makes older links useless
+        manager
+            .create_table(
+                Table::create()
+                    .table(EmailVerification::Table)
+                    .col(&mut crate::id(db_backend, EmailVerification::Id))
+                    .col(crate::id_ref(db_backend, EmailVerification::UserId).not_null())
+                    .col(ColumnDef::new(EmailVerification::Email).string().not_null())
+                    .col(
+                        ColumnDef::new(EmailVerification::Token)
+                            .uuid()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(
+                        ColumnDef::new(EmailVerification::ExpiresAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(EmailVerification::CreatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(EmailVerification::Table, EmailVerification::UserId)
+                            .to(User::Table, User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-email_verification-user_id")
+                    .table(EmailVerification::Table)
+                    .col(EmailVerification::UserId)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(EmailVerification::Table).to_owned())
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .drop_column(User::EmailVerifiedAt)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+    EmailVerifiedAt,
+}
+
+#[derive(Iden)]
+enum EmailVerification {
+    Table,
+    Id,
+    UserId,
+    Email,
+    Token,
+    ExpiresAt,
+    CreatedAt,
+}
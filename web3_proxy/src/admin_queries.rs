@@ -1,19 +1,22 @@
 use crate::app::Web3ProxyApp;
 use crate::frontend::errors::FrontendErrorResponse;
+use crate::rpcs::admin::Web3RpcCommand;
 use crate::user_queries::get_user_id_from_params;
+use crate::user_token::revoke_logins;
 use anyhow::Context;
 use axum::response::{IntoResponse, Response};
 use axum::{
     headers::{authorization::Bearer, Authorization},
     Json, TypedHeader,
 };
-use entities::{admin, login, user, user_tier};
+use entities::{admin, admin_trail, login, user, user_tier};
 use ethers::prelude::Address;
 use hashbrown::HashMap;
 use log::{debug, info};
 use migration::sea_orm::{
     self, ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter,
 };
+use serde_json::json;
 
 // TODO: Add some logic to check if the operating user is an admin
 // If he is, return true
@@ -68,8 +71,16 @@ pub async fn query_admin_modify_usertier<'a>(
     // Try to get the user who is calling from redis (if existent) / else from the database
     // TODO: Make a single query, where you retrieve the user, and directly from it the secondary user (otherwise we do two jumpy, which is unnecessary)
     // get the user id first. if it is 0, we should use a cache on the app
-    let caller_id =
-        get_user_id_from_params(&mut redis_conn, &db_conn, &db_replica, bearer, params).await?;
+    let caller_id = get_user_id_from_params(
+        app,
+        &mut redis_conn,
+        &db_conn,
+        &db_replica,
+        bearer,
+        params,
+        None,
+    )
+    .await?;
 
     debug!("Caller id is: {:?}", caller_id);
 
@@ -93,7 +104,7 @@ pub async fn query_admin_modify_usertier<'a>(
     // Return early if the target user_tier_id is the same as the original user_tier_id
     response_body.insert(
         "user_tier_title",
-        serde_json::Value::Number(user.user_tier_id.into()),
+        serde_json::Value::Number(user.user_tier_id.0.into()),
     );
 
     // Now we can modify the user's tier
@@ -111,6 +122,8 @@ pub async fn query_admin_modify_usertier<'a>(
         let mut user = user.clone().into_active_model();
 
         user.user_tier_id = sea_orm::Set(new_user_tier.id);
+        // an admin's choice replaces any automatic downgrade
+        user.downgraded_from_tier_id = sea_orm::Set(None);
 
         user.save(&db_conn).await?;
 
@@ -118,10 +131,110 @@ pub async fn query_admin_modify_usertier<'a>(
     }
 
     // Now delete all bearer tokens of this user
-    login::Entity::delete_many()
-        .filter(login::Column::UserId.eq(user.id))
-        .exec(&db_conn)
-        .await?;
+    revoke_logins(
+        &db_conn,
+        Some(&mut redis_conn),
+        login::Column::UserId.eq(user.id),
+    )
+    .await?;
 
     Ok(Json(&response_body).into_response())
 }
+
+/// Drain, disable, enable, reconfigure, or reconnect a backend rpc on every proxy
+pub async fn query_admin_rpc_command(
+    app: &Web3ProxyApp,
+    bearer: Bearer,
+    rpc_name: String,
+    command: Web3RpcCommand,
+) -> Result<Response, FrontendErrorResponse> {
+    let db_conn = app
+        .db_conn()
+        .context("query_admin_rpc_command needs a db")?;
+
+    let (caller, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    // Check if the caller is an admin (i.e. if he is in an admin table)
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(&db_conn)
+        .await?
+        .ok_or(FrontendErrorResponse::AccessDenied)?;
+
+    // save the trail first so that failed attempts are recorded too
+    let trail = admin_trail::ActiveModel {
+        caller: sea_orm::Set(caller.id),
+        imitating_user: sea_orm::Set(None),
+        endpoint: sea_orm::Set("admin_rpc_command".to_string()),
+        payload: sea_orm::Set(format!("{} {:?}", rpc_name, command)),
+        ..Default::default()
+    };
+    trail
+        .save(&db_conn)
+        .await
+        .context("saving admin trail for rpc command")?;
+
+    let rpc = app
+        .rpc_admin_command(&rpc_name, command)
+        .await
+        .map_err(|err| FrontendErrorResponse::BadRequest(format!("{:#}", err)))?
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    info!("admin {} changed {}", caller.id, rpc);
+
+    Ok(Json(&*rpc).into_response())
+}
+
+/// Log a user out everywhere. Their bearer tokens and jwts stop working on every proxy
+pub async fn query_admin_revoke_sessions(
+    app: &Web3ProxyApp,
+    bearer: Bearer,
+    user_address: Address,
+) -> Result<Response, FrontendErrorResponse> {
+    let db_conn = app
+        .db_conn()
+        .context("query_admin_revoke_sessions needs a db")?;
+
+    let (caller, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    // Check if the caller is an admin (i.e. if he is in an admin table)
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(&db_conn)
+        .await?
+        .ok_or(FrontendErrorResponse::AccessDenied)?;
+
+    let user = user::Entity::find()
+        .filter(user::Column::Address.eq(user_address.as_bytes()))
+        .one(&db_conn)
+        .await?
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    let trail = admin_trail::ActiveModel {
+        caller: sea_orm::Set(caller.id),
+        imitating_user: sea_orm::Set(Some(user.id)),
+        endpoint: sea_orm::Set("admin_revoke_sessions".to_string()),
+        payload: sea_orm::Set(format!("{:?}", user_address)),
+        ..Default::default()
+    };
+    trail
+        .save(&db_conn)
+        .await
+        .context("saving admin trail for revoking sessions")?;
+
+    let mut redis_conn = app.redis_conn().await?;
+
+    let revoked = revoke_logins(
+        &db_conn,
+        redis_conn.as_mut(),
+        login::Column::UserId.eq(user.id),
+    )
+    .await?;
+
+    info!(
+        "admin {} revoked {} sessions for user {}",
+        caller.id, revoked, user.id
+    );
+
+    Ok(Json(json!({ "user_id": user.id, "revoked": revoked })).into_response())
+}
@@ -0,0 +1,202 @@
+//! Monotonic reads. Clients that opt in never get a response from an older block than one they have already seen.
+
+use super::Web3ProxyApp;
+use crate::frontend::authorization::Authorization;
+use anyhow::Context;
+use ethers::prelude::U64;
+use log::{trace, warn};
+use redis_rate_limiter::redis;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// sessions are forgotten after this long without a request
+pub const BLOCK_SESSION_TTL_SECONDS: u64 = 600;
+
+/// Save the larger of the stored block and ours. Returns the stored block.
+/// Redis doesn't have a `SET GT`, so this is a script to keep it atomic
+const SET_MAX_SCRIPT: &str = r#"
+local highest = tonumber(redis.call('GET', KEYS[1]) or '0')
+local block_num = tonumber(ARGV[1])
+if block_num > highest then
+    highest = block_num
+end
+redis.call('SET', KEYS[1], highest, 'EX', ARGV[2])
+return highest
+"#;
+
+/// The highest block that a client has been shown.
+/// Redis has the real value so that sessions work across proxies. This is a local copy of it
+#[derive(Debug)]
+pub struct BlockSession {
+    redis_key: String,
+    /// 0 until the client has seen a block
+    highest: AtomicU64,
+}
+
+impl BlockSession {
+    pub fn new(redis_key: String) -> Self {
+        Self {
+            redis_key,
+            highest: AtomicU64::new(0),
+        }
+    }
+
+    pub fn highest(&self) -> Option<U64> {
+        match self.highest.load(Ordering::Acquire) {
+            0 => None,
+            x => Some(x.into()),
+        }
+    }
+
+    /// the client was shown this block. blocks older than the highest are ignored.
+    /// returns true if this is a new highest block
+    fn saw_local(&self, block_num: u64) -> bool {
+        self.highest.fetch_max(block_num, Ordering::AcqRel) < block_num
+    }
+}
+
+impl Web3ProxyApp {
+    /// Find the session for a client. Session ids are scoped to the rpc key (or the ip if there is no key)
+    pub async fn block_session(
+        &self,
+        authorization: &Authorization,
+        session_id: &str,
+    ) -> Arc<BlockSession> {
+        let key = match authorization.checks.rpc_secret_key_id {
+            Some(rpc_secret_key_id) => format!("key:{}:{}", rpc_secret_key_id, session_id),
+            None => format!("ip:{}:{}", authorization.ip, session_id),
+        };
+
+        let redis_key = format!("block_session:{}:{}", self.config().chain_id, key);
+
+        self.block_sessions
+            .get_with(key, async { Arc::new(BlockSession::new(redis_key)) })
+            .await
+    }
+
+    /// The client was shown this block.
+    /// It is saved to redis before the response is sent so that the client's next request sees it on any proxy
+    pub(super) async fn block_session_saw(&self, block_session: &BlockSession, block_num: U64) {
+        if !block_session.saw_local(block_num.as_u64()) {
+            // redis already has this block or a newer one
+            return;
+        }
+
+        if let Err(err) = self
+            .block_session_redis(block_session, block_num.as_u64())
+            .await
+        {
+            warn!("unable to save block session. err={:?}", err);
+        }
+    }
+
+    /// save our highest block to redis and copy the highest from other proxies
+    async fn block_session_redis(
+        &self,
+        block_session: &BlockSession,
+        block_num: u64,
+    ) -> anyhow::Result<()> {
+        let mut redis_conn = match self.redis_conn().await? {
+            Some(x) => x,
+            // without redis, sessions only work on one proxy
+            None => return Ok(()),
+        };
+
+        let highest: u64 = redis::cmd("EVAL")
+            .arg(SET_MAX_SCRIPT)
+            .arg(1)
+            .arg(&block_session.redis_key)
+            .arg(block_num)
+            .arg(BLOCK_SESSION_TTL_SECONDS)
+            .query_async(&mut redis_conn)
+            .await
+            .context("saving block session")?;
+
+        block_session.saw_local(highest);
+
+        Ok(())
+    }
+
+    /// The block to use as "latest" for a request.
+    /// If the client has already seen a newer block than our consensus head, wait a little for our servers to catch up.
+    /// If they don't, use the client's block anyway. Only servers that have it will get the request.
+    pub(super) async fn session_head_block_num(
+        &self,
+        block_session: Option<&BlockSession>,
+    ) -> anyhow::Result<U64> {
+        if let Some(block_session) = block_session {
+            // another proxy might have shown the client a newer block. 0 leaves our copy alone
+            if let Err(err) = self.block_session_redis(block_session, 0).await {
+                warn!("unable to load block session. err={:?}", err);
+            }
+        }
+
+        let head_block_num = self.balanced_rpcs.head_block_num();
+
+        let min_block_num = match block_session.and_then(|x| x.highest()) {
+            None => return head_block_num.context("no servers synced"),
+            Some(x) => x,
+        };
+
+        if let Some(head_block_num) = head_block_num {
+            if head_block_num >= min_block_num {
+                return Ok(head_block_num);
+            }
+        }
+
+        let mut head_block_receiver = self.head_block_receiver();
+
+        let max_wait = Duration::from_millis(self.config().block_session_max_wait_ms);
+
+        let caught_up = timeout(max_wait, async {
+            loop {
+                if let Some(head_block) = head_block_receiver.borrow_and_update().as_ref() {
+                    if *head_block.number() >= min_block_num {
+                        return Some(*head_block.number());
+                    }
+                }
+
+                if head_block_receiver.changed().await.is_err() {
+                    return None;
+                }
+            }
+        })
+        .await;
+
+        match caught_up {
+            Ok(Some(head_block_num)) => Ok(head_block_num),
+            _ => {
+                trace!(
+                    "consensus head is behind the session's block {}. rerouting",
+                    min_block_num
+                );
+
+                Ok(min_block_num)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_session_never_goes_backwards() {
+        let session = BlockSession::new("test".to_string());
+
+        assert_eq!(session.highest(), None);
+
+        assert!(session.saw_local(100));
+        assert!(!session.saw_local(99));
+
+        assert_eq!(session.highest(), Some(100.into()));
+
+        assert!(session.saw_local(101));
+        assert!(!session.saw_local(101));
+
+        assert_eq!(session.highest(), Some(101.into()));
+    }
+}
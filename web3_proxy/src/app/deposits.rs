@@ -0,0 +1,709 @@
+//! Prepaid balances. Users pay the deposit contract and their balance is credited once the payment is deep enough.
+//!
+//! Every change to a balance is a row in `balance_ledger`. A user's balance is the sum of their rows.
+//! Multiple proxies can watch the same contract. The ledger's unique index keeps them from crediting a deposit twice.
+
+use super::Web3ProxyApp;
+use crate::billing::update_user_tier;
+use crate::config::{BillingConfig, DepositConfig};
+use crate::email::Notification;
+use crate::frontend::authorization::Authorization;
+use crate::frontend::errors::FrontendErrorResponse;
+use anyhow::Context;
+use axum::http::StatusCode;
+use entities::sea_orm_active_enums::{BalanceLedgerKind, DepositStatus};
+use entities::{balance_ledger, deposit, user};
+use ethers::prelude::{Address, Block, Log, TransactionReceipt, TxHash, H256, U256, U64};
+use ethers::utils::keccak256;
+use hashbrown::HashMap;
+use log::{debug, info, trace, warn};
+use migration::sea_orm::prelude::Decimal;
+use migration::sea_orm::{
+    self, ActiveModelTrait, ColumnTrait, Condition, DatabaseConnection, DatabaseTransaction,
+    EntityTrait, QueryFilter, TransactionTrait,
+};
+use migration::sea_query::Expr;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// The event that the deposit contract emits. `account` is indexed.
+pub const PAYMENT_RECEIVED_EVENT: &str = "PaymentReceived(address,address,uint256)";
+
+/// A `PaymentReceived` log in one of the configured tokens
+#[derive(Debug, PartialEq, Eq)]
+pub struct Payment {
+    pub account: Address,
+    pub token: Address,
+    /// the token amount scaled by its decimals. 1 token is 1 USD
+    pub amount: Decimal,
+    pub tx_hash: TxHash,
+    pub log_index: u64,
+    pub block_hash: H256,
+    pub block_number: u64,
+}
+
+impl Payment {
+    /// Returns None if the log isn't a payment we accept
+    pub fn from_log(config: &DepositConfig, log: &Log) -> Option<Self> {
+        if log.address != config.contract
+            || log.removed == Some(true)
+            || log.topics.len() != 2
+            || log.topics[0] != H256::from(keccak256(PAYMENT_RECEIVED_EVENT))
+            || log.data.len() != 64
+        {
+            return None;
+        }
+
+        let account = Address::from(log.topics[1]);
+        let token = Address::from_slice(&log.data[12..32]);
+
+        let decimals = match config.tokens.get(&token) {
+            Some(x) => *x,
+            None => {
+                debug!("ignoring payment in unknown token {:?}", token);
+                return None;
+            }
+        };
+
+        let amount = U256::from_big_endian(&log.data[32..64]);
+
+        // Decimal only has 96 bits. nobody is depositing that much
+        if amount.bits() > 96 {
+            warn!("ignoring payment that is too large: {:?}", log);
+            return None;
+        }
+
+        let amount = Decimal::try_from_i128_with_scale(amount.as_u128() as i128, decimals).ok()?;
+
+        Some(Self {
+            account,
+            token,
+            amount,
+            tx_hash: log.transaction_hash?,
+            log_index: log.log_index?.as_u64(),
+            block_hash: log.block_hash?,
+            block_number: log.block_number?.as_u64(),
+        })
+    }
+}
+
+impl Web3ProxyApp {
+    /// Scan for new payments and credit the ones that are deep enough. Runs until the app exits.
+    pub(super) async fn watch_deposits(self: Arc<Self>) -> anyhow::Result<()> {
+        // the first scan goes back `lookback_blocks`. payments older than that can still be submitted with POST /user/balance/:txid
+        let mut scanned_to = None;
+
+        loop {
+            let config = self.config();
+
+            let deposit_config = match config.deposits.as_ref() {
+                Some(x) => x,
+                None => {
+                    // keep_restart_fields doesn't let this change, but there is no reason to panic if it does
+                    sleep(Duration::from_secs(60)).await;
+                    continue;
+                }
+            };
+
+            match self.check_deposits(deposit_config, scanned_to).await {
+                Ok(x) => scanned_to = Some(x),
+                Err(err) => warn!("failed checking deposits. err={:?}", err),
+            }
+
+            sleep(Duration::from_secs(deposit_config.poll_seconds.max(1))).await;
+        }
+    }
+
+    /// Returns the block that was scanned up to
+    async fn check_deposits(
+        &self,
+        deposit_config: &DepositConfig,
+        scanned_to: Option<u64>,
+    ) -> anyhow::Result<u64> {
+        let chain_id = self.config().chain_id;
+
+        let db_conn = self.db_conn().context("deposits require a db")?;
+
+        let authorization = Arc::new(Authorization::internal(Some(db_conn.clone()))?);
+
+        let head_block_num = self
+            .balanced_rpcs
+            .head_block_num()
+            .context("no servers synced")?
+            .as_u64();
+
+        // rescan recent blocks every time. a payment that was reorged out might be back in a new block
+        let mut from_block = match scanned_to {
+            Some(x) => x.saturating_sub(deposit_config.reorg_depth) + 1,
+            None => head_block_num.saturating_sub(deposit_config.lookback_blocks),
+        };
+
+        while from_block <= head_block_num {
+            let to_block =
+                head_block_num.min(from_block + deposit_config.max_blocks_per_scan.max(1) - 1);
+
+            let logs: Vec<Log> = self
+                .internal_request(
+                    &authorization,
+                    "eth_getLogs",
+                    json!([{
+                        "address": deposit_config.contract,
+                        "topics": [H256::from(keccak256(PAYMENT_RECEIVED_EVENT))],
+                        "fromBlock": U64::from(from_block),
+                        "toBlock": U64::from(to_block),
+                    }]),
+                    Some(from_block.into()),
+                )
+                .await?;
+
+            trace!(
+                "{} deposit logs in {}..={}",
+                logs.len(),
+                from_block,
+                to_block
+            );
+
+            for log in logs {
+                if let Some(payment) = Payment::from_log(deposit_config, &log) {
+                    save_payment(&db_conn, chain_id, &payment, head_block_num).await?;
+                }
+            }
+
+            from_block = to_block + 1;
+        }
+
+        self.confirm_deposits(&db_conn, &authorization, deposit_config, head_block_num)
+            .await?;
+
+        Ok(head_block_num)
+    }
+
+    /// Compare pending and recently credited deposits against the canonical chain.
+    /// Deposits in blocks that are no longer canonical are reorged. Deep enough deposits are credited.
+    async fn confirm_deposits(
+        &self,
+        db_conn: &DatabaseConnection,
+        authorization: &Arc<Authorization>,
+        deposit_config: &DepositConfig,
+        head_block_num: u64,
+    ) -> anyhow::Result<()> {
+        let config = self.config();
+
+        let chain_id = config.chain_id;
+
+        let billing = config.billing.as_ref();
+
+        let recheck_after = head_block_num.saturating_sub(deposit_config.reorg_depth);
+
+        let deposits = deposit::Entity::find()
+            .filter(deposit::Column::ChainId.eq(chain_id))
+            .filter(
+                Condition::any()
+                    .add(deposit::Column::Status.eq(DepositStatus::Pending))
+                    .add(
+                        Condition::all()
+                            .add(deposit::Column::Status.eq(DepositStatus::Credited))
+                            .add(deposit::Column::BlockNumber.gt(recheck_after)),
+                    ),
+            )
+            .all(db_conn)
+            .await?;
+
+        // many deposits can be in the same block
+        let mut canonical_hashes: HashMap<u64, Option<H256>> = HashMap::new();
+
+        for deposit in deposits {
+            let canonical_hash = match canonical_hashes.get(&deposit.block_number.0) {
+                Some(x) => *x,
+                None => {
+                    let block: Option<Block<TxHash>> = self
+                        .internal_request(
+                            authorization,
+                            "eth_getBlockByNumber",
+                            json!([U64::from(deposit.block_number.0), false]),
+                            Some(deposit.block_number.0.into()),
+                        )
+                        .await?;
+
+                    let hash = block.and_then(|x| x.hash);
+
+                    canonical_hashes.insert(deposit.block_number.0, hash);
+
+                    hash
+                }
+            };
+
+            let canonical_hash = match canonical_hash {
+                Some(x) => x,
+                None => {
+                    // our servers are behind the block that the log came from. check again later
+                    continue;
+                }
+            };
+
+            if canonical_hash.as_bytes() != deposit.block_hash.as_slice() {
+                if let Some(true) = reorg_deposit(db_conn, billing, &deposit).await? {
+                    self.invalidate_user_rpc_keys(deposit.user_id.into())
+                        .await?;
+                }
+
+                continue;
+            }
+
+            let confirmations = head_block_num.saturating_sub(deposit.block_number.0) + 1;
+
+            if deposit.status == DepositStatus::Pending {
+                if confirmations >= deposit_config.confirmations {
+                    let tier_changed =
+                        match credit_deposit(db_conn, billing, &deposit, confirmations).await? {
+                            Some(x) => x,
+                            // another proxy got here first
+                            None => continue,
+                        };
+
+                    self.notify_user(
+                        deposit.user_id.into(),
+                        Notification::DepositCredited {
+                            amount: deposit.amount,
+                        },
+                    );
+
+                    if tier_changed {
+                        self.invalidate_user_rpc_keys(deposit.user_id.into())
+                            .await?;
+                    }
+                } else if confirmations != deposit.confirmations {
+                    deposit::Entity::update_many()
+                        .col_expr(deposit::Column::Confirmations, Expr::value(confirmations))
+                        .filter(deposit::Column::Id.eq(deposit.id))
+                        .exec(db_conn)
+                        .await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Process the payments in a transaction that the deposit watcher might have missed.
+    /// Returns the deposits in the transaction. They stay pending until they have enough confirmations.
+    pub async fn submit_deposit_tx(
+        &self,
+        tx_hash: TxHash,
+    ) -> Result<Vec<deposit::Model>, FrontendErrorResponse> {
+        let config = self.config();
+
+        let deposit_config = config.deposits.as_ref().ok_or_else(|| {
+            FrontendErrorResponse::StatusCode(
+                StatusCode::NOT_FOUND,
+                "deposits are not enabled".to_string(),
+                None,
+            )
+        })?;
+
+        let db_conn = self.db_conn().context("deposits require a db")?;
+
+        let authorization = Arc::new(Authorization::internal(Some(db_conn.clone()))?);
+
+        let head_block_num = self
+            .balanced_rpcs
+            .head_block_num()
+            .context("no servers synced")?
+            .as_u64();
+
+        let receipt: Option<TransactionReceipt> = self
+            .internal_request(
+                &authorization,
+                "eth_getTransactionReceipt",
+                json!([tx_hash]),
+                None,
+            )
+            .await?;
+
+        let receipt = receipt.ok_or_else(|| {
+            FrontendErrorResponse::StatusCode(
+                StatusCode::NOT_FOUND,
+                "transaction not found".to_string(),
+                None,
+            )
+        })?;
+
+        if receipt.status != Some(1.into()) {
+            return Err(FrontendErrorResponse::BadRequest(
+                "transaction reverted".to_string(),
+            ));
+        }
+
+        let mut deposits = vec![];
+
+        for log in receipt.logs.iter() {
+            if let Some(payment) = Payment::from_log(deposit_config, log) {
+                deposits
+                    .push(save_payment(&db_conn, config.chain_id, &payment, head_block_num).await?);
+            }
+        }
+
+        Ok(deposits)
+    }
+}
+
+/// Record a payment as a pending deposit. Saving the same payment again does nothing.
+/// A reorged deposit that is back in the same block is pending again.
+/// The account is registered if this is the first we have heard of it.
+async fn save_payment(
+    db_conn: &DatabaseConnection,
+    chain_id: u64,
+    payment: &Payment,
+    head_block_num: u64,
+) -> anyhow::Result<deposit::Model> {
+    let find_deposit = || {
+        deposit::Entity::find()
+            .filter(deposit::Column::ChainId.eq(chain_id))
+            .filter(deposit::Column::TxHash.eq(payment.tx_hash.as_bytes()))
+            .filter(deposit::Column::LogIndex.eq(payment.log_index))
+            .filter(deposit::Column::BlockHash.eq(payment.block_hash.as_bytes()))
+            .one(db_conn)
+    };
+
+    if let Some(x) = find_deposit().await? {
+        if x.status != DepositStatus::Reorged {
+            return Ok(x);
+        }
+
+        // the chain went back to the block with this log. confirm_deposits counts its confirmations again
+        let updated = deposit::Entity::update_many()
+            .col_expr(deposit::Column::Status, Expr::value(DepositStatus::Pending))
+            .filter(deposit::Column::Id.eq(x.id))
+            .filter(deposit::Column::Status.eq(DepositStatus::Reorged))
+            .exec(db_conn)
+            .await?;
+
+        if updated.rows_affected > 0 {
+            info!("deposit {} is back in block {}", x.id, payment.block_number);
+        }
+
+        return find_deposit().await?.context("reloading deposit");
+    }
+
+    let user = get_or_create_deposit_user(db_conn, payment.account).await?;
+
+    let new_deposit = deposit::ActiveModel {
+        chain_id: sea_orm::Set(chain_id.into()),
+        tx_hash: sea_orm::Set(payment.tx_hash.as_bytes().to_vec()),
+        log_index: sea_orm::Set(payment.log_index.into()),
+        block_hash: sea_orm::Set(payment.block_hash.as_bytes().to_vec()),
+        block_number: sea_orm::Set(payment.block_number.into()),
+        user_id: sea_orm::Set(user.id),
+        token: sea_orm::Set(payment.token.as_bytes().to_vec()),
+        amount: sea_orm::Set(payment.amount),
+        confirmations: sea_orm::Set(
+            (head_block_num.saturating_sub(payment.block_number) + 1).into(),
+        ),
+        status: sea_orm::Set(DepositStatus::Pending),
+        ..Default::default()
+    };
+
+    match new_deposit.insert(db_conn).await {
+        Ok(x) => {
+            info!(
+                "new deposit of {} from {:?} in {:?}",
+                x.amount, payment.account, payment.tx_hash
+            );
+
+            Ok(x)
+        }
+        Err(err) => {
+            // another proxy might have saved it first
+            find_deposit().await?.context(err).context("saving deposit")
+        }
+    }
+}
+
+async fn get_or_create_deposit_user(
+    db_conn: &DatabaseConnection,
+    address: Address,
+) -> anyhow::Result<user::Model> {
+    let find_user = || {
+        user::Entity::find()
+            .filter(user::Column::Address.eq(address.as_bytes()))
+            .one(db_conn)
+    };
+
+    if let Some(x) = find_user().await? {
+        return Ok(x);
+    }
+
+    // they can log in later to get a key. paying is a better filter than an invite code
+    let new_user = user::ActiveModel {
+        address: sea_orm::Set(address.as_bytes().to_vec()),
+        ..Default::default()
+    };
+
+    match new_user.insert(db_conn).await {
+        Ok(x) => {
+            info!("registered {:?} from their deposit", address);
+
+            Ok(x)
+        }
+        Err(err) => find_user()
+            .await?
+            .context(err)
+            .context("registering deposit user"),
+    }
+}
+
+/// Move a pending deposit to the user's balance.
+/// Returns None if another proxy credited it first. Otherwise returns true if the user's tier changed.
+async fn credit_deposit(
+    db_conn: &DatabaseConnection,
+    billing: Option<&BillingConfig>,
+    deposit: &deposit::Model,
+    confirmations: u64,
+) -> anyhow::Result<Option<bool>> {
+    let txn = db_conn.begin().await?;
+
+    // only one proxy gets to flip the status
+    let updated = deposit::Entity::update_many()
+        .col_expr(
+            deposit::Column::Status,
+            Expr::value(DepositStatus::Credited),
+        )
+        .col_expr(deposit::Column::Confirmations, Expr::value(confirmations))
+        .filter(deposit::Column::Id.eq(deposit.id))
+        .filter(deposit::Column::Status.eq(DepositStatus::Pending))
+        .exec(&txn)
+        .await?;
+
+    if updated.rows_affected == 0 {
+        return Ok(None);
+    }
+
+    // a deposit that was credited before it was reorged out already has a credit. undo the reversal instead
+    let reversals = balance_ledger::Entity::delete_many()
+        .filter(balance_ledger::Column::DepositId.eq(deposit.id))
+        .filter(balance_ledger::Column::Kind.eq(BalanceLedgerKind::Reorg))
+        .exec(&txn)
+        .await?;
+
+    if reversals.rows_affected == 0 {
+        balance_ledger::ActiveModel {
+            user_id: sea_orm::Set(deposit.user_id),
+            amount: sea_orm::Set(deposit.amount),
+            kind: sea_orm::Set(BalanceLedgerKind::Deposit),
+            deposit_id: sea_orm::Set(Some(deposit.id)),
+            ..Default::default()
+        }
+        .insert(&txn)
+        .await?;
+    }
+
+    let tier_changed = update_deposit_user_tier(&txn, billing, deposit.user_id.into()).await?;
+
+    txn.commit().await?;
+
+    info!(
+        "credited deposit {} of {} to user {}",
+        deposit.id, deposit.amount, deposit.user_id
+    );
+
+    Ok(Some(tier_changed))
+}
+
+/// The deposit's block is no longer canonical. If it was already credited, take the credit back.
+/// Returns None if another proxy reorged it first. Otherwise returns true if the user's tier changed.
+async fn reorg_deposit(
+    db_conn: &DatabaseConnection,
+    billing: Option<&BillingConfig>,
+    deposit: &deposit::Model,
+) -> anyhow::Result<Option<bool>> {
+    let txn = db_conn.begin().await?;
+
+    let updated = deposit::Entity::update_many()
+        .col_expr(deposit::Column::Status, Expr::value(DepositStatus::Reorged))
+        .filter(deposit::Column::Id.eq(deposit.id))
+        .filter(deposit::Column::Status.eq(deposit.status.clone()))
+        .exec(&txn)
+        .await?;
+
+    if updated.rows_affected == 0 {
+        return Ok(None);
+    }
+
+    if deposit.status == DepositStatus::Credited {
+        balance_ledger::ActiveModel {
+            user_id: sea_orm::Set(deposit.user_id),
+            amount: sea_orm::Set(-deposit.amount),
+            kind: sea_orm::Set(BalanceLedgerKind::Reorg),
+            deposit_id: sea_orm::Set(Some(deposit.id)),
+            ..Default::default()
+        }
+        .insert(&txn)
+        .await?;
+    }
+
+    let tier_changed = update_deposit_user_tier(&txn, billing, deposit.user_id.into()).await?;
+
+    txn.commit().await?;
+
+    warn!(
+        "deposit {} was reorged out of block {}. it was {:?}",
+        deposit.id, deposit.block_number, deposit.status
+    );
+
+    Ok(Some(tier_changed))
+}
+
+/// A deposit can give a user the balance to go back to their paid tier. A reorg can take it away
+async fn update_deposit_user_tier(
+    txn: &DatabaseTransaction,
+    billing: Option<&BillingConfig>,
+    user_id: u64,
+) -> anyhow::Result<bool> {
+    match billing {
+        Some(billing) => Ok(update_user_tier(txn, billing, user_id).await?.is_some()),
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::billing::get_user_balance;
+    use ethers::types::Bytes;
+    use migration::sea_orm::Database;
+    use migration::{Migrator, MigratorTrait};
+
+    fn payment_log(token: Address, amount: U256) -> Log {
+        let mut data = [0u8; 64];
+        data[12..32].copy_from_slice(token.as_bytes());
+        amount.to_big_endian(&mut data[32..64]);
+
+        Log {
+            address: Address::repeat_byte(1),
+            topics: vec![
+                H256::from(keccak256(PAYMENT_RECEIVED_EVENT)),
+                H256::from(Address::repeat_byte(2)),
+            ],
+            data: Bytes::from(data.to_vec()),
+            block_hash: Some(H256::repeat_byte(3)),
+            block_number: Some(100.into()),
+            transaction_hash: Some(H256::repeat_byte(4)),
+            log_index: Some(5.into()),
+            ..Default::default()
+        }
+    }
+
+    fn deposit_config() -> DepositConfig {
+        DepositConfig {
+            contract: Address::repeat_byte(1),
+            tokens: HashMap::from([(Address::repeat_byte(6), 6)]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn payment_from_log() {
+        let log = payment_log(Address::repeat_byte(6), U256::from(12_500_000));
+
+        let payment = Payment::from_log(&deposit_config(), &log).unwrap();
+
+        assert_eq!(payment.account, Address::repeat_byte(2));
+        assert_eq!(payment.amount, Decimal::new(125, 1));
+        assert_eq!(payment.block_number, 100);
+        assert_eq!(payment.log_index, 5);
+    }
+
+    #[test]
+    fn payment_from_log_ignores_others() {
+        let config = deposit_config();
+
+        // unknown token
+        let log = payment_log(Address::repeat_byte(7), U256::from(1));
+        assert_eq!(Payment::from_log(&config, &log), None);
+
+        // some other contract
+        let mut log = payment_log(Address::repeat_byte(6), U256::from(1));
+        log.address = Address::repeat_byte(8);
+        assert_eq!(Payment::from_log(&config, &log), None);
+
+        // removed by a reorg
+        let mut log = payment_log(Address::repeat_byte(6), U256::from(1));
+        log.removed = Some(true);
+        assert_eq!(Payment::from_log(&config, &log), None);
+
+        // too big for a Decimal
+        let log = payment_log(Address::repeat_byte(6), U256::MAX);
+        assert_eq!(Payment::from_log(&config, &log), None);
+    }
+
+    #[tokio::test]
+    async fn reorged_deposit_comes_back() {
+        let db_conn = Database::connect("sqlite::memory:").await.unwrap();
+
+        Migrator::up(&db_conn, None).await.unwrap();
+
+        let log = payment_log(Address::repeat_byte(6), U256::from(12_500_000));
+
+        let payment = Payment::from_log(&deposit_config(), &log).unwrap();
+
+        let deposit = save_payment(&db_conn, 1, &payment, 100).await.unwrap();
+        assert_eq!(deposit.status, DepositStatus::Pending);
+
+        let user_id = deposit.user_id.into();
+
+        // a pending deposit is reorged out and then mined in the same block again
+        assert_eq!(
+            reorg_deposit(&db_conn, None, &deposit).await.unwrap(),
+            Some(false)
+        );
+
+        let deposit = save_payment(&db_conn, 1, &payment, 101).await.unwrap();
+        assert_eq!(deposit.status, DepositStatus::Pending);
+
+        assert_eq!(
+            credit_deposit(&db_conn, None, &deposit, 2).await.unwrap(),
+            Some(false)
+        );
+        assert_eq!(
+            get_user_balance(&db_conn, user_id).await.unwrap(),
+            payment.amount
+        );
+
+        // crediting twice does nothing
+        assert_eq!(
+            credit_deposit(&db_conn, None, &deposit, 2).await.unwrap(),
+            None
+        );
+
+        // a credited deposit is reorged out. the credit is taken back
+        let deposit = save_payment(&db_conn, 1, &payment, 102).await.unwrap();
+        assert_eq!(deposit.status, DepositStatus::Credited);
+
+        assert_eq!(
+            reorg_deposit(&db_conn, None, &deposit).await.unwrap(),
+            Some(false)
+        );
+        assert_eq!(
+            get_user_balance(&db_conn, user_id).await.unwrap(),
+            Decimal::ZERO
+        );
+
+        // and it comes back. the user is credited once
+        let deposit = save_payment(&db_conn, 1, &payment, 103).await.unwrap();
+        assert_eq!(deposit.status, DepositStatus::Pending);
+
+        assert_eq!(
+            credit_deposit(&db_conn, None, &deposit, 4).await.unwrap(),
+            Some(false)
+        );
+        assert_eq!(
+            get_user_balance(&db_conn, user_id).await.unwrap(),
+            payment.amount
+        );
+
+        let deposit = save_payment(&db_conn, 1, &payment, 103).await.unwrap();
+        assert_eq!(deposit.status, DepositStatus::Credited);
+    }
+}
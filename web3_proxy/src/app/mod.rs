@@ -1,22 +1,37 @@
 // TODO: this file is way too big now. move things into other modules
+mod block_session;
+mod deposits;
+mod notifications;
+mod rpc_admin;
+mod rpc_keys;
+mod shadow;
+mod shutdown;
+mod signatures;
 mod ws;
 
+pub use block_session::BlockSession;
+pub use shutdown::ShutdownPhase;
+
 use crate::app_stats::{ProxyResponseStat, StatEmitter, Web3ProxyStat};
 use crate::block_number::{block_needed, BlockNeeded};
 use crate::config::{AppConfig, TopConfig};
+use crate::email::{MailTransport, SmtpMailTransport};
 use crate::frontend::authorization::{Authorization, RequestMetadata, RpcSecretKey};
 use crate::frontend::errors::FrontendErrorResponse;
 use crate::frontend::rpc_proxy_ws::ProxyMode;
 use crate::jsonrpc::{
     JsonRpcForwardedResponse, JsonRpcForwardedResponseEnum, JsonRpcRequest, JsonRpcRequestEnum,
 };
+use crate::jwt::RpcJwtKey;
 use crate::rpcs::blockchain::Web3ProxyBlock;
 use crate::rpcs::many::Web3Rpcs;
 use crate::rpcs::one::Web3Rpc;
 use crate::rpcs::transactions::TxStatus;
 use crate::user_token::UserBearerToken;
 use anyhow::Context;
+use arc_swap::ArcSwap;
 use axum::headers::{Origin, Referer, UserAgent};
+use block_session::BLOCK_SESSION_TTL_SECONDS;
 use chrono::Utc;
 use deferred_rate_limiter::DeferredRateLimiter;
 use derive_more::From;
@@ -41,17 +56,19 @@ use rdkafka::message::{Header, OwnedHeaders};
 use rdkafka::producer::FutureRecord;
 use redis_rate_limiter::redis::AsyncCommands;
 use redis_rate_limiter::{redis, DeadpoolRuntime, RedisConfig, RedisPool, RedisRateLimiter};
+use serde::de::DeserializeOwned;
 use serde::Serialize;
 use serde_json::json;
-use serde_json::value::to_raw_value;
+use serde_json::value::{to_raw_value, RawValue};
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::net::IpAddr;
 use std::num::NonZeroU64;
 use std::str::FromStr;
-use std::sync::{atomic, Arc};
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::{broadcast, watch, Semaphore};
+use tokio::sync::{broadcast, watch, Mutex as AsyncMutex, Semaphore};
 use tokio::task::JoinHandle;
 use tokio::time::{sleep, timeout};
 use ulid::Ulid;
@@ -148,9 +165,37 @@ impl Hash for ResponseCacheKey {
     }
 }
 
+/// these caches don't have a weigher, so their capacity is a number of entries
+pub const PENDING_TRANSACTIONS_CAPACITY: u64 = 10_000;
+pub const RPC_SECRET_KEY_CACHE_CAPACITY: u64 = 10_000;
+pub const JWT_PUBLIC_KEY_CACHE_CAPACITY: u64 = 10_000;
+pub const BLOCK_SESSIONS_CAPACITY: u64 = 100_000;
+
 type ResponseCache =
     Cache<ResponseCacheKey, JsonRpcForwardedResponse, hashbrown::hash_map::DefaultHashBuilder>;
 
+/// responses can be very different in sizes, so this is a cache with a max capacity and a weigher
+// TODO: don't allow any response to be bigger than X% of the cache
+fn new_response_cache(max_bytes: u64) -> ResponseCache {
+    Cache::builder()
+        .max_capacity(max_bytes)
+        .weigher(|k: &ResponseCacheKey, v| {
+            // TODO: is this good enough?
+            if let Ok(v) = serde_json::to_string(v) {
+                let weight = k.weight() + v.len();
+
+                // the or in unwrap_or is probably never called
+                weight.try_into().unwrap_or(u32::MAX)
+            } else {
+                // this seems impossible
+                u32::MAX
+            }
+        })
+        // TODO: what should we set? 10 minutes is arbitrary. the nodes themselves hold onto transactions for much longer
+        .time_to_idle(Duration::from_secs(600))
+        .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default())
+}
+
 pub type AnyhowJoinHandle<T> = JoinHandle<anyhow::Result<T>>;
 
 #[derive(Clone, Debug, Default, From)]
@@ -205,12 +250,19 @@ pub struct Web3ProxyApp {
     pub http_client: Option<reqwest::Client>,
     /// Send private requests (like eth_sendRawTransaction) to all these servers
     pub private_rpcs: Option<Arc<Web3Rpcs>>,
-    response_cache: ResponseCache,
+    /// replaced when the config changes the max size
+    response_cache: ArcSwap<ResponseCache>,
     // don't drop this or the sender will stop working
     // TODO: broadcast channel instead?
     watch_consensus_head_receiver: watch::Receiver<Option<Web3ProxyBlock>>,
     pending_tx_sender: broadcast::Sender<TxStatus>,
-    pub config: AppConfig,
+    /// swapped when the config file changes. use `config()` to read it
+    config: ArcSwap<AppConfig>,
+    /// the last config given to `apply_top_config`. the lock keeps reloads from overlapping
+    applied_top_config: AsyncMutex<Option<TopConfig>>,
+    shutdown_phase_sender: watch::Sender<ShutdownPhase>,
+    /// websockets that have not been closed yet. used to end shutdown early
+    pub open_websockets: AtomicUsize,
     pub db_conn: Option<sea_orm::DatabaseConnection>,
     pub db_replica: Option<DatabaseReplica>,
     /// store pending transactions that we've seen so that we don't send duplicates to subscribers
@@ -222,6 +274,9 @@ pub struct Web3ProxyApp {
     // TODO: this key should be our RpcSecretKey class, not Ulid
     pub rpc_secret_key_cache:
         Cache<Ulid, AuthorizationChecks, hashbrown::hash_map::DefaultHashBuilder>,
+    /// public keys for rpc jwts by their `kid`. None if the key id is unknown
+    pub jwt_public_key_cache:
+        Cache<Ulid, Option<Arc<RpcJwtKey>>, hashbrown::hash_map::DefaultHashBuilder>,
     pub registered_user_semaphores:
         Cache<NonZeroU64, Arc<Semaphore>, hashbrown::hash_map::DefaultHashBuilder>,
     pub ip_semaphores: Cache<IpAddr, Arc<Semaphore>, hashbrown::hash_map::DefaultHashBuilder>,
@@ -229,6 +284,14 @@ pub struct Web3ProxyApp {
         Cache<UserBearerToken, Arc<Semaphore>, hashbrown::hash_map::DefaultHashBuilder>,
     pub stat_sender: Option<flume::Sender<Web3ProxyStat>>,
     pub kafka_producer: Option<rdkafka::producer::FutureProducer>,
+    /// None if email is not configured
+    pub mailer: Option<Arc<dyn MailTransport>>,
+    /// users that were recently told that they are being rate limited
+    rate_limit_notifications: Cache<u64, (), hashbrown::hash_map::DefaultHashBuilder>,
+    /// the highest block each monotonic reads session has seen
+    block_sessions: Cache<String, Arc<BlockSession>, hashbrown::hash_map::DefaultHashBuilder>,
+    /// unique to this process. used to ignore our own messages on redis pub/sub
+    instance_id: Ulid,
 }
 
 /// flatten a JoinError into an anyhow error
@@ -265,9 +328,17 @@ pub async fn get_db(
     // TODO: scrub credentials and then include the db_url in logs
     info!("Connecting to db");
 
+    // every connection to an in-memory sqlite db gets its own empty db. only one connection can be used
+    let (min_connections, max_connections) =
+        if db_url.starts_with("sqlite:") && db_url.contains(":memory:") {
+            (1, 1)
+        } else {
+            (min_connections, max_connections)
+        };
+
     let mut db_opt = sea_orm::ConnectOptions::new(db_url);
 
-    // TODO: load all these options from the config file. i think mysql default max is 100
+    // TODO: load all these options from the config file. i think mysql and postgres default max is 100
     // TODO: sqlx logging only in debug. way too verbose for production
     db_opt
         .connect_timeout(Duration::from_secs(30))
@@ -375,14 +446,9 @@ impl Web3ProxyApp {
         shutdown_receiver: broadcast::Receiver<()>,
     ) -> anyhow::Result<Web3ProxyAppSpawn> {
         // safety checks on the config
-        // while i would prefer this to be in a "apply_top_config" function, that is a larger refactor
+        // apply_top_config does these checks too, but some of the config is only used here
         // TODO: maybe don't spawn with a config at all. have all config updates come through an apply_top_config call
-        if let Some(redirect) = &top_config.app.redirect_rpc_key_url {
-            assert!(
-                redirect.contains("{{rpc_key_id}}"),
-                "redirect_rpc_key_url user url must contain \"{{rpc_key_id}}\""
-            );
-        }
+        top_config.app.validate()?;
 
         if !top_config.extra.is_empty() {
             warn!(
@@ -512,11 +578,33 @@ impl Web3ProxyApp {
             }
         };
 
+        // identifies this proxy to the others. also used to keep stats from different proxies apart
+        let instance_id = Ulid::new();
+
+        let mailer: Option<Arc<dyn MailTransport>> = match top_config.app.email.as_ref() {
+            Some(email_config) => {
+                let x = SmtpMailTransport::try_new(email_config).context("configuring email")?;
+
+                Some(Arc::new(x))
+            }
+            None => None,
+        };
+
         // setup a channel for receiving stats (generally with a high cardinality, such as per-user)
         // we do this in a channel so we don't slow down our response to the users
+        let (tier_change_sender, tier_change_receiver) = flume::unbounded();
+
         let stat_sender = if let Some(db_conn) = db_conn.clone() {
-            let emitter_spawn =
-                StatEmitter::spawn(top_config.app.chain_id, db_conn, 60, shutdown_receiver)?;
+            let emitter_spawn = StatEmitter::spawn(
+                top_config.app.chain_id,
+                db_conn,
+                60,
+                top_config.app.billing.clone(),
+                mailer.clone(),
+                tier_change_sender,
+                instance_id,
+                shutdown_receiver,
+            )?;
 
             important_background_handles.push(emitter_spawn.background_handle);
 
@@ -596,41 +684,35 @@ impl Web3ProxyApp {
         // all these are the same size, so no need for a weigher
         // TODO: ttl on this? or is max_capacity fine?
         let pending_transactions = Cache::builder()
-            .max_capacity(10_000)
+            .max_capacity(PENDING_TRANSACTIONS_CAPACITY)
             // TODO: different chains might handle this differently
             // TODO: what should we set? 5 minutes is arbitrary. the nodes themselves hold onto transactions for much longer
             .time_to_idle(Duration::from_secs(300))
             .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
 
-        // responses can be very different in sizes, so this is a cache with a max capacity and a weigher
-        // TODO: don't allow any response to be bigger than X% of the cache
-        let response_cache = Cache::builder()
-            .max_capacity(top_config.app.response_cache_max_bytes)
-            .weigher(|k: &ResponseCacheKey, v| {
-                // TODO: is this good enough?
-                if let Ok(v) = serde_json::to_string(v) {
-                    let weight = k.weight() + v.len();
-
-                    // the or in unwrap_or is probably never called
-                    weight.try_into().unwrap_or(u32::MAX)
-                } else {
-                    // this seems impossible
-                    u32::MAX
-                }
-            })
-            // TODO: what should we set? 10 minutes is arbitrary. the nodes themselves hold onto transactions for much longer
-            .time_to_idle(Duration::from_secs(600))
-            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+        let response_cache = new_response_cache(top_config.app.response_cache_max_bytes);
 
         // all the users are the same size, so no need for a weigher
         // if there is no database of users, there will be no keys and so this will be empty
         // TODO: max_capacity from config
         // TODO: ttl from config
         let rpc_secret_key_cache = Cache::builder()
-            .max_capacity(10_000)
+            .max_capacity(RPC_SECRET_KEY_CACHE_CAPACITY)
             .time_to_live(Duration::from_secs(600))
             .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
 
+        // deleted public keys keep working on other proxies until they expire from here
+        let jwt_public_key_cache = Cache::builder()
+            .max_capacity(JWT_PUBLIC_KEY_CACHE_CAPACITY)
+            .time_to_live(Duration::from_secs(600))
+            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+
+        // redis has the real sessions. these are local copies
+        let block_sessions = Cache::builder()
+            .max_capacity(BLOCK_SESSIONS_CAPACITY)
+            .time_to_idle(Duration::from_secs(BLOCK_SESSION_TTL_SECONDS))
+            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+
         // create semaphores for concurrent connection limits
         // TODO: what should tti be for semaphores?
         let bearer_token_semaphores = Cache::builder()
@@ -643,6 +725,11 @@ impl Web3ProxyApp {
             .time_to_idle(Duration::from_secs(120))
             .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
 
+        // proxies also check redis so that users get one email a day no matter how many proxies they hit
+        let rate_limit_notifications = Cache::builder()
+            .time_to_live(Duration::from_secs(86_400))
+            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+
         // prepare a Web3Rpcs to hold all our balanced connections
         let (balanced_rpcs, balanced_rpcs_handle) = Web3Rpcs::spawn(
             top_config.app.chain_id,
@@ -652,6 +739,7 @@ impl Web3ProxyApp {
             top_config.app.max_block_lag,
             top_config.app.min_synced_rpcs,
             top_config.app.min_sum_soft_limit,
+            top_config.app.hedge.clone(),
             pending_transactions.clone(),
             Some(pending_tx_sender.clone()),
             Some(watch_consensus_head_sender),
@@ -677,6 +765,8 @@ impl Web3ProxyApp {
                 None,
                 0,
                 0,
+                // private rpcs are only used for transactions. those are already sent to multiple servers
+                None,
                 pending_transactions.clone(),
                 // TODO: subscribe to pending transactions on the private rpcs? they seem to have low rate limits, but they should have
                 None,
@@ -695,12 +785,16 @@ impl Web3ProxyApp {
         };
 
         let app = Self {
-            config: top_config.app.clone(),
+            config: ArcSwap::from_pointee(top_config.app.clone()),
+            applied_top_config: AsyncMutex::new(None),
+            shutdown_phase_sender: watch::channel(ShutdownPhase::default()).0,
+            open_websockets: AtomicUsize::new(0),
             balanced_rpcs,
             http_client,
             kafka_producer,
+            mailer,
             private_rpcs,
-            response_cache,
+            response_cache: ArcSwap::from_pointee(response_cache),
             watch_consensus_head_receiver,
             pending_tx_sender,
             pending_transactions,
@@ -711,14 +805,50 @@ impl Web3ProxyApp {
             db_replica,
             vredis_pool,
             rpc_secret_key_cache,
+            jwt_public_key_cache,
+            block_sessions,
+            instance_id,
             bearer_token_semaphores,
             ip_semaphores,
             registered_user_semaphores,
+            rate_limit_notifications,
             stat_sender,
         };
 
         let app = Arc::new(app);
 
+        // admins can change rpcs on any proxy. listen for their changes
+        if let Some(redis_url) = top_config.app.volatile_redis_url.clone() {
+            let handle = tokio::spawn(app.clone().subscribe_rpc_commands(redis_url));
+
+            app_handles.push(handle);
+        }
+
+        // rotated and deleted keys need to be forgotten by every proxy
+        if let Some(redis_url) = top_config.app.volatile_redis_url.clone() {
+            let handle = tokio::spawn(app.clone().subscribe_rpc_key_invalidations(redis_url));
+
+            app_handles.push(handle);
+        }
+
+        if app.db_conn.is_some() {
+            let handle = tokio::spawn(app.clone().watch_rpc_key_expirations());
+
+            app_handles.push(handle);
+
+            // users that ran out of balance have new limits
+            let handle = tokio::spawn(app.clone().watch_tier_changes(tier_change_receiver));
+
+            app_handles.push(handle);
+        }
+
+        // credit user balances with payments to the deposit contract
+        if top_config.app.deposits.is_some() && app.db_conn.is_some() {
+            let handle = tokio::spawn(app.clone().watch_deposits());
+
+            app_handles.push(handle);
+        }
+
         // watch for config changes
         // TODO: initial config reload should be from this channel. not from the call to spawn
 
@@ -727,12 +857,21 @@ impl Web3ProxyApp {
         {
             let app = app.clone();
             let config_handle = tokio::spawn(async move {
+                let mut first = true;
+
                 loop {
                     let new_top_config = new_top_config_receiver.borrow_and_update().to_owned();
 
-                    app.apply_top_config(new_top_config)
-                        .await
-                        .context("failed applying new top_config")?;
+                    if let Err(err) = app.apply_top_config(new_top_config).await {
+                        if first {
+                            return Err(err.context("failed applying top_config"));
+                        }
+
+                        // the running config is still good. keep using it
+                        error!("rejected new top_config. err={:?}", err);
+                    }
+
+                    first = false;
 
                     new_top_config_receiver
                         .changed()
@@ -755,25 +894,114 @@ impl Web3ProxyApp {
             .into())
     }
 
-    pub async fn apply_top_config(&self, new_top_config: TopConfig) -> anyhow::Result<()> {
-        // TODO: also update self.config from new_top_config.app
+    /// Check the whole config before changing anything. If any of it is bad, the running config is kept.
+    /// Fields that can't change without a restart keep their running values.
+    pub async fn apply_top_config(&self, mut new_top_config: TopConfig) -> anyhow::Result<()> {
+        // only one reload at a time
+        let mut applied_top_config = self.applied_top_config.lock().await;
 
-        // connect to the backends
-        self.balanced_rpcs
-            .apply_server_configs(self, new_top_config.balanced_rpcs)
-            .await?;
+        new_top_config.app.validate()?;
 
-        if let Some(private_rpc_configs) = new_top_config.private_rpcs {
-            if let Some(private_rpcs) = self.private_rpcs.as_ref() {
-                private_rpcs
-                    .apply_server_configs(self, private_rpc_configs)
-                    .await?;
-            } else {
+        let running_config = self.config();
+
+        let needs_restart = new_top_config.app.keep_restart_fields(&running_config);
+
+        if !needs_restart.is_empty() {
+            warn!(
+                "restart to apply changes to these fields: {:?}",
+                needs_restart
+            );
+        }
+
+        Web3Rpcs::check_server_configs(
+            &new_top_config.balanced_rpcs,
+            new_top_config.app.min_synced_rpcs,
+            new_top_config.app.min_sum_soft_limit,
+        )
+        .context("checking balanced_rpcs")?;
+
+        if let Some(private_rpc_configs) = new_top_config.private_rpcs.as_ref() {
+            if self.private_rpcs.is_none() && !private_rpc_configs.is_empty() {
                 // TODO: maybe we should have private_rpcs just be empty instead of being None
-                todo!("handle toggling private_rpcs")
+                return Err(anyhow::anyhow!("restart to add private_rpcs"));
             }
+
+            Web3Rpcs::check_server_configs(private_rpc_configs, 0, 0)
+                .context("checking private_rpcs")?;
+        }
+
+        // reconnecting to the backends is slow. skip it if their configs did not change
+        let (rpcs_changed, private_rpcs_changed) = match applied_top_config.as_ref() {
+            None => (true, true),
+            Some(x) => (
+                x.balanced_rpcs != new_top_config.balanced_rpcs,
+                x.private_rpcs != new_top_config.private_rpcs,
+            ),
+        };
+
+        // connect to everything before changing anything. if any of this fails, the running config is untouched
+        let new_rpcs = if rpcs_changed {
+            self.balanced_rpcs
+                .connect_server_configs(self, new_top_config.balanced_rpcs.clone())
+                .await
+                .context("connecting to balanced_rpcs")?
+        } else {
+            vec![]
+        };
+
+        let new_private_rpcs = match (
+            private_rpcs_changed,
+            self.private_rpcs.as_ref(),
+            new_top_config.private_rpcs.clone(),
+        ) {
+            (true, Some(private_rpcs), Some(private_rpc_configs)) => {
+                match private_rpcs
+                    .connect_server_configs(self, private_rpc_configs)
+                    .await
+                {
+                    Ok(x) => x,
+                    Err(err) => {
+                        Web3Rpcs::disconnect_rpcs(new_rpcs).await;
+
+                        return Err(err.context("connecting to private_rpcs"));
+                    }
+                }
+            }
+            _ => vec![],
+        };
+
+        let new_response_cache = if new_top_config.app.response_cache_max_bytes
+            != running_config.response_cache_max_bytes
+        {
+            Some(new_response_cache(
+                new_top_config.app.response_cache_max_bytes,
+            ))
+        } else {
+            None
+        };
+
+        // everything looks good. start changing things. nothing below here can fail
+        if let Some(new_response_cache) = new_response_cache {
+            info!(
+                "resizing the response cache to {} bytes. it will start empty",
+                new_top_config.app.response_cache_max_bytes
+            );
+
+            self.response_cache.store(Arc::new(new_response_cache));
         }
 
+        self.balanced_rpcs.apply_app_config(&new_top_config.app);
+
+        self.config.store(Arc::new(new_top_config.app.clone()));
+
+        self.balanced_rpcs.swap_rpcs(new_rpcs).await;
+
+        if let Some(private_rpcs) = self.private_rpcs.as_ref() {
+            private_rpcs.swap_rpcs(new_private_rpcs).await;
+        }
+
+        *applied_top_config = Some(new_top_config);
+
         Ok(())
     }
 
@@ -837,10 +1065,10 @@ impl Web3ProxyApp {
                 let one_hour_ago = Utc::now().timestamp() - ONE_HOUR;
                 let one_minute_ago = Utc::now().timestamp() - ONE_MINUTE;
 
-                let recent_users_by_id = format!("recent_users:id:{}", self.config.chain_id);
-                let recent_users_by_ip = format!("recent_users:ip:{}", self.config.chain_id);
+                let recent_users_by_id = format!("recent_users:id:{}", self.config().chain_id);
+                let recent_users_by_ip = format!("recent_users:ip:{}", self.config().chain_id);
                 let recent_transactions =
-                    format!("eth_sendRawTransaction:{}", self.config.chain_id);
+                    format!("eth_sendRawTransaction:{}", self.config().chain_id);
 
                 match redis::pipe()
                     .atomic()
@@ -958,11 +1186,14 @@ impl Web3ProxyApp {
     }
 
     /// send the request or batch of requests to the approriate RPCs
+    /// also returns the block that the response was computed at (if any)
     pub async fn proxy_web3_rpc(
         self: &Arc<Self>,
         authorization: Arc<Authorization>,
         request: JsonRpcRequestEnum,
-    ) -> Result<(JsonRpcForwardedResponseEnum, Vec<Arc<Web3Rpc>>), FrontendErrorResponse> {
+        block_session: Option<&BlockSession>,
+    ) -> Result<(JsonRpcForwardedResponseEnum, Vec<Arc<Web3Rpc>>, Option<U64>), FrontendErrorResponse>
+    {
         // trace!(?request, "proxy_web3_rpc");
 
         // even though we have timeouts on the requests to our backend providers,
@@ -972,22 +1203,51 @@ impl Web3ProxyApp {
 
         let response = match request {
             JsonRpcRequestEnum::Single(request) => {
-                let (response, rpcs) = timeout(
-                    max_time,
-                    self.proxy_cached_request(&authorization, request, None),
-                )
+                let (response, rpcs, response_block) = timeout(max_time, async {
+                    // sessions need their head picked before the request. otherwise it is looked up when needed
+                    let head_block_num = match block_session {
+                        Some(block_session) => {
+                            Some(self.session_head_block_num(Some(block_session)).await?)
+                        }
+                        None => None,
+                    };
+
+                    self.proxy_cached_request(&authorization, request, head_block_num)
+                        .await
+                })
                 .await??;
 
-                (JsonRpcForwardedResponseEnum::Single(response), rpcs)
+                if response.error.is_none() {
+                    if let (Some(block_session), Some(response_block)) =
+                        (block_session, response_block)
+                    {
+                        self.block_session_saw(block_session, response_block).await;
+                    }
+                }
+
+                (
+                    JsonRpcForwardedResponseEnum::Single(response),
+                    rpcs,
+                    response_block,
+                )
             }
             JsonRpcRequestEnum::Batch(requests) => {
-                let (responses, rpcs) = timeout(
+                let (responses, rpcs, response_block) = timeout(
                     max_time,
-                    self.proxy_web3_rpc_requests(&authorization, requests),
+                    self.proxy_web3_rpc_requests(&authorization, requests, block_session),
                 )
                 .await??;
 
-                (JsonRpcForwardedResponseEnum::Batch(responses), rpcs)
+                if let (Some(block_session), Some(response_block)) = (block_session, response_block)
+                {
+                    self.block_session_saw(block_session, response_block).await;
+                }
+
+                (
+                    JsonRpcForwardedResponseEnum::Batch(responses),
+                    rpcs,
+                    response_block,
+                )
             }
         };
 
@@ -1000,7 +1260,15 @@ impl Web3ProxyApp {
         self: &Arc<Self>,
         authorization: &Arc<Authorization>,
         requests: Vec<JsonRpcRequest>,
-    ) -> Result<(Vec<JsonRpcForwardedResponse>, Vec<Arc<Web3Rpc>>), FrontendErrorResponse> {
+        block_session: Option<&BlockSession>,
+    ) -> Result<
+        (
+            Vec<JsonRpcForwardedResponse>,
+            Vec<Arc<Web3Rpc>>,
+            Option<U64>,
+        ),
+        FrontendErrorResponse,
+    > {
         // TODO: we should probably change ethers-rs to support this directly. they pushed this off to v2 though
         let num_requests = requests.len();
 
@@ -1010,10 +1278,7 @@ impl Web3ProxyApp {
         // get the head block now so that any requests that need it all use the same block
         // TODO: FrontendErrorResponse that handles "no servers synced" in a consistent way
         // TODO: this still has an edge condition if there is a reorg in the middle of the request!!!
-        let head_block_num = self
-            .balanced_rpcs
-            .head_block_num()
-            .context(anyhow::anyhow!("no servers synced"))?;
+        let head_block_num = self.session_head_block_num(block_session).await?;
 
         let responses = join_all(
             requests
@@ -1030,9 +1295,15 @@ impl Web3ProxyApp {
         let mut collected: Vec<JsonRpcForwardedResponse> = Vec::with_capacity(num_requests);
         let mut collected_rpc_names: HashSet<String> = HashSet::new();
         let mut collected_rpcs: Vec<Arc<Web3Rpc>> = vec![];
+        // the newest block of any successful response in the batch
+        let mut collected_block: Option<U64> = None;
         for response in responses {
             // TODO: any way to attach the tried rpcs to the error? it is likely helpful
-            let (response, rpcs) = response?;
+            let (response, rpcs, response_block) = response?;
+
+            if response.error.is_none() {
+                collected_block = collected_block.max(response_block);
+            }
 
             collected.push(response);
             collected_rpcs.extend(rpcs.into_iter().filter(|x| {
@@ -1045,7 +1316,12 @@ impl Web3ProxyApp {
             }));
         }
 
-        Ok((collected, collected_rpcs))
+        Ok((collected, collected_rpcs, collected_block))
+    }
+
+    /// The running app config. Reloads replace it, so don't hold on to it for long
+    pub fn config(&self) -> Arc<AppConfig> {
+        self.config.load_full()
     }
 
     /// TODO: i don't think we want or need this. just use app.db_conn, or maybe app.db_conn.clone() or app.db_conn.as_ref()
@@ -1069,13 +1345,53 @@ impl Web3ProxyApp {
         }
     }
 
+    /// Send a request to the balanced rpcs and parse its result. Internal requests don't count against any user's limits
+    pub(crate) async fn internal_request<R: DeserializeOwned>(
+        &self,
+        authorization: &Arc<Authorization>,
+        method: &str,
+        params: serde_json::Value,
+        min_block_needed: Option<U64>,
+    ) -> anyhow::Result<R> {
+        let request = JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: RawValue::from_string("1".to_string())?,
+            method: method.to_string(),
+            params: Some(params),
+        };
+
+        let response = timeout(
+            Duration::from_secs(30),
+            self.balanced_rpcs.try_send_best_consensus_head_connection(
+                authorization,
+                request,
+                None,
+                min_block_needed.as_ref(),
+                None,
+            ),
+        )
+        .await
+        .with_context(|| format!("timeout during {}", method))??;
+
+        if let Some(err) = response.error {
+            return Err(anyhow::anyhow!("{} failed: {}", method, err.message));
+        }
+
+        let result = response
+            .result
+            .with_context(|| format!("no result from {}", method))?;
+
+        serde_json::from_str(result.get()).with_context(|| format!("parsing {}", method))
+    }
+
     // #[measure([ErrorCount, HitCount, ResponseTime, Throughput])]
     async fn proxy_cached_request(
         self: &Arc<Self>,
         authorization: &Arc<Authorization>,
         mut request: JsonRpcRequest,
         head_block_num: Option<U64>,
-    ) -> Result<(JsonRpcForwardedResponse, Vec<Arc<Web3Rpc>>), FrontendErrorResponse> {
+    ) -> Result<(JsonRpcForwardedResponse, Vec<Arc<Web3Rpc>>, Option<U64>), FrontendErrorResponse>
+    {
         // trace!("Received request: {:?}", request);
 
         let request_metadata = Arc::new(RequestMetadata::new(REQUEST_PERIOD, request.num_bytes())?);
@@ -1098,7 +1414,7 @@ impl Web3ProxyApp {
 
                 let request_hash = Some(keccak256(&request_bytes));
 
-                let chain_id = self.config.chain_id;
+                let chain_id = self.config().chain_id;
 
                 // another item is added with the response, so initial_capacity is +1 what is needed here
                 let kafka_headers = OwnedHeaders::new_with_capacity(4)
@@ -1146,6 +1462,9 @@ impl Web3ProxyApp {
         let request_id = request.id.clone();
         let request_method = request.method.clone();
 
+        // the block that the response was computed at. only set for responses that depend on a block
+        let mut response_block = None;
+
         // TODO: if eth_chainId or net_version, serve those without querying the backend
         // TODO: don't clone?
         let partial_response: serde_json::Value = match request_method.as_ref() {
@@ -1225,6 +1544,7 @@ impl Web3ProxyApp {
                         Some(request_id),
                     ),
                     vec![],
+                    None,
                 ));
             }
             // TODO: implement these commands
@@ -1244,6 +1564,7 @@ impl Web3ProxyApp {
                         Some(request_id),
                     ),
                     vec![],
+                    None,
                 ));
             }
             // some commands can use local data or caches
@@ -1254,6 +1575,8 @@ impl Web3ProxyApp {
             "eth_blockNumber" => {
                 match head_block_num.or(self.balanced_rpcs.head_block_num()) {
                     Some(head_block_num) => {
+                        response_block = Some(head_block_num);
+
                         json!(head_block_num)
                     }
                     None => {
@@ -1265,7 +1588,7 @@ impl Web3ProxyApp {
                     }
                 }
             }
-            "eth_chainId" => json!(U64::from(self.config.chain_id)),
+            "eth_chainId" => json!(U64::from(self.config().chain_id)),
             // TODO: eth_callBundle (https://docs.flashbots.net/flashbots-auction/searchers/advanced/rpc-endpoint#eth_callbundle)
             // TODO: eth_cancelPrivateTransaction (https://docs.flashbots.net/flashbots-auction/searchers/advanced/rpc-endpoint#eth_cancelprivatetransaction, but maybe just reject)
             // TODO: eth_sendPrivateTransaction (https://docs.flashbots.net/flashbots-auction/searchers/advanced/rpc-endpoint#eth_sendprivatetransaction)
@@ -1293,19 +1616,20 @@ impl Web3ProxyApp {
                     // i think this is always an error response
                     let rpcs = request_metadata.backend_requests.lock().clone();
 
-                    return Ok((response, rpcs));
+                    return Ok((response, rpcs, None));
                 };
 
-                let gas_increase =
-                    if let Some(gas_increase_percent) = self.config.gas_increase_percent {
-                        let gas_increase = gas_estimate * gas_increase_percent / U256::from(100);
+                let config = self.config();
 
-                        let min_gas_increase = self.config.gas_increase_min.unwrap_or_default();
+                let gas_increase = if let Some(gas_increase_percent) = config.gas_increase_percent {
+                    let gas_increase = gas_estimate * gas_increase_percent / U256::from(100);
 
-                        gas_increase.max(min_gas_increase)
-                    } else {
-                        self.config.gas_increase_min.unwrap_or_default()
-                    };
+                    let min_gas_increase = config.gas_increase_min.unwrap_or_default();
+
+                    gas_increase.max(min_gas_increase)
+                } else {
+                    config.gas_increase_min.unwrap_or_default()
+                };
 
                 gas_estimate += gas_increase;
 
@@ -1333,6 +1657,7 @@ impl Web3ProxyApp {
                     // TODO: maybe having the third party rpcs in their own Web3Rpcs would be good for this
                     ProxyMode::Fastest(x) => Some(x * 4),
                     ProxyMode::Versus => None,
+                    ProxyMode::Quorum(x) => Some(x),
                 };
 
                 let (private_rpcs, num) = if let Some(private_rpcs) = self.private_rpcs.as_ref() {
@@ -1409,7 +1734,7 @@ impl Web3ProxyApp {
                 let rpcs = request_metadata.backend_requests.lock().clone();
 
                 // emit stats
-                if let Some(salt) = self.config.public_recent_ips_salt.as_ref() {
+                if let Some(salt) = self.config().public_recent_ips_salt.as_ref() {
                     if let Some(tx_hash) = response.result.clone() {
                         let now = Utc::now().timestamp();
                         let salt = salt.clone();
@@ -1424,7 +1749,7 @@ impl Web3ProxyApp {
                                         Bytes::from(keccak256(salted_tx_hash.as_bytes()));
 
                                     let recent_tx_hash_key =
-                                        format!("eth_sendRawTransaction:{}", app.config.chain_id);
+                                        format!("eth_sendRawTransaction:{}", app.config().chain_id);
 
                                     redis_conn
                                         .zadd(recent_tx_hash_key, hashed_tx_hash.to_string(), now)
@@ -1446,7 +1771,7 @@ impl Web3ProxyApp {
                     }
                 }
 
-                return Ok((response, rpcs));
+                return Ok((response, rpcs, None));
             }
             "eth_syncing" => {
                 // no stats on this. its cheap
@@ -1461,6 +1786,7 @@ impl Web3ProxyApp {
                         Some(request_id),
                     ),
                     vec![],
+                    None,
                 ));
             }
             "eth_unsubscribe" => {
@@ -1471,6 +1797,7 @@ impl Web3ProxyApp {
                         Some(request_id),
                     ),
                     vec![],
+                    None,
                 ));
             }
             "net_listening" => {
@@ -1502,6 +1829,7 @@ impl Web3ProxyApp {
                                     Some(request_id),
                                 ),
                                 vec![],
+                                None,
                             ));
                         }
 
@@ -1531,6 +1859,7 @@ impl Web3ProxyApp {
                                 Some(request_id),
                             ),
                             vec![],
+                            None,
                         ));
                     }
                 }
@@ -1543,6 +1872,7 @@ impl Web3ProxyApp {
                         Some(request_id),
                     ),
                     vec![],
+                    None,
                 ));
             }
             // anything else gets sent to backend rpcs and cached
@@ -1588,7 +1918,7 @@ impl Web3ProxyApp {
                             .block_hash(authorization, &block_num)
                             .await?;
 
-                        if block_depth < self.config.archive_depth {
+                        if block_depth < self.config().archive_depth {
                             request_metadata
                                 .archive_request
                                 .store(true, atomic::Ordering::Relaxed);
@@ -1618,7 +1948,7 @@ impl Web3ProxyApp {
                             .block_hash(authorization, &from_block_num)
                             .await?;
 
-                        if block_depth < self.config.archive_depth {
+                        if block_depth < self.config().archive_depth {
                             request_metadata
                                 .archive_request
                                 .store(true, atomic::Ordering::Relaxed);
@@ -1651,16 +1981,39 @@ impl Web3ProxyApp {
                 };
                 trace!("cache_key: {:#?}", cache_key);
 
+                let from_block_num = cache_key
+                    .as_ref()
+                    .and_then(|x| x.from_block.as_ref())
+                    .map(|x| *x.number());
+                let to_block_num = cache_key
+                    .as_ref()
+                    .and_then(|x| x.to_block.as_ref())
+                    .map(|x| *x.number());
+
+                // a quorum needs fresh answers from every server. it is also already checked, so skip the shadow check
+                let cache_key = cache_key
+                    .filter(|_| !matches!(authorization.checks.proxy_mode, ProxyMode::Quorum(_)));
+
+                // only responses for a single known block can be checked on another server
+                let shadow_check = match cache_key.as_ref() {
+                    Some(ResponseCacheKey {
+                        from_block: Some(block),
+                        to_block: None,
+                        ..
+                    }) if self.shadow_verify_sample(method) => {
+                        Some((request.clone(), block.clone()))
+                    }
+                    _ => None,
+                };
+
                 let mut response = {
                     let request_metadata = request_metadata.clone();
 
                     let authorization = authorization.clone();
 
                     if let Some(cache_key) = cache_key {
-                        let from_block_num = cache_key.from_block.as_ref().map(|x| *x.number());
-                        let to_block_num = cache_key.to_block.as_ref().map(|x| *x.number());
-
                         self.response_cache
+                            .load_full()
                             .try_get_with(cache_key, async move {
                                 // TODO: put the hash here instead of the block number? its in the request already.
                                 let mut response = self
@@ -1697,8 +2050,8 @@ impl Web3ProxyApp {
                                 &authorization,
                                 request,
                                 Some(&request_metadata),
-                                None,
-                                None,
+                                from_block_num.as_ref(),
+                                to_block_num.as_ref(),
                             )
                             .await?
                     }
@@ -1711,6 +2064,17 @@ impl Web3ProxyApp {
                 // TODO: DRY!
                 let rpcs = request_metadata.backend_requests.lock().clone();
 
+                if let Some((shadow_request, shadow_block)) = shadow_check {
+                    if response.error.is_none() {
+                        self.spawn_shadow_verify(
+                            shadow_request,
+                            shadow_block,
+                            response.clone(),
+                            rpcs.clone(),
+                        );
+                    }
+                }
+
                 if let Some(stat_sender) = self.stat_sender.as_ref() {
                     let response_stat = ProxyResponseStat::new(
                         method.to_string(),
@@ -1725,7 +2089,7 @@ impl Web3ProxyApp {
                         .context("stat_sender sending response_stat")?;
                 }
 
-                return Ok((response, rpcs));
+                return Ok((response, rpcs, to_block_num.or(from_block_num)));
             }
         };
 
@@ -1774,7 +2138,7 @@ impl Web3ProxyApp {
             tokio::spawn(f);
         }
 
-        Ok((response, rpcs))
+        Ok((response, rpcs, response_block))
     }
 }
 
@@ -1784,3 +2148,72 @@ impl fmt::Debug for Web3ProxyApp {
         f.debug_struct("Web3ProxyApp").finish_non_exhaustive()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpcs::mock::{wait_for_head, MockChain, MockNode};
+
+    #[tokio::test]
+    async fn test_cached_response_skips_the_backend() {
+        let chain = MockChain::new(999_006);
+
+        chain.mine_n(5);
+
+        let node = MockNode::spawn(&chain).await.unwrap();
+
+        let top_config = TopConfig {
+            app: AppConfig {
+                chain_id: chain.chain_id(),
+                min_sum_soft_limit: 1,
+                min_synced_rpcs: 1,
+                response_cache_max_bytes: 10_u64.pow(7),
+                ..Default::default()
+            },
+            balanced_rpcs: HashMap::from([("node".to_string(), node.config())]),
+            private_rpcs: None,
+            extra: Default::default(),
+        };
+
+        let (_shutdown_sender, shutdown_receiver) = broadcast::channel(1);
+
+        let app = Web3ProxyApp::spawn(top_config, 1, shutdown_receiver)
+            .await
+            .unwrap()
+            .app;
+
+        wait_for_head(&mut app.head_block_receiver(), &chain.head())
+            .await
+            .unwrap();
+
+        let authorization = Arc::new(Authorization::internal(None).unwrap());
+
+        let request = || {
+            JsonRpcRequestEnum::Single(JsonRpcRequest {
+                jsonrpc: "2.0".to_string(),
+                id: RawValue::from_string("1".to_string()).unwrap(),
+                method: "eth_getBalance".to_string(),
+                params: Some(json!([Address::zero(), "0x3"])),
+            })
+        };
+
+        let (first, first_rpcs, _) = app
+            .proxy_web3_rpc(authorization.clone(), request(), None)
+            .await
+            .unwrap();
+
+        assert_eq!(first_rpcs.len(), 1);
+
+        let (second, second_rpcs, _) = app
+            .proxy_web3_rpc(authorization, request(), None)
+            .await
+            .unwrap();
+
+        // the second answer came out of the response cache
+        assert!(second_rpcs.is_empty());
+        assert_eq!(
+            serde_json::to_string(&first).unwrap(),
+            serde_json::to_string(&second).unwrap()
+        );
+    }
+}
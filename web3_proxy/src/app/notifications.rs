@@ -0,0 +1,80 @@
+//! Email users about their accounts.
+
+use super::Web3ProxyApp;
+use crate::email::{send_email_verification, spawn_notify_user, Notification};
+use anyhow::Context;
+use log::{debug, warn};
+use redis_rate_limiter::redis;
+
+impl Web3ProxyApp {
+    /// Send the user a link to confirm their email.
+    /// Returns false if email is not configured or a link was sent less than a minute ago.
+    pub async fn send_email_verification(&self, user_id: u64, email: &str) -> anyhow::Result<bool> {
+        let (mailer, email_config) = match (self.mailer.as_ref(), self.config().email.clone()) {
+            (Some(mailer), Some(email_config)) => (mailer, email_config),
+            _ => {
+                debug!("email is not configured. {} stays unverified", email);
+                return Ok(false);
+            }
+        };
+
+        let db_conn = self
+            .db_conn()
+            .context("sending an email verification requires a db")?;
+
+        send_email_verification(&db_conn, mailer.as_ref(), &email_config, user_id, email).await
+    }
+
+    /// Email the user in the background. Users without a verified email are skipped.
+    pub fn notify_user(&self, user_id: u64, notification: Notification) {
+        if let (Some(db_conn), Some(mailer)) = (self.db_conn(), self.mailer.clone()) {
+            spawn_notify_user(db_conn, mailer, user_id, notification);
+        }
+    }
+
+    /// Tell the user that they hit their rate limit. At most once a day.
+    pub async fn notify_rate_limited(&self, user_id: u64, max_requests_per_period: u64) {
+        if self.mailer.is_none() || self.rate_limit_notifications.contains_key(&user_id) {
+            return;
+        }
+
+        self.rate_limit_notifications.insert(user_id, ()).await;
+
+        // other proxies may have already told them
+        if let Some(redis_pool) = self.vredis_pool.as_ref() {
+            let redis_key = format!("notified:rate_limited:{}", user_id);
+
+            let first = match redis_pool.get().await {
+                Ok(mut redis_conn) => redis::cmd("SET")
+                    .arg(&redis_key)
+                    .arg(1)
+                    .arg("NX")
+                    .arg("EX")
+                    .arg(86_400)
+                    .query_async::<_, Option<String>>(&mut redis_conn)
+                    .await
+                    .map(|x| x.is_some()),
+                Err(err) => {
+                    warn!("no redis to dedupe rate limit notifications. err={:?}", err);
+                    return;
+                }
+            };
+
+            match first {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(err) => {
+                    warn!("failed deduping rate limit notification. err={:?}", err);
+                    return;
+                }
+            }
+        }
+
+        self.notify_user(
+            user_id,
+            Notification::RateLimited {
+                max_requests_per_period,
+            },
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Let admins change backend rpcs without a restart. Changes are shared with the other proxies through redis pub/sub.
+
+use super::Web3ProxyApp;
+use crate::rpcs::admin::{Web3RpcCommand, Web3RpcCommandMessage, RPC_COMMANDS_CHANNEL};
+use crate::rpcs::one::Web3Rpc;
+use anyhow::Context;
+use futures::StreamExt;
+use log::{info, warn};
+use redis_rate_limiter::redis::{self, AsyncCommands};
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+
+impl Web3ProxyApp {
+    fn rpc_commands_channel(&self) -> String {
+        format!("{}:{}", RPC_COMMANDS_CHANNEL, self.config().chain_id)
+    }
+
+    /// Apply the command to the named rpc here and then publish it to the other proxies.
+    /// Returns None if there is no rpc with that name.
+    pub async fn rpc_admin_command(
+        &self,
+        rpc_name: &str,
+        command: Web3RpcCommand,
+    ) -> anyhow::Result<Option<Arc<Web3Rpc>>> {
+        let rpc = match self.apply_rpc_command(rpc_name, &command).await? {
+            None => return Ok(None),
+            Some(x) => x,
+        };
+
+        if let Some(mut redis_conn) = self.redis_conn().await? {
+            let message = Web3RpcCommandMessage {
+                sender: self.instance_id,
+                rpc: rpc_name.to_string(),
+                command,
+            };
+
+            let message = serde_json::to_string(&message)?;
+
+            redis_conn
+                .publish::<_, _, ()>(self.rpc_commands_channel(), message)
+                .await
+                .context("publishing rpc command")?;
+        } else {
+            warn!("no redis. rpc command only applied to this proxy");
+        }
+
+        Ok(Some(rpc))
+    }
+
+    /// Apply the command on this proxy only
+    async fn apply_rpc_command(
+        &self,
+        rpc_name: &str,
+        command: &Web3RpcCommand,
+    ) -> anyhow::Result<Option<Arc<Web3Rpc>>> {
+        // the name might be in either group
+        for rpcs in [Some(&self.balanced_rpcs), self.private_rpcs.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            if let Some(rpc) = rpcs.get(rpc_name) {
+                rpcs.apply_rpc_command(&rpc, command).await?;
+
+                return Ok(Some(rpc));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Apply commands that other proxies publish. Runs forever.
+    /// TODO: proxies that start later don't get the commands that were sent before they subscribed
+    pub(super) async fn subscribe_rpc_commands(
+        self: Arc<Self>,
+        redis_url: String,
+    ) -> anyhow::Result<()> {
+        let channel = self.rpc_commands_channel();
+
+        // pub/sub needs a dedicated connection. it can't use the pool
+        let client = redis::Client::open(redis_url.as_str()).context("parsing redis url")?;
+
+        loop {
+            let mut pubsub = match client.get_async_connection().await {
+                Ok(x) => x.into_pubsub(),
+                Err(err) => {
+                    warn!("unable to connect for rpc commands. err={:?}", err);
+                    sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+
+            if let Err(err) = pubsub.subscribe(&channel).await {
+                warn!("unable to subscribe to rpc commands. err={:?}", err);
+                sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+
+            info!("subscribed to {}", channel);
+
+            let mut messages = pubsub.on_message();
+
+            while let Some(msg) = messages.next().await {
+                let message = msg
+                    .get_payload::<String>()
+                    .context("reading rpc command")
+                    .and_then(|x| {
+                        serde_json::from_str::<Web3RpcCommandMessage>(&x)
+                            .context("parsing rpc command")
+                    });
+
+                let message = match message {
+                    Ok(x) => x,
+                    Err(err) => {
+                        warn!("{:?}", err);
+                        continue;
+                    }
+                };
+
+                if message.sender == self.instance_id {
+                    // we applied this before publishing it
+                    continue;
+                }
+
+                match self.apply_rpc_command(&message.rpc, &message.command).await {
+                    Ok(Some(_)) => {}
+                    Ok(None) => warn!("rpc command for unknown rpc {}", message.rpc),
+                    Err(err) => warn!(
+                        "failed applying rpc command {:?} to {}. err={:?}",
+                        message.command, message.rpc, err
+                    ),
+                }
+            }
+
+            warn!("rpc commands subscription ended. resubscribing");
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Keep every proxy's rpc key caches in sync with rotations and deletes.
+
+use super::Web3ProxyApp;
+use crate::rpc_keys::{
+    publish_rpc_key_invalidation, revoke_expired_rpc_keys, user_rpc_key_invalidation,
+    RpcKeyInvalidation, RPC_KEY_INVALIDATIONS_CHANNEL,
+};
+use anyhow::Context;
+use futures::StreamExt;
+use log::{info, warn};
+use redis_rate_limiter::redis;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+
+impl Web3ProxyApp {
+    /// Forget the keys here and then publish them to the other proxies.
+    pub async fn invalidate_rpc_keys(
+        &self,
+        mut invalidation: RpcKeyInvalidation,
+    ) -> anyhow::Result<()> {
+        if invalidation.is_empty() {
+            return Ok(());
+        }
+
+        self.apply_rpc_key_invalidation(&invalidation).await;
+
+        if let Some(mut redis_conn) = self.redis_conn().await? {
+            invalidation.sender = Some(self.instance_id);
+
+            publish_rpc_key_invalidation(&mut redis_conn, &invalidation).await?;
+        } else {
+            warn!("no redis. rpc keys only invalidated on this proxy");
+        }
+
+        Ok(())
+    }
+
+    /// The user's tier changed. Every proxy needs to load their keys' new limits
+    pub async fn invalidate_user_rpc_keys(&self, user_id: u64) -> anyhow::Result<()> {
+        let db_conn = self
+            .db_conn()
+            .context("invalidating a user's rpc keys requires a db")?;
+
+        let invalidation = user_rpc_key_invalidation(&db_conn, user_id).await?;
+
+        self.invalidate_rpc_keys(invalidation).await
+    }
+
+    /// Invalidate the keys of users that the stat emitter moved to a new tier. Runs until the stat emitter exits.
+    pub(super) async fn watch_tier_changes(
+        self: Arc<Self>,
+        tier_change_receiver: flume::Receiver<u64>,
+    ) -> anyhow::Result<()> {
+        while let Ok(user_id) = tier_change_receiver.recv_async().await {
+            if let Err(err) = self.invalidate_user_rpc_keys(user_id).await {
+                warn!(
+                    "failed invalidating rpc keys for user {}. err={:?}",
+                    user_id, err
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forget the keys on this proxy only
+    async fn apply_rpc_key_invalidation(&self, invalidation: &RpcKeyInvalidation) {
+        for x in invalidation.secret_keys.iter() {
+            self.rpc_secret_key_cache.invalidate(x).await;
+        }
+
+        for x in invalidation.jwt_key_ids.iter() {
+            self.jwt_public_key_cache.invalidate(x).await;
+        }
+    }
+
+    /// Forget keys that other proxies rotated or deleted. Runs forever.
+    pub(super) async fn subscribe_rpc_key_invalidations(
+        self: Arc<Self>,
+        redis_url: String,
+    ) -> anyhow::Result<()> {
+        // pub/sub needs a dedicated connection. it can't use the pool
+        let client = redis::Client::open(redis_url.as_str()).context("parsing redis url")?;
+
+        loop {
+            let mut pubsub = match client.get_async_connection().await {
+                Ok(x) => x.into_pubsub(),
+                Err(err) => {
+                    warn!("unable to connect for rpc key invalidations. err={:?}", err);
+                    sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+
+            if let Err(err) = pubsub.subscribe(RPC_KEY_INVALIDATIONS_CHANNEL).await {
+                warn!(
+                    "unable to subscribe to rpc key invalidations. err={:?}",
+                    err
+                );
+                sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+
+            info!("subscribed to {}", RPC_KEY_INVALIDATIONS_CHANNEL);
+
+            let mut messages = pubsub.on_message();
+
+            while let Some(msg) = messages.next().await {
+                let invalidation = msg
+                    .get_payload::<String>()
+                    .context("reading rpc key invalidation")
+                    .and_then(|x| {
+                        serde_json::from_str::<RpcKeyInvalidation>(&x)
+                            .context("parsing rpc key invalidation")
+                    });
+
+                let invalidation = match invalidation {
+                    Ok(x) => x,
+                    Err(err) => {
+                        warn!("{:?}", err);
+                        continue;
+                    }
+                };
+
+                if invalidation.sender == Some(self.instance_id) {
+                    // we applied this before publishing it
+                    continue;
+                }
+
+                self.apply_rpc_key_invalidation(&invalidation).await;
+            }
+
+            warn!("rpc key invalidations subscription ended. resubscribing");
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    /// Revoke old secrets once their grace period is over. Runs forever.
+    /// Every proxy runs this. Whichever one gets to a key first publishes it for the rest.
+    pub(super) async fn watch_rpc_key_expirations(self: Arc<Self>) -> anyhow::Result<()> {
+        loop {
+            sleep(Duration::from_secs(60)).await;
+
+            let db_conn = match self.db_conn() {
+                Some(x) => x,
+                None => continue,
+            };
+
+            let invalidation = match revoke_expired_rpc_keys(&db_conn).await {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!("failed revoking expired rpc keys. err={:?}", err);
+                    continue;
+                }
+            };
+
+            if !invalidation.is_empty() {
+                info!(
+                    "revoked {} expired rpc secret keys",
+                    invalidation.secret_keys.len()
+                );
+            }
+
+            if let Err(err) = self.invalidate_rpc_keys(invalidation).await {
+                warn!("failed invalidating expired rpc keys. err={:?}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,261 @@
+///! Check a sample of responses against a second backend at the same block.
+///! Mismatches are saved to kafka. The config requires `kafka_urls` when shadow checks are on.
+use super::Web3ProxyApp;
+use crate::frontend::authorization::Authorization;
+use crate::jsonrpc::{JsonRpcForwardedResponse, JsonRpcRequest};
+use crate::rpcs::blockchain::Web3ProxyBlock;
+use crate::rpcs::normalize::{BackendErrorClass, ClientKind, NormalizedError};
+use crate::rpcs::one::Web3Rpc;
+use anyhow::Context;
+use ethers::prelude::{H256, U64};
+use log::{debug, error, trace, warn};
+use rdkafka::producer::FutureRecord;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use thread_fast_rng::rand::Rng;
+use thread_fast_rng::thread_fast_rng;
+
+/// Everything needed to figure out which backend was wrong
+#[derive(Serialize)]
+struct ShadowMismatch<'a> {
+    chain_id: u64,
+    method: &'a str,
+    params: &'a Option<serde_json::Value>,
+    block_num: &'a U64,
+    block_hash: &'a H256,
+    primary_rpc: &'a str,
+    primary_response: &'a JsonRpcForwardedResponse,
+    shadow_rpc: &'a str,
+    shadow_response: &'a JsonRpcForwardedResponse,
+}
+
+impl Web3ProxyApp {
+    /// roll the dice to see if a response for this method should be checked
+    pub(super) fn shadow_verify_sample(&self, method: &str) -> bool {
+        let config = self.config();
+
+        let percent = config
+            .shadow_verify_percent
+            .get(method)
+            .or_else(|| config.shadow_verify_percent.get("*"));
+
+        match percent {
+            None => false,
+            Some(percent) => thread_fast_rng().gen_range(0.0..100.0) < percent.0,
+        }
+    }
+
+    /// check the response in the background. the user does not wait for this
+    pub(super) fn spawn_shadow_verify(
+        self: &Arc<Self>,
+        request: JsonRpcRequest,
+        block: Web3ProxyBlock,
+        primary_response: JsonRpcForwardedResponse,
+        primary_rpcs: Vec<Arc<Web3Rpc>>,
+    ) {
+        let app = self.clone();
+
+        tokio::spawn(async move {
+            let method = request.method.clone();
+
+            if let Err(err) = app
+                .shadow_verify(request, block, primary_response, primary_rpcs)
+                .await
+            {
+                debug!("failed shadow check for {}. err={:?}", method, err);
+            }
+        });
+    }
+
+    async fn shadow_verify(
+        &self,
+        request: JsonRpcRequest,
+        block: Web3ProxyBlock,
+        primary_response: JsonRpcForwardedResponse,
+        primary_rpcs: Vec<Arc<Web3Rpc>>,
+    ) -> anyhow::Result<()> {
+        // the last rpc is the one that gave the response. earlier ones errored
+        let primary_rpc = match primary_rpcs.last() {
+            Some(x) => x.clone(),
+            None => {
+                // this response came from the cache
+                return Ok(());
+            }
+        };
+
+        // use an internal authorization so that the user isn't charged for our checks
+        let authorization = Arc::new(Authorization::internal(self.db_conn())?);
+
+        let (shadow_rpc, shadow_response) = match self
+            .balanced_rpcs
+            .try_send_shadow_request(&authorization, &request, &primary_rpcs, &block)
+            .await?
+        {
+            Some(x) => x,
+            None => {
+                trace!("no servers available to check {}", request.method);
+                return Ok(());
+            }
+        };
+
+        let matched = match compare_responses(&primary_response, &shadow_response) {
+            Some(x) => x,
+            None => {
+                trace!(
+                    "unable to compare {} from {} and {}",
+                    request.method,
+                    primary_rpc,
+                    shadow_rpc
+                );
+                return Ok(());
+            }
+        };
+
+        let affects_health = self.config().shadow_verify_affects_health;
+
+        primary_rpc.record_shadow_check(matched, affects_health);
+        shadow_rpc.record_shadow_check(matched, affects_health);
+
+        if matched {
+            trace!(
+                "{} matched on {} and {}",
+                request.method,
+                primary_rpc,
+                shadow_rpc
+            );
+            return Ok(());
+        }
+
+        warn!(
+            "shadow check mismatch! method={} block={} rpcs={},{}",
+            request.method,
+            block.number(),
+            primary_rpc,
+            shadow_rpc
+        );
+
+        let mismatch = ShadowMismatch {
+            chain_id: self.config().chain_id,
+            method: &request.method,
+            params: &request.params,
+            block_num: block.number(),
+            block_hash: block.hash(),
+            primary_rpc: &primary_rpc.name,
+            primary_response: &primary_response,
+            shadow_rpc: &shadow_rpc.name,
+            shadow_response: &shadow_response,
+        };
+
+        if let Some(kafka_producer) = self.kafka_producer.as_ref() {
+            let kafka_key = rmp_serde::to_vec(&request.method)?;
+
+            let payload =
+                rmp_serde::to_vec(&mismatch).context("failed msgpack serialize mismatch")?;
+
+            let produce_future = kafka_producer.send(
+                FutureRecord::to("shadow_verify")
+                    .key(&kafka_key)
+                    .payload(&payload),
+                Duration::from_secs(0),
+            );
+
+            if let Err((err, _)) = produce_future.await {
+                error!("produce kafka shadow mismatch: {}", err);
+            }
+        } else {
+            // kafka_urls is required for shadow checks, but connecting to kafka can fail at startup. keep the mismatch in the logs
+            error!(
+                "no kafka producer. shadow check mismatch was not saved: {}",
+                serde_json::to_string(&mismatch)?
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Some(true) if the responses match. None if the shadow server wasn't able to give a useful answer
+fn compare_responses(
+    primary_response: &JsonRpcForwardedResponse,
+    shadow_response: &JsonRpcForwardedResponse,
+) -> Option<bool> {
+    // only successes are checked
+    let primary_result = primary_response.result.as_ref()?;
+
+    if let Some(error) = shadow_response.error.as_ref() {
+        // a lagging or pruned server doesn't tell us anything about the primary's answer
+        let normalized = NormalizedError::new(ClientKind::Unknown, error.clone());
+
+        return if normalized.class == BackendErrorClass::Retryable {
+            None
+        } else {
+            Some(false)
+        };
+    }
+
+    let shadow_result = shadow_response.result.as_ref()?;
+
+    // compare parsed values so that whitespace and key order don't matter
+    match (
+        serde_json::from_str::<serde_json::Value>(primary_result.get()),
+        serde_json::from_str::<serde_json::Value>(shadow_result.get()),
+    ) {
+        (Ok(a), Ok(b)) => Some(a == b),
+        _ => Some(primary_result.get() == shadow_result.get()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jsonrpc::JsonRpcErrorData;
+    use serde_json::json;
+
+    fn ok(result: serde_json::Value) -> JsonRpcForwardedResponse {
+        JsonRpcForwardedResponse::from_value(result, Default::default())
+    }
+
+    fn err(code: i64, message: &str) -> JsonRpcForwardedResponse {
+        JsonRpcForwardedResponse {
+            jsonrpc: "2.0".to_string(),
+            id: Default::default(),
+            result: None,
+            error: Some(JsonRpcErrorData {
+                code,
+                message: message.to_string(),
+                data: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_compare_responses() {
+        let a = ok(json!({"a": 1, "b": "0x2"}));
+        let b = JsonRpcForwardedResponse::from_response(
+            serde_json::value::RawValue::from_string(r#"{ "b": "0x2", "a": 1 }"#.to_string())
+                .unwrap(),
+            Default::default(),
+        );
+
+        assert_eq!(compare_responses(&a, &b), Some(true));
+
+        // geth sometimes gives an empty response instead of an error
+        assert_eq!(compare_responses(&a, &ok(json!(null))), Some(false));
+
+        // a lagging shadow server can't tell us anything
+        assert_eq!(
+            compare_responses(&a, &err(-32000, "header not found")),
+            None
+        );
+
+        // a revert on one server and a success on the other is a mismatch
+        assert_eq!(
+            compare_responses(&a, &err(3, "execution reverted")),
+            Some(false)
+        );
+
+        // errors are not checked
+        assert_eq!(compare_responses(&err(3, "execution reverted"), &a), None);
+    }
+}
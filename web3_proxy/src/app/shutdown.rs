@@ -0,0 +1,81 @@
+//! Shut down in phases so that load balancers and clients can move to other proxies without dropping requests.
+
+use super::Web3ProxyApp;
+use log::{info, warn};
+use std::sync::atomic::Ordering;
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration, Instant};
+
+/// The phases are in order. Each phase includes everything done by the phases before it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShutdownPhase {
+    #[default]
+    Running,
+    /// `/health` returns 503 so that load balancers stop sending us new traffic
+    Unhealthy,
+    /// no new connections. in-flight HTTP requests are finishing
+    DrainingHttp,
+    /// websocket subscriptions are ended. clients get a little time to reconnect somewhere else
+    ClosingWebsockets,
+    /// websockets are sent a close frame
+    Closed,
+}
+
+impl Web3ProxyApp {
+    pub fn shutdown_phase(&self) -> ShutdownPhase {
+        *self.shutdown_phase_sender.borrow()
+    }
+
+    pub fn shutdown_phase_receiver(&self) -> watch::Receiver<ShutdownPhase> {
+        self.shutdown_phase_sender.subscribe()
+    }
+
+    /// Phases only move forward
+    pub fn set_shutdown_phase(&self, phase: ShutdownPhase) {
+        self.shutdown_phase_sender.send_if_modified(|x| {
+            if phase > *x {
+                info!("shutdown phase: {:?}", phase);
+                *x = phase;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    /// Wait until the phase is at least `phase`
+    pub async fn wait_for_shutdown_phase(&self, phase: ShutdownPhase) {
+        let mut receiver = self.shutdown_phase_receiver();
+
+        // the sender is owned by the app, so this can't error while we have a reference to it
+        while *receiver.borrow_and_update() < phase {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// End every websocket subscription and then close the websockets once the clients leave or `max_wait` passes.
+    pub async fn close_websockets(&self, max_wait: Duration) {
+        self.set_shutdown_phase(ShutdownPhase::ClosingWebsockets);
+
+        let deadline = Instant::now() + max_wait;
+
+        loop {
+            let open_websockets = self.open_websockets.load(Ordering::Acquire);
+
+            if open_websockets == 0 {
+                break;
+            }
+
+            if Instant::now() >= deadline {
+                warn!("closing {} websockets", open_websockets);
+                break;
+            }
+
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        self.set_shutdown_phase(ShutdownPhase::Closed);
+    }
+}
@@ -0,0 +1,281 @@
+//! Check "Sign In with Ethereum" signatures from normal accounts and from smart contract wallets.
+//!
+//! Contract wallets can't sign with a private key. EIP-1271 has them check signatures with `isValidSignature` instead.
+//! EIP-6492 wraps the signature of a wallet that isn't deployed yet with its factory call. We deploy it inside an `eth_call`.
+
+use super::Web3ProxyApp;
+use crate::frontend::authorization::Authorization;
+use anyhow::Context;
+use ethers::abi::{self, ParamType, Token};
+use ethers::prelude::{Address, Bytes, H256};
+use ethers::utils::hash_message;
+use log::trace;
+use serde_json::json;
+use siwe::{Message, VerificationOpts};
+use std::sync::Arc;
+
+/// `bytes4(keccak256("isValidSignature(bytes32,bytes)"))`. Also what the wallet returns if the signature is valid
+pub const EIP1271_MAGIC_VALUE: [u8; 4] = [0x16, 0x26, 0xba, 0x7e];
+
+/// the end of every EIP-6492 signature
+pub const EIP6492_MAGIC_SUFFIX: [u8; 32] = [
+    0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92,
+    0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92,
+];
+
+/// length of the code built by `eip6492_validator`
+const EIP6492_VALIDATOR_LEN: u16 = 91;
+
+impl Web3ProxyApp {
+    /// Check a signature of our copy of the message.
+    /// Plain ECDSA signatures are checked first. If they don't match the message's address, ask the address if it is a contract wallet.
+    pub async fn verify_siwe_signature(&self, message: &Message, sig: &[u8]) -> anyhow::Result<()> {
+        let ecdsa_err = match <[u8; 65]>::try_from(sig) {
+            Ok(sig) => {
+                // default options are fine. the message includes timestamp and domain and nonce
+                let verify_config = VerificationOpts::default();
+
+                // Check with both verify and verify_eip191
+                match message.verify(&sig, &verify_config).await {
+                    Ok(()) => return Ok(()),
+                    Err(err_1) => match message.verify_eip191(&sig) {
+                        Ok(_) => return Ok(()),
+                        Err(err_191) => anyhow::anyhow!(
+                            "both the primary and eip191 verification failed: {:#?}; {:#?}",
+                            err_1,
+                            err_191
+                        ),
+                    },
+                }
+            }
+            Err(_) => anyhow::anyhow!("{} byte signature is not ecdsa", sig.len()),
+        };
+
+        // verify checks the time for us. contract wallets don't
+        if !message.valid_now() {
+            return Err(ecdsa_err.context("message is expired or not yet valid"));
+        }
+
+        let address = Address::from(message.address);
+
+        let hash = hash_message(message.to_string());
+
+        match self
+            .contract_wallet_signature_is_valid(address, hash, sig)
+            .await
+        {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ecdsa_err.context("contract wallet signature is not valid")),
+            Err(err) => {
+                Err(ecdsa_err.context(format!("checking contract wallet failed: {:#}", err)))
+            }
+        }
+    }
+
+    /// Ask the wallet at `address` if it signed `hash`.
+    /// EIP-6492 signatures deploy the wallet first. Nothing is saved on chain since this is only an `eth_call`.
+    pub async fn contract_wallet_signature_is_valid(
+        &self,
+        address: Address,
+        hash: H256,
+        sig: &[u8],
+    ) -> anyhow::Result<bool> {
+        let authorization = Arc::new(Authorization::internal(self.db_conn())?);
+
+        let call = match sig.strip_suffix(&EIP6492_MAGIC_SUFFIX) {
+            Some(wrapped_sig) => {
+                let (factory, factory_calldata, sig) = unwrap_eip6492(wrapped_sig)?;
+
+                trace!(
+                    "checking counterfactual wallet {:?} from {:?}",
+                    address,
+                    factory
+                );
+
+                let code = eip6492_validator(
+                    factory,
+                    &factory_calldata,
+                    address,
+                    &is_valid_signature_calldata(hash, &sig),
+                )?;
+
+                // no "to" means the code runs as if it is deploying a contract
+                json!({ "data": code })
+            }
+            None => json!({
+                "to": address,
+                "data": is_valid_signature_calldata(hash, sig),
+            }),
+        };
+
+        let result: Bytes = self
+            .internal_request(&authorization, "eth_call", json!([call, "latest"]), None)
+            .await?;
+
+        // accounts without code return nothing
+        Ok(result.len() >= 4 && result[..4] == EIP1271_MAGIC_VALUE)
+    }
+}
+
+fn is_valid_signature_calldata(hash: H256, sig: &[u8]) -> Bytes {
+    let mut calldata = EIP1271_MAGIC_VALUE.to_vec();
+
+    calldata.extend(abi::encode(&[
+        Token::FixedBytes(hash.as_bytes().to_vec()),
+        Token::Bytes(sig.to_vec()),
+    ]));
+
+    calldata.into()
+}
+
+/// `abi.encode(factory, factoryCalldata, signature)`. The magic suffix has already been removed
+fn unwrap_eip6492(wrapped_sig: &[u8]) -> anyhow::Result<(Address, Vec<u8>, Vec<u8>)> {
+    let mut tokens = abi::decode(
+        &[ParamType::Address, ParamType::Bytes, ParamType::Bytes],
+        wrapped_sig,
+    )
+    .context("decoding eip6492 signature")?
+    .into_iter();
+
+    let factory = tokens.next().and_then(Token::into_address);
+    let factory_calldata = tokens.next().and_then(Token::into_bytes);
+    let sig = tokens.next().and_then(Token::into_bytes);
+
+    match (factory, factory_calldata, sig) {
+        (Some(factory), Some(factory_calldata), Some(sig)) => Ok((factory, factory_calldata, sig)),
+        _ => Err(anyhow::anyhow!("unexpected eip6492 signature")),
+    }
+}
+
+/// Code for an `eth_call` without a `to`. It calls the factory to deploy the wallet and then calls `isValidSignature`.
+/// It returns the wallet's answer, or zero if the wallet reverted.
+/// The calldata for both calls goes after the code. The code copies it into memory.
+fn eip6492_validator(
+    factory: Address,
+    factory_calldata: &[u8],
+    signer: Address,
+    is_valid_signature_calldata: &[u8],
+) -> anyhow::Result<Bytes> {
+    const MUL: u8 = 0x02;
+    const CODECOPY: u8 = 0x39;
+    const POP: u8 = 0x50;
+    const MLOAD: u8 = 0x51;
+    const MSTORE: u8 = 0x52;
+    const GAS: u8 = 0x5a;
+    const PUSH1: u8 = 0x60;
+    const PUSH2: u8 = 0x61;
+    const PUSH20: u8 = 0x73;
+    const CALL: u8 = 0xf1;
+    const RETURN: u8 = 0xf3;
+    const STATICCALL: u8 = 0xfa;
+
+    let data_len: u16 = (factory_calldata.len() + is_valid_signature_calldata.len())
+        .try_into()
+        .context("eip6492 signature is too long")?;
+
+    // both of these fit since their sum does
+    let factory_calldata_len = factory_calldata.len() as u16;
+    let is_valid_signature_calldata_len = is_valid_signature_calldata.len() as u16;
+
+    // the wallet's answer goes after the data, where memory is still zeroed
+    let output = data_len;
+
+    let mut code = Vec::with_capacity(EIP6492_VALIDATOR_LEN as usize + data_len as usize);
+
+    // memory[0..data_len] = code[EIP6492_VALIDATOR_LEN..]
+    code.push(PUSH2);
+    code.extend(data_len.to_be_bytes());
+    code.push(PUSH2);
+    code.extend(EIP6492_VALIDATOR_LEN.to_be_bytes());
+    code.extend([PUSH1, 0, CODECOPY]);
+
+    // deploy the wallet. if it is already deployed this fails, and that is fine
+    code.extend([PUSH1, 0, PUSH1, 0]);
+    code.push(PUSH2);
+    code.extend(factory_calldata_len.to_be_bytes());
+    code.extend([PUSH1, 0, PUSH1, 0]);
+    code.push(PUSH20);
+    code.extend(factory.as_bytes());
+    code.extend([GAS, CALL, POP]);
+
+    // ask the wallet. 32 bytes of its answer go to memory[output..]
+    code.extend([PUSH1, 32]);
+    code.push(PUSH2);
+    code.extend(output.to_be_bytes());
+    code.push(PUSH2);
+    code.extend(is_valid_signature_calldata_len.to_be_bytes());
+    code.push(PUSH2);
+    code.extend(factory_calldata_len.to_be_bytes());
+    code.push(PUSH20);
+    code.extend(signer.as_bytes());
+    code.extend([GAS, STATICCALL]);
+
+    // a revert's data is copied to memory too. multiplying by the success flag zeroes it
+    code.push(PUSH2);
+    code.extend(output.to_be_bytes());
+    code.extend([MLOAD, MUL, PUSH1, 0, MSTORE]);
+
+    // return memory[0..32]
+    code.extend([PUSH1, 32, PUSH1, 0, RETURN]);
+
+    debug_assert_eq!(code.len(), EIP6492_VALIDATOR_LEN as usize);
+
+    code.extend(factory_calldata);
+    code.extend(is_valid_signature_calldata);
+
+    Ok(code.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_signature_calldata() {
+        let calldata = is_valid_signature_calldata(H256::repeat_byte(1), &[2; 65]);
+
+        assert_eq!(calldata[..4], EIP1271_MAGIC_VALUE);
+        // hash, offset, length, and the signature padded to 96 bytes
+        assert_eq!(calldata.len(), 4 + 32 * 3 + 96);
+        assert_eq!(calldata[4..36], [1; 32]);
+    }
+
+    #[test]
+    fn test_unwrap_eip6492() {
+        let factory = Address::repeat_byte(1);
+
+        let wrapped = abi::encode(&[
+            Token::Address(factory),
+            Token::Bytes(vec![3; 100]),
+            Token::Bytes(vec![4; 65]),
+        ]);
+
+        let (x, factory_calldata, sig) = unwrap_eip6492(&wrapped).unwrap();
+
+        assert_eq!(x, factory);
+        assert_eq!(factory_calldata, vec![3; 100]);
+        assert_eq!(sig, vec![4; 65]);
+
+        assert!(unwrap_eip6492(&[0; 10]).is_err());
+    }
+
+    #[test]
+    fn test_eip6492_validator() {
+        let factory = Address::repeat_byte(1);
+        let signer = Address::repeat_byte(2);
+
+        let code = eip6492_validator(factory, &[3; 100], signer, &[4; 200]).unwrap();
+
+        let code_len = EIP6492_VALIDATOR_LEN as usize;
+
+        assert_eq!(code.len(), code_len + 300);
+
+        // the code copies everything after itself
+        assert_eq!(code[0..6], [0x61, 0x01, 0x2c, 0x61, 0x00, code_len as u8]);
+
+        assert_eq!(code[code_len..code_len + 100], [3; 100]);
+        assert_eq!(code[code_len + 100..], [4; 200]);
+
+        assert!(eip6492_validator(factory, &[3; 60_000], signer, &[4; 10_000]).is_err());
+    }
+}
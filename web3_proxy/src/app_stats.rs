@@ -1,3 +1,6 @@
+use crate::billing::{debit_usage, request_price, TierChange};
+use crate::config::BillingConfig;
+use crate::email::{spawn_notify_user, MailTransport, Notification};
 use crate::frontend::authorization::{Authorization, RequestMetadata};
 use axum::headers::Origin;
 use chrono::{TimeZone, Utc};
@@ -7,6 +10,7 @@ use entities::sea_orm_active_enums::LogLevel;
 use hashbrown::HashMap;
 use hdrhistogram::{Histogram, RecordError};
 use log::{error, info};
+use migration::sea_orm::prelude::Decimal;
 use migration::sea_orm::{self, ActiveModelTrait, DatabaseConnection, DbErr};
 use std::num::NonZeroU64;
 use std::sync::atomic::Ordering;
@@ -15,6 +19,7 @@ use std::time::{Duration, SystemTime};
 use tokio::sync::broadcast;
 use tokio::task::JoinHandle;
 use tokio::time::{interval_at, Instant};
+use ulid::Ulid;
 
 /// TODO: where should this be defined?
 /// TODO: can we use something inside sea_orm instead?
@@ -78,6 +83,17 @@ impl ProxyResponseStat {
             rpc_key_id,
         }
     }
+
+    /// Billing is by user no matter what the rpc key's log level is
+    fn price(&self, billing: &BillingConfig) -> Decimal {
+        request_price(
+            billing,
+            &self.method,
+            self.archive_request,
+            self.backend_requests == 0,
+            self.error_response,
+        )
+    }
 }
 
 pub struct ProxyResponseHistograms {
@@ -146,6 +162,13 @@ pub struct StatEmitter {
     chain_id: u64,
     db_conn: DatabaseConnection,
     period_seconds: u64,
+    billing: Option<BillingConfig>,
+    /// users whose balance runs out are emailed
+    mailer: Option<Arc<dyn MailTransport>>,
+    /// users whose balance runs out are sent here so that every proxy loads their new limits
+    tier_change_sender: flume::Sender<u64>,
+    /// part of every usage debit's idempotency key. each proxy debits only the requests that it served
+    instance_id: Ulid,
 }
 
 // TODO: impl `+=<ProxyResponseStat>` for ProxyResponseAggregate?
@@ -224,41 +247,41 @@ impl ProxyResponseAggregate {
             // origin: sea_orm::Set(key.authorization.origin.to_string()),
             rpc_key_id: sea_orm::Set(key.rpc_key_id.map(Into::into)),
             origin: sea_orm::Set(key.origin.map(|x| x.to_string())),
-            chain_id: sea_orm::Set(chain_id),
+            chain_id: sea_orm::Set(chain_id.into()),
             method: sea_orm::Set(key.method),
             archive_request: sea_orm::Set(key.archive_request),
             error_response: sea_orm::Set(key.error_response),
             period_datetime: sea_orm::Set(period_datetime),
-            frontend_requests: sea_orm::Set(self.frontend_requests),
-            backend_requests: sea_orm::Set(self.backend_requests),
+            frontend_requests: sea_orm::Set(self.frontend_requests.into()),
+            backend_requests: sea_orm::Set(self.backend_requests.into()),
             // backend_retries: sea_orm::Set(self.backend_retries),
             // no_servers: sea_orm::Set(self.no_servers),
-            cache_misses: sea_orm::Set(self.cache_misses),
-            cache_hits: sea_orm::Set(self.cache_hits),
+            cache_misses: sea_orm::Set(self.cache_misses.into()),
+            cache_hits: sea_orm::Set(self.cache_hits.into()),
 
-            sum_request_bytes: sea_orm::Set(self.sum_request_bytes),
-            min_request_bytes: sea_orm::Set(min_request_bytes),
+            sum_request_bytes: sea_orm::Set(self.sum_request_bytes.into()),
+            min_request_bytes: sea_orm::Set(min_request_bytes.into()),
             mean_request_bytes: sea_orm::Set(mean_request_bytes),
-            p50_request_bytes: sea_orm::Set(p50_request_bytes),
-            p90_request_bytes: sea_orm::Set(p90_request_bytes),
-            p99_request_bytes: sea_orm::Set(p99_request_bytes),
-            max_request_bytes: sea_orm::Set(max_request_bytes),
+            p50_request_bytes: sea_orm::Set(p50_request_bytes.into()),
+            p90_request_bytes: sea_orm::Set(p90_request_bytes.into()),
+            p99_request_bytes: sea_orm::Set(p99_request_bytes.into()),
+            max_request_bytes: sea_orm::Set(max_request_bytes.into()),
 
-            sum_response_millis: sea_orm::Set(self.sum_response_millis),
-            min_response_millis: sea_orm::Set(min_response_millis),
+            sum_response_millis: sea_orm::Set(self.sum_response_millis.into()),
+            min_response_millis: sea_orm::Set(min_response_millis.into()),
             mean_response_millis: sea_orm::Set(mean_response_millis),
-            p50_response_millis: sea_orm::Set(p50_response_millis),
-            p90_response_millis: sea_orm::Set(p90_response_millis),
-            p99_response_millis: sea_orm::Set(p99_response_millis),
-            max_response_millis: sea_orm::Set(max_response_millis),
+            p50_response_millis: sea_orm::Set(p50_response_millis.into()),
+            p90_response_millis: sea_orm::Set(p90_response_millis.into()),
+            p99_response_millis: sea_orm::Set(p99_response_millis.into()),
+            max_response_millis: sea_orm::Set(max_response_millis.into()),
 
-            sum_response_bytes: sea_orm::Set(self.sum_response_bytes),
-            min_response_bytes: sea_orm::Set(min_response_bytes),
+            sum_response_bytes: sea_orm::Set(self.sum_response_bytes.into()),
+            min_response_bytes: sea_orm::Set(min_response_bytes.into()),
             mean_response_bytes: sea_orm::Set(mean_response_bytes),
-            p50_response_bytes: sea_orm::Set(p50_response_bytes),
-            p90_response_bytes: sea_orm::Set(p90_response_bytes),
-            p99_response_bytes: sea_orm::Set(p99_response_bytes),
-            max_response_bytes: sea_orm::Set(max_response_bytes),
+            p50_response_bytes: sea_orm::Set(p50_response_bytes.into()),
+            p90_response_bytes: sea_orm::Set(p90_response_bytes.into()),
+            p99_response_bytes: sea_orm::Set(p99_response_bytes.into()),
+            max_response_bytes: sea_orm::Set(max_response_bytes.into()),
         };
 
         aggregated_stat_model.save(db_conn).await?;
@@ -301,10 +324,15 @@ impl ProxyResponseStat {
 }
 
 impl StatEmitter {
+    #[allow(clippy::too_many_arguments)]
     pub fn spawn(
         chain_id: u64,
         db_conn: DatabaseConnection,
         period_seconds: u64,
+        billing: Option<BillingConfig>,
+        mailer: Option<Arc<dyn MailTransport>>,
+        tier_change_sender: flume::Sender<u64>,
+        instance_id: Ulid,
         shutdown_receiver: broadcast::Receiver<()>,
     ) -> anyhow::Result<StatEmitterSpawn> {
         let (stat_sender, stat_receiver) = flume::unbounded();
@@ -313,6 +341,10 @@ impl StatEmitter {
             chain_id,
             db_conn,
             period_seconds,
+            billing,
+            mailer,
+            tier_change_sender,
+            instance_id,
         };
 
         // TODO: send any errors somewhere
@@ -350,12 +382,24 @@ impl StatEmitter {
         let mut period_timestamp = current_period.as_secs();
         let mut response_aggregate_map =
             HashMap::<ProxyResponseAggregateKey, ProxyResponseAggregate>::new();
+        // what each user owes for the current period
+        let mut period_usage = HashMap::<u64, Decimal>::new();
+        // debits that failed to save. keyed by (period_timestamp, user_id) so retries reuse the same idempotency key
+        let mut unsaved_usage = HashMap::<(u64, u64), Decimal>::new();
 
         loop {
             tokio::select! {
                 stat = stat_receiver.recv_async() => {
                     match stat? {
                         Web3ProxyStat::Response(stat) => {
+                            if let Some(billing) = self.billing.as_ref() {
+                                let user_id = stat.authorization.checks.user_id;
+
+                                if user_id != 0 {
+                                    *period_usage.entry(user_id).or_default() += stat.price(billing);
+                                }
+                            }
+
                             let key = stat.key();
 
                             // TODO: does hashmap have get_or_insert?
@@ -381,6 +425,9 @@ impl StatEmitter {
                             error!("Unable to save stat while shutting down! {:?}", err);
                         };
                     }
+
+                    self.save_usage(period_timestamp, &mut period_usage, &mut unsaved_usage).await;
+
                     // advance to the next period
                     // TODO: is this safe? what if there is drift?
                     period_timestamp += self.period_seconds;
@@ -409,8 +456,78 @@ impl StatEmitter {
             };
         }
 
+        self.save_usage(period_timestamp, &mut period_usage, &mut unsaved_usage)
+            .await;
+
+        if !unsaved_usage.is_empty() {
+            error!(
+                "unable to debit {} users while shutting down",
+                unsaved_usage.len()
+            );
+        }
+
         info!("aggregated stat_loop shut down");
 
         Ok(())
     }
+
+    /// Debit this period's usage and retry any debits that failed before
+    async fn save_usage(
+        &self,
+        period_timestamp: u64,
+        period_usage: &mut HashMap<u64, Decimal>,
+        unsaved_usage: &mut HashMap<(u64, u64), Decimal>,
+    ) {
+        let billing = match self.billing.as_ref() {
+            Some(x) => x,
+            None => return,
+        };
+
+        for (user_id, amount) in period_usage.drain() {
+            if !amount.is_zero() {
+                unsaved_usage.insert((period_timestamp, user_id), amount);
+            }
+        }
+
+        let mut failed = HashMap::new();
+
+        for ((period_timestamp, user_id), amount) in unsaved_usage.drain() {
+            let idempotency_key = format!(
+                "usage:{}:{}:{}:{}",
+                self.chain_id, self.instance_id, period_timestamp, user_id
+            );
+
+            match debit_usage(&self.db_conn, billing, user_id, amount, idempotency_key).await {
+                Ok(Some(tier_change)) => {
+                    if let Err(err) = self.tier_change_sender.send_async(user_id).await {
+                        error!(
+                            "unable to invalidate rpc keys for user {}! err={:?}",
+                            user_id, err
+                        );
+                    }
+
+                    if tier_change != TierChange::Downgraded {
+                        // a restored tier is already covered by the deposit's email
+                        continue;
+                    }
+
+                    if let Some(mailer) = self.mailer.clone() {
+                        let notification = Notification::OutOfBalance {
+                            free_tier: billing.free_tier.clone(),
+                        };
+
+                        spawn_notify_user(self.db_conn.clone(), mailer, user_id, notification);
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    error!("unable to debit user {}! err={:?}", user_id, err);
+
+                    failed.insert((period_timestamp, user_id), amount);
+                }
+            }
+        }
+
+        *unsaved_usage = failed;
+    }
 }
@@ -0,0 +1,354 @@
+//! Price requests and debit them from prepaid balances.
+//!
+//! Users that run out of balance are moved to the free tier. A deposit moves them back to the tier they paid for.
+//! Tier changes take effect right away. `watch_tier_changes` invalidates the user's cached rpc keys on every proxy over redis pub/sub.
+
+use crate::config::BillingConfig;
+use anyhow::Context;
+use entities::sea_orm_active_enums::BalanceLedgerKind;
+use entities::{balance_ledger, user, user_tier};
+use log::info;
+use migration::sea_orm::prelude::Decimal;
+use migration::sea_orm::{
+    self, ActiveModelTrait, ColumnTrait, ConnectionTrait, DatabaseConnection, DbErr, EntityTrait,
+    QueryFilter, QuerySelect, TransactionTrait,
+};
+use migration::Expr;
+
+/// How `update_user_tier` moved a user
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TierChange {
+    /// Their balance ran out and they were moved to the free tier
+    Downgraded,
+    /// They have a balance again and were moved back to the tier they paid for
+    Restored,
+}
+
+/// What a request costs. Error responses are free
+pub fn request_price(
+    config: &BillingConfig,
+    method: &str,
+    archive_request: bool,
+    cache_hit: bool,
+    error_response: bool,
+) -> Decimal {
+    if error_response {
+        return Decimal::ZERO;
+    }
+
+    let compute_units = config
+        .compute_units
+        .get(method)
+        .or_else(|| config.compute_units.get("*"))
+        .copied()
+        .unwrap_or(1);
+
+    let mut price = config.usd_per_compute_unit * Decimal::from(compute_units);
+
+    if cache_hit {
+        price *= config.cache_hit_multiplier;
+    }
+
+    if archive_request {
+        price *= config.archive_multiplier;
+    }
+
+    price
+}
+
+/// Sum of everything in the user's ledger
+pub async fn get_user_balance<C: ConnectionTrait>(db: &C, user_id: u64) -> Result<Decimal, DbErr> {
+    sum_ledger(db, user_id, None).await
+}
+
+/// Total that the user has been billed. This is positive even though the ledger rows are negative
+pub async fn get_user_usage<C: ConnectionTrait>(db: &C, user_id: u64) -> Result<Decimal, DbErr> {
+    let usage = sum_ledger(db, user_id, Some(BalanceLedgerKind::Usage)).await?;
+
+    Ok(-usage)
+}
+
+async fn sum_ledger<C: ConnectionTrait>(
+    db: &C,
+    user_id: u64,
+    kind: Option<BalanceLedgerKind>,
+) -> Result<Decimal, DbErr> {
+    let mut q = balance_ledger::Entity::find()
+        .select_only()
+        .column_as(balance_ledger::Column::Amount.sum(), "amount")
+        .filter(balance_ledger::Column::UserId.eq(user_id));
+
+    if let Some(kind) = kind {
+        q = q.filter(balance_ledger::Column::Kind.eq(kind));
+    }
+
+    let amount: Option<Option<Decimal>> = q.into_tuple().one(db).await?;
+
+    Ok(amount.flatten().unwrap_or_default())
+}
+
+/// Debit a user's usage. Saving the same `idempotency_key` twice only debits once.
+/// Users on the free tier or an unbilled tier are skipped.
+/// Returns how their tier changed, if it did.
+pub async fn debit_usage(
+    db_conn: &DatabaseConnection,
+    config: &BillingConfig,
+    user_id: u64,
+    amount: Decimal,
+    idempotency_key: String,
+) -> anyhow::Result<Option<TierChange>> {
+    let txn = db_conn.begin().await?;
+
+    let user = match user::Entity::find_by_id(user_id).one(&txn).await? {
+        Some(x) => x,
+        None => {
+            // the user was deleted since the request
+            return Ok(None);
+        }
+    };
+
+    let user_tier = user_tier::Entity::find_by_id(user.user_tier_id)
+        .one(&txn)
+        .await?
+        .context("related user tier")?;
+
+    if user_tier.title == config.free_tier || config.unbilled_tiers.contains(&user_tier.title) {
+        return Ok(None);
+    }
+
+    let existing = balance_ledger::Entity::find()
+        .filter(balance_ledger::Column::IdempotencyKey.eq(idempotency_key.as_str()))
+        .one(&txn)
+        .await?;
+
+    if existing.is_some() {
+        // a previous try saved this but didn't hear back
+        return Ok(None);
+    }
+
+    balance_ledger::ActiveModel {
+        user_id: sea_orm::Set(user_id.into()),
+        amount: sea_orm::Set(-amount),
+        kind: sea_orm::Set(BalanceLedgerKind::Usage),
+        idempotency_key: sea_orm::Set(Some(idempotency_key)),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+
+    let tier_changed = update_user_tier(&txn, config, user_id).await?;
+
+    txn.commit().await?;
+
+    Ok(tier_changed)
+}
+
+/// Move the user to the free tier if their balance is gone, or back to their paid tier if it has been topped up.
+/// Returns how the tier changed, if it did. If multiple proxies do this at once, only one of them changes anything.
+pub async fn update_user_tier<C: ConnectionTrait>(
+    db: &C,
+    config: &BillingConfig,
+    user_id: u64,
+) -> anyhow::Result<Option<TierChange>> {
+    let user = user::Entity::find_by_id(user_id)
+        .one(db)
+        .await?
+        .context("unknown user")?;
+
+    let free_tier = user_tier::Entity::find()
+        .filter(user_tier::Column::Title.eq(config.free_tier.as_str()))
+        .one(db)
+        .await?
+        .context("billing.free_tier does not exist")?;
+
+    let balance = get_user_balance(db, user_id).await?;
+
+    if balance <= Decimal::ZERO {
+        if user.user_tier_id == free_tier.id {
+            return Ok(None);
+        }
+
+        let user_tier = user_tier::Entity::find_by_id(user.user_tier_id)
+            .one(db)
+            .await?
+            .context("related user tier")?;
+
+        if config.unbilled_tiers.contains(&user_tier.title) {
+            return Ok(None);
+        }
+
+        let updated = user::Entity::update_many()
+            .col_expr(user::Column::UserTierId, Expr::value(free_tier.id))
+            .col_expr(
+                user::Column::DowngradedFromTierId,
+                Expr::value(Some(user.user_tier_id)),
+            )
+            .filter(user::Column::Id.eq(user_id))
+            .filter(user::Column::UserTierId.eq(user.user_tier_id))
+            .exec(db)
+            .await?;
+
+        if updated.rows_affected == 0 {
+            return Ok(None);
+        }
+
+        info!(
+            "user {} is out of balance. moved from tier {} to {}",
+            user_id, user_tier.title, free_tier.title
+        );
+
+        Ok(Some(TierChange::Downgraded))
+    } else if let Some(paid_tier_id) = user.downgraded_from_tier_id {
+        // if an admin moved them off the free tier, leave their new tier alone
+        let updated = user::Entity::update_many()
+            .col_expr(user::Column::UserTierId, Expr::value(paid_tier_id))
+            .col_expr(user::Column::DowngradedFromTierId, Expr::value(None::<u64>))
+            .filter(user::Column::Id.eq(user_id))
+            .filter(user::Column::UserTierId.eq(free_tier.id))
+            .filter(user::Column::DowngradedFromTierId.eq(paid_tier_id))
+            .exec(db)
+            .await?;
+
+        if updated.rows_affected == 0 {
+            return Ok(None);
+        }
+
+        info!(
+            "user {} has a balance again. moved back to tier {}",
+            user_id, paid_tier_id
+        );
+
+        Ok(Some(TierChange::Restored))
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashbrown::HashMap;
+    use migration::sea_orm::Database;
+    use migration::{Migrator, MigratorTrait};
+
+    #[test]
+    fn test_request_price() {
+        let config = BillingConfig {
+            compute_units: HashMap::from([("eth_call".to_string(), 20), ("*".to_string(), 10)]),
+            usd_per_compute_unit: Decimal::new(1, 6),
+            cache_hit_multiplier: Decimal::new(5, 1),
+            archive_multiplier: Decimal::new(2, 0),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            request_price(&config, "eth_call", false, false, false),
+            Decimal::new(20, 6)
+        );
+        assert_eq!(
+            request_price(&config, "eth_blockNumber", false, false, false),
+            Decimal::new(10, 6)
+        );
+        assert_eq!(
+            request_price(&config, "eth_call", true, true, false),
+            Decimal::new(20, 6)
+        );
+        assert_eq!(
+            request_price(&config, "eth_call", true, false, true),
+            Decimal::ZERO
+        );
+    }
+
+    #[tokio::test]
+    async fn tier_changes_both_ways() {
+        let db_conn = Database::connect("sqlite::memory:").await.unwrap();
+
+        Migrator::up(&db_conn, None).await.unwrap();
+
+        let config = BillingConfig {
+            usd_per_compute_unit: Decimal::new(1, 6),
+            free_tier: "Free".to_string(),
+            ..Default::default()
+        };
+
+        let paid_tier = user_tier::Entity::find()
+            .filter(user_tier::Column::Title.eq("Unlimited"))
+            .one(&db_conn)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let user = user::ActiveModel {
+            address: sea_orm::Set(vec![1; 20]),
+            user_tier_id: sea_orm::Set(paid_tier.id),
+            ..Default::default()
+        }
+        .insert(&db_conn)
+        .await
+        .unwrap();
+
+        let user_id: u64 = user.id.into();
+
+        balance_ledger::ActiveModel {
+            user_id: sea_orm::Set(user.id),
+            amount: sea_orm::Set(Decimal::new(1, 0)),
+            kind: sea_orm::Set(BalanceLedgerKind::Deposit),
+            ..Default::default()
+        }
+        .insert(&db_conn)
+        .await
+        .unwrap();
+
+        // some balance is left
+        assert_eq!(
+            debit_usage(&db_conn, &config, user_id, Decimal::new(5, 1), "a".into())
+                .await
+                .unwrap(),
+            None
+        );
+
+        // the balance is gone
+        assert_eq!(
+            debit_usage(&db_conn, &config, user_id, Decimal::new(5, 1), "b".into())
+                .await
+                .unwrap(),
+            Some(TierChange::Downgraded)
+        );
+
+        // free tier users are not billed
+        assert_eq!(
+            debit_usage(&db_conn, &config, user_id, Decimal::new(5, 1), "c".into())
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            get_user_usage(&db_conn, user_id).await.unwrap(),
+            Decimal::ONE
+        );
+
+        balance_ledger::ActiveModel {
+            user_id: sea_orm::Set(user.id),
+            amount: sea_orm::Set(Decimal::new(1, 0)),
+            kind: sea_orm::Set(BalanceLedgerKind::Deposit),
+            ..Default::default()
+        }
+        .insert(&db_conn)
+        .await
+        .unwrap();
+
+        assert_eq!(
+            update_user_tier(&db_conn, &config, user_id).await.unwrap(),
+            Some(TierChange::Restored)
+        );
+
+        let user = user::Entity::find_by_id(user.id)
+            .one(&db_conn)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(user.user_tier_id, paid_tier.id);
+        assert_eq!(user.downgraded_from_tier_id, None);
+    }
+}
@@ -39,20 +39,26 @@ impl ChangeUserTierSubCommand {
         let mut user_tier = user_tier.into_active_model();
 
         if let Some(max_requests_per_period) = self.max_requests_per_period {
-            if user_tier.max_requests_per_period == sea_orm::Set(Some(max_requests_per_period)) {
+            if user_tier.max_requests_per_period
+                == sea_orm::Set(Some(max_requests_per_period.into()))
+            {
                 info!("max_requests_per_period already has this value");
             } else {
-                user_tier.max_requests_per_period = sea_orm::Set(Some(max_requests_per_period));
+                user_tier.max_requests_per_period =
+                    sea_orm::Set(Some(max_requests_per_period.into()));
 
                 info!("changed max_requests_per_period")
             }
         }
 
         if let Some(max_concurrent_requests) = self.max_concurrent_requests {
-            if user_tier.max_concurrent_requests == sea_orm::Set(Some(max_concurrent_requests)) {
+            if user_tier.max_concurrent_requests
+                == sea_orm::Set(Some(max_concurrent_requests.into()))
+            {
                 info!("max_concurrent_requests already has this value");
             } else {
-                user_tier.max_concurrent_requests = sea_orm::Set(Some(max_concurrent_requests));
+                user_tier.max_concurrent_requests =
+                    sea_orm::Set(Some(max_concurrent_requests.into()));
 
                 info!("changed max_concurrent_requests")
             }
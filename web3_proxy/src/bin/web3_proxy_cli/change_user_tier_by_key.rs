@@ -52,6 +52,8 @@ impl ChangeUserTierByKeySubCommand {
             let mut user = user.into_active_model();
 
             user.user_tier_id = sea_orm::Set(user_tier.id);
+            // an admin's choice replaces any automatic downgrade
+            user.downgraded_from_tier_id = sea_orm::Set(None);
 
             user.save(db_conn).await?;
 
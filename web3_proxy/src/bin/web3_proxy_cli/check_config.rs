@@ -1,7 +1,48 @@
+//! Check the config for problems. With `--online`, also connect to everything it points at
+use anyhow::Context;
 use argh::FromArgs;
+use ethers::prelude::{Http, JsonRpcClient, Middleware, Provider, Ws};
+use ethers::types::{Address, BlockId, BlockNumber, TxHash, H256, U64};
+use futures::future::join_all;
 use log::{error, info, warn};
+use migration::sea_orm::{ConnectionTrait, Statement};
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::ClientConfig;
+use redis_rate_limiter::{redis, DeadpoolRuntime, RedisConfig};
+use serde::Serialize;
 use std::fs;
-use web3_proxy::config::TopConfig;
+use std::future::Future;
+use std::mem::size_of;
+use std::sync::Arc;
+use tokio::time::{timeout, Duration, Instant};
+use ulid::Ulid;
+use web3_proxy::app::{
+    get_db, AuthorizationChecks, BlockSession, BLOCK_SESSIONS_CAPACITY,
+    JWT_PUBLIC_KEY_CACHE_CAPACITY, PENDING_TRANSACTIONS_CAPACITY, RPC_SECRET_KEY_CACHE_CAPACITY,
+};
+use web3_proxy::config::{TopConfig, Web3RpcConfig};
+use web3_proxy::jwt::RpcJwtKey;
+use web3_proxy::rpcs::block_data_limit::{
+    block_data_limit_from_oldest, find_oldest_block_with_state,
+};
+use web3_proxy::rpcs::errors::BackendErrorDetails;
+use web3_proxy::rpcs::many::{BLOCKS_BY_HASH_CAPACITY, BLOCKS_BY_NUMBER_CAPACITY};
+use web3_proxy::rpcs::transactions::TxStatus;
+
+/// the http and ws heads are fetched at slightly different times
+const HEAD_BLOCK_SLACK: u64 = 3;
+
+/// a rough guess at moka's bookkeeping for each entry
+const CACHE_ENTRY_OVERHEAD: u64 = 128;
+
+/// transactions are mostly heap (input data, access lists). this is a guess for an average one
+const TRANSACTION_HEAP_GUESS: u64 = 1024;
+
+/// block session ids come from clients. assume they are about this long
+const BLOCK_SESSION_ID_GUESS: u64 = 64;
+
+/// parsed public keys live on the heap. an rsa key is the biggest of them
+const JWT_PUBLIC_KEY_HEAP_GUESS: u64 = 512;
 
 #[derive(FromArgs, PartialEq, Eq, Debug)]
 /// Check the config for any problems.
@@ -10,21 +51,98 @@ pub struct CheckConfigSubCommand {
     #[argh(positional)]
     /// path to the configuration toml.
     path: String,
+
+    #[argh(switch)]
+    /// also connect to every rpc, the databases, redis, and kafka.
+    online: bool,
+
+    #[argh(switch)]
+    /// print the report as json on stdout.
+    json: bool,
+
+    #[argh(option, default = "10")]
+    /// how long each request of the online checks can take.
+    timeout_seconds: u64,
+}
+
+/// Everything found by the checks. Any errors make the command exit non-zero
+#[derive(Debug, Default, Serialize)]
+struct CheckConfigReport {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+    caches: Vec<CacheEstimate>,
+    /// the sum of every cache with a known limit
+    caches_max_bytes: u64,
+    /// only with --online
+    services: Vec<ServiceReport>,
+    /// only with --online
+    rpcs: Vec<RpcReport>,
+}
+
+impl CheckConfigReport {
+    fn error(&mut self, msg: String) {
+        error!("{}", msg);
+        self.errors.push(msg);
+    }
+
+    fn warn(&mut self, msg: String) {
+        warn!("{}", msg);
+        self.warnings.push(msg);
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CacheEstimate {
+    name: String,
+    /// None if the cache is weighed by something other than entries, or only expires by time
+    max_entries: Option<u64>,
+    /// None if the cache only expires by time
+    max_bytes: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ServiceReport {
+    name: &'static str,
+    millis: u64,
+    details: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct RpcReport {
+    name: String,
+    /// "balanced_rpcs" or "private_rpcs"
+    group: &'static str,
+    chain_id: Option<u64>,
+    client_version: Option<String>,
+    http_head_block: Option<u64>,
+    ws_head_block: Option<u64>,
+    /// how many blocks of state the server keeps. u64::MAX is an archive node
+    block_data_limit: Option<u64>,
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+/// what a single http or ws connection answered
+struct EndpointCheck {
+    chain_id: u64,
+    client_version: Option<String>,
+    head_block_num: u64,
 }
 
 impl CheckConfigSubCommand {
     pub async fn main(self) -> anyhow::Result<()> {
-        let mut num_errors = 0;
+        let mut report = CheckConfigReport::default();
 
         info!("Loading config @ {}", self.path);
-        let top_config: String = fs::read_to_string(self.path)?;
+        let top_config: String = fs::read_to_string(&self.path)?;
         let top_config: TopConfig = toml::from_str(&top_config)?;
 
         // TODO: pretty print
         info!("config: {:#?}", top_config);
 
         if top_config.app.db_url.is_none() {
-            warn!("app.db_url is not set! Some features disabled")
+            report.warn("app.db_url is not set! Some features disabled".to_string())
         }
 
         match top_config.app.public_requests_per_period {
@@ -41,7 +159,7 @@ impl CheckConfigSubCommand {
             None => {
                 info!("app.default_user_requests_per_period is None. Fully open to registered requests!")
             }
-            Some(0) => warn!("app.default_user_requests_per_period is 0. Registered user's requests are blocked! Are you sure you want that?"),
+            Some(0) => report.warn("app.default_user_requests_per_period is 0. Registered user's requests are blocked! Are you sure you want that?".to_string()),
             Some(_) => {
                 // TODO: make sure this isn't < anonymous requests per period
             }
@@ -58,36 +176,560 @@ impl CheckConfigSubCommand {
         // TODO: check login_rate_limit_per_period is a reasonable amount. requires redis
 
         if top_config.app.volatile_redis_url.is_none() {
-            warn!("app.volatile_redis_url is not set! Some features disabled")
+            report.warn("app.volatile_redis_url is not set! Some features disabled".to_string())
         }
 
-        // TODO: check response_cache_max_bytes is a reasonable amount
-
         if top_config.app.redirect_public_url.is_none() {
-            warn!("app.redirect_public_url is None. Anonyoumous users will get an error page instead of a redirect")
+            report.warn("app.redirect_public_url is None. Anonyoumous users will get an error page instead of a redirect".to_string())
         }
 
         // TODO: also check that it contains rpc_key_id!
-        match top_config.app.redirect_rpc_key_url {
+        match &top_config.app.redirect_rpc_key_url {
             None => {
-                warn!("app.redirect_rpc_key_url is None. Registered users will get an error page instead of a redirect")
+                report.warn("app.redirect_rpc_key_url is None. Registered users will get an error page instead of a redirect".to_string())
             }
             Some(x) => {
                 if !x.contains("{{rpc_key_id}}") {
-                    num_errors += 1;
-                    error!("redirect_rpc_key_url user url must contain \"{{rpc_key_id}}\"")
+                    report.error(
+                        "redirect_rpc_key_url user url must contain \"{{rpc_key_id}}\"".to_string(),
+                    )
                 }
             }
         }
 
-        // TODO: print num warnings and have a flag to fail even on warnings
+        report.caches = cache_estimates(&top_config);
+        report.caches_max_bytes = report.caches.iter().filter_map(|x| x.max_bytes).sum();
+
+        for x in report.caches.iter() {
+            match x.max_bytes {
+                Some(max_bytes) => {
+                    info!("{} can use up to {} MiB", x.name, max_bytes / 1024 / 1024)
+                }
+                None => info!("{} only expires by time", x.name),
+            }
+        }
+
+        info!(
+            "caches can use up to {} MiB",
+            report.caches_max_bytes / 1024 / 1024
+        );
+
+        if self.online {
+            self.check_online(&top_config, &mut report).await;
+        }
+
+        info!(
+            "{} errors and {} warnings",
+            report.errors.len(),
+            report.warnings.len()
+        );
 
-        if num_errors == 0 {
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+
+        // TODO: have a flag to fail even on warnings
+        if report.errors.is_empty() {
             Ok(())
         } else {
-            Err(anyhow::anyhow!("there were {} errors!", num_errors))
+            Err(anyhow::anyhow!(
+                "there were {} errors!",
+                report.errors.len()
+            ))
+        }
+    }
+
+    async fn check_online(&self, top_config: &TopConfig, report: &mut CheckConfigReport) {
+        let max_wait = Duration::from_secs(self.timeout_seconds);
+
+        let app = &top_config.app;
+
+        if let Some(db_url) = app.db_url.clone() {
+            report
+                .services
+                .push(check_service("db", max_wait, check_db(db_url)).await);
+        }
+
+        if let Some(db_replica_url) = app.db_replica_url.clone() {
+            report
+                .services
+                .push(check_service("db_replica", max_wait, check_db(db_replica_url)).await);
+        }
+
+        if let Some(redis_url) = app.volatile_redis_url.clone() {
+            report
+                .services
+                .push(check_service("volatile_redis", max_wait, check_redis(redis_url)).await);
+        }
+
+        if let Some(kafka_urls) = app.kafka_urls.clone() {
+            report
+                .services
+                .push(check_service("kafka", max_wait, check_kafka(kafka_urls, max_wait)).await);
+        }
+
+        for x in report.services.iter() {
+            match &x.error {
+                None => info!(
+                    "{} is reachable in {}ms. {}",
+                    x.name,
+                    x.millis,
+                    x.details.as_deref().unwrap_or_default()
+                ),
+                Some(err) => {
+                    let msg = format!("{} is not reachable: {}", x.name, err);
+                    error!("{}", msg);
+                    report.errors.push(msg);
+                }
+            }
+        }
+
+        let private_rpcs = top_config.private_rpcs.clone().unwrap_or_default();
+
+        let checks = top_config
+            .balanced_rpcs
+            .clone()
+            .into_iter()
+            .map(|(name, config)| ("balanced_rpcs", name, config))
+            .chain(
+                private_rpcs
+                    .into_iter()
+                    .map(|(name, config)| ("private_rpcs", name, config)),
+            )
+            .filter(|(group, name, config)| {
+                if config.disabled {
+                    info!("{}.{} is disabled", group, name);
+                }
+                !config.disabled
+            })
+            .map(|(group, name, config)| check_rpc(group, name, config, app.chain_id, max_wait));
+
+        report.rpcs = join_all(checks).await;
+
+        let mut errors = vec![];
+        let mut warnings = vec![];
+
+        for rpc in report.rpcs.iter() {
+            for err in rpc.errors.iter() {
+                errors.push(format!("{}.{}: {}", rpc.group, rpc.name, err));
+            }
+            for warning in rpc.warnings.iter() {
+                warnings.push(format!("{}.{}: {}", rpc.group, rpc.name, warning));
+            }
+        }
+
+        for x in errors {
+            report.error(x);
+        }
+
+        for x in warnings {
+            report.warn(x);
+        }
+
+        let deepest = report
+            .rpcs
+            .iter()
+            .filter(|x| x.group == "balanced_rpcs")
+            .filter_map(|x| x.block_data_limit)
+            .max();
+
+        match deepest {
+            None => report.error("no balanced rpcs are serving requests".to_string()),
+            Some(deepest) if deepest < app.archive_depth => report.warn(format!(
+                "the deepest balanced rpc only has {} blocks of state. archive requests (older than {} blocks) will fail",
+                deepest, app.archive_depth
+            )),
+            Some(_) => {}
+        }
+    }
+}
+
+/// worst case sizes for the caches that `Web3ProxyApp` creates
+fn cache_estimates(top_config: &TopConfig) -> Vec<CacheEstimate> {
+    let entry_bytes = |key: usize, value: usize| key as u64 + value as u64 + CACHE_ENTRY_OVERHEAD;
+
+    let mut caches = vec![
+        CacheEstimate {
+            name: "response_cache".to_string(),
+            max_entries: None,
+            max_bytes: Some(top_config.app.response_cache_max_bytes),
+        },
+        CacheEstimate {
+            name: "pending_transactions".to_string(),
+            max_entries: Some(PENDING_TRANSACTIONS_CAPACITY),
+            max_bytes: Some(
+                PENDING_TRANSACTIONS_CAPACITY
+                    * (entry_bytes(size_of::<TxHash>(), size_of::<TxStatus>())
+                        + TRANSACTION_HEAP_GUESS),
+            ),
+        },
+        CacheEstimate {
+            name: "rpc_secret_key_cache".to_string(),
+            max_entries: Some(RPC_SECRET_KEY_CACHE_CAPACITY),
+            max_bytes: Some(
+                RPC_SECRET_KEY_CACHE_CAPACITY
+                    * entry_bytes(size_of::<Ulid>(), size_of::<AuthorizationChecks>()),
+            ),
+        },
+        CacheEstimate {
+            name: "jwt_public_key_cache".to_string(),
+            max_entries: Some(JWT_PUBLIC_KEY_CACHE_CAPACITY),
+            max_bytes: Some(
+                JWT_PUBLIC_KEY_CACHE_CAPACITY
+                    * (entry_bytes(
+                        size_of::<Ulid>(),
+                        size_of::<Option<Arc<RpcJwtKey>>>() + size_of::<RpcJwtKey>(),
+                    ) + JWT_PUBLIC_KEY_HEAP_GUESS),
+            ),
+        },
+        CacheEstimate {
+            name: "block_sessions".to_string(),
+            max_entries: Some(BLOCK_SESSIONS_CAPACITY),
+            // the cache key and the session's redis key both have the id
+            max_bytes: Some(
+                BLOCK_SESSIONS_CAPACITY
+                    * (entry_bytes(
+                        size_of::<String>(),
+                        size_of::<Arc<BlockSession>>() + size_of::<BlockSession>(),
+                    ) + 2 * BLOCK_SESSION_ID_GUESS),
+            ),
+        },
+    ];
+
+    for name in [
+        "bearer_token_semaphores",
+        "ip_semaphores",
+        "registered_user_semaphores",
+    ] {
+        caches.push(CacheEstimate {
+            name: name.to_string(),
+            max_entries: None,
+            max_bytes: None,
+        });
+    }
+
+    // every group of rpcs has its own block caches
+    let mut groups = vec!["balanced_rpcs"];
+    if top_config.private_rpcs.is_some() {
+        groups.push("private_rpcs");
+    }
+
+    for group in groups {
+        // the weigher counts transaction hashes. assume every unit is one
+        caches.push(CacheEstimate {
+            name: format!("{}.blocks_by_hash", group),
+            max_entries: None,
+            max_bytes: Some(BLOCKS_BY_HASH_CAPACITY * size_of::<H256>() as u64),
+        });
+
+        caches.push(CacheEstimate {
+            name: format!("{}.blocks_by_number", group),
+            max_entries: Some(BLOCKS_BY_NUMBER_CAPACITY),
+            max_bytes: Some(
+                BLOCKS_BY_NUMBER_CAPACITY * entry_bytes(size_of::<U64>(), size_of::<H256>()),
+            ),
+        });
+    }
+
+    caches
+}
+
+async fn check_service<F>(name: &'static str, max_wait: Duration, f: F) -> ServiceReport
+where
+    F: Future<Output = anyhow::Result<String>>,
+{
+    let start = Instant::now();
+
+    let (details, error) = match timeout(max_wait, f).await {
+        Ok(Ok(details)) => (Some(details), None),
+        Ok(Err(err)) => (None, Some(format!("{:#}", err))),
+        Err(_) => (None, Some("timed out".to_string())),
+    };
+
+    ServiceReport {
+        name,
+        millis: start.elapsed().as_millis() as u64,
+        details,
+        error,
+    }
+}
+
+async fn check_db(db_url: String) -> anyhow::Result<String> {
+    let db_conn = get_db(db_url, 1, 1).await?;
+
+    let db_backend = db_conn.get_database_backend();
+
+    db_conn
+        .execute(Statement::from_string(db_backend, "SELECT 1".to_string()))
+        .await?;
+
+    Ok(format!("{:?}", db_backend))
+}
+
+async fn check_redis(redis_url: String) -> anyhow::Result<String> {
+    let redis_pool = RedisConfig::from_url(redis_url)
+        .builder()?
+        .max_size(1)
+        .runtime(DeadpoolRuntime::Tokio1)
+        .build()?;
+
+    let mut redis_conn = redis_pool.get().await?;
+
+    let pong: String = redis::cmd("PING").query_async(&mut redis_conn).await?;
+
+    Ok(pong)
+}
+
+async fn check_kafka(kafka_urls: String, max_wait: Duration) -> anyhow::Result<String> {
+    let consumer: BaseConsumer = ClientConfig::new()
+        .set("bootstrap.servers", kafka_urls)
+        .create()?;
+
+    // fetching metadata blocks
+    let (brokers, topics) = tokio::task::spawn_blocking(move || {
+        consumer
+            .fetch_metadata(None, max_wait)
+            .map(|x| (x.brokers().len(), x.topics().len()))
+    })
+    .await??;
+
+    Ok(format!("{} brokers and {} topics", brokers, topics))
+}
+
+async fn check_rpc(
+    group: &'static str,
+    name: String,
+    config: Web3RpcConfig,
+    chain_id: u64,
+    max_wait: Duration,
+) -> RpcReport {
+    let mut report = RpcReport {
+        name,
+        group,
+        ..Default::default()
+    };
+
+    let mut http_url = config.http_url;
+    let mut ws_url = config.ws_url;
+
+    // same fallback as Web3Rpc::spawn
+    if http_url.is_none() && ws_url.is_none() {
+        match config.url {
+            Some(url) if url.starts_with("ws") => ws_url = Some(url),
+            Some(url) if url.starts_with("http") => http_url = Some(url),
+            Some(_) => {
+                report
+                    .errors
+                    .push("only ws or http urls are supported".to_string());
+                return report;
+            }
+            None => {
+                report
+                    .errors
+                    .push("either ws_url or http_url are required".to_string());
+                return report;
+            }
+        }
+    }
+
+    if group == "balanced_rpcs" && (http_url.is_none() || ws_url.is_none()) {
+        report
+            .warnings
+            .push("only one of ws_url and http_url is set. it is best to set both".to_string());
+    }
+
+    let http = http_url.and_then(|url| match Provider::<Http>::try_from(url.as_str()) {
+        Ok(x) => Some(x),
+        Err(err) => {
+            report.errors.push(format!("invalid http_url: {}", err));
+            None
+        }
+    });
+
+    let ws = match ws_url {
+        None => None,
+        Some(url) => match timeout(max_wait, Provider::<Ws>::connect(url)).await {
+            Ok(Ok(x)) => Some(x),
+            Ok(Err(err)) => {
+                report
+                    .errors
+                    .push(format!("unable to connect to ws_url: {}", err));
+                None
+            }
+            Err(_) => {
+                report
+                    .errors
+                    .push("timed out connecting to ws_url".to_string());
+                None
+            }
+        },
+    };
+
+    let (http_check, ws_check) = tokio::join!(
+        check_endpoint(http.as_ref(), max_wait),
+        check_endpoint(ws.as_ref(), max_wait)
+    );
+
+    for (transport, check) in [("http", &http_check), ("ws", &ws_check)] {
+        match check {
+            None => {}
+            Some(Ok(x)) => {
+                if x.chain_id != chain_id {
+                    report.errors.push(format!(
+                        "{} is on chain {}, not {}",
+                        transport, x.chain_id, chain_id
+                    ));
+                }
+
+                report.chain_id = Some(x.chain_id);
+
+                if report.client_version.is_none() {
+                    report.client_version = x.client_version.clone();
+                }
+            }
+            Some(Err(err)) => {
+                let msg = format!("{} check failed: {:#}", transport, err);
+
+                // private rpcs are often send-only relays that don't answer much else
+                if group == "private_rpcs" {
+                    report.warnings.push(msg);
+                } else {
+                    report.errors.push(msg);
+                }
+            }
+        }
+    }
+
+    report.http_head_block = http_check
+        .as_ref()
+        .and_then(|x| x.as_ref().ok())
+        .map(|x| x.head_block_num);
+    report.ws_head_block = ws_check
+        .as_ref()
+        .and_then(|x| x.as_ref().ok())
+        .map(|x| x.head_block_num);
+
+    if let (Some(http_head), Some(ws_head)) = (report.http_head_block, report.ws_head_block) {
+        if http_head.abs_diff(ws_head) > HEAD_BLOCK_SLACK {
+            report.errors.push(format!(
+                "http and ws heads disagree: {} vs {}",
+                http_head, ws_head
+            ));
         }
     }
+
+    if group != "balanced_rpcs" {
+        return report;
+    }
+
+    // http is preferred because archive checks are a lot of requests
+    let found = match (&http, report.http_head_block, &ws, report.ws_head_block) {
+        (Some(http), Some(head_block_num), _, _) => {
+            Some(find_block_data_limit(http, head_block_num, max_wait).await)
+        }
+        (_, _, Some(ws), Some(head_block_num)) => {
+            Some(find_block_data_limit(ws, head_block_num, max_wait).await)
+        }
+        _ => None,
+    };
+
+    match found {
+        None => {}
+        Some(Ok(0)) => {
+            report.block_data_limit = Some(0);
+            report
+                .errors
+                .push("no state at the head block. is it still syncing?".to_string());
+        }
+        Some(Ok(limit)) => {
+            report.block_data_limit = Some(limit);
+
+            if let Some(configured) = config.block_data_limit {
+                if configured > limit {
+                    report.warnings.push(format!(
+                        "block_data_limit is set to {}, but only {} blocks of state were found",
+                        configured, limit
+                    ));
+                }
+            }
+        }
+        Some(Err(err)) => {
+            report
+                .warnings
+                .push(format!("unable to check block data limit: {:#}", err));
+        }
+    }
+
+    report
+}
+
+async fn check_endpoint<P: JsonRpcClient>(
+    provider: Option<&Provider<P>>,
+    max_wait: Duration,
+) -> Option<anyhow::Result<EndpointCheck>> {
+    let provider = provider?;
+
+    let f = async {
+        let chain_id = timeout(max_wait, provider.get_chainid())
+            .await
+            .context("timeout fetching eth_chainId")?
+            .context("eth_chainId")?
+            .as_u64();
+
+        // not every server answers web3_clientVersion. that's fine
+        let client_version = timeout(max_wait, provider.client_version())
+            .await
+            .ok()
+            .and_then(Result::ok);
+
+        let head_block_num = timeout(max_wait, provider.get_block_number())
+            .await
+            .context("timeout fetching eth_blockNumber")?
+            .context("eth_blockNumber")?
+            .as_u64();
+
+        Ok(EndpointCheck {
+            chain_id,
+            client_version,
+            head_block_num,
+        })
+    };
+
+    Some(f.await)
+}
+
+/// the same search that `Web3Rpc` does when it connects
+async fn find_block_data_limit<P: JsonRpcClient>(
+    provider: &Provider<P>,
+    head_block_num: u64,
+    max_wait: Duration,
+) -> anyhow::Result<u64> {
+    let address: Address = "0xdead00000000000000000000000000000000beef".parse()?;
+
+    let oldest = find_oldest_block_with_state(head_block_num, move |block_num| async move {
+        let block = Some(BlockId::Number(BlockNumber::Number(block_num.into())));
+
+        let result = timeout(max_wait, provider.get_code(address, block))
+            .await
+            .context("timeout fetching eth_getCode")?;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(err) => {
+                let error_details = BackendErrorDetails::from_provider_error(&err);
+
+                // a jsonrpc error means the server answered, but doesn't have the state
+                if error_details.code.is_none() || error_details.rate_limit().is_some() {
+                    Err(err).context("unable to check for state")
+                } else {
+                    Ok(false)
+                }
+            }
+        }
+    })
+    .await?;
+
+    Ok(block_data_limit_from_oldest(head_block_num, oldest))
 }
 
 #[cfg(test)]
@@ -116,4 +758,35 @@ mod tests {
 
         check_config_result.expect("the config should pass all checks");
     }
+
+    #[test]
+    fn private_rpcs_double_the_block_caches() {
+        let config_path = env::current_dir()
+            .expect("path")
+            .parent()
+            .expect("always a parent")
+            .join("config")
+            .join("example.toml");
+
+        let mut top_config: TopConfig =
+            toml::from_str(&fs::read_to_string(config_path).unwrap()).unwrap();
+
+        top_config.private_rpcs = None;
+        let without_private = cache_estimates(&top_config);
+
+        top_config.private_rpcs = Some(Default::default());
+        let with_private = cache_estimates(&top_config);
+
+        assert_eq!(with_private.len(), without_private.len() + 2);
+
+        let response_cache = with_private
+            .iter()
+            .find(|x| x.name == "response_cache")
+            .unwrap();
+
+        assert_eq!(
+            response_cache.max_bytes,
+            Some(top_config.app.response_cache_max_bytes)
+        );
+    }
 }
@@ -1 +1,227 @@
 //! delete user. don't delete rpc_accounting because we need that for our own accounting. have a "deleted" user that takes them over
+use anyhow::Context;
+use argh::FromArgs;
+use entities::{
+    admin, admin_trail, balance_ledger, deposit, email_verification, jwt_public_key, login,
+    pending_login, revert_log, rpc_accounting, rpc_key, secondary_user, team_trail, user,
+};
+use ethers::types::Address;
+use log::{debug, info};
+use migration::{
+    sea_orm::{
+        self, ActiveModelTrait, ColumnTrait, ConnectionTrait, DatabaseConnection, EntityTrait,
+        QueryFilter, TransactionTrait,
+    },
+    Expr,
+};
+use web3_proxy::frontend::authorization::RpcSecretKey;
+
+/// delete a user and everything tied to them except for their stats.
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "delete_user")]
+pub struct DeleteUserSubCommand {
+    #[argh(positional)]
+    /// the address of the user you want to delete.
+    user_address: Address,
+}
+
+impl DeleteUserSubCommand {
+    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
+        let address: Vec<u8> = self.user_address.to_fixed_bytes().into();
+
+        let txn = db_conn.begin().await?;
+
+        let u = user::Entity::find()
+            .filter(user::Column::Address.eq(address))
+            .one(&txn)
+            .await?
+            .context("No user found with that address")?;
+
+        debug!("user: {:#?}", u);
+
+        let (deleted_user_id, deleted_rpc_key_id) = get_or_create_deleted_user(&txn).await?;
+
+        anyhow::ensure!(u.id != deleted_user_id, "the deleted user can't be deleted");
+
+        let rpc_key_ids: Vec<u64> = rpc_key::Entity::find()
+            .filter(rpc_key::Column::UserId.eq(u.id))
+            .all(&txn)
+            .await?
+            .into_iter()
+            .map(|x| x.id.into())
+            .collect();
+
+        // keep the stats, but don't keep them connected to the user
+        let moved = rpc_accounting::Entity::update_many()
+            .col_expr(
+                rpc_accounting::Column::RpcKeyId,
+                Expr::value(deleted_rpc_key_id),
+            )
+            .filter(rpc_accounting::Column::RpcKeyId.is_in(rpc_key_ids.clone()))
+            .exec(&txn)
+            .await?;
+
+        info!("moved {} rpc_accounting rows", moved.rows_affected);
+
+        // revert logs include call data. that is the user's data, so it goes
+        let deleted = revert_log::Entity::delete_many()
+            .filter(revert_log::Column::RpcKeyId.is_in(rpc_key_ids.clone()))
+            .exec(&txn)
+            .await?;
+
+        info!("deleted {} revert logs", deleted.rows_affected);
+
+        let deleted = jwt_public_key::Entity::delete_many()
+            .filter(jwt_public_key::Column::RpcKeyId.is_in(rpc_key_ids.clone()))
+            .exec(&txn)
+            .await?;
+
+        info!("deleted {} jwt public keys", deleted.rows_affected);
+
+        let deleted = rpc_key::Entity::delete_many()
+            .filter(rpc_key::Column::Id.is_in(rpc_key_ids))
+            .exec(&txn)
+            .await?;
+
+        info!("deleted {} rpc keys", deleted.rows_affected);
+
+        let deleted = login::Entity::delete_many()
+            .filter(login::Column::UserId.eq(u.id))
+            .exec(&txn)
+            .await?;
+
+        info!("deleted {} logins", deleted.rows_affected);
+
+        pending_login::Entity::delete_many()
+            .filter(pending_login::Column::ImitatingUser.eq(u.id))
+            .exec(&txn)
+            .await?;
+
+        email_verification::Entity::delete_many()
+            .filter(email_verification::Column::UserId.eq(u.id))
+            .exec(&txn)
+            .await?;
+
+        // their own members and their memberships in other accounts
+        let deleted = secondary_user::Entity::delete_many()
+            .filter(
+                secondary_user::Column::UserId
+                    .eq(u.id)
+                    .or(secondary_user::Column::MemberUserId.eq(u.id)),
+            )
+            .exec(&txn)
+            .await?;
+
+        info!("deleted {} secondary users", deleted.rows_affected);
+
+        admin::Entity::delete_many()
+            .filter(admin::Column::UserId.eq(u.id))
+            .exec(&txn)
+            .await?;
+
+        // the admin trail is our audit log. keep the rows, but point them at the deleted user
+        admin_trail::Entity::update_many()
+            .col_expr(admin_trail::Column::Caller, Expr::value(deleted_user_id))
+            .filter(admin_trail::Column::Caller.eq(u.id))
+            .exec(&txn)
+            .await?;
+
+        admin_trail::Entity::update_many()
+            .col_expr(
+                admin_trail::Column::ImitatingUser,
+                Expr::value(deleted_user_id),
+            )
+            .filter(admin_trail::Column::ImitatingUser.eq(u.id))
+            .exec(&txn)
+            .await?;
+
+        // other accounts' team trails are theirs to keep
+        team_trail::Entity::update_many()
+            .col_expr(team_trail::Column::Caller, Expr::value(deleted_user_id))
+            .filter(team_trail::Column::Caller.eq(u.id))
+            .exec(&txn)
+            .await?;
+
+        let deleted = team_trail::Entity::delete_many()
+            .filter(team_trail::Column::UserId.eq(u.id))
+            .exec(&txn)
+            .await?;
+
+        info!("deleted {} team trail rows", deleted.rows_affected);
+
+        // deposits and the ledger are our own accounting too
+        deposit::Entity::update_many()
+            .col_expr(deposit::Column::UserId, Expr::value(deleted_user_id))
+            .filter(deposit::Column::UserId.eq(u.id))
+            .exec(&txn)
+            .await?;
+
+        balance_ledger::Entity::update_many()
+            .col_expr(balance_ledger::Column::UserId, Expr::value(deleted_user_id))
+            .filter(balance_ledger::Column::UserId.eq(u.id))
+            .exec(&txn)
+            .await?;
+
+        user::Entity::delete_by_id(u.id).exec(&txn).await?;
+
+        txn.commit().await?;
+
+        info!("deleted user #{}", u.id);
+
+        Ok(())
+    }
+}
+
+/// the "deleted" user owns a single inactive key. rpc_accounting rows of deleted users are moved to it.
+async fn get_or_create_deleted_user<C: ConnectionTrait>(db_conn: &C) -> anyhow::Result<(u64, u64)> {
+    // same padding as a test user made with `create_user --address deleted`
+    let address: Vec<u8> = format!("{:\x00>20}", "deleted").into_bytes();
+
+    let deleted_user_id = match user::Entity::find()
+        .filter(user::Column::Address.eq(address.clone()))
+        .one(db_conn)
+        .await?
+    {
+        Some(x) => x.id,
+        None => {
+            let u = user::ActiveModel {
+                address: sea_orm::Set(address),
+                description: sea_orm::Set(Some("takes over the stats of deleted users".into())),
+                ..Default::default()
+            };
+
+            let u = u
+                .insert(db_conn)
+                .await
+                .context("Failed saving deleted user")?;
+
+            info!("created the deleted user #{}", u.id);
+
+            u.id
+        }
+    };
+
+    let deleted_rpc_key_id = match rpc_key::Entity::find()
+        .filter(rpc_key::Column::UserId.eq(deleted_user_id))
+        .one(db_conn)
+        .await?
+    {
+        Some(x) => x.id,
+        None => {
+            let uk = rpc_key::ActiveModel {
+                user_id: sea_orm::Set(deleted_user_id),
+                secret_key: sea_orm::Set(RpcSecretKey::new().into()),
+                description: sea_orm::Set(Some("stats of deleted users".into())),
+                active: sea_orm::Set(false),
+                ..Default::default()
+            };
+
+            uk.insert(db_conn)
+                .await
+                .context("Failed saving deleted user's key")?
+                .id
+        }
+    };
+
+    Ok((deleted_user_id.into(), deleted_rpc_key_id.into()))
+}
@@ -1 +1,123 @@
 //! List users that have recently made a request
+use anyhow::Context;
+use argh::FromArgs;
+use chrono::{TimeZone, Utc};
+use entities::user;
+use ethers::{
+    types::{Address, Bytes},
+    utils::keccak256,
+};
+use hashbrown::HashMap;
+use log::info;
+use migration::sea_orm::{DatabaseConnection, EntityTrait};
+use prettytable::{row, Table};
+use redis_rate_limiter::{redis::AsyncCommands, DeadpoolRuntime, RedisConfig};
+use web3_proxy::config::TopConfig;
+
+/// list users that have recently made a request.
+#[derive(FromArgs, PartialEq, Debug, Eq)]
+#[argh(subcommand, name = "list_recent_users")]
+pub struct ListRecentUsersSubCommand {
+    #[argh(option, default = "60")]
+    /// how many minutes back to look. the proxy only keeps a week
+    minutes: i64,
+}
+
+impl ListRecentUsersSubCommand {
+    pub async fn main(
+        self,
+        top_config: TopConfig,
+        db_conn: Option<&DatabaseConnection>,
+    ) -> anyhow::Result<()> {
+        let redis_url = top_config
+            .app
+            .volatile_redis_url
+            .context("top_config.app.volatile_redis_url is required")?;
+
+        let redis_pool = RedisConfig::from_url(redis_url)
+            .builder()?
+            .max_size(1)
+            .runtime(DeadpoolRuntime::Tokio1)
+            .build()?;
+
+        let mut redis_conn = redis_pool.get().await?;
+
+        let chain_id = top_config.app.chain_id;
+        let since = Utc::now().timestamp() - self.minutes * 60;
+
+        // ips are salted and hashed before they are saved. all we can do is count them
+        let recent_ips: usize = redis_conn
+            .zcount(format!("recent_users:ip:{}", chain_id), since, i64::MAX)
+            .await?;
+
+        // user ids are salted and hashed too, but there aren't many users so we can check them all
+        let recent_user_ids: Vec<(String, i64)> = redis_conn
+            .zrangebyscore_withscores(format!("recent_users:id:{}", chain_id), since, i64::MAX)
+            .await?;
+
+        info!(
+            "{} ips and {} users in the last {} minutes",
+            recent_ips,
+            recent_user_ids.len(),
+            self.minutes
+        );
+
+        let salt = match top_config.app.public_recent_ips_salt {
+            Some(x) => x,
+            None => {
+                info!("public_recent_ips_salt is not set. users can't be identified");
+                return Ok(());
+            }
+        };
+
+        let db_conn = match db_conn {
+            Some(x) => x,
+            None => {
+                info!("no database. users can't be identified");
+                return Ok(());
+            }
+        };
+
+        // this matches how the frontend hashes ids. user id 0 is anonymous traffic that used a key
+        let mut users_by_hash: HashMap<String, (u64, Option<Address>)> = user::Entity::find()
+            .all(db_conn)
+            .await?
+            .into_iter()
+            .map(|x| (x.id.into(), Some(Address::from_slice(&x.address))))
+            .chain([(0, None)])
+            .map(|(id, address)| {
+                let hashed = Bytes::from(keccak256(format!("{}:{}", salt, id).as_bytes()));
+
+                (hashed.to_string(), (id, address))
+            })
+            .collect();
+
+        let mut table = Table::new();
+
+        table.add_row(row!["user_id", "address", "last_request"]);
+
+        // newest first
+        for (hashed, timestamp) in recent_user_ids.into_iter().rev() {
+            let last_request = Utc
+                .timestamp_opt(timestamp, 0)
+                .single()
+                .map(|x| x.to_rfc3339())
+                .unwrap_or_default();
+
+            match users_by_hash.remove(&hashed) {
+                Some((id, address)) => {
+                    let address = address.map(|x| format!("{:?}", x)).unwrap_or_default();
+
+                    table.add_row(row![id, address, last_request]);
+                }
+                None => {
+                    table.add_row(row!["?", hashed, last_request]);
+                }
+            }
+        }
+
+        table.printstd();
+
+        Ok(())
+    }
+}
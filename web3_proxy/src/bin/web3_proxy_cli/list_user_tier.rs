@@ -1 +1,69 @@
-//! TODO: write this
+//! List every user tier and how many users are in it
+use argh::FromArgs;
+use entities::unsigned::BigUnsigned;
+use entities::{user, user_tier};
+use hashbrown::HashMap;
+use migration::sea_orm::{
+    self, ColumnTrait, DatabaseConnection, EntityTrait, FromQueryResult, QueryOrder, QuerySelect,
+};
+use prettytable::{row, Table};
+
+/// list user tiers and how many users are in each.
+#[derive(FromArgs, PartialEq, Debug, Eq)]
+#[argh(subcommand, name = "list_user_tier")]
+pub struct ListUserTierSubCommand {}
+
+impl ListUserTierSubCommand {
+    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
+        #[derive(FromQueryResult)]
+        struct SelectResult {
+            user_tier_id: BigUnsigned,
+            users: i64,
+        }
+
+        let user_counts: HashMap<BigUnsigned, i64> = user::Entity::find()
+            .select_only()
+            .column(user::Column::UserTierId)
+            .column_as(user::Column::Id.count(), "users")
+            .group_by(user::Column::UserTierId)
+            .into_model::<SelectResult>()
+            .all(db_conn)
+            .await?
+            .into_iter()
+            .map(|x| (x.user_tier_id, x.users))
+            .collect();
+
+        let user_tiers = user_tier::Entity::find()
+            .order_by_asc(user_tier::Column::Id)
+            .all(db_conn)
+            .await?;
+
+        let mut table = Table::new();
+
+        table.add_row(row![
+            "id",
+            "title",
+            "max_requests_per_period",
+            "max_concurrent_requests",
+            "users",
+        ]);
+
+        for x in user_tiers {
+            table.add_row(row![
+                x.id,
+                x.title,
+                x.max_requests_per_period
+                    .map(|x| x.to_string())
+                    .unwrap_or_else(|| "unlimited".to_string()),
+                x.max_concurrent_requests
+                    .map(|x| x.to_string())
+                    .unwrap_or_else(|| "unlimited".to_string()),
+                user_counts.get(&x.id).copied().unwrap_or_default(),
+            ]);
+        }
+
+        table.printstd();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,419 @@
+//! Ramp up the request rate against a single backend to estimate its soft and hard limits.
+//!
+//! The soft limit is the highest rate where latency stayed close to the first step.
+//! The hard limit is the highest rate where errors stayed rare.
+use anyhow::Context;
+use argh::FromArgs;
+use futures::future::join_all;
+use hdrhistogram::Histogram;
+use log::info;
+use prettytable::{row, Table};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use thread_fast_rng::rand::seq::SliceRandom;
+use thread_fast_rng::rand::Rng;
+use thread_fast_rng::thread_fast_rng;
+use tokio::sync::Semaphore;
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// ramp requests against one backend and suggest soft_limit and hard_limit.
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "loadtest")]
+pub struct LoadtestSubCommand {
+    #[argh(positional)]
+    /// the backend's http url. don't point this at a server that other people rely on!
+    rpc: String,
+
+    #[argh(option, default = "\"loadtest\".to_string()")]
+    /// name for the suggested balanced_rpcs entry
+    name: String,
+
+    #[argh(option, default = "10")]
+    /// requests per second for the first step. its latency is the baseline
+    start_rps: u64,
+
+    #[argh(option, default = "5_000")]
+    /// stop ramping once this rate is reached
+    max_rps: u64,
+
+    #[argh(option, default = "50")]
+    /// how much to increase the rate by on each step
+    step_percent: u64,
+
+    #[argh(option, default = "10")]
+    /// how long to send at each rate
+    step_seconds: u64,
+
+    #[argh(option, default = "2.0")]
+    /// the soft limit is passed once p90 latency is this many times the baseline's
+    latency_ratio: f64,
+
+    #[argh(option, default = "1.0")]
+    /// the hard limit is passed once this percent of requests fail
+    max_error_percent: f64,
+
+    #[argh(option, default = "1_000")]
+    /// requests in flight beyond this are counted as errors
+    concurrency: usize,
+
+    #[argh(option, default = "10")]
+    /// requests that take longer than this are counted as errors
+    timeout_seconds: u64,
+}
+
+/// A rough guess at what mainnet traffic looks like. The weights are relative
+const METHOD_MIX: [(&str, u32); 10] = [
+    ("eth_blockNumber", 20),
+    ("eth_chainId", 5),
+    ("eth_getBalance", 15),
+    ("eth_getBlockByNumber", 15),
+    ("eth_getCode", 5),
+    ("eth_getLogs", 5),
+    ("eth_getTransactionByHash", 10),
+    ("eth_getTransactionCount", 10),
+    ("eth_getTransactionReceipt", 10),
+    ("eth_call", 5),
+];
+
+/// real values from a recent block so that requests look like actual traffic
+struct Samples {
+    head_block_num: u64,
+    block_hashes: Vec<Value>,
+    tx_hashes: Vec<Value>,
+    senders: Vec<Value>,
+    contracts: Vec<Value>,
+}
+
+impl Samples {
+    async fn fetch(client: &reqwest::Client, rpc: &str) -> anyhow::Result<Self> {
+        let head_block_num = jsonrpc(client, rpc, "eth_blockNumber", json!([]))
+            .await?
+            .as_str()
+            .and_then(|x| u64::from_str_radix(x.trim_start_matches("0x"), 16).ok())
+            .context("parsing eth_blockNumber")?;
+
+        let mut samples = Self {
+            head_block_num,
+            block_hashes: vec![],
+            tx_hashes: vec![],
+            senders: vec![],
+            contracts: vec![],
+        };
+
+        // a few blocks gives enough variety without taking long
+        for num in head_block_num.saturating_sub(4)..=head_block_num {
+            let block = jsonrpc(
+                client,
+                rpc,
+                "eth_getBlockByNumber",
+                json!([format!("{:#x}", num), true]),
+            )
+            .await?;
+
+            if let Some(hash) = block.get("hash") {
+                samples.block_hashes.push(hash.clone());
+            }
+
+            for tx in block
+                .get("transactions")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+            {
+                if let Some(hash) = tx.get("hash") {
+                    samples.tx_hashes.push(hash.clone());
+                }
+                if let Some(from) = tx.get("from") {
+                    samples.senders.push(from.clone());
+                }
+                // only transactions with input are likely to be calling a contract
+                if tx.get("input").and_then(Value::as_str).unwrap_or("0x") != "0x" {
+                    if let Some(to) = tx.get("to").filter(|x| !x.is_null()) {
+                        samples.contracts.push(to.clone());
+                    }
+                }
+            }
+        }
+
+        anyhow::ensure!(
+            !samples.tx_hashes.is_empty() && !samples.contracts.is_empty(),
+            "recent blocks have no transactions to sample from"
+        );
+
+        info!(
+            "sampled {} transactions near block {}",
+            samples.tx_hashes.len(),
+            head_block_num
+        );
+
+        Ok(samples)
+    }
+
+    fn random_request(&self, id: u64) -> Value {
+        let mut rng = thread_fast_rng();
+
+        let method = METHOD_MIX
+            .choose_weighted(&mut rng, |x| x.1)
+            .expect("method mix is not empty")
+            .0;
+
+        // stay near the head so that pruned nodes can answer too
+        let block_num = format!(
+            "{:#x}",
+            self.head_block_num.saturating_sub(rng.gen_range(0..64))
+        );
+
+        let tx_hash = self.tx_hashes.choose(&mut rng).unwrap();
+        let sender = self.senders.choose(&mut rng).unwrap();
+        let contract = self.contracts.choose(&mut rng).unwrap();
+
+        let params = match method {
+            "eth_getBalance" | "eth_getTransactionCount" => json!([sender, "latest"]),
+            "eth_getBlockByNumber" => json!([block_num, false]),
+            "eth_getCode" => json!([contract, "latest"]),
+            "eth_getLogs" => json!([{ "blockHash": self.block_hashes.choose(&mut rng).unwrap() }]),
+            "eth_getTransactionByHash" | "eth_getTransactionReceipt" => json!([tx_hash]),
+            // an empty call to a contract hits its fallback. good enough to exercise the evm
+            "eth_call" => json!([{ "to": contract, "data": "0x" }, "latest"]),
+            _ => json!([]),
+        };
+
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        })
+    }
+}
+
+struct StepResult {
+    target_rps: u64,
+    achieved_rps: f64,
+    latency_ms: Histogram<u64>,
+    requests: u64,
+    errors: u64,
+}
+
+impl StepResult {
+    fn error_percent(&self) -> f64 {
+        if self.requests == 0 {
+            0.0
+        } else {
+            self.errors as f64 / self.requests as f64 * 100.0
+        }
+    }
+}
+
+impl LoadtestSubCommand {
+    pub async fn main(self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.start_rps > 0, "start_rps must be more than 0");
+        anyhow::ensure!(self.step_percent > 0, "step_percent must be more than 0");
+        anyhow::ensure!(self.step_seconds > 0, "step_seconds must be more than 0");
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(self.timeout_seconds))
+            .build()?;
+
+        let samples = Arc::new(Samples::fetch(&client, &self.rpc).await?);
+
+        let rpc = Arc::new(self.rpc.clone());
+
+        let mut table = Table::new();
+        table.add_row(row![
+            "target_rps",
+            "achieved_rps",
+            "p50_ms",
+            "p90_ms",
+            "p99_ms",
+            "error_pct",
+        ]);
+
+        let mut baseline_p90: Option<u64> = None;
+        let mut soft_limit: Option<u64> = None;
+        let mut hard_limit: Option<u64> = None;
+        let mut soft_limit_passed = false;
+
+        let mut target_rps = self.start_rps;
+
+        loop {
+            let step = self.run_step(&client, &rpc, &samples, target_rps).await?;
+
+            let p90 = step.latency_ms.value_at_quantile(0.9);
+
+            table.add_row(row![
+                step.target_rps,
+                format!("{:.1}", step.achieved_rps),
+                step.latency_ms.value_at_quantile(0.5),
+                p90,
+                step.latency_ms.value_at_quantile(0.99),
+                format!("{:.2}", step.error_percent()),
+            ]);
+
+            info!(
+                "{} rps: p90 {}ms, {:.2}% errors",
+                target_rps,
+                p90,
+                step.error_percent()
+            );
+
+            if step.error_percent() > self.max_error_percent {
+                if hard_limit.is_none() {
+                    table.printstd();
+
+                    anyhow::bail!(
+                        "{:.2}% errors at the first step! try a lower --start-rps",
+                        step.error_percent()
+                    );
+                }
+                break;
+            }
+
+            hard_limit = Some(target_rps);
+
+            let baseline_p90 = *baseline_p90.get_or_insert(p90.max(1));
+
+            // falling behind the target rate means requests are queueing somewhere
+            let degraded = p90 as f64 > baseline_p90 as f64 * self.latency_ratio
+                || step.achieved_rps < target_rps as f64 * 0.9;
+
+            if degraded {
+                soft_limit_passed = true;
+            } else if !soft_limit_passed {
+                soft_limit = Some(target_rps);
+            }
+
+            if target_rps >= self.max_rps {
+                info!("reached max_rps without passing the hard limit");
+                hard_limit = None;
+                break;
+            }
+
+            target_rps = (target_rps * (100 + self.step_percent) / 100)
+                .max(target_rps + 1)
+                .min(self.max_rps);
+        }
+
+        table.printstd();
+
+        // the soft limit is used for weighting, so it needs to be set even if we didn't find it
+        let soft_limit = soft_limit.or(hard_limit).unwrap_or(self.start_rps);
+
+        println!();
+        println!("[balanced_rpcs.{}]", self.name);
+        println!("http_url = \"{}\"", self.rpc);
+        println!("soft_limit = {}", soft_limit);
+        match hard_limit {
+            Some(hard_limit) => println!("hard_limit = {}", hard_limit),
+            None => println!("# no hard limit found up to {} rps", self.max_rps),
+        }
+
+        Ok(())
+    }
+
+    /// send at `target_rps` for `step_seconds`.
+    /// this is open loop. a slow server doesn't slow down how fast we send
+    async fn run_step(
+        &self,
+        client: &reqwest::Client,
+        rpc: &Arc<String>,
+        samples: &Arc<Samples>,
+        target_rps: u64,
+    ) -> anyhow::Result<StepResult> {
+        let total = target_rps
+            .checked_mul(self.step_seconds)
+            .context("target_rps * step_seconds is too large")?;
+
+        let semaphore = Arc::new(Semaphore::new(self.concurrency.max(1)));
+
+        let mut handles = Vec::with_capacity(total as usize);
+        let mut errors = 0;
+
+        let start = Instant::now();
+
+        for i in 0..total {
+            sleep_until(start + Duration::from_secs_f64(i as f64 / target_rps as f64)).await;
+
+            let permit = match semaphore.clone().try_acquire_owned() {
+                Ok(x) => x,
+                Err(_) => {
+                    // too many requests in flight. the server isn't keeping up
+                    errors += 1;
+                    continue;
+                }
+            };
+
+            let client = client.clone();
+            let rpc = rpc.clone();
+            let request = samples.random_request(i);
+
+            let f = async move {
+                let start = Instant::now();
+
+                let ok = match client.post(rpc.as_str()).json(&request).send().await {
+                    Ok(response) if response.status().is_success() => response
+                        .json::<Value>()
+                        .await
+                        .map(|x| x.get("error").is_none())
+                        .unwrap_or(false),
+                    _ => false,
+                };
+
+                drop(permit);
+
+                (start.elapsed().as_millis() as u64, ok)
+            };
+
+            handles.push(tokio::spawn(f));
+        }
+
+        let send_duration = start.elapsed();
+
+        let mut latency_ms =
+            Histogram::new_with_bounds(1, 60_000, 3).expect("creating latency histogram");
+
+        for x in join_all(handles).await {
+            let (latency, ok) = x?;
+
+            latency_ms.saturating_record(latency.max(1));
+
+            if !ok {
+                errors += 1;
+            }
+        }
+
+        Ok(StepResult {
+            target_rps,
+            achieved_rps: total as f64 / send_duration.as_secs_f64().max(0.001),
+            latency_ms,
+            requests: total,
+            errors,
+        })
+    }
+}
+
+/// a single request used to gather samples. errors here are fatal
+async fn jsonrpc(
+    client: &reqwest::Client,
+    rpc: &str,
+    method: &str,
+    params: Value,
+) -> anyhow::Result<Value> {
+    let mut response: Value = client
+        .post(rpc)
+        .json(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    if let Some(err) = response.get("error") {
+        anyhow::bail!("{} failed: {}", method, err);
+    }
+
+    response
+        .get_mut("result")
+        .map(Value::take)
+        .with_context(|| format!("{} had no result", method))
+}
@@ -7,14 +7,21 @@ mod check_config;
 mod count_users;
 mod create_key;
 mod create_user;
+mod delete_user;
 mod drop_migration_lock;
+mod list_recent_users;
 mod list_user_tier;
+mod loadtest;
 mod pagerduty;
 mod popularity_contest;
 mod proxyd;
+mod replay;
+mod revoke_sessions;
+mod rotate_key;
 mod rpc_accounting;
 mod search_kafka;
 mod sentryd;
+mod stat_age;
 mod transfer_key;
 mod user_export;
 mod user_import;
@@ -76,13 +83,21 @@ enum SubCommand {
     CountUsers(count_users::CountUsersSubCommand),
     CreateKey(create_key::CreateKeySubCommand),
     CreateUser(create_user::CreateUserSubCommand),
+    DeleteUser(delete_user::DeleteUserSubCommand),
     DropMigrationLock(drop_migration_lock::DropMigrationLockSubCommand),
+    ListRecentUsers(list_recent_users::ListRecentUsersSubCommand),
+    ListUserTier(list_user_tier::ListUserTierSubCommand),
+    Loadtest(loadtest::LoadtestSubCommand),
     Pagerduty(pagerduty::PagerdutySubCommand),
     PopularityContest(popularity_contest::PopularityContestSubCommand),
     Proxyd(proxyd::ProxydSubCommand),
+    Replay(replay::ReplaySubCommand),
+    RevokeSessions(revoke_sessions::RevokeSessionsSubCommand),
+    RotateKey(rotate_key::RotateKeySubCommand),
     RpcAccounting(rpc_accounting::RpcAccountingSubCommand),
     SearchKafka(search_kafka::SearchKafkaSubCommand),
     Sentryd(sentryd::SentrydSubCommand),
+    StatAge(stat_age::StatAgeSubCommand),
     TransferKey(transfer_key::TransferKeySubCommand),
     UserExport(user_export::UserExportSubCommand),
     UserImport(user_import::UserImportSubCommand),
@@ -342,6 +357,15 @@ fn main() -> anyhow::Result<()> {
 
                 x.main(&db_conn).await
             }
+            SubCommand::DeleteUser(x) => {
+                let db_url = cli_config
+                    .db_url
+                    .expect("'--config' (with a db) or '--db-url' is required to run delete_user");
+
+                let db_conn = get_migrated_db(db_url, 1, 1).await?;
+
+                x.main(&db_conn).await
+            }
             SubCommand::CountUsers(x) => {
                 let db_url = cli_config
                     .db_url
@@ -368,6 +392,27 @@ fn main() -> anyhow::Result<()> {
 
                 x.main(&db_conn).await
             }
+            SubCommand::ListRecentUsers(x) => {
+                let top_config = top_config.expect("--config is required to run list_recent_users");
+
+                // the db is optional. without it, users can't be identified
+                let db_conn = match cli_config.db_url {
+                    Some(db_url) => Some(get_db(db_url, 1, 1).await?),
+                    None => None,
+                };
+
+                x.main(top_config, db_conn.as_ref()).await
+            }
+            SubCommand::ListUserTier(x) => {
+                let db_url = cli_config
+                    .db_url
+                    .expect("'--config' (with a db) or '--db-url' is required to run list_user_tier");
+
+                let db_conn = get_db(db_url, 1, 1).await?;
+
+                x.main(&db_conn).await
+            }
+            SubCommand::Loadtest(x) => x.main().await,
             SubCommand::Pagerduty(x) => {
                 if cli_config.sentry_url.is_none() {
                     warn!("sentry_url is not set! Logs will only show in this console");
@@ -376,6 +421,25 @@ fn main() -> anyhow::Result<()> {
                 x.main(pagerduty_async, top_config).await
             }
             SubCommand::PopularityContest(x) => x.main().await,
+            SubCommand::Replay(x) => x.main(top_config).await,
+            SubCommand::RevokeSessions(x) => {
+                let db_url = cli_config
+                    .db_url
+                    .expect("'--config' (with a db) or '--db-url' is required to run revoke_sessions");
+
+                let db_conn = get_migrated_db(db_url, 1, 1).await?;
+
+                x.main(top_config.as_ref(), &db_conn).await
+            }
+            SubCommand::RotateKey(x) => {
+                let db_url = cli_config
+                    .db_url
+                    .expect("'--config' (with a db) or '--db-url' is required to run rotate_key");
+
+                let db_conn = get_migrated_db(db_url, 1, 1).await?;
+
+                x.main(top_config.as_ref(), &db_conn).await
+            }
             SubCommand::SearchKafka(x) => {
                 x.main(top_config.unwrap()).await
             },
@@ -386,6 +450,15 @@ fn main() -> anyhow::Result<()> {
 
                 x.main(pagerduty_async, top_config).await
             }
+            SubCommand::StatAge(x) => {
+                let db_url = cli_config
+                    .db_url
+                    .expect("'--config' (with a db) or '--db-url' is required to run stat_age");
+
+                let db_conn = get_db(db_url, 1, 1).await?;
+
+                x.main(&db_conn).await
+            }
             SubCommand::RpcAccounting(x) => {
                 let db_url = cli_config
                     .db_url
@@ -2,12 +2,14 @@
 use argh::FromArgs;
 use futures::StreamExt;
 use log::{error, info, warn};
+use notify::{RecursiveMode, Watcher};
 use num::Zero;
+use std::fs;
 use std::path::PathBuf;
 use std::time::Duration;
-use std::{fs, thread};
-use tokio::sync::broadcast;
-use web3_proxy::app::{flatten_handle, flatten_handles, Web3ProxyApp};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::{sleep, timeout};
+use web3_proxy::app::{flatten_handle, flatten_handles, ShutdownPhase, Web3ProxyApp};
 use web3_proxy::config::TopConfig;
 use web3_proxy::{frontend, metrics_frontend};
 
@@ -49,7 +51,7 @@ impl ProxydSubCommand {
 }
 
 async fn run(
-    mut top_config: TopConfig,
+    top_config: TopConfig,
     top_config_path: Option<PathBuf>,
     frontend_port: u16,
     prometheus_port: u16,
@@ -68,79 +70,11 @@ async fn run(
     let mut spawned_app =
         Web3ProxyApp::spawn(top_config.clone(), num_workers, shutdown_sender.subscribe()).await?;
 
-    // start thread for watching config
+    // watch the config file. new configs are checked and applied by the app
     if let Some(top_config_path) = top_config_path {
         let config_sender = spawned_app.new_top_config_sender;
-        /*
-        #[cfg(feature = "inotify")]
-        {
-            let mut inotify = Inotify::init().expect("Failed to initialize inotify");
-
-            inotify
-                .add_watch(top_config_path.clone(), WatchMask::MODIFY)
-                .expect("Failed to add inotify watch on config");
-
-            let mut buffer = [0u8; 4096];
-
-            // TODO: exit the app if this handle exits
-            thread::spawn(move || loop {
-                // TODO: debounce
-
-                let events = inotify
-                    .read_events_blocking(&mut buffer)
-                    .expect("Failed to read inotify events");
-
-                for event in events {
-                    if event.mask.contains(EventMask::MODIFY) {
-                        info!("config changed");
-                        match fs::read_to_string(&top_config_path) {
-                            Ok(top_config) => match toml::from_str(&top_config) {
-                                Ok(top_config) => {
-                                    config_sender.send(top_config).unwrap();
-                                }
-                                Err(err) => {
-                                    // TODO: panic?
-                                    error!("Unable to parse config! {:#?}", err);
-                                }
-                            },
-                            Err(err) => {
-                                // TODO: panic?
-                                error!("Unable to read config! {:#?}", err);
-                            }
-                        };
-                    } else {
-                        // TODO: is "MODIFY" enough, or do we want CLOSE_WRITE?
-                        unimplemented!();
-                    }
-                }
-            });
-        }
-        */
-        // #[cfg(not(feature = "inotify"))]
-        {
-            thread::spawn(move || loop {
-                match fs::read_to_string(&top_config_path) {
-                    Ok(new_top_config) => match toml::from_str(&new_top_config) {
-                        Ok(new_top_config) => {
-                            if new_top_config != top_config {
-                                top_config = new_top_config;
-                                config_sender.send(top_config.clone()).unwrap();
-                            }
-                        }
-                        Err(err) => {
-                            // TODO: panic?
-                            error!("Unable to parse config! {:#?}", err);
-                        }
-                    },
-                    Err(err) => {
-                        // TODO: panic?
-                        error!("Unable to read config! {:#?}", err);
-                    }
-                }
 
-                thread::sleep(Duration::from_secs(10));
-            });
-        }
+        watch_config(top_config, top_config_path, config_sender)?;
     }
 
     // start the prometheus metrics port
@@ -156,6 +90,10 @@ async fn run(
     // start the frontend port
     let frontend_handle = tokio::spawn(frontend::serve(app_frontend_port, spawned_app.app.clone()));
 
+    // the frontend keeps running during shutdown so that in-flight requests can finish
+    let mut frontend_exit = Box::pin(flatten_handle(frontend_handle));
+    let mut frontend_exited = false;
+
     // if everything is working, these should all run forever
     tokio::select! {
         x = flatten_handles(spawned_app.app_handles) => {
@@ -166,7 +104,9 @@ async fn run(
                 }
             }
         }
-        x = flatten_handle(frontend_handle) => {
+        x = &mut frontend_exit => {
+            frontend_exited = true;
+
             match x {
                 Ok(_) => info!("frontend exited"),
                 Err(e) => {
@@ -200,7 +140,39 @@ async fn run(
         }
     };
 
-    // one of the handles stopped. send a value so the others know to shut down
+    // shut down in phases so that nothing is dropped
+    // the timeouts are read now in case the config changes while we wait
+    let app = spawned_app.app;
+    let app_config = app.config();
+
+    // stop getting new traffic from the load balancer
+    app.set_shutdown_phase(ShutdownPhase::Unhealthy);
+
+    if !frontend_exited {
+        sleep(Duration::from_secs(app_config.shutdown_unhealthy_seconds)).await;
+    }
+
+    // stop accepting connections and let in-flight requests finish
+    app.set_shutdown_phase(ShutdownPhase::DrainingHttp);
+
+    if !frontend_exited {
+        match timeout(
+            Duration::from_secs(app_config.shutdown_http_seconds),
+            frontend_exit,
+        )
+        .await
+        {
+            Ok(Ok(_)) => info!("http requests drained"),
+            Ok(Err(err)) => error!("frontend errored while draining. err={:?}", err),
+            Err(_) => warn!("timed out waiting for http requests to finish"),
+        }
+    }
+
+    // end subscriptions and then close the websockets
+    app.close_websockets(Duration::from_secs(app_config.shutdown_websocket_seconds))
+        .await;
+
+    // send a value so the background tasks know to shut down
     if let Err(err) = shutdown_sender.send(()) {
         warn!("shutdown sender err={:?}", err);
     };
@@ -208,18 +180,32 @@ async fn run(
     // wait for things like saving stats to the database to complete
     info!("waiting on important background tasks");
     let mut background_errors = 0;
-    while let Some(x) = spawned_app.background_handles.next().await {
-        match x {
-            Err(e) => {
-                error!("{:?}", e);
-                background_errors += 1;
-            }
-            Ok(Err(e)) => {
-                error!("{:?}", e);
-                background_errors += 1;
+
+    let wait_for_background = async {
+        while let Some(x) = spawned_app.background_handles.next().await {
+            match x {
+                Err(e) => {
+                    error!("{:?}", e);
+                    background_errors += 1;
+                }
+                Ok(Err(e)) => {
+                    error!("{:?}", e);
+                    background_errors += 1;
+                }
+                Ok(Ok(_)) => continue,
             }
-            Ok(Ok(_)) => continue,
         }
+    };
+
+    if timeout(
+        Duration::from_secs(app_config.shutdown_stats_seconds),
+        wait_for_background,
+    )
+    .await
+    .is_err()
+    {
+        error!("timed out waiting on important background tasks. some stats may be lost");
+        background_errors += 1;
     }
 
     if background_errors.is_zero() {
@@ -231,6 +217,87 @@ async fn run(
     }
 }
 
+/// Send the config to the app every time the file changes.
+/// Editors often save by replacing the file, so the whole directory is watched.
+fn watch_config(
+    mut top_config: TopConfig,
+    top_config_path: PathBuf,
+    config_sender: watch::Sender<TopConfig>,
+) -> anyhow::Result<()> {
+    let top_config_path = fs::canonicalize(top_config_path)?;
+
+    let top_config_dir = top_config_path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("config has no parent directory"))?
+        .to_owned();
+
+    let (event_sender, mut event_receiver) = mpsc::unbounded_channel();
+
+    let mut watcher = {
+        let top_config_path = top_config_path.clone();
+
+        notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+            Ok(event) => {
+                if !event.kind.is_access()
+                    && event
+                        .paths
+                        .iter()
+                        .any(|x| x.file_name() == top_config_path.file_name())
+                {
+                    let _ = event_sender.send(());
+                }
+            }
+            Err(err) => error!("config watcher err={:?}", err),
+        })?
+    };
+
+    watcher.watch(&top_config_dir, RecursiveMode::NonRecursive)?;
+
+    info!("watching {} for changes", top_config_path.display());
+
+    // TODO: exit the app if this handle exits
+    tokio::spawn(async move {
+        // the watcher stops when it is dropped
+        let _watcher = watcher;
+
+        while event_receiver.recv().await.is_some() {
+            // saving a file can be multiple events. wait for them to stop
+            // TODO: make the debounce time configurable?
+            while let Ok(Some(_)) = timeout(Duration::from_millis(500), event_receiver.recv()).await
+            {
+            }
+
+            let new_top_config = match fs::read_to_string(&top_config_path) {
+                Ok(x) => x,
+                Err(err) => {
+                    // the file might be in the middle of being replaced. the next event will try again
+                    error!("Unable to read config! {:#?}", err);
+                    continue;
+                }
+            };
+
+            match toml::from_str::<TopConfig>(&new_top_config) {
+                Ok(new_top_config) => {
+                    if new_top_config != top_config {
+                        info!("config file changed");
+                        top_config = new_top_config;
+
+                        if config_sender.send(top_config.clone()).is_err() {
+                            warn!("app is no longer listening for config changes");
+                            break;
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("Unable to parse config! Keeping the old one. {:#}", err);
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use ethers::{
@@ -0,0 +1,479 @@
+//! Replay captured requests against one or two proxies.
+//!
+//! Requests come from a jsonl file or from the msgpack records that `ProxyMode::Debug` writes to kafka.
+//! Each jsonl line is either a bare JSON-RPC request or `{"timestamp_ms": 1680000000000, "request": {...}}`.
+use anyhow::Context;
+use argh::FromArgs;
+use futures::future::join_all;
+use hdrhistogram::Histogram;
+use log::{info, warn};
+use prettytable::{row, Table};
+use rdkafka::{
+    consumer::{Consumer, StreamConsumer},
+    ClientConfig, Message,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::Semaphore;
+use tokio::time::{sleep_until, timeout, Duration, Instant};
+use ulid::Ulid;
+use web3_proxy::config::TopConfig;
+
+/// replay captured traffic for benchmarking and regression testing.
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "replay")]
+pub struct ReplaySubCommand {
+    #[argh(positional)]
+    /// the web3-proxy url to send requests to. include the rpc key if you need one
+    target: String,
+
+    #[argh(option)]
+    /// a second url to send every request to. responses are compared against the first target
+    compare: Option<String>,
+
+    #[argh(option)]
+    /// jsonl file of captured requests
+    file: Option<String>,
+
+    #[argh(option)]
+    /// kafka topic of captured requests. requires top_config.app.kafka_urls
+    kafka_topic: Option<String>,
+
+    #[argh(option)]
+    /// optional kafka group id. defaults to a new group that reads from the start of the topic
+    group_id: Option<String>,
+
+    #[argh(option, default = "5")]
+    /// stop reading from kafka after this many seconds without a new message
+    kafka_idle_seconds: u64,
+
+    #[argh(option)]
+    /// maximum number of requests to replay
+    limit: Option<usize>,
+
+    #[argh(option, default = "1.0")]
+    /// multiplier for the original request rate. 2.0 sends twice as fast. 0 sends as fast as possible
+    speed: f64,
+
+    #[argh(option)]
+    /// send at a fixed number of requests per second instead of the captured timing
+    rps: Option<f64>,
+
+    #[argh(option, default = "100")]
+    /// maximum number of requests in flight
+    concurrency: usize,
+
+    #[argh(option, default = "5")]
+    /// how many mismatched responses to print
+    show_diffs: usize,
+}
+
+/// a request and when it was originally sent
+struct CapturedRequest {
+    timestamp_ms: Option<i64>,
+    request: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CapturedLine {
+    Timed { timestamp_ms: i64, request: Value },
+    Bare(Value),
+}
+
+/// The layout of `JsonRpcRequest` when written with `rmp_serde::to_vec`.
+/// `RawValue` serializes as a struct with one field, so the id arrives as a 1-tuple of json.
+/// Response records have the same key and headers, but they don't have a string in the method position.
+#[derive(Deserialize)]
+struct KafkaJsonRpcRequest {
+    jsonrpc: String,
+    id: (String,),
+    method: String,
+    params: Option<Value>,
+}
+
+enum ReplayOutcome {
+    /// the target responded with a result
+    Ok(Value),
+    /// the target responded with a JSON-RPC error
+    JsonRpcError(Value),
+    /// the request did not get a JSON-RPC response at all
+    Failed(String),
+}
+
+impl ReplayOutcome {
+    /// the part of the response that should match between targets. ids are ignored
+    fn comparable(&self) -> Option<Value> {
+        match self {
+            Self::Ok(x) => Some(json!({ "result": x })),
+            Self::JsonRpcError(x) => Some(json!({ "error": x })),
+            Self::Failed(_) => None,
+        }
+    }
+}
+
+struct ReplayResult {
+    request: Value,
+    target: (u64, ReplayOutcome),
+    compare: Option<(u64, ReplayOutcome)>,
+}
+
+#[derive(Default)]
+struct TargetStats {
+    latency_ms: Option<Histogram<u64>>,
+    ok: u64,
+    jsonrpc_errors: u64,
+    failed: u64,
+}
+
+impl TargetStats {
+    fn record(&mut self, latency_ms: u64, outcome: &ReplayOutcome) {
+        let histogram = self.latency_ms.get_or_insert_with(|| {
+            Histogram::new_with_bounds(1, 60_000, 3).expect("creating latency histogram")
+        });
+
+        histogram.saturating_record(latency_ms.max(1));
+
+        match outcome {
+            ReplayOutcome::Ok(_) => self.ok += 1,
+            ReplayOutcome::JsonRpcError(_) => self.jsonrpc_errors += 1,
+            ReplayOutcome::Failed(err) => {
+                if self.failed == 0 {
+                    warn!("first failure: {}", err);
+                }
+                self.failed += 1
+            }
+        }
+    }
+
+    fn add_row(&self, table: &mut Table, name: &str) {
+        let total = self.ok + self.jsonrpc_errors + self.failed;
+
+        let pct = |x: u64| {
+            if total == 0 {
+                0.0
+            } else {
+                x as f64 / total as f64 * 100.0
+            }
+        };
+
+        let quantile = |q: f64| {
+            self.latency_ms
+                .as_ref()
+                .map(|x| x.value_at_quantile(q))
+                .unwrap_or_default()
+        };
+
+        table.add_row(row![
+            name,
+            total,
+            format!("{:.2}", pct(self.jsonrpc_errors)),
+            format!("{:.2}", pct(self.failed)),
+            quantile(0.5),
+            quantile(0.9),
+            quantile(0.99),
+            quantile(1.0),
+        ]);
+    }
+}
+
+impl ReplaySubCommand {
+    pub async fn main(self, top_config: Option<TopConfig>) -> anyhow::Result<()> {
+        if let Some(rps) = self.rps {
+            anyhow::ensure!(rps > 0.0, "rps must be more than 0");
+        }
+
+        anyhow::ensure!(self.speed >= 0.0, "speed must not be negative");
+
+        let mut requests = match (&self.file, &self.kafka_topic) {
+            (Some(file), None) => self.read_file(file).await?,
+            (None, Some(topic)) => {
+                let brokers = top_config
+                    .and_then(|x| x.app.kafka_urls)
+                    .context("top_config.app.kafka_urls is required to replay from kafka")?;
+
+                self.read_kafka(&brokers, topic).await?
+            }
+            _ => anyhow::bail!("exactly one of --file or --kafka-topic is required"),
+        };
+
+        if let Some(limit) = self.limit {
+            requests.truncate(limit);
+        }
+
+        anyhow::ensure!(!requests.is_empty(), "no requests to replay");
+
+        info!("replaying {} requests", requests.len());
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(60))
+            .build()?;
+
+        let target = Arc::new(self.target.clone());
+        let compare = self.compare.clone().map(Arc::new);
+
+        let semaphore = Arc::new(Semaphore::new(self.concurrency.max(1)));
+
+        let first_timestamp_ms = requests.iter().find_map(|x| x.timestamp_ms);
+
+        let start = Instant::now();
+
+        let mut handles = Vec::with_capacity(requests.len());
+
+        for (i, captured) in requests.into_iter().enumerate() {
+            let delay = if let Some(rps) = self.rps {
+                Duration::from_secs_f64(i as f64 / rps)
+            } else if let (Some(first), Some(x)) = (first_timestamp_ms, captured.timestamp_ms) {
+                if self.speed > 0.0 {
+                    Duration::from_secs_f64((x - first).max(0) as f64 / 1000.0 / self.speed)
+                } else {
+                    Duration::ZERO
+                }
+            } else {
+                Duration::ZERO
+            };
+
+            sleep_until(start + delay).await;
+
+            let permit = semaphore.clone().acquire_owned().await?;
+
+            let client = client.clone();
+            let target = target.clone();
+            let compare = compare.clone();
+
+            let f = async move {
+                let request = captured.request;
+
+                let (target, compare) =
+                    tokio::join!(send_request(&client, &target, &request), async {
+                        match compare {
+                            Some(compare) => Some(send_request(&client, &compare, &request).await),
+                            None => None,
+                        }
+                    });
+
+                drop(permit);
+
+                ReplayResult {
+                    request,
+                    target,
+                    compare,
+                }
+            };
+
+            handles.push(tokio::spawn(f));
+        }
+
+        let mut target_stats = TargetStats::default();
+        let mut compare_stats = TargetStats::default();
+        let mut compared = 0;
+        let mut diffs = 0;
+
+        for result in join_all(handles).await {
+            let result = result?;
+
+            target_stats.record(result.target.0, &result.target.1);
+
+            if let Some((latency_ms, outcome)) = result.compare.as_ref() {
+                compare_stats.record(*latency_ms, outcome);
+
+                // only compare when both targets gave a JSON-RPC response
+                // TODO: ignore methods like eth_blockNumber whose answers change with the head block
+                if let (Some(a), Some(b)) = (result.target.1.comparable(), outcome.comparable()) {
+                    compared += 1;
+
+                    if a != b {
+                        if diffs < self.show_diffs {
+                            warn!(
+                                "response mismatch for {}\n{}: {}\n{}: {}",
+                                result.request,
+                                self.target,
+                                a,
+                                self.compare.as_ref().unwrap(),
+                                b
+                            );
+                        }
+                        diffs += 1;
+                    }
+                }
+            }
+        }
+
+        let elapsed = start.elapsed();
+
+        let mut table = Table::new();
+
+        table.add_row(row![
+            "target",
+            "requests",
+            "error_pct",
+            "failed_pct",
+            "p50_ms",
+            "p90_ms",
+            "p99_ms",
+            "max_ms",
+        ]);
+
+        target_stats.add_row(&mut table, &self.target);
+
+        if let Some(compare) = self.compare.as_ref() {
+            compare_stats.add_row(&mut table, compare);
+        }
+
+        table.printstd();
+
+        info!("replayed in {:?}", elapsed);
+
+        if self.compare.is_some() {
+            info!("{} of {} compared responses differed", diffs, compared);
+        }
+
+        Ok(())
+    }
+
+    async fn read_file(&self, path: &str) -> anyhow::Result<Vec<CapturedRequest>> {
+        let file = File::open(path)
+            .await
+            .with_context(|| format!("opening {}", path))?;
+
+        let mut lines = BufReader::new(file).lines();
+
+        let mut requests = vec![];
+
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            if self.limit.map(|x| requests.len() >= x).unwrap_or(false) {
+                break;
+            }
+
+            let captured = match serde_json::from_str(&line)
+                .with_context(|| format!("parsing line {}", requests.len() + 1))?
+            {
+                CapturedLine::Timed {
+                    timestamp_ms,
+                    request,
+                } => CapturedRequest {
+                    timestamp_ms: Some(timestamp_ms),
+                    request,
+                },
+                CapturedLine::Bare(request) => CapturedRequest {
+                    timestamp_ms: None,
+                    request,
+                },
+            };
+
+            requests.push(captured);
+        }
+
+        Ok(requests)
+    }
+
+    async fn read_kafka(&self, brokers: &str, topic: &str) -> anyhow::Result<Vec<CapturedRequest>> {
+        // a fresh group id means we read the topic from the start
+        let group_id = self
+            .group_id
+            .clone()
+            .unwrap_or_else(|| format!("web3_proxy_replay_{}", Ulid::new()));
+
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("group.id", &group_id)
+            .set("auto.offset.reset", "earliest")
+            .set("enable.partition.eof", "false")
+            .set("session.timeout.ms", "6000")
+            .set("enable.auto.commit", "false")
+            .create()
+            .context("kafka consumer creation failed")?;
+
+        consumer
+            .subscribe(&[topic])
+            .context("subscribing to kafka topic")?;
+
+        let idle = Duration::from_secs(self.kafka_idle_seconds);
+
+        let mut requests = vec![];
+        let mut skipped = 0;
+
+        while let Ok(msg) = timeout(idle, consumer.recv()).await {
+            if self.limit.map(|x| requests.len() >= x).unwrap_or(false) {
+                break;
+            }
+
+            let msg = msg?;
+
+            let payload = match msg.payload() {
+                Some(x) => x,
+                None => continue,
+            };
+
+            // responses share the topic. they fail to decode as requests
+            let request: KafkaJsonRpcRequest = match rmp_serde::from_slice(payload) {
+                Ok(x) => x,
+                Err(_) => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+
+            let id: Value = serde_json::from_str(&request.id.0).context("parsing request id")?;
+
+            requests.push(CapturedRequest {
+                timestamp_ms: msg.timestamp().to_millis(),
+                request: json!({
+                    "jsonrpc": request.jsonrpc,
+                    "id": id,
+                    "method": request.method,
+                    "params": request.params,
+                }),
+            });
+        }
+
+        info!(
+            "read {} requests from kafka. skipped {} other records",
+            requests.len(),
+            skipped
+        );
+
+        // partitions are read in parallel, so put them back in the order they were sent
+        requests.sort_by_key(|x| x.timestamp_ms);
+
+        Ok(requests)
+    }
+}
+
+/// send one request and return how long it took in milliseconds
+async fn send_request(
+    client: &reqwest::Client,
+    url: &str,
+    request: &Value,
+) -> (u64, ReplayOutcome) {
+    let start = Instant::now();
+
+    let outcome = match client.post(url).json(request).send().await {
+        Ok(response) => {
+            let status = response.status();
+
+            match response.json::<Value>().await {
+                Ok(mut body) => {
+                    if let Some(error) = body.get_mut("error").map(Value::take) {
+                        ReplayOutcome::JsonRpcError(error)
+                    } else if let Some(result) = body.get_mut("result").map(Value::take) {
+                        ReplayOutcome::Ok(result)
+                    } else {
+                        ReplayOutcome::Failed(format!("{}: {}", status, body))
+                    }
+                }
+                Err(err) => ReplayOutcome::Failed(format!("{}: {}", status, err)),
+            }
+        }
+        Err(err) => ReplayOutcome::Failed(err.to_string()),
+    };
+
+    (start.elapsed().as_millis() as u64, outcome)
+}
@@ -0,0 +1,53 @@
+use anyhow::Context;
+use argh::FromArgs;
+use entities::{login, user};
+use ethers::types::Address;
+use log::{info, warn};
+use migration::sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
+use redis_rate_limiter::redis;
+use web3_proxy::config::TopConfig;
+use web3_proxy::user_token::revoke_logins;
+
+/// log a user out of every session. their bearer tokens and jwts stop working
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "revoke_sessions")]
+pub struct RevokeSessionsSubCommand {
+    #[argh(positional)]
+    /// the address of the user to log out.
+    user_address: Address,
+}
+
+impl RevokeSessionsSubCommand {
+    pub async fn main(
+        self,
+        top_config: Option<&TopConfig>,
+        db_conn: &DatabaseConnection,
+    ) -> anyhow::Result<()> {
+        let u = user::Entity::find()
+            .filter(user::Column::Address.eq(self.user_address.as_bytes()))
+            .one(db_conn)
+            .await?
+            .context("No user found with that address")?;
+
+        // proxies cache which user a bearer token belongs to
+        let mut redis_conn = match top_config.and_then(|x| x.app.volatile_redis_url.as_ref()) {
+            Some(redis_url) => {
+                let client =
+                    redis::Client::open(redis_url.as_str()).context("parsing redis url")?;
+
+                Some(client.get_async_connection().await?)
+            }
+            None => {
+                warn!("no redis in the config. cached bearer tokens keep working until they expire from the cache");
+                None
+            }
+        };
+
+        let revoked =
+            revoke_logins(db_conn, redis_conn.as_mut(), login::Column::UserId.eq(u.id)).await?;
+
+        info!("revoked {} sessions for user #{}", revoked, u.id);
+
+        Ok(())
+    }
+}
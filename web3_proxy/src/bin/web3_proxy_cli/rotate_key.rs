@@ -0,0 +1,77 @@
+use anyhow::Context;
+use argh::FromArgs;
+use entities::rpc_key;
+use log::{info, warn};
+use migration::sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
+use redis_rate_limiter::redis;
+use ulid::Ulid;
+use uuid::Uuid;
+use web3_proxy::config::TopConfig;
+use web3_proxy::frontend::authorization::RpcSecretKey;
+use web3_proxy::rpc_keys::{publish_rpc_key_invalidation, rotate_rpc_key};
+
+/// give an rpc key a new secret. the old secret keeps working for a while
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "rotate_key")]
+pub struct RotateKeySubCommand {
+    #[argh(positional)]
+    /// the RPC key to rotate.
+    rpc_secret_key: RpcSecretKey,
+
+    /// how many seconds the old secret keeps working.
+    /// Defaults to the config's rpc_key_rotation_grace_seconds or 1 day.
+    #[argh(option)]
+    grace_seconds: Option<u64>,
+}
+
+impl RotateKeySubCommand {
+    pub async fn main(
+        self,
+        top_config: Option<&TopConfig>,
+        db_conn: &DatabaseConnection,
+    ) -> anyhow::Result<()> {
+        let rpc_secret_key: Uuid = self.rpc_secret_key.into();
+
+        let uk = rpc_key::Entity::find()
+            .filter(rpc_key::Column::SecretKey.eq(rpc_secret_key))
+            .filter(rpc_key::Column::DeletedAt.is_null())
+            .one(db_conn)
+            .await?
+            .context("No key found")?;
+
+        let grace_seconds = self
+            .grace_seconds
+            .or_else(|| top_config.map(|x| x.app.rpc_key_rotation_grace_seconds))
+            .unwrap_or(86_400);
+
+        let (uk, invalidation) =
+            rotate_rpc_key(db_conn, uk, chrono::Duration::seconds(grace_seconds as i64)).await?;
+
+        info!("rpc key #{} rotated", uk.id);
+        info!("new key as ULID: {}", Ulid::from(uk.secret_key.as_u128()));
+        info!("new key as UUID: {}", uk.secret_key);
+
+        if let Some(expires_at) = uk.old_secret_key_expires_at {
+            info!("old key works until {}", expires_at);
+        }
+
+        // the proxies need to forget any secret that stopped working now
+        if !invalidation.is_empty() {
+            match top_config.and_then(|x| x.app.volatile_redis_url.as_ref()) {
+                Some(redis_url) => {
+                    let client =
+                        redis::Client::open(redis_url.as_str()).context("parsing redis url")?;
+
+                    let mut redis_conn = client.get_async_connection().await?;
+
+                    publish_rpc_key_invalidation(&mut redis_conn, &invalidation).await?;
+                }
+                None => {
+                    warn!("no redis in the config. proxies keep using the revoked secret until their caches expire")
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -14,6 +14,7 @@ use migration::{
 };
 use serde::Serialize;
 use serde_json::json;
+use web3_proxy::user_queries::sum_error_responses;
 
 /// count requests
 #[derive(FromArgs, PartialEq, Debug, Eq)]
@@ -71,11 +72,7 @@ impl RpcAccountingSubCommand {
                 rpc_accounting::Column::SumResponseBytes.sum(),
                 "total_response_bytes",
             )
-            .column_as(
-                // TODO: can we sum bools like this?
-                rpc_accounting::Column::ErrorResponse.sum(),
-                "total_error_responses",
-            )
+            .column_as(sum_error_responses(), "total_error_responses")
             // .column_as(
             //     rpc_accounting::Column::SumResponseMillis.sum(),
             //     "total_response_millis",
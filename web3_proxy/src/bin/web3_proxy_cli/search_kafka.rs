@@ -45,7 +45,7 @@ impl SearchKafkaSubCommand {
                 .await?
                 .context("key not found")?;
 
-            rpc_key_id = Some(x.id);
+            rpc_key_id = Some(x.id.into());
         }
 
         let wanted_kafka_key = rpc_key_id.map(|x| rmp_serde::to_vec(&x).unwrap());
@@ -1 +1,61 @@
 //! show how old the most recently saved stat is
+use argh::FromArgs;
+use chrono::Utc;
+use entities::rpc_accounting;
+use entities::unsigned::BigUnsigned;
+use migration::sea_orm::{
+    self, prelude::DateTimeUtc, ColumnTrait, DatabaseConnection, EntityTrait, FromQueryResult,
+    QueryOrder, QuerySelect,
+};
+use prettytable::{row, Table};
+
+/// show how old the newest saved stat is for each chain.
+#[derive(FromArgs, PartialEq, Debug, Eq)]
+#[argh(subcommand, name = "stat_age")]
+pub struct StatAgeSubCommand {}
+
+impl StatAgeSubCommand {
+    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
+        #[derive(FromQueryResult)]
+        struct SelectResult {
+            chain_id: BigUnsigned,
+            newest_period_datetime: DateTimeUtc,
+        }
+
+        let newest = rpc_accounting::Entity::find()
+            .select_only()
+            .column(rpc_accounting::Column::ChainId)
+            .column_as(
+                rpc_accounting::Column::PeriodDatetime.max(),
+                "newest_period_datetime",
+            )
+            .group_by(rpc_accounting::Column::ChainId)
+            .order_by_asc(rpc_accounting::Column::ChainId)
+            .into_model::<SelectResult>()
+            .all(db_conn)
+            .await?;
+
+        if newest.is_empty() {
+            println!("no stats have been saved");
+            return Ok(());
+        }
+
+        let now = Utc::now();
+
+        let mut table = Table::new();
+
+        table.add_row(row!["chain_id", "newest_stat", "age_seconds"]);
+
+        for x in newest {
+            table.add_row(row![
+                x.chain_id,
+                x.newest_period_datetime.to_rfc3339(),
+                (now - x.newest_period_datetime).num_seconds(),
+            ]);
+        }
+
+        table.printstd();
+
+        Ok(())
+    }
+}
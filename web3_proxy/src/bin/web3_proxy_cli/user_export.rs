@@ -1,11 +1,44 @@
+use anyhow::Context;
 use argh::FromArgs;
-use entities::{rpc_key, user};
-use log::info;
-use migration::sea_orm::{DatabaseConnection, EntityTrait, PaginatorTrait};
+use chrono::{TimeZone, Utc};
+use entities::unsigned::BigUnsigned;
+use entities::{
+    balance_ledger, deposit, jwt_public_key, revert_log, rpc_accounting, rpc_key, secondary_user,
+    user, user_tier,
+};
+use ethers::types::Address;
+use log::{info, warn};
+use migration::sea_orm::{
+    ColumnTrait, DatabaseConnection, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder,
+    QuerySelect, QueryTrait, Select,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::{self, create_dir_all};
 use std::path::Path;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Bump this whenever the files change in a way that an older `user_import` can't read.
+/// Version 0 had no manifest and only users and rpc keys.
+/// Version 2 added deposits and the balance ledger.
+/// Version 3 added jwt public keys.
+pub const EXPORT_FORMAT_VERSION: u32 = 3;
+
+/// Written last, so an export without one is incomplete (or from version 0).
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportManifest {
+    pub format_version: u32,
+    pub exported_at: u64,
+    /// number of files written for each table
+    pub files: BTreeMap<String, u64>,
+    /// unix timestamps of the exported `rpc_accounting` range. None if it wasn't exported
+    pub rpc_accounting_start: Option<u64>,
+    pub rpc_accounting_end: Option<u64>,
+    /// only these users were exported. empty if every user was
+    #[serde(default)]
+    pub user_addresses: Vec<Address>,
+}
+
 #[derive(FromArgs, PartialEq, Eq, Debug)]
 /// Export users from the database.
 #[argh(subcommand, name = "user_export")]
@@ -14,6 +47,22 @@ pub struct UserExportSubCommand {
     /// TODO: validate this is a valid path here?
     #[argh(positional, default = "\"./data/users\".to_string()")]
     output_dir: String,
+
+    /// also export rpc_accounting rows. these can be very large
+    #[argh(switch)]
+    rpc_accounting: bool,
+
+    /// unix epoch timestamp of the earliest rpc_accounting row to export
+    #[argh(option)]
+    rpc_accounting_start: Option<u64>,
+
+    /// unix epoch timestamp of the last rpc_accounting row to export
+    #[argh(option)]
+    rpc_accounting_end: Option<u64>,
+
+    /// only export this user and the rows that belong to them. can be given more than once
+    #[argh(option)]
+    user_address: Vec<Address>,
 }
 
 impl UserExportSubCommand {
@@ -25,52 +74,260 @@ impl UserExportSubCommand {
 
         let export_dir = Path::new(&self.output_dir);
 
-        // get all the users from the database (paged)
-        let mut user_pages = user::Entity::find().paginate(db_conn, 1000);
+        let mut files = BTreeMap::new();
 
-        // TODO: for now all user_tier tables match in all databases, but in the future we might need to export/import this
+        // None exports everyone
+        let (user_ids, rpc_key_ids) = if self.user_address.is_empty() {
+            (None, None)
+        } else {
+            let addresses: Vec<Vec<u8>> = self
+                .user_address
+                .iter()
+                .map(|x| x.to_fixed_bytes().into())
+                .collect();
 
-        // save all users to a file
-        let mut user_file_count = 0;
-        while let Some(users) = user_pages.fetch_and_next().await? {
-            let export_file = export_dir.join(format!("{}-users-{}.json", now, user_file_count));
+            let user_ids: Vec<BigUnsigned> = user::Entity::find()
+                .select_only()
+                .column(user::Column::Id)
+                .filter(user::Column::Address.is_in(addresses))
+                .into_tuple()
+                .all(db_conn)
+                .await?;
 
-            fs::write(
-                export_file,
-                serde_json::to_string_pretty(&users).expect("users should serialize"),
-            )?;
+            if user_ids.len() < self.user_address.len() {
+                warn!(
+                    "only {} of {} users were found",
+                    user_ids.len(),
+                    self.user_address.len()
+                );
+            }
 
-            user_file_count += 1;
-        }
+            let rpc_key_ids: Vec<BigUnsigned> = rpc_key::Entity::find()
+                .select_only()
+                .column(rpc_key::Column::Id)
+                .filter(rpc_key::Column::UserId.is_in(user_ids.clone()))
+                .into_tuple()
+                .all(db_conn)
+                .await?;
 
-        info!(
-            "Saved {} user file(s) to {}",
-            user_file_count,
-            export_dir.to_string_lossy()
+            (Some(user_ids), Some(rpc_key_ids))
+        };
+
+        // parents are exported before their children so that import can map ids in one pass
+        // tiers are shared by every user. they are always exported so that imported users can be mapped to them
+        files.insert(
+            "user_tiers".to_string(),
+            export_pages(
+                db_conn,
+                user_tier::Entity::find().order_by_asc(user_tier::Column::Id),
+                export_dir,
+                now,
+                "user_tiers",
+            )
+            .await?,
+        );
+
+        files.insert(
+            "users".to_string(),
+            export_pages(
+                db_conn,
+                user::Entity::find()
+                    .apply_if(user_ids.clone(), |q, x| q.filter(user::Column::Id.is_in(x)))
+                    .order_by_asc(user::Column::Id),
+                export_dir,
+                now,
+                "users",
+            )
+            .await?,
+        );
+
+        files.insert(
+            "rpc_keys".to_string(),
+            export_pages(
+                db_conn,
+                rpc_key::Entity::find()
+                    .apply_if(rpc_key_ids.clone(), |q, x| {
+                        q.filter(rpc_key::Column::Id.is_in(x))
+                    })
+                    .order_by_asc(rpc_key::Column::Id),
+                export_dir,
+                now,
+                "rpc_keys",
+            )
+            .await?,
+        );
+
+        files.insert(
+            "jwt_public_keys".to_string(),
+            export_pages(
+                db_conn,
+                jwt_public_key::Entity::find()
+                    .apply_if(rpc_key_ids.clone(), |q, x| {
+                        q.filter(jwt_public_key::Column::RpcKeyId.is_in(x))
+                    })
+                    .order_by_asc(jwt_public_key::Column::Id),
+                export_dir,
+                now,
+                "jwt_public_keys",
+            )
+            .await?,
+        );
+
+        files.insert(
+            "secondary_users".to_string(),
+            export_pages(
+                db_conn,
+                // members are only exported if they were asked for too
+                secondary_user::Entity::find()
+                    .apply_if(user_ids.clone(), |q, x| {
+                        q.filter(secondary_user::Column::UserId.is_in(x.clone()))
+                            .filter(secondary_user::Column::MemberUserId.is_in(x))
+                    })
+                    .order_by_asc(secondary_user::Column::Id),
+                export_dir,
+                now,
+                "secondary_users",
+            )
+            .await?,
         );
 
-        // get all the rpc keys from the database (paged)
-        let mut rpc_key_pages = rpc_key::Entity::find().paginate(db_conn, 1000);
+        files.insert(
+            "deposits".to_string(),
+            export_pages(
+                db_conn,
+                deposit::Entity::find()
+                    .apply_if(user_ids.clone(), |q, x| {
+                        q.filter(deposit::Column::UserId.is_in(x))
+                    })
+                    .order_by_asc(deposit::Column::Id),
+                export_dir,
+                now,
+                "deposits",
+            )
+            .await?,
+        );
+
+        files.insert(
+            "balance_ledger".to_string(),
+            export_pages(
+                db_conn,
+                balance_ledger::Entity::find()
+                    .apply_if(user_ids.clone(), |q, x| {
+                        q.filter(balance_ledger::Column::UserId.is_in(x))
+                    })
+                    .order_by_asc(balance_ledger::Column::Id),
+                export_dir,
+                now,
+                "balance_ledger",
+            )
+            .await?,
+        );
+
+        files.insert(
+            "revert_logs".to_string(),
+            export_pages(
+                db_conn,
+                revert_log::Entity::find()
+                    .apply_if(rpc_key_ids.clone(), |q, x| {
+                        q.filter(revert_log::Column::RpcKeyId.is_in(x))
+                    })
+                    .order_by_asc(revert_log::Column::Id),
+                export_dir,
+                now,
+                "revert_logs",
+            )
+            .await?,
+        );
+
+        let (rpc_accounting_start, rpc_accounting_end) = if self.rpc_accounting {
+            // anonymous stats don't belong to any user
+            let mut q = rpc_accounting::Entity::find()
+                .filter(rpc_accounting::Column::RpcKeyId.is_not_null())
+                .apply_if(rpc_key_ids.clone(), |q, x| {
+                    q.filter(rpc_accounting::Column::RpcKeyId.is_in(x))
+                })
+                .order_by_asc(rpc_accounting::Column::Id);
+
+            if let Some(start) = self.rpc_accounting_start {
+                let start = Utc
+                    .timestamp_opt(start as i64, 0)
+                    .single()
+                    .context("invalid timestamp")?;
+                q = q.filter(rpc_accounting::Column::PeriodDatetime.gte(start));
+            }
+
+            if let Some(end) = self.rpc_accounting_end {
+                let end = Utc
+                    .timestamp_opt(end as i64, 0)
+                    .single()
+                    .context("invalid timestamp")?;
+                q = q.filter(rpc_accounting::Column::PeriodDatetime.lte(end));
+            }
+
+            files.insert(
+                "rpc_accounting".to_string(),
+                export_pages(db_conn, q, export_dir, now, "rpc_accounting").await?,
+            );
 
-        let mut rpc_key_file_count = 0;
-        while let Some(rpc_keys) = rpc_key_pages.fetch_and_next().await? {
-            let export_file =
-                export_dir.join(format!("{}-rpc_keys-{}.json", now, rpc_key_file_count));
+            (
+                Some(self.rpc_accounting_start.unwrap_or(0)),
+                Some(self.rpc_accounting_end.unwrap_or(now)),
+            )
+        } else {
+            (None, None)
+        };
 
-            fs::write(
-                export_file,
-                serde_json::to_string_pretty(&rpc_keys).expect("rpc_keys should serialize"),
-            )?;
+        let manifest = ExportManifest {
+            format_version: EXPORT_FORMAT_VERSION,
+            exported_at: now,
+            files,
+            rpc_accounting_start,
+            rpc_accounting_end,
+            user_addresses: self.user_address.clone(),
+        };
 
-            rpc_key_file_count += 1;
-        }
+        fs::write(
+            export_dir.join(format!("{}-manifest.json", now)),
+            serde_json::to_string_pretty(&manifest).expect("manifest should serialize"),
+        )?;
 
         info!(
-            "Saved {} rpc key file(s) to {}",
-            rpc_key_file_count,
-            export_dir.to_string_lossy()
+            "Exported to {} with timestamp {}",
+            export_dir.to_string_lossy(),
+            now
         );
 
         Ok(())
     }
 }
+
+/// save a query to numbered json files of up to 1000 rows each
+async fn export_pages<E>(
+    db_conn: &DatabaseConnection,
+    q: Select<E>,
+    export_dir: &Path,
+    now: u64,
+    name: &str,
+) -> anyhow::Result<u64>
+where
+    E: EntityTrait,
+    E::Model: Serialize + Sync,
+{
+    let mut pages = q.paginate(db_conn, 1000);
+
+    let mut file_count = 0;
+    while let Some(rows) = pages.fetch_and_next().await? {
+        let export_file = export_dir.join(format!("{}-{}-{}.json", now, name, file_count));
+
+        fs::write(
+            export_file,
+            serde_json::to_string_pretty(&rows).expect("rows should serialize"),
+        )?;
+
+        file_count += 1;
+    }
+
+    info!("Saved {} {} file(s)", file_count, name);
+
+    Ok(file_count)
+}
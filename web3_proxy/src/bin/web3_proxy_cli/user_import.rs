@@ -1,17 +1,54 @@
+use crate::user_export::{ExportManifest, EXPORT_FORMAT_VERSION};
 use anyhow::Context;
 use argh::FromArgs;
-use entities::{rpc_key, user};
+use entities::unsigned::BigUnsigned;
+use entities::{
+    balance_ledger, deposit, jwt_public_key, revert_log, rpc_accounting, rpc_key, secondary_user,
+    user, user_tier,
+};
 use glob::glob;
 use hashbrown::HashMap;
 use log::{info, warn};
-use migration::sea_orm::ActiveValue::NotSet;
+use migration::sea_orm::ActiveValue::{self, NotSet};
 use migration::sea_orm::{
-    ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter,
-    Set,
+    ActiveModelTrait, ColumnTrait, ConnectionTrait, DatabaseConnection, DatabaseTransaction,
+    DbBackend, EntityName, EntityTrait, IntoActiveModel, PrimaryKeyTrait, QueryFilter, Set,
+    Statement, TransactionTrait, Value,
 };
-use std::path::{Path, PathBuf};
+use migration::SimpleExpr;
+use serde::de::DeserializeOwned;
+use std::path::Path;
+use std::str::FromStr;
 use std::{fs::File, io::BufReader};
 
+/// What to do with an imported row when it already exists.
+/// Rows are matched on what makes them unique to a customer (address, secret key, tier title, ...), not on their ids.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    /// Leave existing rows alone. New rows keep their exported ids. If that id is taken, the row is skipped.
+    #[default]
+    Skip,
+    /// Update existing rows to match the export. New rows keep their exported ids. If that id is taken, the row is skipped.
+    Overwrite,
+    /// Leave existing rows alone. New rows always get new ids. Use this when merging into a database that is in use.
+    Remap,
+}
+
+impl FromStr for ConflictStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "overwrite" => Ok(Self::Overwrite),
+            "remap" => Ok(Self::Remap),
+            _ => Err(anyhow::anyhow!(
+                "unknown conflict strategy. expected skip, overwrite, or remap"
+            )),
+        }
+    }
+}
+
 #[derive(FromArgs, PartialEq, Eq, Debug)]
 /// Import users from another database.
 #[argh(subcommand, name = "user_import")]
@@ -23,10 +60,39 @@ pub struct UserImportSubCommand {
     /// where to write the file
     /// TODO: validate this is a file here?
     input_dir: String,
+
+    /// what to do with rows that already exist: skip, overwrite, or remap
+    #[argh(option, default = "ConflictStrategy::Skip")]
+    on_conflict: ConflictStrategy,
+
+    /// with `--on-conflict overwrite`, also move rpc keys that belong to a different user here
+    #[argh(switch)]
+    move_rpc_keys: bool,
+
+    /// do the whole import inside a transaction and then roll it back
+    #[argh(switch)]
+    dry_run: bool,
 }
 
 /// Map ids in the export to ids in our database.
-type UserMap = HashMap<u64, u64>;
+type IdMap = HashMap<BigUnsigned, BigUnsigned>;
+
+#[derive(Default)]
+struct IdMaps {
+    user_tiers: IdMap,
+    users: IdMap,
+    rpc_keys: IdMap,
+    deposits: IdMap,
+}
+
+/// what happened to the rows of one table
+#[derive(Debug, Default)]
+struct ImportCount {
+    inserted: u64,
+    updated: u64,
+    unchanged: u64,
+    skipped: u64,
+}
 
 impl UserImportSubCommand {
     pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
@@ -39,166 +105,870 @@ impl UserImportSubCommand {
             ));
         }
 
-        let user_glob_path = import_dir.join(format!("{}-users-*.json", self.export_timestamp));
+        let manifest_path = import_dir.join(format!("{}-manifest.json", self.export_timestamp));
 
-        let user_glob_path = user_glob_path.to_string_lossy();
+        let format_version = if manifest_path.exists() {
+            let manifest: ExportManifest =
+                serde_json::from_reader(BufReader::new(File::open(manifest_path)?))
+                    .context("parsing manifest")?;
 
-        info!("Scanning {}", user_glob_path);
+            anyhow::ensure!(
+                manifest.format_version <= EXPORT_FORMAT_VERSION,
+                "export format {} is newer than this importer ({})",
+                manifest.format_version,
+                EXPORT_FORMAT_VERSION
+            );
 
-        let mut user_map = HashMap::new();
-        let mut user_file_count = 0;
-        let mut imported_user_count = 0;
-        for entry in glob(&user_glob_path)? {
-            match entry {
-                Ok(path) => {
-                    imported_user_count +=
-                        self.import_user_file(db_conn, path, &mut user_map).await?
-                }
-                Err(e) => {
-                    warn!(
-                        "imported {} users from {} files.",
-                        imported_user_count, user_file_count
-                    );
-                    return Err(e.into());
-                }
+            info!("manifest: {:?}", manifest);
+
+            manifest.format_version
+        } else {
+            warn!("no manifest. assuming an old export with only users and rpc keys");
+            0
+        };
+
+        // everything happens in one transaction so that a failure leaves the database untouched
+        let txn = db_conn.begin().await?;
+
+        let mut id_maps = IdMaps::default();
+
+        // tiers are matched by title. old exports assume the tiers are the same in both databases
+        if format_version == 0 {
+            for x in user_tier::Entity::find().all(&txn).await? {
+                id_maps.user_tiers.insert(x.id, x.id);
             }
-            user_file_count += 1;
+        } else {
+            let count = self.import_user_tiers(&txn, &mut id_maps).await?;
+            info!("user_tiers: {:?}", count);
         }
 
-        info!(
-            "Imported {} user(s) from {} file(s). {} user(s) mapped.",
-            imported_user_count,
-            user_file_count,
-            user_map.len()
-        );
+        let count = self.import_users(&txn, &mut id_maps).await?;
+        info!("users: {:?}", count);
+
+        let count = self.import_rpc_keys(&txn, &mut id_maps).await?;
+        info!("rpc_keys: {:?}", count);
+
+        if format_version >= 1 {
+            let count = self.import_secondary_users(&txn, &id_maps).await?;
+            info!("secondary_users: {:?}", count);
+
+            let count = self.import_revert_logs(&txn, &id_maps).await?;
+            info!("revert_logs: {:?}", count);
+
+            let count = self.import_rpc_accounting(&txn, &id_maps).await?;
+            info!("rpc_accounting: {:?}", count);
+        }
+
+        if format_version >= 2 {
+            let count = self.import_deposits(&txn, &mut id_maps).await?;
+            info!("deposits: {:?}", count);
+
+            let count = self.import_balance_ledger(&txn, &id_maps).await?;
+            info!("balance_ledger: {:?}", count);
+        }
+
+        if format_version >= 3 {
+            let count = self.import_jwt_public_keys(&txn, &id_maps).await?;
+            info!("jwt_public_keys: {:?}", count);
+        }
+
+        advance_id_sequences(&txn).await?;
+
+        if self.dry_run {
+            txn.rollback().await?;
+
+            info!("dry run. nothing was saved");
+        } else {
+            txn.commit().await?;
+
+            info!("import saved");
+        }
+
+        Ok(())
+    }
+
+    /// read every page of a table from the export
+    fn read_rows<T: DeserializeOwned>(&self, name: &str) -> anyhow::Result<Vec<T>> {
+        let glob_path =
+            Path::new(&self.input_dir).join(format!("{}-{}-*.json", self.export_timestamp, name));
+
+        let glob_path = glob_path.to_string_lossy();
+
+        info!("Scanning {}", glob_path);
+
+        let mut rows = vec![];
+
+        for entry in glob(&glob_path)? {
+            let path = entry?;
+
+            // TODO: do this with async things from tokio
+            let reader = BufReader::new(File::open(&path)?);
+
+            let page: Vec<T> = serde_json::from_reader(reader)
+                .with_context(|| format!("parsing {}", path.to_string_lossy()))?;
 
-        let rpc_key_glob_path =
-            import_dir.join(format!("{}-rpc_keys-*.json", self.export_timestamp));
+            rows.extend(page);
+        }
+
+        Ok(rows)
+    }
 
-        let rpc_key_glob_path = rpc_key_glob_path.to_string_lossy();
+    /// The id for a row that doesn't exist yet. None if the row has to be skipped
+    async fn id_for_new_row<E>(
+        &self,
+        txn: &DatabaseTransaction,
+        exported_id: BigUnsigned,
+    ) -> anyhow::Result<Option<ActiveValue<BigUnsigned>>>
+    where
+        E: EntityTrait,
+        <E::PrimaryKey as PrimaryKeyTrait>::ValueType: From<BigUnsigned>,
+    {
+        if self.on_conflict == ConflictStrategy::Remap {
+            return Ok(Some(NotSet));
+        }
 
-        info!("Scanning {}", rpc_key_glob_path);
+        if E::find_by_id(exported_id).one(txn).await?.is_some() {
+            warn!(
+                "id {} is already used by a different row. use `--on-conflict remap` to import it with a new id",
+                exported_id
+            );
+            Ok(None)
+        } else {
+            Ok(Some(Set(exported_id)))
+        }
+    }
 
-        let mut rpc_key_file_count = 0;
-        let mut imported_rpc_key_count = 0;
-        for entry in glob(&rpc_key_glob_path)? {
-            match entry {
-                Ok(path) => {
-                    imported_rpc_key_count +=
-                        self.import_rpc_key_file(db_conn, path, &user_map).await?
+    async fn import_user_tiers(
+        &self,
+        txn: &DatabaseTransaction,
+        id_maps: &mut IdMaps,
+    ) -> anyhow::Result<ImportCount> {
+        let mut count = ImportCount::default();
+
+        for mut import in self.read_rows::<user_tier::Model>("user_tiers")? {
+            let exported_id = import.id;
+
+            let existing = user_tier::Entity::find()
+                .filter(user_tier::Column::Title.eq(import.title.clone()))
+                .one(txn)
+                .await?;
+
+            let local_id = if let Some(existing) = existing {
+                import.id = existing.id;
+
+                if self.on_conflict == ConflictStrategy::Overwrite && existing != import {
+                    import.into_active_model().reset_all().update(txn).await?;
+                    count.updated += 1;
+                } else {
+                    count.unchanged += 1;
                 }
-                Err(e) => {
+
+                existing.id
+            } else if let Some(id) = self
+                .id_for_new_row::<user_tier::Entity>(txn, exported_id)
+                .await?
+            {
+                let mut x = import.into_active_model();
+                x.id = id;
+                count.inserted += 1;
+                x.insert(txn).await?.id
+            } else {
+                count.skipped += 1;
+                continue;
+            };
+
+            id_maps.user_tiers.insert(exported_id, local_id);
+        }
+
+        Ok(count)
+    }
+
+    async fn import_users(
+        &self,
+        txn: &DatabaseTransaction,
+        id_maps: &mut IdMaps,
+    ) -> anyhow::Result<ImportCount> {
+        let mut count = ImportCount::default();
+
+        for mut import in self.read_rows::<user::Model>("users")? {
+            let exported_id = import.id;
+
+            import.user_tier_id = match id_maps.user_tiers.get(&import.user_tier_id) {
+                Some(x) => *x,
+                None => {
                     warn!(
-                        "imported {} users from {} files.",
-                        imported_rpc_key_count, rpc_key_file_count
+                        "user {} has an unknown tier {}. skipping",
+                        exported_id, import.user_tier_id
                     );
-                    return Err(e.into());
+                    count.skipped += 1;
+                    continue;
                 }
-            }
-            rpc_key_file_count += 1;
-        }
+            };
 
-        info!(
-            "Imported {} rpc key(s) from {} file(s)",
-            imported_rpc_key_count, rpc_key_file_count
-        );
+            import.downgraded_from_tier_id = import
+                .downgraded_from_tier_id
+                .and_then(|x| id_maps.user_tiers.get(&x).copied());
 
-        Ok(())
+            // first, check if a user already exists with this address
+            let existing = user::Entity::find()
+                .filter(user::Column::Address.eq(import.address.clone()))
+                .one(txn)
+                .await?;
+
+            let local_id = if let Some(existing) = existing {
+                import.id = existing.id;
+
+                if self.on_conflict == ConflictStrategy::Overwrite && existing != import {
+                    import.into_active_model().reset_all().update(txn).await?;
+                    count.updated += 1;
+                } else {
+                    count.unchanged += 1;
+                }
+
+                existing.id
+            } else if let Some(id) = self
+                .id_for_new_row::<user::Entity>(txn, exported_id)
+                .await?
+            {
+                let mut x = import.into_active_model();
+                x.id = id;
+                count.inserted += 1;
+                x.insert(txn).await?.id
+            } else {
+                count.skipped += 1;
+                continue;
+            };
+
+            id_maps.users.insert(exported_id, local_id);
+        }
+
+        Ok(count)
     }
 
-    pub async fn import_user_file(
+    async fn import_rpc_keys(
         &self,
-        db_conn: &DatabaseConnection,
-        path: PathBuf,
-        user_map: &mut UserMap,
-    ) -> anyhow::Result<u64> {
-        let mut count = 0;
+        txn: &DatabaseTransaction,
+        id_maps: &mut IdMaps,
+    ) -> anyhow::Result<ImportCount> {
+        let mut count = ImportCount::default();
+
+        for mut import in self.read_rows::<rpc_key::Model>("rpc_keys")? {
+            let exported_id = import.id;
+
+            import.user_id = match id_maps.users.get(&import.user_id) {
+                Some(x) => *x,
+                None => {
+                    // the user was skipped
+                    count.skipped += 1;
+                    continue;
+                }
+            };
 
-        // TODO: do this all inside a database transaction?
+            let existing = rpc_key::Entity::find()
+                .filter(rpc_key::Column::SecretKey.eq(import.secret_key))
+                .one(txn)
+                .await?;
 
-        // TODO: do this with async things from tokio
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
+            let local_id = if let Some(existing) = existing {
+                import.id = existing.id;
 
-        // Read the JSON contents of the file as an instance of `User`
-        let us = serde_json::from_reader::<_, Vec<user::Model>>(reader)?;
+                if existing.user_id != import.user_id
+                    && !(self.on_conflict == ConflictStrategy::Overwrite && self.move_rpc_keys)
+                {
+                    // don't take a key away from someone or attach the import's revert logs and stats to them
+                    warn!(
+                        "rpc key {} belongs to a different user here. use `--on-conflict overwrite --move-rpc-keys` to move it. skipping",
+                        exported_id
+                    );
+                    count.skipped += 1;
+                    continue;
+                }
 
-        for import_u in us.into_iter() {
-            // first, check if a user already exists with this address
-            if let Some(existing_u) = user::Entity::find()
-                .filter(user::Column::Address.eq(import_u.address.clone()))
-                .one(db_conn)
+                if self.on_conflict == ConflictStrategy::Overwrite && existing != import {
+                    import.into_active_model().reset_all().update(txn).await?;
+                    count.updated += 1;
+                } else {
+                    count.unchanged += 1;
+                }
+
+                existing.id
+            } else if let Some(id) = self
+                .id_for_new_row::<rpc_key::Entity>(txn, exported_id)
                 .await?
             {
-                user_map.insert(import_u.id, existing_u.id);
+                let mut x = import.into_active_model();
+                x.id = id;
+                count.inserted += 1;
+                x.insert(txn).await?.id
+            } else {
+                count.skipped += 1;
+                continue;
+            };
 
-                // don't increment count because the user already existed
+            id_maps.rpc_keys.insert(exported_id, local_id);
+        }
+
+        Ok(count)
+    }
+
+    async fn import_deposits(
+        &self,
+        txn: &DatabaseTransaction,
+        id_maps: &mut IdMaps,
+    ) -> anyhow::Result<ImportCount> {
+        let mut count = ImportCount::default();
+
+        for mut import in self.read_rows::<deposit::Model>("deposits")? {
+            let exported_id = import.id;
+
+            import.user_id = match id_maps.users.get(&import.user_id) {
+                Some(x) => *x,
+                None => {
+                    count.skipped += 1;
+                    continue;
+                }
+            };
+
+            // the same columns as the unique index on deposit
+            let existing = deposit::Entity::find()
+                .filter(deposit::Column::ChainId.eq(import.chain_id))
+                .filter(deposit::Column::TxHash.eq(import.tx_hash.clone()))
+                .filter(deposit::Column::LogIndex.eq(import.log_index))
+                .filter(deposit::Column::BlockHash.eq(import.block_hash.clone()))
+                .one(txn)
+                .await?;
+
+            let local_id = if let Some(existing) = existing {
+                import.id = existing.id;
+
+                if self.on_conflict == ConflictStrategy::Overwrite && existing != import {
+                    import.into_active_model().reset_all().update(txn).await?;
+                    count.updated += 1;
+                } else {
+                    count.unchanged += 1;
+                }
+
+                existing.id
+            } else if let Some(id) = self
+                .id_for_new_row::<deposit::Entity>(txn, exported_id)
+                .await?
+            {
+                let mut x = import.into_active_model();
+                x.id = id;
+                count.inserted += 1;
+                x.insert(txn).await?.id
             } else {
-                // user address is not known to the local database. no existing_u
-                let import_id = import_u.id;
+                count.skipped += 1;
+                continue;
+            };
 
-                let mut new_u = import_u.into_active_model();
+            id_maps.deposits.insert(exported_id, local_id);
+        }
 
-                new_u.id = NotSet;
+        Ok(count)
+    }
 
-                let new_u = new_u.save(db_conn).await?;
+    /// Ledger entries are never updated.
+    /// They are matched on the same keys that keep proxies from crediting a deposit or debiting usage twice.
+    async fn import_balance_ledger(
+        &self,
+        txn: &DatabaseTransaction,
+        id_maps: &IdMaps,
+    ) -> anyhow::Result<ImportCount> {
+        let mut count = ImportCount::default();
+
+        for mut import in self.read_rows::<balance_ledger::Model>("balance_ledger")? {
+            let exported_id = import.id;
+
+            import.user_id = match id_maps.users.get(&import.user_id) {
+                Some(x) => *x,
+                None => {
+                    count.skipped += 1;
+                    continue;
+                }
+            };
+
+            if let Some(deposit_id) = import.deposit_id {
+                import.deposit_id = match id_maps.deposits.get(&deposit_id) {
+                    Some(x) => Some(*x),
+                    None => {
+                        count.skipped += 1;
+                        continue;
+                    }
+                };
+            }
 
-                let new_id = *new_u.id.as_ref();
+            let mut q = balance_ledger::Entity::find();
 
-                user_map.insert(import_id, new_id);
+            if let Some(deposit_id) = import.deposit_id {
+                q = q
+                    .filter(balance_ledger::Column::DepositId.eq(deposit_id))
+                    .filter(balance_ledger::Column::Kind.eq(import.kind.clone()));
+            } else if let Some(idempotency_key) = import.idempotency_key.clone() {
+                q = q.filter(balance_ledger::Column::IdempotencyKey.eq(idempotency_key));
+            } else {
+                q = q
+                    .filter(balance_ledger::Column::UserId.eq(import.user_id))
+                    .filter(balance_ledger::Column::Kind.eq(import.kind.clone()))
+                    .filter(balance_ledger::Column::Amount.eq(import.amount))
+                    .filter(balance_ledger::Column::CreatedAt.eq(import.created_at));
+            }
 
-                count += 1;
+            if q.one(txn).await?.is_some() {
+                count.unchanged += 1;
+            } else if let Some(id) = self
+                .id_for_new_row::<balance_ledger::Entity>(txn, exported_id)
+                .await?
+            {
+                let mut x = import.into_active_model();
+                x.id = id;
+                x.insert(txn).await?;
+                count.inserted += 1;
+            } else {
+                count.skipped += 1;
             }
         }
 
         Ok(count)
     }
 
-    pub async fn import_rpc_key_file(
+    async fn import_jwt_public_keys(
         &self,
-        db_conn: &DatabaseConnection,
-        path: PathBuf,
-        user_map: &UserMap,
-    ) -> anyhow::Result<u64> {
-        let mut count = 0;
-
-        // TODO: do this with async things from tokio
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-
-        // Read the JSON contents of the file as an instance of `User`
-        let rks = serde_json::from_reader::<_, Vec<rpc_key::Model>>(reader)?;
-
-        for import_rk in rks.into_iter() {
-            let mapped_id = *user_map
-                .get(&import_rk.user_id)
-                .context("user mapping required")?;
-
-            if let Some(existing_rk) = rpc_key::Entity::find()
-                .filter(rpc_key::Column::SecretKey.eq(import_rk.secret_key))
-                .one(db_conn)
+        txn: &DatabaseTransaction,
+        id_maps: &IdMaps,
+    ) -> anyhow::Result<ImportCount> {
+        let mut count = ImportCount::default();
+
+        for mut import in self.read_rows::<jwt_public_key::Model>("jwt_public_keys")? {
+            let exported_id = import.id;
+
+            import.rpc_key_id = match id_maps.rpc_keys.get(&import.rpc_key_id) {
+                Some(x) => *x,
+                None => {
+                    count.skipped += 1;
+                    continue;
+                }
+            };
+
+            // jwts name their key with the key_id, so that is what has to match
+            let existing = jwt_public_key::Entity::find()
+                .filter(jwt_public_key::Column::KeyId.eq(import.key_id))
+                .one(txn)
+                .await?;
+
+            if let Some(existing) = existing {
+                import.id = existing.id;
+
+                if self.on_conflict == ConflictStrategy::Overwrite {
+                    if existing != import {
+                        import.into_active_model().reset_all().update(txn).await?;
+                        count.updated += 1;
+                    } else {
+                        count.unchanged += 1;
+                    }
+                } else if existing.rpc_key_id != import.rpc_key_id {
+                    warn!(
+                        "jwt public key {} belongs to a different rpc key here. skipping",
+                        exported_id
+                    );
+                    count.skipped += 1;
+                } else {
+                    count.unchanged += 1;
+                }
+            } else if let Some(id) = self
+                .id_for_new_row::<jwt_public_key::Entity>(txn, exported_id)
                 .await?
             {
-                // make sure it belongs to the mapped user
-                if existing_rk.user_id != mapped_id {
-                    // TODO: error or import the rest?
-                    return Err(anyhow::anyhow!("unexpected user id"));
+                let mut x = import.into_active_model();
+                x.id = id;
+                x.insert(txn).await?;
+                count.inserted += 1;
+            } else {
+                count.skipped += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    async fn import_secondary_users(
+        &self,
+        txn: &DatabaseTransaction,
+        id_maps: &IdMaps,
+    ) -> anyhow::Result<ImportCount> {
+        let mut count = ImportCount::default();
+
+        for mut import in self.read_rows::<secondary_user::Model>("secondary_users")? {
+            let exported_id = import.id;
+
+            // both users need to have been imported
+            match (
+                id_maps.users.get(&import.user_id),
+                id_maps.users.get(&import.member_user_id),
+            ) {
+                (Some(user_id), Some(member_user_id)) => {
+                    import.user_id = *user_id;
+                    import.member_user_id = *member_user_id;
+                }
+                _ => {
+                    count.skipped += 1;
+                    continue;
                 }
+            }
 
-                // the key exists under the expected user. we are good to continue
+            // an account has each member once. keep the role that is already here
+            let existing = secondary_user::Entity::find()
+                .filter(secondary_user::Column::UserId.eq(import.user_id))
+                .filter(secondary_user::Column::MemberUserId.eq(import.member_user_id))
+                .one(txn)
+                .await?;
+
+            if existing.is_some() {
+                count.unchanged += 1;
+            } else if let Some(id) = self
+                .id_for_new_row::<secondary_user::Entity>(txn, exported_id)
+                .await?
+            {
+                let mut x = import.into_active_model();
+                x.id = id;
+                x.insert(txn).await?;
+                count.inserted += 1;
             } else {
-                // user address is not known to the local database. no existing_rk
-                let mut new_rk = import_rk.into_active_model();
+                count.skipped += 1;
+            }
+        }
 
-                new_rk.id = NotSet;
-                new_rk.user_id = Set(mapped_id);
+        Ok(count)
+    }
 
-                new_rk.save(db_conn).await?;
+    async fn import_revert_logs(
+        &self,
+        txn: &DatabaseTransaction,
+        id_maps: &IdMaps,
+    ) -> anyhow::Result<ImportCount> {
+        let mut count = ImportCount::default();
+
+        for mut import in self.read_rows::<revert_log::Model>("revert_logs")? {
+            let exported_id = import.id;
+
+            import.rpc_key_id = match id_maps.rpc_keys.get(&import.rpc_key_id) {
+                Some(x) => *x,
+                None => {
+                    count.skipped += 1;
+                    continue;
+                }
+            };
+
+            let existing = revert_log::Entity::find()
+                .filter(revert_log::Column::RpcKeyId.eq(import.rpc_key_id))
+                .filter(revert_log::Column::Timestamp.eq(import.timestamp))
+                .filter(revert_log::Column::ChainId.eq(import.chain_id))
+                .filter(revert_log::Column::Method.eq(import.method.clone()))
+                .filter(revert_log::Column::To.eq(import.to.clone()))
+                .one(txn)
+                .await?;
+
+            if let Some(existing) = existing {
+                import.id = existing.id;
+
+                if self.on_conflict == ConflictStrategy::Overwrite && existing != import {
+                    import.into_active_model().reset_all().update(txn).await?;
+                    count.updated += 1;
+                } else {
+                    count.unchanged += 1;
+                }
+            } else if let Some(id) = self
+                .id_for_new_row::<revert_log::Entity>(txn, exported_id)
+                .await?
+            {
+                let mut x = import.into_active_model();
+                x.id = id;
+                x.insert(txn).await?;
+                count.inserted += 1;
+            } else {
+                count.skipped += 1;
+            }
+        }
+
+        Ok(count)
+    }
 
-                count += 1;
+    async fn import_rpc_accounting(
+        &self,
+        txn: &DatabaseTransaction,
+        id_maps: &IdMaps,
+    ) -> anyhow::Result<ImportCount> {
+        let mut count = ImportCount::default();
+
+        for mut import in self.read_rows::<rpc_accounting::Model>("rpc_accounting")? {
+            let exported_id = import.id;
+
+            import.rpc_key_id = match import.rpc_key_id.and_then(|x| id_maps.rpc_keys.get(&x)) {
+                Some(x) => Some(*x),
+                None => {
+                    count.skipped += 1;
+                    continue;
+                }
+            };
+
+            // every column that the stat emitter groups by
+            let existing = rpc_accounting::Entity::find()
+                .filter(rpc_accounting::Column::RpcKeyId.eq(import.rpc_key_id))
+                .filter(rpc_accounting::Column::ChainId.eq(import.chain_id))
+                .filter(rpc_accounting::Column::PeriodDatetime.eq(import.period_datetime))
+                .filter(eq_or_null(
+                    rpc_accounting::Column::Method,
+                    import.method.clone(),
+                ))
+                .filter(eq_or_null(
+                    rpc_accounting::Column::Origin,
+                    import.origin.clone(),
+                ))
+                .filter(rpc_accounting::Column::ArchiveRequest.eq(import.archive_request))
+                .filter(rpc_accounting::Column::ErrorResponse.eq(import.error_response))
+                .one(txn)
+                .await?;
+
+            if let Some(existing) = existing {
+                import.id = existing.id;
+
+                if self.on_conflict == ConflictStrategy::Overwrite && existing != import {
+                    import.into_active_model().reset_all().update(txn).await?;
+                    count.updated += 1;
+                } else {
+                    count.unchanged += 1;
+                }
+            } else if let Some(id) = self
+                .id_for_new_row::<rpc_accounting::Entity>(txn, exported_id)
+                .await?
+            {
+                let mut x = import.into_active_model();
+                x.id = id;
+                x.insert(txn).await?;
+                count.inserted += 1;
+            } else {
+                count.skipped += 1;
             }
         }
 
         Ok(count)
     }
 }
+
+/// New rows keep their exported ids, but postgres's sequences don't see explicit ids.
+/// Move every sequence past the largest id so that the next normal insert doesn't collide with an imported row.
+/// mysql and sqlite already do this for their auto increment columns.
+async fn advance_id_sequences(txn: &DatabaseTransaction) -> anyhow::Result<()> {
+    if txn.get_database_backend() != DbBackend::Postgres {
+        return Ok(());
+    }
+
+    let tables = [
+        user_tier::Entity.table_name(),
+        user::Entity.table_name(),
+        rpc_key::Entity.table_name(),
+        secondary_user::Entity.table_name(),
+        revert_log::Entity.table_name(),
+        rpc_accounting::Entity.table_name(),
+        deposit::Entity.table_name(),
+        balance_ledger::Entity.table_name(),
+        jwt_public_key::Entity.table_name(),
+    ];
+
+    for table in tables {
+        // setval is strict, so an empty table (MAX is NULL) leaves its sequence alone
+        let sql = format!(
+            r#"SELECT setval(pg_get_serial_sequence('"{0}"', 'id'), MAX(id)) FROM "{0}""#,
+            table
+        );
+
+        txn.execute(Statement::from_string(DbBackend::Postgres, sql))
+            .await
+            .with_context(|| format!("advancing the id sequence of {}", table))?;
+    }
+
+    Ok(())
+}
+
+/// `= NULL` never matches, so None needs `IS NULL`
+fn eq_or_null<C: ColumnTrait, V: Into<Value>>(col: C, value: Option<V>) -> SimpleExpr {
+    match value {
+        Some(x) => col.eq(x),
+        None => col.is_null(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user_export::UserExportSubCommand;
+    use ethers::types::Address;
+    use migration::sea_orm::Database;
+    use migration::{Migrator, MigratorTrait};
+    use std::{env, fs};
+    use ulid::Ulid;
+    use uuid::Uuid;
+
+    async fn migrated_db() -> DatabaseConnection {
+        let db_conn = Database::connect("sqlite::memory:").await.unwrap();
+
+        Migrator::up(&db_conn, None).await.unwrap();
+
+        db_conn
+    }
+
+    async fn insert_user(db_conn: &DatabaseConnection, id: u64, address: Address) -> user::Model {
+        let user_tier = user_tier::Entity::find()
+            .one(db_conn)
+            .await
+            .unwrap()
+            .unwrap();
+
+        user::ActiveModel {
+            id: Set(id.into()),
+            address: Set(address.as_bytes().to_vec()),
+            email: Set(Some(format!("{}@example.com", id))),
+            user_tier_id: Set(user_tier.id),
+            ..Default::default()
+        }
+        .insert(db_conn)
+        .await
+        .unwrap()
+    }
+
+    async fn insert_rpc_key(
+        db_conn: &DatabaseConnection,
+        id: u64,
+        user_id: BigUnsigned,
+        secret_key: Uuid,
+    ) -> rpc_key::Model {
+        rpc_key::ActiveModel {
+            id: Set(id.into()),
+            user_id: Set(user_id),
+            secret_key: Set(secret_key),
+            description: Set(Some(format!("key {}", id))),
+            ..Default::default()
+        }
+        .insert(db_conn)
+        .await
+        .unwrap()
+    }
+
+    async fn import(
+        db_conn: &DatabaseConnection,
+        export_timestamp: &str,
+        dir: &str,
+        args: &[&str],
+    ) {
+        let args: Vec<&str> = [export_timestamp, dir]
+            .iter()
+            .chain(args)
+            .copied()
+            .collect();
+
+        UserImportSubCommand::from_args(&["user_import"], &args)
+            .unwrap()
+            .main(db_conn)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn export_import_round_trip() {
+        let source = migrated_db().await;
+
+        let alice = insert_user(&source, 1, Address::repeat_byte(1)).await;
+        let bob = insert_user(&source, 2, Address::repeat_byte(2)).await;
+
+        let alice_key = insert_rpc_key(&source, 1, alice.id, Uuid::from_u128(1)).await;
+        let bob_key = insert_rpc_key(&source, 2, bob.id, Uuid::from_u128(2)).await;
+
+        let dir = env::temp_dir().join(format!("user_import_test-{}", Ulid::new()));
+        let dir_str = dir.to_str().unwrap();
+
+        UserExportSubCommand::from_args(&["user_export"], &[dir_str])
+            .unwrap()
+            .main(&source)
+            .await
+            .unwrap();
+
+        let export_timestamp = fs::read_dir(&dir)
+            .unwrap()
+            .find_map(|x| {
+                x.unwrap()
+                    .file_name()
+                    .to_str()
+                    .unwrap()
+                    .strip_suffix("-manifest.json")
+                    .map(str::to_string)
+            })
+            .expect("the export should have a manifest");
+
+        // someone else already has bob's key here
+        let dest = migrated_db().await;
+
+        let carol = insert_user(&dest, 10, Address::repeat_byte(3)).await;
+        let carol_key = insert_rpc_key(&dest, 10, carol.id, bob_key.secret_key).await;
+
+        import(
+            &dest,
+            &export_timestamp,
+            dir_str,
+            &["--on-conflict", "overwrite"],
+        )
+        .await;
+
+        // new rows keep their exported ids
+        assert_eq!(
+            user::Entity::find_by_id(alice.id).one(&dest).await.unwrap(),
+            Some(alice.clone())
+        );
+        assert_eq!(
+            user::Entity::find_by_id(bob.id).one(&dest).await.unwrap(),
+            Some(bob.clone())
+        );
+        assert_eq!(
+            rpc_key::Entity::find_by_id(alice_key.id)
+                .one(&dest)
+                .await
+                .unwrap(),
+            Some(alice_key.clone())
+        );
+
+        // overwrite alone doesn't take a key away from its owner
+        assert_eq!(
+            rpc_key::Entity::find_by_id(carol_key.id)
+                .one(&dest)
+                .await
+                .unwrap(),
+            Some(carol_key.clone())
+        );
+        assert_eq!(rpc_key::Entity::find().all(&dest).await.unwrap().len(), 2);
+
+        // moving the key has to be asked for
+        import(
+            &dest,
+            &export_timestamp,
+            dir_str,
+            &["--on-conflict", "overwrite", "--move-rpc-keys"],
+        )
+        .await;
+
+        let moved_key = rpc_key::Model {
+            id: carol_key.id,
+            ..bob_key
+        };
+
+        assert_eq!(
+            rpc_key::Entity::find_by_id(carol_key.id)
+                .one(&dest)
+                .await
+                .unwrap(),
+            Some(moved_key)
+        );
+        assert_eq!(user::Entity::find().all(&dest).await.unwrap().len(), 3);
+        assert_eq!(rpc_key::Entity::find().all(&dest).await.unwrap().len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
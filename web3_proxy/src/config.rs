@@ -3,10 +3,12 @@ use crate::rpcs::blockchain::{BlocksByHashCache, Web3ProxyBlock};
 use crate::rpcs::one::Web3Rpc;
 use argh::FromArgs;
 use ethers::prelude::TxHash;
-use ethers::types::{U256, U64};
+use ethers::types::{Address, U256, U64};
 use hashbrown::HashMap;
 use log::warn;
+use migration::sea_orm::prelude::Decimal;
 use migration::sea_orm::DatabaseConnection;
+use ordered_float::OrderedFloat;
 use serde::Deserialize;
 use std::sync::Arc;
 use tokio::sync::broadcast;
@@ -67,7 +69,7 @@ pub struct AppConfig {
     pub chain_id: u64,
 
     /// Database is used for user data.
-    /// Currently supports mysql or compatible backend.
+    /// Supports mysql (or compatible), postgres, and sqlite urls. Production has only been tested with mysql.
     pub db_url: Option<String>,
 
     /// minimum size of the connection pool for the database.
@@ -100,6 +102,16 @@ pub struct AppConfig {
     /// percentage to increase eth_estimateGas results. 100 == 100%
     pub gas_increase_percent: Option<U256>,
 
+    /// Credit users' balances with tokens sent to a deposit contract.
+    /// None = /user/balance only shows what is already in the database
+    #[serde(default)]
+    pub deposits: Option<DepositConfig>,
+
+    /// Send verification links and notifications to users' emails.
+    /// None = emails are saved without being verified and nothing is sent
+    #[serde(default)]
+    pub email: Option<EmailConfig>,
+
     /// Restrict user registration.
     /// None = no code needed
     pub invite_code: Option<String>,
@@ -111,17 +123,32 @@ pub struct AppConfig {
     /// domain in sign-in-with-ethereum messages
     pub login_domain: Option<String>,
 
+    /// Sign a jwt for every login. Proxies that share this secret accept each other's jwts.
+    /// A jwt is tied to its login. Logging out or revoking the session stops it too.
+    /// None = /user/login only returns a bearer token
+    pub jwt_secret: Option<String>,
+
     /// do not serve any requests if the best known block is older than this many seconds.
     pub max_block_age: Option<u64>,
 
     /// do not serve any requests if the best known block is behind the best known block by more than this many blocks.
     pub max_block_lag: Option<U64>,
 
+    /// Debit users' balances for their requests.
+    /// None = requests are free
+    #[serde(default)]
+    pub billing: Option<BillingConfig>,
+
     /// Rate limit for bearer token authenticated entrypoints.
     /// This is separate from the rpc limits.
     #[serde(default = "default_bearer_token_max_concurrent_requests")]
     pub bearer_token_max_concurrent_requests: u64,
 
+    /// How long a request in a monotonic reads session waits for our servers to catch up to a block that the client has already seen.
+    /// After this, the request is only sent to servers that have that block.
+    #[serde(default = "default_block_session_max_wait_ms")]
+    pub block_session_max_wait_ms: u64,
+
     /// Rate limit for the login entrypoint.
     /// This is separate from the rpc limits.
     #[serde(default = "default_login_rate_limit_per_period")]
@@ -158,9 +185,45 @@ pub struct AppConfig {
     /// the stats page url for a logged in user. if set, must contain "{rpc_key_id}"
     pub redirect_rpc_key_url: Option<String>,
 
+    /// After a key is rotated, its old secret keeps working for this many seconds.
+    /// 0 = the old secret stops working immediately
+    #[serde(default = "default_rpc_key_rotation_grace_seconds")]
+    pub rpc_key_rotation_grace_seconds: u64,
+
     /// Optionally send errors to <https://sentry.io>
     pub sentry_url: Option<String>,
 
+    /// On shutdown, how long `/health` returns 503 before we stop accepting connections.
+    /// This should be longer than the load balancer's health check interval.
+    #[serde(default = "default_shutdown_unhealthy_seconds")]
+    pub shutdown_unhealthy_seconds: u64,
+
+    /// On shutdown, how long to wait for in-flight HTTP requests to finish
+    #[serde(default = "default_shutdown_http_seconds")]
+    pub shutdown_http_seconds: u64,
+
+    /// On shutdown, how long websocket clients have to move their subscriptions before they are sent a close frame
+    #[serde(default = "default_shutdown_websocket_seconds")]
+    pub shutdown_websocket_seconds: u64,
+
+    /// On shutdown, how long to wait for stats to be saved
+    #[serde(default = "default_shutdown_stats_seconds")]
+    pub shutdown_stats_seconds: u64,
+
+    /// Percent (0-100) of successful responses to check against a second backend at the same block.
+    /// Keys are method names. "*" is used for any method that is not listed.
+    /// Mismatches are saved to kafka, so this requires `kafka_urls`.
+    #[serde(default)]
+    pub shadow_verify_percent: HashMap<String, OrderedFloat<f64>>,
+
+    /// If true, backends with mismatched shadow checks are less likely to be picked
+    #[serde(default)]
+    pub shadow_verify_affects_health: bool,
+
+    /// Send a second copy of slow requests to the next best server and use whichever answers first
+    #[serde(default)]
+    pub hedge: Option<HedgeConfig>,
+
     /// Track rate limits in a redis (or compatible backend)
     /// It is okay if this data is lost.
     pub volatile_redis_url: Option<String>,
@@ -174,6 +237,203 @@ pub struct AppConfig {
     pub extra: HashMap<String, serde_json::Value>,
 }
 
+impl AppConfig {
+    /// Copy the fields that are only read at startup from the running config.
+    /// Everything else can be applied to a running app.
+    /// Returns the names of the fields that were different. Changing those needs a restart.
+    pub fn keep_restart_fields(&mut self, running: &Self) -> Vec<&'static str> {
+        fn keep<T: Clone + PartialEq>(
+            name: &'static str,
+            new: &mut T,
+            running: &T,
+            changed: &mut Vec<&'static str>,
+        ) {
+            if new != running {
+                *new = running.clone();
+                changed.push(name);
+            }
+        }
+
+        let mut changed = vec![];
+
+        keep("billing", &mut self.billing, &running.billing, &mut changed);
+        // the semaphore caches keep the size that each semaphore was created with
+        keep(
+            "bearer_token_max_concurrent_requests",
+            &mut self.bearer_token_max_concurrent_requests,
+            &running.bearer_token_max_concurrent_requests,
+            &mut changed,
+        );
+        keep(
+            "chain_id",
+            &mut self.chain_id,
+            &running.chain_id,
+            &mut changed,
+        );
+        keep("db_url", &mut self.db_url, &running.db_url, &mut changed);
+        keep("email", &mut self.email, &running.email, &mut changed);
+        keep(
+            "db_min_connections",
+            &mut self.db_min_connections,
+            &running.db_min_connections,
+            &mut changed,
+        );
+        keep(
+            "db_max_connections",
+            &mut self.db_max_connections,
+            &running.db_max_connections,
+            &mut changed,
+        );
+        keep(
+            "db_replica_url",
+            &mut self.db_replica_url,
+            &running.db_replica_url,
+            &mut changed,
+        );
+        keep(
+            "db_replica_min_connections",
+            &mut self.db_replica_min_connections,
+            &running.db_replica_min_connections,
+            &mut changed,
+        );
+        keep(
+            "db_replica_max_connections",
+            &mut self.db_replica_max_connections,
+            &running.db_replica_max_connections,
+            &mut changed,
+        );
+        keep(
+            "public_max_concurrent_requests",
+            &mut self.public_max_concurrent_requests,
+            &running.public_max_concurrent_requests,
+            &mut changed,
+        );
+        keep(
+            "kafka_urls",
+            &mut self.kafka_urls,
+            &running.kafka_urls,
+            &mut changed,
+        );
+        keep(
+            "sentry_url",
+            &mut self.sentry_url,
+            &running.sentry_url,
+            &mut changed,
+        );
+        keep(
+            "volatile_redis_url",
+            &mut self.volatile_redis_url,
+            &running.volatile_redis_url,
+            &mut changed,
+        );
+        keep(
+            "volatile_redis_max_connections",
+            &mut self.volatile_redis_max_connections,
+            &running.volatile_redis_max_connections,
+            &mut changed,
+        );
+
+        // the public rate limiter is only created if this is set at startup. changing the limit is fine
+        if self.public_requests_per_period.is_some() != running.public_requests_per_period.is_some()
+        {
+            self.public_requests_per_period = running.public_requests_per_period;
+            changed.push("public_requests_per_period");
+        }
+
+        // the deposit watcher is only spawned if this is set at startup. changing its settings is fine
+        if self.deposits.is_some() != running.deposits.is_some() {
+            self.deposits = running.deposits.clone();
+            changed.push("deposits");
+        }
+
+        changed
+    }
+
+    /// Checks that don't need any connections
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(redirect) = &self.redirect_rpc_key_url {
+            if !redirect.contains("{{rpc_key_id}}") {
+                return Err(anyhow::anyhow!(
+                    "redirect_rpc_key_url user url must contain \"{{{{rpc_key_id}}}}\""
+                ));
+            }
+        }
+
+        if let Some(email) = &self.email {
+            if !email.verify_url.contains("{{token}}") {
+                return Err(anyhow::anyhow!(
+                    "email.verify_url must contain \"{{{{token}}}}\""
+                ));
+            }
+        }
+
+        if let Some(gas_increase_percent) = self.gas_increase_percent {
+            if gas_increase_percent > U256::from(1_000) {
+                return Err(anyhow::anyhow!(
+                    "gas_increase_percent is a percent. {} is too high",
+                    gas_increase_percent
+                ));
+            }
+        }
+
+        for (method, percent) in self.shadow_verify_percent.iter() {
+            if !(0.0..=100.0).contains(&percent.0) {
+                return Err(anyhow::anyhow!(
+                    "shadow_verify_percent for {} must be between 0 and 100",
+                    method
+                ));
+            }
+        }
+
+        if self.kafka_urls.is_none() && self.shadow_verify_percent.values().any(|x| x.0 > 0.0) {
+            return Err(anyhow::anyhow!(
+                "shadow_verify_percent needs kafka_urls. mismatches are saved to kafka"
+            ));
+        }
+
+        if let Some(jwt_secret) = &self.jwt_secret {
+            // HS256 keys shorter than the hash are easier to brute force
+            if jwt_secret.len() < 32 {
+                return Err(anyhow::anyhow!("jwt_secret must be at least 32 bytes"));
+            }
+        }
+
+        if let Some(billing) = &self.billing {
+            if billing.usd_per_compute_unit.is_sign_negative()
+                || billing.cache_hit_multiplier.is_sign_negative()
+                || billing.archive_multiplier.is_sign_negative()
+            {
+                return Err(anyhow::anyhow!("billing prices can not be negative"));
+            }
+        }
+
+        if let Some(deposits) = &self.deposits {
+            if deposits.confirmations == 0 {
+                return Err(anyhow::anyhow!("deposits.confirmations must be at least 1"));
+            }
+
+            if deposits.tokens.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "deposits.tokens must list at least one token"
+                ));
+            }
+
+            for (token, decimals) in deposits.tokens.iter() {
+                // balances are stored with 18 decimal places
+                if *decimals > 18 {
+                    return Err(anyhow::anyhow!(
+                        "deposits.tokens {:?} has {} decimals. at most 18 are supported",
+                        token,
+                        decimals
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
 fn default_archive_depth() -> u64 {
     90_000
 }
@@ -197,17 +457,166 @@ fn default_bearer_token_max_concurrent_requests() -> u64 {
     2
 }
 
+/// servers are usually only a block or two behind. a block is about 12 seconds on mainnet
+fn default_block_session_max_wait_ms() -> u64 {
+    1_000
+}
+
 /// Having a low amount of requests per period (usually minute) for login is safest.
 fn default_login_rate_limit_per_period() -> u64 {
     10
 }
 
+/// One day is enough time to deploy a new secret to most clients
+fn default_rpc_key_rotation_grace_seconds() -> u64 {
+    86_400
+}
+
 fn default_response_cache_max_bytes() -> u64 {
     // TODO: default to some percentage of the system?
     // 100 megabytes
     10u64.pow(8)
 }
 
+/// haproxy checks every 2 seconds by default and needs a few failures to mark a server down
+fn default_shutdown_unhealthy_seconds() -> u64 {
+    10
+}
+
+fn default_shutdown_http_seconds() -> u64 {
+    30
+}
+
+fn default_shutdown_websocket_seconds() -> u64 {
+    10
+}
+
+fn default_shutdown_stats_seconds() -> u64 {
+    30
+}
+
+/// Which requests get hedged and how long to wait before sending the second copy.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct HedgeConfig {
+    /// Delay in milliseconds for each method. "*" is used for any method that is not listed.
+    /// Methods that are not listed (and have no "*") are never hedged.
+    /// A delay of 0 waits for the first server's p95 latency.
+    #[serde(default)]
+    pub methods: HashMap<String, u64>,
+    /// Delay in milliseconds for each tier. Used when the method's delay is 0.
+    /// If any tiers are listed, servers in other tiers are never hedged.
+    #[serde(default)]
+    pub tiers: HashMap<String, u64>,
+}
+
+/// Requests cost `compute_units * usd_per_compute_unit`, adjusted by the multipliers.
+/// Error responses are not billed.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct BillingConfig {
+    /// Compute units for each method. "*" is used for any method that is not listed. Unlisted methods cost 1
+    #[serde(default)]
+    pub compute_units: HashMap<String, u64>,
+    pub usd_per_compute_unit: Decimal,
+    /// Responses from our cache are cheaper for us. 0.5 bills them at half price
+    #[serde(default = "default_billing_cache_hit_multiplier")]
+    pub cache_hit_multiplier: Decimal,
+    /// Applied to requests that needed an archive server
+    #[serde(default = "default_billing_archive_multiplier")]
+    pub archive_multiplier: Decimal,
+    /// Users on this tier are not billed. Users that run out of balance are moved here until they deposit again
+    #[serde(default = "default_billing_free_tier")]
+    pub free_tier: String,
+    /// Other tiers that are never billed
+    #[serde(default)]
+    pub unbilled_tiers: Vec<String>,
+}
+
+fn default_billing_cache_hit_multiplier() -> Decimal {
+    Decimal::new(5, 1)
+}
+
+fn default_billing_archive_multiplier() -> Decimal {
+    Decimal::new(2, 0)
+}
+
+fn default_billing_free_tier() -> String {
+    "Free".to_string()
+}
+
+/// The deposit contract emits `PaymentReceived(address indexed account, address token, uint256 amount)`.
+/// The account gets credited the amount as USD, scaled by the token's decimals.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct DepositConfig {
+    pub contract: Address,
+    /// Stablecoins that are accepted and their decimals. Payments in any other token are ignored.
+    pub tokens: HashMap<Address, u32>,
+    /// How deep a deposit needs to be before it is credited
+    #[serde(default = "default_deposit_confirmations")]
+    pub confirmations: u64,
+    /// Credited deposits this close to the head are still checked for reorgs
+    #[serde(default = "default_deposit_reorg_depth")]
+    pub reorg_depth: u64,
+    /// Where to start scanning if no deposits have been seen yet
+    #[serde(default = "default_deposit_lookback_blocks")]
+    pub lookback_blocks: u64,
+    /// Limit on the size of each eth_getLogs range
+    #[serde(default = "default_deposit_max_blocks_per_scan")]
+    pub max_blocks_per_scan: u64,
+    #[serde(default = "default_deposit_poll_seconds")]
+    pub poll_seconds: u64,
+}
+
+fn default_deposit_confirmations() -> u64 {
+    12
+}
+
+/// well past anything mainnet has seen since the merge
+fn default_deposit_reorg_depth() -> u64 {
+    64
+}
+
+/// about a day and a half on mainnet
+fn default_deposit_lookback_blocks() -> u64 {
+    10_000
+}
+
+/// most providers cap eth_getLogs ranges somewhere between 2k and 10k blocks
+fn default_deposit_max_blocks_per_scan() -> u64 {
+    2_000
+}
+
+fn default_deposit_poll_seconds() -> u64 {
+    12
+}
+
+/// Mail is sent through SMTP. A local test server like mailpit works with `smtp_starttls = false`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct EmailConfig {
+    /// like "Web3 Proxy <noreply@example.com>"
+    pub from: String,
+    pub smtp_host: String,
+    /// None = 587 with starttls or 25 without
+    pub smtp_port: Option<u16>,
+    /// only turn this off for a local test server. credentials would be sent in plaintext
+    #[serde(default = "default_smtp_starttls")]
+    pub smtp_starttls: bool,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    /// the link in verification emails. "{{token}}" is replaced with the token for `GET /user/email/verify/:token`
+    pub verify_url: String,
+    /// How long a verification link works
+    #[serde(default = "default_email_verification_seconds")]
+    pub verification_seconds: u64,
+}
+
+fn default_smtp_starttls() -> bool {
+    true
+}
+
+fn default_email_verification_seconds() -> u64 {
+    86_400
+}
+
 /// Configuration for a backend web3 RPC server
 #[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
 pub struct Web3RpcConfig {
@@ -224,6 +633,9 @@ pub struct Web3RpcConfig {
     pub http_url: Option<String>,
     /// block data limit. If None, will be queried
     pub block_data_limit: Option<u64>,
+    /// how often to check a queried block data limit again. 0 only checks when connecting
+    #[serde(default = "default_block_data_limit_check_seconds")]
+    pub block_data_limit_check_seconds: u64,
     /// the requests per second at which the server starts slowing down
     pub soft_limit: u32,
     /// the requests per second at which the server throws errors (rate limit or otherwise)
@@ -247,6 +659,11 @@ fn default_tier() -> u64 {
     0
 }
 
+fn default_block_data_limit_check_seconds() -> u64 {
+    // TODO: different default depending on the chain?
+    600
+}
+
 impl Web3RpcConfig {
     /// Create a Web3Rpc from config
     /// TODO: move this into Web3Rpc? (just need to make things pub(crate))
@@ -284,3 +701,45 @@ impl Web3RpcConfig {
         .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keep_restart_fields() {
+        let running = AppConfig {
+            chain_id: 1,
+            archive_depth: 100,
+            public_requests_per_period: Some(100),
+            ..Default::default()
+        };
+
+        let mut new = AppConfig {
+            chain_id: 137,
+            archive_depth: 200,
+            public_requests_per_period: Some(200),
+            public_max_concurrent_requests: Some(10),
+            response_cache_max_bytes: 1_000,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            new.keep_restart_fields(&running),
+            vec!["chain_id", "public_max_concurrent_requests"]
+        );
+        assert_eq!(new.chain_id, 1);
+        assert_eq!(new.archive_depth, 200);
+        assert_eq!(new.public_requests_per_period, Some(200));
+        assert_eq!(new.public_max_concurrent_requests, None);
+        assert_eq!(new.response_cache_max_bytes, 1_000);
+
+        new.public_requests_per_period = None;
+
+        assert_eq!(
+            new.keep_restart_fields(&running),
+            vec!["public_requests_per_period"]
+        );
+        assert_eq!(new.public_requests_per_period, Some(100));
+    }
+}
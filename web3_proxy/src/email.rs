@@ -0,0 +1,601 @@
+//! Email verification and notifications.
+//!
+//! Mail goes out through a `MailTransport`. SMTP is the only one so far.
+//! Users have to click the link in a verification email before anything else is sent to them.
+
+use crate::config::EmailConfig;
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use entities::{email_verification, user};
+use lettre::message::{header::ContentType, Mailbox};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::{debug, warn};
+use migration::sea_orm::prelude::{DateTimeUtc, Decimal, Uuid};
+use migration::sea_orm::{
+    self, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel,
+    QueryFilter,
+};
+use std::sync::Arc;
+use ulid::Ulid;
+
+/// A plain text email
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Something that can deliver mail
+#[async_trait]
+pub trait MailTransport: Send + Sync {
+    async fn send(&self, email: Email) -> anyhow::Result<()>;
+}
+
+pub struct SmtpMailTransport {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailTransport {
+    pub fn try_new(config: &EmailConfig) -> anyhow::Result<Self> {
+        let from: Mailbox = config.from.parse().context("parsing email.from")?;
+
+        let mut builder = if config.smtp_starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)
+                .context("parsing email.smtp_host")?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.smtp_host)
+        };
+
+        if let Some(port) = config.smtp_port {
+            builder = builder.port(port);
+        }
+
+        if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(Self {
+            from,
+            transport: builder.build(),
+        })
+    }
+}
+
+#[async_trait]
+impl MailTransport for SmtpMailTransport {
+    async fn send(&self, email: Email) -> anyhow::Result<()> {
+        let to: Mailbox = email.to.parse().context("parsing recipient")?;
+
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(email.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.body)
+            .context("building email")?;
+
+        self.transport
+            .send(message)
+            .await
+            .context("sending email over smtp")?;
+
+        Ok(())
+    }
+}
+
+/// Only checks the syntax. The verification link is what proves that the address works
+pub fn is_valid_email(email: &str) -> bool {
+    email.parse::<lettre::Address>().is_ok()
+}
+
+/// Save a new token for the email and send the user a link to confirm it.
+/// Links that were sent before stop working.
+/// Returns false without sending anything if the last link was sent less than a minute ago.
+pub async fn send_email_verification(
+    db_conn: &DatabaseConnection,
+    mailer: &dyn MailTransport,
+    config: &EmailConfig,
+    user_id: u64,
+    email: &str,
+) -> anyhow::Result<bool> {
+    // changing emails over and over shouldn't be a way to flood someone's inbox
+    let recent = email_verification::Entity::find()
+        .filter(email_verification::Column::UserId.eq(user_id))
+        .filter(email_verification::Column::CreatedAt.gt(Utc::now() - Duration::minutes(1)))
+        .one(db_conn)
+        .await
+        .context("checking for recent email verifications")?;
+
+    if recent.is_some() {
+        return Ok(false);
+    }
+
+    email_verification::Entity::delete_many()
+        .filter(email_verification::Column::UserId.eq(user_id))
+        .exec(db_conn)
+        .await
+        .context("deleting old email verifications")?;
+
+    let token = Ulid::new();
+    let now = Utc::now();
+    let expires_at = now + Duration::seconds(config.verification_seconds as i64);
+
+    // set here instead of by the column default so that sqlite stores it in the same format as the query above
+    email_verification::ActiveModel {
+        user_id: sea_orm::Set(user_id.into()),
+        email: sea_orm::Set(email.to_string()),
+        token: sea_orm::Set(Uuid::from_u128(token.0)),
+        expires_at: sea_orm::Set(expires_at),
+        created_at: sea_orm::Set(now),
+        ..Default::default()
+    }
+    .insert(db_conn)
+    .await
+    .context("saving email verification")?;
+
+    let link = config.verify_url.replace("{{token}}", &token.to_string());
+
+    let email = Email {
+        to: email.to_string(),
+        subject: "Confirm your email".to_string(),
+        body: format!(
+            "Open this link to confirm that this is your email:\n\n{}\n\nThe link works until {}. If you did not ask for this, you can ignore it.\n",
+            link, expires_at
+        ),
+    };
+
+    mailer.send(email).await?;
+
+    Ok(true)
+}
+
+/// Mark the user's email as verified. Each token only works once.
+/// Returns None if the token is unknown, expired, or for an email that the user has since changed.
+pub async fn verify_email(
+    db_conn: &DatabaseConnection,
+    token: Ulid,
+) -> anyhow::Result<Option<user::Model>> {
+    let verification = match email_verification::Entity::find()
+        .filter(email_verification::Column::Token.eq(Uuid::from_u128(token.0)))
+        .one(db_conn)
+        .await
+        .context("fetching email verification")?
+    {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    email_verification::Entity::delete_by_id(verification.id)
+        .exec(db_conn)
+        .await
+        .context("deleting used email verification")?;
+
+    if verification.expires_at <= Utc::now() {
+        return Ok(None);
+    }
+
+    let user = user::Entity::find_by_id(verification.user_id)
+        .one(db_conn)
+        .await?
+        .context("user for email verification")?;
+
+    if user.email.as_deref() != Some(verification.email.as_str()) {
+        return Ok(None);
+    }
+
+    let mut user = user.into_active_model();
+
+    user.email_verified_at = sea_orm::Set(Some(Utc::now()));
+
+    let user = user
+        .update(db_conn)
+        .await
+        .context("saving verified email")?;
+
+    Ok(Some(user))
+}
+
+/// Things on a user's account that are worth an email
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Notification {
+    /// requests are being rejected by their tier's rate limit
+    RateLimited {
+        max_requests_per_period: u64,
+    },
+    DepositCredited {
+        amount: Decimal,
+    },
+    /// their balance ran out and they were moved to the free tier
+    OutOfBalance {
+        free_tier: String,
+    },
+    RpcKeyRotated {
+        rpc_key_id: u64,
+        old_secret_key_expires_at: Option<DateTimeUtc>,
+    },
+}
+
+impl Notification {
+    pub fn subject(&self) -> &'static str {
+        match self {
+            Self::RateLimited { .. } => "You are being rate limited",
+            Self::DepositCredited { .. } => "Your deposit was credited",
+            Self::OutOfBalance { .. } => "Your balance ran out",
+            Self::RpcKeyRotated { .. } => "Your rpc key was rotated",
+        }
+    }
+
+    pub fn body(&self) -> String {
+        match self {
+            Self::RateLimited {
+                max_requests_per_period,
+            } => format!(
+                "Some of your requests were rejected because your tier allows {} requests per period. Upgrade your tier or make a deposit to raise the limit.\n",
+                max_requests_per_period
+            ),
+            Self::DepositCredited { amount } => format!(
+                "A deposit of ${} was added to your balance.\n",
+                amount
+            ),
+            Self::OutOfBalance { free_tier } => format!(
+                "Your balance ran out, so your account was moved to the {} tier. Make a deposit to go back to your old tier.\n",
+                free_tier
+            ),
+            Self::RpcKeyRotated {
+                rpc_key_id,
+                old_secret_key_expires_at,
+            } => match old_secret_key_expires_at {
+                Some(x) => format!(
+                    "Rpc key #{} was given a new secret. The old secret works until {}.\n",
+                    rpc_key_id, x
+                ),
+                None => format!(
+                    "Rpc key #{} was given a new secret. The old secret stopped working immediately.\n",
+                    rpc_key_id
+                ),
+            },
+        }
+    }
+}
+
+/// Email the user. Users without a verified email are skipped.
+/// Returns true if an email was sent.
+pub async fn notify_user(
+    db_conn: &DatabaseConnection,
+    mailer: &dyn MailTransport,
+    user_id: u64,
+    notification: &Notification,
+) -> anyhow::Result<bool> {
+    let user = match user::Entity::find_by_id(user_id).one(db_conn).await? {
+        Some(x) => x,
+        None => return Ok(false),
+    };
+
+    let to = match (user.email, user.email_verified_at) {
+        (Some(x), Some(_)) => x,
+        _ => {
+            debug!(
+                "user {} has no verified email. skipping {:?}",
+                user_id, notification
+            );
+            return Ok(false);
+        }
+    };
+
+    let email = Email {
+        to,
+        subject: notification.subject().to_string(),
+        body: notification.body(),
+    };
+
+    mailer.send(email).await?;
+
+    Ok(true)
+}
+
+/// `notify_user` in the background so that slow mail servers don't slow down the caller
+pub fn spawn_notify_user(
+    db_conn: DatabaseConnection,
+    mailer: Arc<dyn MailTransport>,
+    user_id: u64,
+    notification: Notification,
+) {
+    tokio::spawn(async move {
+        if let Err(err) = notify_user(&db_conn, mailer.as_ref(), user_id, &notification).await {
+            warn!("failed notifying user {}. err={:?}", user_id, err);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::user_tier;
+    use migration::sea_orm::Database;
+    use migration::{Migrator, MigratorTrait};
+    use std::sync::Mutex;
+
+    /// Keeps mail instead of sending it
+    #[derive(Default)]
+    struct MemoryMailTransport {
+        sent: Mutex<Vec<Email>>,
+    }
+
+    #[async_trait]
+    impl MailTransport for MemoryMailTransport {
+        async fn send(&self, email: Email) -> anyhow::Result<()> {
+            self.sent.lock().unwrap().push(email);
+
+            Ok(())
+        }
+    }
+
+    impl MemoryMailTransport {
+        fn sent(&self) -> Vec<Email> {
+            self.sent.lock().unwrap().clone()
+        }
+
+        /// The token from the link in the last verification email
+        fn last_token(&self) -> Ulid {
+            let sent = self.sent.lock().unwrap();
+
+            let body = &sent.last().expect("no email sent").body;
+
+            let token = body
+                .lines()
+                .find_map(|x| x.strip_prefix("https://example.com/verify/"))
+                .expect("no verification link");
+
+            Ulid::from_string(token).unwrap()
+        }
+    }
+
+    fn email_config() -> EmailConfig {
+        EmailConfig {
+            verify_url: "https://example.com/verify/{{token}}".to_string(),
+            verification_seconds: 3600,
+            ..Default::default()
+        }
+    }
+
+    async fn test_db() -> DatabaseConnection {
+        let db_conn = Database::connect("sqlite::memory:").await.unwrap();
+
+        Migrator::up(&db_conn, None).await.unwrap();
+
+        db_conn
+    }
+
+    /// A user that just set their email. It is not verified yet
+    async fn new_user(db_conn: &DatabaseConnection, email: &str) -> u64 {
+        let free_tier = user_tier::Entity::find()
+            .filter(user_tier::Column::Title.eq("Free"))
+            .one(db_conn)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let user = user::ActiveModel {
+            address: sea_orm::Set(vec![1; 20]),
+            email: sea_orm::Set(Some(email.to_string())),
+            user_tier_id: sea_orm::Set(free_tier.id),
+            ..Default::default()
+        }
+        .insert(db_conn)
+        .await
+        .unwrap();
+
+        user.id.into()
+    }
+
+    /// Lets the next verification email go out without waiting a minute
+    async fn age_verifications(db_conn: &DatabaseConnection) {
+        email_verification::Entity::update_many()
+            .col_expr(
+                email_verification::Column::CreatedAt,
+                migration::Expr::value(Utc::now() - Duration::minutes(2)),
+            )
+            .exec(db_conn)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_is_valid_email() {
+        assert!(is_valid_email("user@example.com"));
+        assert!(!is_valid_email("user"));
+        assert!(!is_valid_email("user@"));
+        assert!(!is_valid_email(""));
+    }
+
+    #[test]
+    fn test_rotated_body() {
+        let n = Notification::RpcKeyRotated {
+            rpc_key_id: 5,
+            old_secret_key_expires_at: None,
+        };
+
+        assert!(n.body().contains("#5"));
+        assert!(n.body().contains("immediately"));
+    }
+
+    #[tokio::test]
+    async fn verification_token_works_once() {
+        let db_conn = test_db().await;
+        let mailer = MemoryMailTransport::default();
+        let config = email_config();
+
+        let user_id = new_user(&db_conn, "user@example.com").await;
+
+        assert!(
+            send_email_verification(&db_conn, &mailer, &config, user_id, "user@example.com")
+                .await
+                .unwrap()
+        );
+
+        let sent = mailer.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "user@example.com");
+
+        let token = mailer.last_token();
+
+        let user = verify_email(&db_conn, token).await.unwrap().unwrap();
+        assert!(user.email_verified_at.is_some());
+
+        assert_eq!(verify_email(&db_conn, token).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_rejected() {
+        let db_conn = test_db().await;
+        let mailer = MemoryMailTransport::default();
+        let config = email_config();
+
+        let user_id = new_user(&db_conn, "user@example.com").await;
+
+        send_email_verification(&db_conn, &mailer, &config, user_id, "user@example.com")
+            .await
+            .unwrap();
+
+        email_verification::Entity::update_many()
+            .col_expr(
+                email_verification::Column::ExpiresAt,
+                migration::Expr::value(Utc::now() - Duration::seconds(1)),
+            )
+            .exec(&db_conn)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            verify_email(&db_conn, mailer.last_token()).await.unwrap(),
+            None
+        );
+
+        let user = user::Entity::find_by_id(user_id)
+            .one(&db_conn)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.email_verified_at, None);
+    }
+
+    #[tokio::test]
+    async fn token_stops_working_after_email_changes() {
+        let db_conn = test_db().await;
+        let mailer = MemoryMailTransport::default();
+        let config = email_config();
+
+        let user_id = new_user(&db_conn, "old@example.com").await;
+
+        send_email_verification(&db_conn, &mailer, &config, user_id, "old@example.com")
+            .await
+            .unwrap();
+
+        let old_token = mailer.last_token();
+
+        user::Entity::update_many()
+            .col_expr(
+                user::Column::Email,
+                migration::Expr::value(Some("new@example.com")),
+            )
+            .filter(user::Column::Id.eq(user_id))
+            .exec(&db_conn)
+            .await
+            .unwrap();
+
+        assert_eq!(verify_email(&db_conn, old_token).await.unwrap(), None);
+
+        // a link for the new email replaces the old link
+        age_verifications(&db_conn).await;
+
+        send_email_verification(&db_conn, &mailer, &config, user_id, "new@example.com")
+            .await
+            .unwrap();
+
+        let user = verify_email(&db_conn, mailer.last_token())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.email.as_deref(), Some("new@example.com"));
+        assert!(user.email_verified_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn verification_resend_is_limited() {
+        let db_conn = test_db().await;
+        let mailer = MemoryMailTransport::default();
+        let config = email_config();
+
+        let user_id = new_user(&db_conn, "user@example.com").await;
+
+        assert!(
+            send_email_verification(&db_conn, &mailer, &config, user_id, "user@example.com")
+                .await
+                .unwrap()
+        );
+
+        let first_token = mailer.last_token();
+
+        assert!(
+            !send_email_verification(&db_conn, &mailer, &config, user_id, "user@example.com")
+                .await
+                .unwrap()
+        );
+        assert_eq!(mailer.sent().len(), 1);
+
+        // after a minute, a new link is sent and the old one stops working
+        age_verifications(&db_conn).await;
+
+        assert!(
+            send_email_verification(&db_conn, &mailer, &config, user_id, "user@example.com")
+                .await
+                .unwrap()
+        );
+        assert_eq!(mailer.sent().len(), 2);
+
+        assert_eq!(verify_email(&db_conn, first_token).await.unwrap(), None);
+        assert!(verify_email(&db_conn, mailer.last_token())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn notifications_need_a_verified_email() {
+        let db_conn = test_db().await;
+        let mailer = MemoryMailTransport::default();
+        let config = email_config();
+
+        let user_id = new_user(&db_conn, "user@example.com").await;
+
+        let notification = Notification::DepositCredited {
+            amount: Decimal::new(5, 0),
+        };
+
+        assert!(!notify_user(&db_conn, &mailer, user_id, &notification)
+            .await
+            .unwrap());
+        assert!(mailer.sent().is_empty());
+
+        send_email_verification(&db_conn, &mailer, &config, user_id, "user@example.com")
+            .await
+            .unwrap();
+        verify_email(&db_conn, mailer.last_token())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(notify_user(&db_conn, &mailer, user_id, &notification)
+            .await
+            .unwrap());
+
+        let sent = mailer.sent();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1].to, "user@example.com");
+        assert_eq!(sent[1].subject, notification.subject());
+    }
+}
@@ -2,15 +2,18 @@
 
 use super::authorization::login_is_authorized;
 use super::errors::FrontendResult;
-use crate::admin_queries::query_admin_modify_usertier;
+use crate::admin_queries::{
+    query_admin_modify_usertier, query_admin_revoke_sessions, query_admin_rpc_command,
+};
 use crate::app::Web3ProxyApp;
 use crate::frontend::errors::FrontendErrorResponse;
-use crate::user_token::UserBearerToken;
+use crate::rpcs::admin::Web3RpcCommand;
+use crate::user_token::{revoke_logins, UserBearerToken};
 use crate::PostLogin;
 use anyhow::Context;
 use axum::{
     extract::{Path, Query},
-    headers::{authorization::Bearer, Authorization},
+    headers::{authorization::Bearer, Authorization, UserAgent},
     response::IntoResponse,
     Extension, Json, TypedHeader,
 };
@@ -27,7 +30,7 @@ use migration::sea_orm::{
     self, ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter,
 };
 use serde_json::json;
-use siwe::{Message, VerificationOpts};
+use siwe::Message;
 use std::ops::Add;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -49,6 +52,37 @@ pub async fn admin_change_user_roles(
     Ok(response)
 }
 
+/// `POST /admin/rpcs/:rpc_name` -- As an admin, change a backend rpc on all proxies without a restart
+///
+/// The body is a command like `{"action": "drain", "timeout_seconds": 300}`, `{"action": "disable"}`, `{"action": "enable"}`,
+/// `{"action": "set_tier", "tier": 1}`, `{"action": "set_soft_limit", "soft_limit": 100}`, or `{"action": "reconnect"}`.
+/// Tier and soft limit changes are lost when the config is reloaded. Drains and disables are kept.
+#[debug_handler]
+pub async fn admin_rpc_command_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_name): Path<String>,
+    Json(command): Json<Web3RpcCommand>,
+) -> FrontendResult {
+    let response = query_admin_rpc_command(&app, bearer, rpc_name, command).await?;
+
+    Ok(response)
+}
+
+/// `DELETE /admin/sessions/:user_address` -- As an admin, log a user out of every session
+///
+/// Useful when a user's bearer token or jwt may have leaked.
+#[debug_handler]
+pub async fn admin_sessions_delete(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(user_address): Path<Address>,
+) -> FrontendResult {
+    let response = query_admin_revoke_sessions(&app, bearer, user_address).await?;
+
+    Ok(response)
+}
+
 /// `GET /admin/imitate-login/:admin_address/:user_address` -- Being an admin, login as a user in read-only mode
 ///
 /// - user_address that is to be logged in by
@@ -115,7 +149,7 @@ pub async fn admin_login_get(
 
     // We want to login to llamanodes.com
     let login_domain = app
-        .config
+        .config()
         .login_domain
         .clone()
         .unwrap_or_else(|| "llamanodes.com".to_string());
@@ -232,20 +266,14 @@ pub async fn admin_login_get(
 pub async fn admin_login_post(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     InsecureClientIp(ip): InsecureClientIp,
+    user_agent: Option<TypedHeader<UserAgent>>,
     Json(payload): Json<PostLogin>,
 ) -> FrontendResult {
     login_is_authorized(&app, ip).await?;
 
     // Check for the signed bytes ..
-    // TODO: this seems too verbose. how can we simply convert a String into a [u8; 65]
-    let their_sig_bytes = Bytes::from_str(&payload.sig).context("parsing sig")?;
-    if their_sig_bytes.len() != 65 {
-        return Err(anyhow::anyhow!("checking signature length").into());
-    }
-    let mut their_sig: [u8; 65] = [0; 65];
-    for x in 0..65 {
-        their_sig[x] = their_sig_bytes[x]
-    }
+    // contract wallet signatures can be any length
+    let their_sig = Bytes::from_str(&payload.sig).context("parsing sig")?;
 
     // we can't trust that they didn't tamper with the message in some way. like some clients return it hex encoded
     // TODO: checking 0x seems fragile, but I think it will be fine. siwe message text shouldn't ever start with 0x
@@ -286,40 +314,25 @@ pub async fn admin_login_post(
         .parse()
         .context("parsing siwe message")?;
 
-    // default options are fine. the message includes timestamp and domain and nonce
-    let verify_config = VerificationOpts::default();
-
     let db_conn = app
         .db_conn()
         .context("deleting expired pending logins requires a db")?;
 
-    if let Err(err_1) = our_msg
-        .verify(&their_sig, &verify_config)
-        .await
-        .context("verifying signature against our local message")
-    {
-        // verification method 1 failed. try eip191
-        if let Err(err_191) = our_msg
-            .verify_eip191(&their_sig)
-            .context("verifying eip191 signature against our local message")
-        {
-            // delete ALL expired rows.
-            let now = Utc::now();
-            let delete_result = pending_login::Entity::delete_many()
-                .filter(pending_login::Column::ExpiresAt.lte(now))
-                .exec(&db_conn)
-                .await?;
-
-            // TODO: emit a stat? if this is high something weird might be happening
-            debug!("cleared expired pending_logins: {:?}", delete_result);
-
-            return Err(anyhow::anyhow!(
-                "both the primary and eip191 verification failed: {:#?}; {:#?}",
-                err_1,
-                err_191
-            )
+    // the admin can use a contract wallet too
+    if let Err(err) = app.verify_siwe_signature(&our_msg, &their_sig).await {
+        // delete ALL expired rows.
+        let now = Utc::now();
+        let delete_result = pending_login::Entity::delete_many()
+            .filter(pending_login::Column::ExpiresAt.lte(now))
+            .exec(&db_conn)
+            .await?;
+
+        // TODO: emit a stat? if this is high something weird might be happening
+        debug!("cleared expired pending_logins: {:?}", delete_result);
+
+        return Err(err
+            .context("verifying signature against our local message")
             .into());
-        }
     }
 
     let imitating_user_id = user_pending_login
@@ -384,9 +397,8 @@ pub async fn admin_login_post(
     // add bearer to the database
 
     // expire in 2 days, because this is more critical (and shouldn't need to be done so long!)
-    let expires_at = Utc::now()
-        .checked_add_signed(chrono::Duration::days(2))
-        .unwrap();
+    let now = Utc::now();
+    let expires_at = now.checked_add_signed(chrono::Duration::days(2)).unwrap();
 
     // TODO: Here, the bearer token should include a message
     // TODO: Above, make sure that the calling address is an admin!
@@ -398,6 +410,10 @@ pub async fn admin_login_post(
         user_id: sea_orm::Set(imitating_user.id), // Yes, this should be the user ... because the rest of the applications takes this item, from the initial user
         expires_at: sea_orm::Set(expires_at),
         read_only: sea_orm::Set(true),
+        created_at: sea_orm::Set(Some(now)),
+        last_used_at: sea_orm::Set(None),
+        ip: sea_orm::Set(Some(ip.to_string())),
+        user_agent: sea_orm::Set(user_agent.map(|TypedHeader(x)| x.to_string())),
     };
 
     user_login
@@ -423,14 +439,18 @@ pub async fn admin_logout_post(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
 ) -> FrontendResult {
-    let user_bearer = UserBearerToken::try_from(bearer)?;
+    let user_bearer = app.user_bearer_token(&bearer)?;
 
     let db_conn = app.db_conn().context("database needed for user logout")?;
 
-    if let Err(err) = login::Entity::delete_many()
-        .filter(login::Column::BearerToken.eq(user_bearer.uuid()))
-        .exec(&db_conn)
-        .await
+    let mut redis_conn = app.redis_conn().await?;
+
+    if let Err(err) = revoke_logins(
+        &db_conn,
+        redis_conn.as_mut(),
+        login::Column::BearerToken.eq(user_bearer.uuid()),
+    )
+    .await
     {
         debug!("Failed to delete {}: {}", user_bearer.redis_key(), err);
     }
@@ -2,7 +2,9 @@
 
 use super::errors::FrontendErrorResponse;
 use super::rpc_proxy_ws::ProxyMode;
+use super::teams::{role_allows, team_role};
 use crate::app::{AuthorizationChecks, Web3ProxyApp, APP_USER_AGENT};
+use crate::jwt::{self, LoginClaims, RpcClaims, RpcJwtKey};
 use crate::rpcs::one::Web3Rpc;
 use crate::user_token::UserBearerToken;
 use anyhow::Context;
@@ -10,15 +12,17 @@ use axum::headers::authorization::Bearer;
 use axum::headers::{Header, Origin, Referer, UserAgent};
 use chrono::Utc;
 use deferred_rate_limiter::DeferredRateLimitResult;
-use entities::{login, rpc_key, user, user_tier};
+use entities::sea_orm_active_enums::Role;
+use entities::{jwt_public_key, login, rpc_key, user, user_tier};
 use ethers::types::Bytes;
 use ethers::utils::keccak256;
 use futures::TryFutureExt;
 use hashbrown::HashMap;
-use http::HeaderValue;
+use http::{HeaderValue, StatusCode};
 use ipnet::IpNet;
 use log::{error, warn};
 use migration::sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
+use migration::{Condition, Expr};
 use parking_lot::Mutex;
 use redis_rate_limiter::redis::AsyncCommands;
 use redis_rate_limiter::RedisRateLimitResult;
@@ -338,7 +342,7 @@ pub async fn ip_is_authorized(
     // TODO: move this to an AuthorizedUser extrator
     let (authorization, semaphore) = match app
         .rate_limit_by_ip(
-            &app.config.allowed_origin_requests_per_period,
+            &app.config().allowed_origin_requests_per_period,
             ip,
             origin,
             proxy_mode,
@@ -355,23 +359,23 @@ pub async fn ip_is_authorized(
     };
 
     // in the background, add the ip to a recent_users map
-    if app.config.public_recent_ips_salt.is_some() {
+    if app.config().public_recent_ips_salt.is_some() {
         let app = app.clone();
         let f = async move {
             let now = Utc::now().timestamp();
 
             if let Some(mut redis_conn) = app.redis_conn().await? {
                 let salt = app
-                    .config
+                    .config()
                     .public_recent_ips_salt
-                    .as_ref()
+                    .clone()
                     .expect("public_recent_ips_salt must exist in here");
 
                 let salted_ip = format!("{}:{}", salt, ip);
 
                 let hashed_ip = Bytes::from(keccak256(salted_ip.as_bytes()));
 
-                let recent_ip_key = format!("recent_users:ip:{}", app.config.chain_id);
+                let recent_ip_key = format!("recent_users:ip:{}", app.config().chain_id);
 
                 redis_conn
                     .zadd(recent_ip_key, hashed_ip.to_string(), now)
@@ -417,7 +421,7 @@ pub async fn key_is_authorized(
 
     // TODO: DRY and maybe optimize the hashing
     // in the background, add the ip to a recent_users map
-    if app.config.public_recent_ips_salt.is_some() {
+    if app.config().public_recent_ips_salt.is_some() {
         let app = app.clone();
         let user_id = authorization.checks.user_id;
         let f = async move {
@@ -425,16 +429,16 @@ pub async fn key_is_authorized(
 
             if let Some(mut redis_conn) = app.redis_conn().await? {
                 let salt = app
-                    .config
+                    .config()
                     .public_recent_ips_salt
-                    .as_ref()
+                    .clone()
                     .expect("public_recent_ips_salt must exist in here");
 
                 let salted_user_id = format!("{}:{}", salt, user_id);
 
                 let hashed_user_id = Bytes::from(keccak256(salted_user_id.as_bytes()));
 
-                let recent_user_id_key = format!("recent_users:id:{}", app.config.chain_id);
+                let recent_user_id_key = format!("recent_users:id:{}", app.config().chain_id);
 
                 redis_conn
                     .zadd(recent_user_id_key, hashed_user_id.to_string(), now)
@@ -455,10 +459,48 @@ pub async fn key_is_authorized(
     Ok((authorization, semaphore))
 }
 
+/// The keyless rpc routes take a jwt in the Authorization header in place of an rpc key.
+/// Any other bearer token is an error instead of quietly getting the ip's limits.
+/// Without a bearer token, this is the same as `ip_is_authorized`.
+#[allow(clippy::too_many_arguments)]
+pub async fn jwt_or_ip_is_authorized(
+    app: &Arc<Web3ProxyApp>,
+    bearer: Option<Bearer>,
+    ip: IpAddr,
+    origin: Option<Origin>,
+    proxy_mode: ProxyMode,
+    referer: Option<Referer>,
+    user_agent: Option<UserAgent>,
+) -> Result<
+    (
+        Authorization,
+        Option<OwnedSemaphorePermit>,
+        Option<RpcClaims>,
+    ),
+    FrontendErrorResponse,
+> {
+    match bearer {
+        Some(bearer) => {
+            let (rpc_key, claims) = app.rpc_jwt_is_authorized(bearer.token()).await?;
+
+            let (authorization, semaphore) =
+                key_is_authorized(app, rpc_key, ip, origin, proxy_mode, referer, user_agent)
+                    .await?;
+
+            Ok((authorization, semaphore, Some(claims)))
+        }
+        None => {
+            let (authorization, semaphore) = ip_is_authorized(app, ip, origin, proxy_mode).await?;
+
+            Ok((authorization, semaphore, None))
+        }
+    }
+}
+
 impl Web3ProxyApp {
     /// Limit the number of concurrent requests from the given ip address.
     pub async fn ip_semaphore(&self, ip: IpAddr) -> anyhow::Result<Option<OwnedSemaphorePermit>> {
-        if let Some(max_concurrent_requests) = self.config.public_max_concurrent_requests {
+        if let Some(max_concurrent_requests) = self.config().public_max_concurrent_requests {
             let semaphore = self
                 .ip_semaphores
                 .get_with(ip, async move {
@@ -515,20 +557,62 @@ impl Web3ProxyApp {
         }
     }
 
+    /// Bearer tokens are either the ulid from `/user/login` or the jwt that was returned alongside it.
+    /// The jwt's `jti` is the ulid.
+    pub fn user_bearer_token(
+        &self,
+        bearer: &Bearer,
+    ) -> Result<UserBearerToken, FrontendErrorResponse> {
+        if jwt::is_jwt(bearer.token()) {
+            let claims = self.login_claims(bearer.token())?;
+
+            Ok(claims.jti.into())
+        } else {
+            let user_bearer_token = UserBearerToken::from_str(bearer.token())?;
+
+            Ok(user_bearer_token)
+        }
+    }
+
+    fn login_claims(&self, token: &str) -> Result<LoginClaims, FrontendErrorResponse> {
+        let jwt_secret =
+            self.config().jwt_secret.clone().ok_or_else(|| {
+                FrontendErrorResponse::BadRequest("jwt logins are disabled".into())
+            })?;
+
+        jwt::decode_login(token, &jwt_secret).map_err(|err| {
+            FrontendErrorResponse::StatusCode(
+                StatusCode::UNAUTHORIZED,
+                "invalid jwt".to_string(),
+                Some(err),
+            )
+        })
+    }
+
     /// Verify that the given bearer token and address are allowed to take the specified action.
     /// This includes concurrent request limiting.
     pub async fn bearer_is_authorized(
         &self,
         bearer: Bearer,
     ) -> Result<(user::Model, OwnedSemaphorePermit), FrontendErrorResponse> {
+        // a valid jwt already says who the user is
+        let login_claims = if jwt::is_jwt(bearer.token()) {
+            Some(self.login_claims(bearer.token())?)
+        } else {
+            None
+        };
+
         // get the user id for this bearer token
-        let user_bearer_token = UserBearerToken::try_from(bearer)?;
+        let user_bearer_token = match &login_claims {
+            Some(claims) => UserBearerToken(claims.jti),
+            None => UserBearerToken::try_from(bearer)?,
+        };
 
         // limit concurrent requests
         let semaphore = self
             .bearer_token_semaphores
             .get_with(user_bearer_token.clone(), async move {
-                let s = Semaphore::new(self.config.bearer_token_max_concurrent_requests as usize);
+                let s = Semaphore::new(self.config().bearer_token_max_concurrent_requests as usize);
                 Arc::new(s)
             })
             .await;
@@ -540,19 +624,155 @@ impl Web3ProxyApp {
             .db_replica()
             .context("checking if bearer token is authorized")?;
 
+        // jwts are checked against their login too so that logging out or revoking the session stops them
         let user_bearer_uuid: Uuid = user_bearer_token.into();
 
-        let user = user::Entity::find()
-            .left_join(login::Entity)
+        let (user_login, user) = login::Entity::find()
             .filter(login::Column::BearerToken.eq(user_bearer_uuid))
+            .find_also_related(user::Entity)
             .one(db_replica.conn())
             .await
             .context("fetching user from db by bearer token")?
-            .context("unknown bearer token")?;
+            .ok_or_else(|| {
+                FrontendErrorResponse::StatusCode(
+                    StatusCode::UNAUTHORIZED,
+                    "bearer token is unknown or was revoked".to_string(),
+                    None,
+                )
+            })?;
+
+        let user = user.context("login without a user")?;
+
+        if let Some(claims) = login_claims {
+            if claims.sub != user.id.to_string() {
+                return Err(FrontendErrorResponse::StatusCode(
+                    StatusCode::UNAUTHORIZED,
+                    "invalid jwt".to_string(),
+                    None,
+                ));
+            }
+        }
+
+        let now = Utc::now();
+
+        if user_login.expires_at <= now {
+            return Err(FrontendErrorResponse::StatusCode(
+                StatusCode::UNAUTHORIZED,
+                "bearer token expired".to_string(),
+                None,
+            ));
+        }
+
+        // sessions show when they were last used. writing that on every request would be a lot of writes
+        let stale = user_login
+            .last_used_at
+            .map(|x| now - x > chrono::Duration::seconds(60))
+            .unwrap_or(true);
+
+        if stale {
+            if let Some(db_conn) = self.db_conn() {
+                if let Err(err) = login::Entity::update_many()
+                    .col_expr(login::Column::LastUsedAt, Expr::value(now))
+                    .filter(login::Column::Id.eq(user_login.id))
+                    .exec(&db_conn)
+                    .await
+                {
+                    warn!("failed updating login last_used_at. err={:?}", err);
+                }
+            }
+        }
 
         Ok((user, semaphore_permit))
     }
 
+    /// Check a bearer token and that its user may act on `user_id`'s account with at least `role`.
+    /// `None` is the bearer's own account. Users are always the owner of their own account.
+    /// Returns the caller and the id of the account to act on.
+    pub async fn bearer_is_authorized_for(
+        &self,
+        bearer: Bearer,
+        user_id: Option<u64>,
+        role: Role,
+    ) -> Result<(user::Model, u64, OwnedSemaphorePermit), FrontendErrorResponse> {
+        let (caller, semaphore_permit) = self.bearer_is_authorized(bearer).await?;
+
+        let user_id = user_id.unwrap_or(caller.id.into());
+
+        if user_id != caller.id {
+            let db_replica = self
+                .db_replica()
+                .context("checking the bearer's team role")?;
+
+            match team_role(db_replica.conn(), user_id, caller.id.into()).await? {
+                Some(x) if role_allows(&x, &role) => {}
+                _ => return Err(FrontendErrorResponse::AccessDenied),
+            }
+        }
+
+        Ok((caller, user_id, semaphore_permit))
+    }
+
+    /// Check a jwt that was signed by one of the public keys uploaded for an rpc key.
+    /// Returns the rpc key that the jwt stands in for and the claims that limit it.
+    pub async fn rpc_jwt_is_authorized(
+        &self,
+        token: &str,
+    ) -> Result<(RpcSecretKey, RpcClaims), FrontendErrorResponse> {
+        if !jwt::has_key_id(token) {
+            // login jwts and bearer tokens don't work on the rpc routes
+            return Err(FrontendErrorResponse::StatusCode(
+                StatusCode::UNAUTHORIZED,
+                "the bearer token must be a jwt with the id of one of your rpc key's public keys in its kid header".to_string(),
+                None,
+            ));
+        }
+
+        let kid = jwt::decode_key_id(token).map_err(|err| {
+            FrontendErrorResponse::StatusCode(
+                StatusCode::UNAUTHORIZED,
+                "invalid jwt".to_string(),
+                Some(err),
+            )
+        })?;
+
+        let jwt_key: Result<_, Arc<anyhow::Error>> = self
+            .jwt_public_key_cache
+            .try_get_with(kid, async move {
+                let db_replica = self.db_replica().context("Getting database connection")?;
+
+                match jwt_public_key::Entity::find()
+                    .filter(jwt_public_key::Column::KeyId.eq(Uuid::from_u128(kid.into())))
+                    .find_also_related(rpc_key::Entity)
+                    .one(db_replica.conn())
+                    .await?
+                {
+                    Some((jwt_key_model, Some(rpc_key_model))) => {
+                        let secret_key = Ulid::from(rpc_key_model.secret_key.as_u128());
+
+                        let x = RpcJwtKey::try_new(jwt_key_model, secret_key)?;
+
+                        Ok(Some(Arc::new(x)))
+                    }
+                    _ => Ok(None),
+                }
+            })
+            .await;
+
+        let jwt_key = jwt_key
+            .map_err(|err| anyhow::anyhow!(err))?
+            .ok_or(FrontendErrorResponse::UnknownKey)?;
+
+        let claims = jwt_key.decode(token).map_err(|err| {
+            FrontendErrorResponse::StatusCode(
+                StatusCode::UNAUTHORIZED,
+                "invalid jwt".to_string(),
+                Some(err),
+            )
+        })?;
+
+        Ok((jwt_key.secret_key.into(), claims))
+    }
+
     pub async fn rate_limit_login(
         &self,
         ip: IpAddr,
@@ -562,7 +782,7 @@ impl Web3ProxyApp {
 
         // we don't care about user agent or origin or referer
         let authorization = Authorization::external(
-            &self.config.allowed_origin_requests_per_period,
+            &self.config().allowed_origin_requests_per_period,
             self.db_conn(),
             ip,
             None,
@@ -576,7 +796,13 @@ impl Web3ProxyApp {
         let semaphore = None;
 
         if let Some(rate_limiter) = &self.login_rate_limiter {
-            match rate_limiter.throttle_label(&ip.to_string(), None, 1).await {
+            // the limit is passed on every call so that config reloads take effect
+            let max_per_period = Some(self.config().login_rate_limit_per_period);
+
+            match rate_limiter
+                .throttle_label(&ip.to_string(), max_per_period, 1)
+                .await
+            {
                 Ok(RedisRateLimitResult::Allowed(_)) => {
                     Ok(RateLimitResult::Allowed(authorization, semaphore))
                 }
@@ -628,10 +854,12 @@ impl Web3ProxyApp {
         )?;
 
         if let Some(rate_limiter) = &self.frontend_ip_rate_limiter {
-            match rate_limiter
-                .throttle(ip, authorization.checks.max_requests_per_period, 1)
-                .await
-            {
+            let max_requests_per_period = authorization
+                .checks
+                .max_requests_per_period
+                .or(self.config().public_requests_per_period);
+
+            match rate_limiter.throttle(ip, max_requests_per_period, 1).await {
                 Ok(DeferredRateLimitResult::Allowed) => {
                     // rate limit allowed us. check concurrent request limits
                     let semaphore = self.ip_semaphore(ip).await?;
@@ -684,9 +912,21 @@ impl Web3ProxyApp {
                 // TODO: join the user table to this to return the User? we don't always need it
                 // TODO: join on secondary users
                 // TODO: join on user tier
+                let secret_key = <Uuid>::from(rpc_secret_key);
+
+                // a rotated key's old secret works until its grace period is over
                 match rpc_key::Entity::find()
-                    .filter(rpc_key::Column::SecretKey.eq(<Uuid>::from(rpc_secret_key)))
+                    .filter(
+                        Condition::any()
+                            .add(rpc_key::Column::SecretKey.eq(secret_key))
+                            .add(
+                                Condition::all()
+                                    .add(rpc_key::Column::OldSecretKey.eq(secret_key))
+                                    .add(rpc_key::Column::OldSecretKeyExpiresAt.gt(Utc::now())),
+                            ),
+                    )
                     .filter(rpc_key::Column::Active.eq(true))
+                    .filter(rpc_key::Column::DeletedAt.is_null())
                     .one(db_replica.conn())
                     .await?
                 {
@@ -756,10 +996,10 @@ impl Web3ProxyApp {
                             };
 
                         let rpc_key_id =
-                            Some(rpc_key_model.id.try_into().expect("db ids are never 0"));
+                            Some(rpc_key_model.id.0.try_into().expect("db ids are never 0"));
 
                         Ok(AuthorizationChecks {
-                            user_id: rpc_key_model.user_id,
+                            user_id: rpc_key_model.user_id.into(),
                             rpc_secret_key: Some(rpc_secret_key),
                             rpc_secret_key_id: rpc_key_id,
                             allowed_ips,
@@ -768,8 +1008,12 @@ impl Web3ProxyApp {
                             allowed_user_agents,
                             log_level: rpc_key_model.log_level,
                             log_revert_chance: rpc_key_model.log_revert_chance,
-                            max_concurrent_requests: user_tier_model.max_concurrent_requests,
-                            max_requests_per_period: user_tier_model.max_requests_per_period,
+                            max_concurrent_requests: user_tier_model
+                                .max_concurrent_requests
+                                .map(Into::into),
+                            max_requests_per_period: user_tier_model
+                                .max_requests_per_period
+                                .map(Into::into),
                             private_txs: rpc_key_model.private_txs,
                             proxy_mode,
                         })
@@ -845,6 +1089,12 @@ impl Web3ProxyApp {
                     // TODO: keys are secrets! use the id instead
                     // TODO: emit a stat
                     // // trace!(?rpc_key, "rate limit exceeded until {:?}", retry_at);
+                    self.notify_rate_limited(
+                        authorization.checks.user_id,
+                        user_max_requests_per_period,
+                    )
+                    .await;
+
                     Ok(RateLimitResult::RateLimited(authorization, Some(retry_at)))
                 }
                 Ok(DeferredRateLimitResult::RetryNever) => {
@@ -9,11 +9,12 @@ pub mod errors;
 pub mod rpc_proxy_http;
 pub mod rpc_proxy_ws;
 pub mod status;
+pub mod teams;
 pub mod users;
 
-use crate::app::Web3ProxyApp;
+use crate::app::{ShutdownPhase, Web3ProxyApp};
 use axum::{
-    routing::{get, post, put},
+    routing::{delete, get, post, put},
     Extension, Router,
 };
 use http::header::AUTHORIZATION;
@@ -100,6 +101,15 @@ pub async fn serve(port: u16, proxy_app: Arc<Web3ProxyApp>) -> anyhow::Result<()
             "/versus/:rpc_key",
             post(rpc_proxy_http::versus_proxy_web3_rpc_with_key),
         )
+        // authenticated quorum with and without trailing slash
+        .route(
+            "/quorum/:rpc_key/",
+            post(rpc_proxy_http::quorum_proxy_web3_rpc_with_key),
+        )
+        .route(
+            "/quorum/:rpc_key",
+            post(rpc_proxy_http::quorum_proxy_web3_rpc_with_key),
+        )
         //
         // Websocket RPC (GET)
         // If not an RPC, this will redirect to configurable urls
@@ -153,6 +163,15 @@ pub async fn serve(port: u16, proxy_app: Arc<Web3ProxyApp>) -> anyhow::Result<()
             "/versus/:rpc_key",
             get(rpc_proxy_ws::versus_websocket_handler_with_key),
         )
+        // authenticated quorum with and without trailing slash
+        .route(
+            "/quorum/:rpc_key/",
+            get(rpc_proxy_ws::quorum_websocket_handler_with_key),
+        )
+        .route(
+            "/quorum/:rpc_key",
+            get(rpc_proxy_ws::quorum_websocket_handler_with_key),
+        )
         //
         // System things
         //
@@ -174,6 +193,21 @@ pub async fn serve(port: u16, proxy_app: Arc<Web3ProxyApp>) -> anyhow::Result<()
         .route("/user/keys", get(users::rpc_keys_get))
         .route("/user/keys", post(users::rpc_keys_management))
         .route("/user/keys", put(users::rpc_keys_management))
+        .route("/user/keys/:rpc_key_id", delete(users::rpc_keys_delete))
+        .route(
+            "/user/keys/:rpc_key_id/rotate",
+            post(users::rpc_key_rotate_post),
+        )
+        .route("/user/keys/jwt", get(users::rpc_key_jwts_get))
+        .route("/user/keys/jwt", post(users::rpc_key_jwts_post))
+        .route("/user/keys/jwt/:key_id", delete(users::rpc_key_jwt_delete))
+        .route("/user/members", get(teams::members_get))
+        .route("/user/members", post(teams::members_post))
+        .route("/user/members/trail", get(teams::members_trail_get))
+        .route("/user/members/:member_id", delete(teams::member_delete))
+        .route("/user/invitations", get(teams::invitations_get))
+        .route("/user/invitations/:id", post(teams::invitation_accept_post))
+        .route("/user/invitations/:id", delete(teams::invitation_delete))
         .route("/user/revert_logs", get(users::user_revert_logs_get))
         .route(
             "/user/stats/aggregate",
@@ -184,8 +218,23 @@ pub async fn serve(port: u16, proxy_app: Arc<Web3ProxyApp>) -> anyhow::Result<()
             get(users::user_stats_aggregated_get),
         )
         .route("/user/stats/detailed", get(users::user_stats_detailed_get))
+        .route("/user/email/verify", post(users::user_email_verify_post))
+        .route(
+            "/user/email/verify/:token",
+            get(users::user_email_verify_get),
+        )
         .route("/user/logout", post(users::user_logout_post))
+        .route("/user/sessions", get(users::user_sessions_get))
+        .route(
+            "/user/sessions/:session_id",
+            delete(users::user_session_delete),
+        )
         .route("/admin/modify_role", get(admin::admin_change_user_roles))
+        .route("/admin/rpcs/:rpc_name", post(admin::admin_rpc_command_post))
+        .route(
+            "/admin/sessions/:user_address",
+            delete(admin::admin_sessions_delete),
+        )
         .route(
             "/admin/imitate-login/:admin_address/:user_address",
             get(admin::admin_login_get),
@@ -228,10 +277,19 @@ pub async fn serve(port: u16, proxy_app: Arc<Web3ProxyApp>) -> anyhow::Result<()
     */
     let service = app.into_make_service_with_connect_info::<SocketAddr>();
 
+    // stop accepting connections once shutdown starts draining. in-flight requests are allowed to finish
+    // upgraded websockets are not waited on here. they are closed by `Web3ProxyApp::close_websockets`
+    let shutdown_signal = async move {
+        proxy_app
+            .wait_for_shutdown_phase(ShutdownPhase::DrainingHttp)
+            .await
+    };
+
     // `axum::Server` is a re-export of `hyper::Server`
     axum::Server::bind(&addr)
         // TODO: option to use with_connect_info. we want it in dev, but not when running behind a proxy, but not
         .serve(service)
+        .with_graceful_shutdown(shutdown_signal)
         .await
         .map_err(Into::into)
 }
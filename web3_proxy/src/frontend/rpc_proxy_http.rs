@@ -1,57 +1,112 @@
 //! Take a user's HTTP JSON-RPC requests and either respond from local data or proxy the request to a backend rpc server.
 
-use super::authorization::{ip_is_authorized, key_is_authorized};
-use super::errors::FrontendResult;
-use super::rpc_proxy_ws::ProxyMode;
+use super::authorization::{jwt_or_ip_is_authorized, key_is_authorized};
+use super::errors::{FrontendErrorResponse, FrontendResult};
+use super::rpc_proxy_ws::{BlockSessionId, ProxyMode, QuorumQuery};
 use crate::{app::Web3ProxyApp, jsonrpc::JsonRpcRequestEnum};
-use axum::extract::Path;
-use axum::headers::{Origin, Referer, UserAgent};
+use axum::extract::{Path, Query};
+use axum::headers::authorization::Bearer;
+use axum::headers::{Authorization as AuthorizationHeader, Origin, Referer, UserAgent};
 use axum::TypedHeader;
 use axum::{response::IntoResponse, Extension, Json};
 use axum_client_ip::InsecureClientIp;
 use axum_macros::debug_handler;
+use http::StatusCode;
 use itertools::Itertools;
 use std::sync::Arc;
 
 /// POST /rpc -- Public entrypoint for HTTP JSON-RPC requests. Web3 wallets use this.
-/// Defaults to rate limiting by IP address, but can also read the Authorization header for a bearer token.
+/// Defaults to rate limiting by IP address, but can also read the Authorization header for a jwt signed by one of an rpc key's public keys.
 /// If possible, please use a WebSocket instead.
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn proxy_web3_rpc(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
     origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    bearer: Option<TypedHeader<AuthorizationHeader<Bearer>>>,
+    block_session_id: BlockSessionId,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> FrontendResult {
-    _proxy_web3_rpc(app, ip, origin, payload, ProxyMode::Best).await
+    _proxy_web3_rpc(
+        app,
+        ip,
+        origin,
+        referer,
+        user_agent,
+        bearer,
+        block_session_id,
+        payload,
+        ProxyMode::Best,
+    )
+    .await
 }
 
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn fastest_proxy_web3_rpc(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
     origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    bearer: Option<TypedHeader<AuthorizationHeader<Bearer>>>,
+    block_session_id: BlockSessionId,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> FrontendResult {
     // TODO: read the fastest number from params
     // TODO: check that the app allows this without authentication
-    _proxy_web3_rpc(app, ip, origin, payload, ProxyMode::Fastest(0)).await
+    _proxy_web3_rpc(
+        app,
+        ip,
+        origin,
+        referer,
+        user_agent,
+        bearer,
+        block_session_id,
+        payload,
+        ProxyMode::Fastest(0),
+    )
+    .await
 }
 
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn versus_proxy_web3_rpc(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
     origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    bearer: Option<TypedHeader<AuthorizationHeader<Bearer>>>,
+    block_session_id: BlockSessionId,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> FrontendResult {
-    _proxy_web3_rpc(app, ip, origin, payload, ProxyMode::Versus).await
+    _proxy_web3_rpc(
+        app,
+        ip,
+        origin,
+        referer,
+        user_agent,
+        bearer,
+        block_session_id,
+        payload,
+        ProxyMode::Versus,
+    )
+    .await
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn _proxy_web3_rpc(
     app: Arc<Web3ProxyApp>,
     InsecureClientIp(ip): InsecureClientIp,
     origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    bearer: Option<TypedHeader<AuthorizationHeader<Bearer>>>,
+    block_session_id: BlockSessionId,
     payload: JsonRpcRequestEnum,
     proxy_mode: ProxyMode,
 ) -> FrontendResult {
@@ -59,19 +114,58 @@ async fn _proxy_web3_rpc(
     // TODO: do we care about keeping the TypedHeader wrapper?
     let origin = origin.map(|x| x.0);
 
-    let (authorization, semaphore) = ip_is_authorized(&app, ip, origin, proxy_mode).await?;
+    let (authorization, semaphore, claims) = jwt_or_ip_is_authorized(
+        &app,
+        bearer.map(|TypedHeader(AuthorizationHeader(x))| x),
+        ip,
+        origin,
+        proxy_mode,
+        referer.map(|x| x.0),
+        user_agent.map(|x| x.0),
+    )
+    .await?;
+
+    if let Some(claims) = claims {
+        let methods: Vec<&str> = match &payload {
+            JsonRpcRequestEnum::Single(x) => vec![x.method.as_str()],
+            JsonRpcRequestEnum::Batch(x) => x.iter().map(|x| x.method.as_str()).collect(),
+        };
+
+        if let Some(method) = methods.into_iter().find(|x| !claims.allows_method(x)) {
+            return Err(FrontendErrorResponse::StatusCode(
+                StatusCode::FORBIDDEN,
+                format!("this jwt does not allow {}", method),
+                None,
+            ));
+        }
+    }
 
     let authorization = Arc::new(authorization);
 
-    let (response, rpcs, _semaphore) = app
-        .proxy_web3_rpc(authorization, payload)
+    let block_session = match block_session_id.0 {
+        Some(session_id) => Some(app.block_session(&authorization, &session_id).await),
+        None => None,
+    };
+
+    let (response, rpcs, response_block, _semaphore) = app
+        .proxy_web3_rpc(authorization, payload, block_session.as_deref())
         .await
-        .map(|(x, y)| (x, y, semaphore))?;
+        .map(|(x, y, z)| (x, y, z, semaphore))?;
 
     let mut response = Json(&response).into_response();
 
     let headers = response.headers_mut();
 
+    if let Some(response_block) = response_block {
+        headers.insert(
+            "X-Block-Number",
+            response_block
+                .to_string()
+                .parse()
+                .expect("X-Block-Number should always parse"),
+        );
+    }
+
     // TODO: this might be slow. think about this more
     // TODO: special string if no rpcs were used (cache hit)?
     let mut backup_used = false;
@@ -116,6 +210,7 @@ async fn _proxy_web3_rpc(
 /// Can optionally authorized based on origin, referer, or user agent.
 /// If possible, please use a WebSocket instead.
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn proxy_web3_rpc_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
@@ -123,6 +218,7 @@ pub async fn proxy_web3_rpc_with_key(
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
     Path(rpc_key): Path<String>,
+    block_session_id: BlockSessionId,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> FrontendResult {
     _proxy_web3_rpc_with_key(
@@ -133,12 +229,14 @@ pub async fn proxy_web3_rpc_with_key(
         user_agent,
         rpc_key,
         payload,
+        block_session_id,
         ProxyMode::Best,
     )
     .await
 }
 
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn debug_proxy_web3_rpc_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
@@ -146,6 +244,7 @@ pub async fn debug_proxy_web3_rpc_with_key(
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
     Path(rpc_key): Path<String>,
+    block_session_id: BlockSessionId,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> FrontendResult {
     _proxy_web3_rpc_with_key(
@@ -156,12 +255,14 @@ pub async fn debug_proxy_web3_rpc_with_key(
         user_agent,
         rpc_key,
         payload,
+        block_session_id,
         ProxyMode::Debug,
     )
     .await
 }
 
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn fastest_proxy_web3_rpc_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
@@ -169,6 +270,7 @@ pub async fn fastest_proxy_web3_rpc_with_key(
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
     Path(rpc_key): Path<String>,
+    block_session_id: BlockSessionId,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> FrontendResult {
     _proxy_web3_rpc_with_key(
@@ -179,12 +281,14 @@ pub async fn fastest_proxy_web3_rpc_with_key(
         user_agent,
         rpc_key,
         payload,
+        block_session_id,
         ProxyMode::Fastest(0),
     )
     .await
 }
 
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn versus_proxy_web3_rpc_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
@@ -192,6 +296,7 @@ pub async fn versus_proxy_web3_rpc_with_key(
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
     Path(rpc_key): Path<String>,
+    block_session_id: BlockSessionId,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> FrontendResult {
     _proxy_web3_rpc_with_key(
@@ -202,11 +307,43 @@ pub async fn versus_proxy_web3_rpc_with_key(
         user_agent,
         rpc_key,
         payload,
+        block_session_id,
         ProxyMode::Versus,
     )
     .await
 }
 
+/// POST /quorum/:rpc_key -- send every request to `n` synced servers (default 3).
+/// A response is only returned if a majority of the servers agree on it.
+#[debug_handler]
+#[allow(clippy::too_many_arguments)]
+pub async fn quorum_proxy_web3_rpc_with_key(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    ip: InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    Path(rpc_key): Path<String>,
+    Query(query): Query<QuorumQuery>,
+    block_session_id: BlockSessionId,
+    Json(payload): Json<JsonRpcRequestEnum>,
+) -> FrontendResult {
+    let proxy_mode = query.proxy_mode(app.balanced_rpcs.len())?;
+
+    _proxy_web3_rpc_with_key(
+        app,
+        ip,
+        origin,
+        referer,
+        user_agent,
+        rpc_key,
+        payload,
+        block_session_id,
+        proxy_mode,
+    )
+    .await
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn _proxy_web3_rpc_with_key(
     app: Arc<Web3ProxyApp>,
@@ -216,6 +353,7 @@ async fn _proxy_web3_rpc_with_key(
     user_agent: Option<TypedHeader<UserAgent>>,
     rpc_key: String,
     payload: JsonRpcRequestEnum,
+    block_session_id: BlockSessionId,
     proxy_mode: ProxyMode,
 ) -> FrontendResult {
     // TODO: DRY w/ proxy_web3_rpc
@@ -237,15 +375,30 @@ async fn _proxy_web3_rpc_with_key(
 
     let rpc_secret_key_id = authorization.checks.rpc_secret_key_id;
 
-    let (response, rpcs, _semaphore) = app
-        .proxy_web3_rpc(authorization, payload)
+    let block_session = match block_session_id.0 {
+        Some(session_id) => Some(app.block_session(&authorization, &session_id).await),
+        None => None,
+    };
+
+    let (response, rpcs, response_block, _semaphore) = app
+        .proxy_web3_rpc(authorization, payload, block_session.as_deref())
         .await
-        .map(|(x, y)| (x, y, semaphore))?;
+        .map(|(x, y, z)| (x, y, z, semaphore))?;
 
     let mut response = Json(&response).into_response();
 
     let headers = response.headers_mut();
 
+    if let Some(response_block) = response_block {
+        headers.insert(
+            "X-Block-Number",
+            response_block
+                .to_string()
+                .parse()
+                .expect("X-Block-Number should always parse"),
+        );
+    }
+
     let mut backup_used = false;
 
     // TODO: special string if no rpcs were used (cache hit)? or is an empty string fine? maybe the rpc name + "cached"
@@ -2,18 +2,23 @@
 //!
 //! WebSockets are the preferred method of receiving requests, but not all clients have good support.
 
-use super::authorization::{ip_is_authorized, key_is_authorized, Authorization, RequestMetadata};
+use super::authorization::{
+    jwt_or_ip_is_authorized, key_is_authorized, Authorization, RequestMetadata,
+};
 use super::errors::{FrontendErrorResponse, FrontendResult};
-use crate::app::REQUEST_PERIOD;
+use crate::app::{BlockSession, ShutdownPhase, REQUEST_PERIOD};
 use crate::app_stats::ProxyResponseStat;
+use crate::jwt::RpcClaims;
 use crate::{
     app::Web3ProxyApp,
     jsonrpc::{JsonRpcForwardedResponse, JsonRpcForwardedResponseEnum, JsonRpcRequest},
 };
-use axum::headers::{Origin, Referer, UserAgent};
+use axum::headers::authorization::Bearer;
+use axum::headers::{Authorization as AuthorizationHeader, Origin, Referer, UserAgent};
 use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    extract::Path,
+    async_trait,
+    extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    extract::{FromRequestParts, Path, Query},
     response::{IntoResponse, Redirect},
     Extension, TypedHeader,
 };
@@ -26,15 +31,19 @@ use futures::{
 };
 use handlebars::Handlebars;
 use hashbrown::HashMap;
+use http::request::Parts;
 use http::StatusCode;
 use log::{error, info, trace, warn};
+use serde::Deserialize;
 use serde_json::json;
 use serde_json::value::to_raw_value;
+use std::convert::Infallible;
+use std::str::from_utf8_mut;
+use std::sync::atomic::{self, AtomicUsize};
 use std::sync::Arc;
-use std::{str::from_utf8_mut, sync::atomic::AtomicUsize};
 use tokio::sync::{broadcast, OwnedSemaphorePermit, RwLock};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ProxyMode {
     /// send to the "best" synced server
     Best,
@@ -44,6 +53,8 @@ pub enum ProxyMode {
     Versus,
     /// send all requests and responses to kafka
     Debug,
+    /// send to this many synced servers at the same block. only return a response if most of them agree
+    Quorum(usize),
 }
 
 impl Default for ProxyMode {
@@ -52,64 +63,192 @@ impl Default for ProxyMode {
     }
 }
 
+/// Opt in to monotonic reads with the `X-W3P-Session` header or the `session` url param.
+/// Requests with the same session id never get a response from an older block than a previous response.
+/// On a websocket, the whole connection shares one session.
+#[derive(Debug)]
+pub struct BlockSessionId(pub Option<String>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for BlockSessionId
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let session_id = parts
+            .headers
+            .get("X-W3P-Session")
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.to_string())
+            .or_else(|| {
+                let query = parts.uri.query()?;
+
+                url::form_urlencoded::parse(query.as_bytes())
+                    .find(|(k, _)| k == "session")
+                    .map(|(_, v)| v.into_owned())
+            })
+            .filter(|x| !x.is_empty());
+
+        Ok(Self(session_id))
+    }
+}
+
+/// Query params for the quorum endpoints
+#[derive(Debug, Deserialize)]
+pub struct QuorumQuery {
+    /// how many servers to ask. defaults to 3
+    n: Option<usize>,
+}
+
+impl QuorumQuery {
+    /// `max` is the number of servers that could possibly answer. asking for more than that is an error
+    pub fn proxy_mode(&self, max: usize) -> Result<ProxyMode, FrontendErrorResponse> {
+        let n = match self.n {
+            None => 3.min(max),
+            Some(n) if n > max => {
+                return Err(FrontendErrorResponse::BadRequest(format!(
+                    "n must be at most {}",
+                    max
+                )));
+            }
+            Some(n) => n,
+        };
+
+        Ok(ProxyMode::Quorum(n.max(1)))
+    }
+}
+
 /// Public entrypoint for WebSocket JSON-RPC requests.
 /// Queries a single server at a time
+/// Defaults to rate limiting by IP address, but can also read the Authorization header for a jwt signed by one of an rpc key's public keys.
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn websocket_handler(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
     origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    bearer: Option<TypedHeader<AuthorizationHeader<Bearer>>>,
+    block_session_id: BlockSessionId,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> FrontendResult {
-    _websocket_handler(ProxyMode::Best, app, ip, origin, ws_upgrade).await
+    _websocket_handler(
+        ProxyMode::Best,
+        app,
+        ip,
+        origin,
+        referer,
+        user_agent,
+        bearer,
+        block_session_id,
+        ws_upgrade,
+    )
+    .await
 }
 
 /// Public entrypoint for WebSocket JSON-RPC requests that uses all synced servers.
 /// Queries all synced backends with every request! This might get expensive!
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn fastest_websocket_handler(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
     origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    bearer: Option<TypedHeader<AuthorizationHeader<Bearer>>>,
+    block_session_id: BlockSessionId,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> FrontendResult {
     // TODO: get the fastest number from the url params (default to 0/all)
     // TODO: config to disable this
-    _websocket_handler(ProxyMode::Fastest(0), app, ip, origin, ws_upgrade).await
+    _websocket_handler(
+        ProxyMode::Fastest(0),
+        app,
+        ip,
+        origin,
+        referer,
+        user_agent,
+        bearer,
+        block_session_id,
+        ws_upgrade,
+    )
+    .await
 }
 
 /// Public entrypoint for WebSocket JSON-RPC requests that uses all synced servers.
 /// Queries **all** backends with every request! This might get expensive!
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn versus_websocket_handler(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
     origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    bearer: Option<TypedHeader<AuthorizationHeader<Bearer>>>,
+    block_session_id: BlockSessionId,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> FrontendResult {
     // TODO: config to disable this
-    _websocket_handler(ProxyMode::Versus, app, ip, origin, ws_upgrade).await
+    _websocket_handler(
+        ProxyMode::Versus,
+        app,
+        ip,
+        origin,
+        referer,
+        user_agent,
+        bearer,
+        block_session_id,
+        ws_upgrade,
+    )
+    .await
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn _websocket_handler(
     proxy_mode: ProxyMode,
     app: Arc<Web3ProxyApp>,
     InsecureClientIp(ip): InsecureClientIp,
     origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    bearer: Option<TypedHeader<AuthorizationHeader<Bearer>>>,
+    block_session_id: BlockSessionId,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> FrontendResult {
-    let origin = origin.map(|x| x.0);
+    let (authorization, _semaphore, claims) = jwt_or_ip_is_authorized(
+        &app,
+        bearer.map(|TypedHeader(AuthorizationHeader(x))| x),
+        ip,
+        origin.map(|x| x.0),
+        proxy_mode,
+        referer.map(|x| x.0),
+        user_agent.map(|x| x.0),
+    )
+    .await?;
 
-    let (authorization, _semaphore) = ip_is_authorized(&app, ip, origin, proxy_mode).await?;
+    // the jwt's claims are checked against every request on the socket
+    let claims = claims.map(Arc::new);
+
+    let block_session = match block_session_id.0 {
+        Some(session_id) => Some(app.block_session(&authorization, &session_id).await),
+        None => None,
+    };
 
     let authorization = Arc::new(authorization);
 
     match ws_upgrade {
         Some(ws) => Ok(ws
-            .on_upgrade(move |socket| proxy_web3_socket(app, authorization, socket))
+            .on_upgrade(move |socket| {
+                proxy_web3_socket(app, authorization, claims, block_session, socket)
+            })
             .into_response()),
         None => {
-            if let Some(redirect) = &app.config.redirect_public_url {
+            if let Some(redirect) = &app.config().redirect_public_url {
                 // this is not a websocket. redirect to a friendly page
                 Ok(Redirect::permanent(redirect).into_response())
             } else {
@@ -127,6 +266,7 @@ async fn _websocket_handler(
 /// Rate limit and billing based on the api key in the url.
 /// Can optionally authorized based on origin, referer, or user agent.
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn websocket_handler_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
@@ -134,6 +274,7 @@ pub async fn websocket_handler_with_key(
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    block_session_id: BlockSessionId,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> FrontendResult {
     _websocket_handler_with_key(
@@ -144,12 +285,14 @@ pub async fn websocket_handler_with_key(
         origin,
         referer,
         user_agent,
+        block_session_id,
         ws_upgrade,
     )
     .await
 }
 
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn debug_websocket_handler_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
@@ -157,6 +300,7 @@ pub async fn debug_websocket_handler_with_key(
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    block_session_id: BlockSessionId,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> FrontendResult {
     _websocket_handler_with_key(
@@ -167,12 +311,14 @@ pub async fn debug_websocket_handler_with_key(
         origin,
         referer,
         user_agent,
+        block_session_id,
         ws_upgrade,
     )
     .await
 }
 
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn fastest_websocket_handler_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
@@ -180,6 +326,7 @@ pub async fn fastest_websocket_handler_with_key(
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    block_session_id: BlockSessionId,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> FrontendResult {
     // TODO: get the fastest number from the url params (default to 0/all)
@@ -191,12 +338,14 @@ pub async fn fastest_websocket_handler_with_key(
         origin,
         referer,
         user_agent,
+        block_session_id,
         ws_upgrade,
     )
     .await
 }
 
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn versus_websocket_handler_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
@@ -204,6 +353,7 @@ pub async fn versus_websocket_handler_with_key(
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    block_session_id: BlockSessionId,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> FrontendResult {
     _websocket_handler_with_key(
@@ -214,6 +364,38 @@ pub async fn versus_websocket_handler_with_key(
         origin,
         referer,
         user_agent,
+        block_session_id,
+        ws_upgrade,
+    )
+    .await
+}
+
+/// Authenticated entrypoint for WebSocket JSON-RPC requests that need multiple servers to agree.
+/// Queries `n` synced backends with every request.
+#[debug_handler]
+#[allow(clippy::too_many_arguments)]
+pub async fn quorum_websocket_handler_with_key(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    ip: InsecureClientIp,
+    Path(rpc_key): Path<String>,
+    Query(query): Query<QuorumQuery>,
+    origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    block_session_id: BlockSessionId,
+    ws_upgrade: Option<WebSocketUpgrade>,
+) -> FrontendResult {
+    let proxy_mode = query.proxy_mode(app.balanced_rpcs.len())?;
+
+    _websocket_handler_with_key(
+        proxy_mode,
+        app,
+        ip,
+        rpc_key,
+        origin,
+        referer,
+        user_agent,
+        block_session_id,
         ws_upgrade,
     )
     .await
@@ -228,6 +410,7 @@ async fn _websocket_handler_with_key(
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    block_session_id: BlockSessionId,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> FrontendResult {
     let rpc_key = rpc_key.parse()?;
@@ -245,20 +428,25 @@ async fn _websocket_handler_with_key(
 
     trace!("websocket_handler_with_key {:?}", authorization);
 
+    let block_session = match block_session_id.0 {
+        Some(session_id) => Some(app.block_session(&authorization, &session_id).await),
+        None => None,
+    };
+
     let authorization = Arc::new(authorization);
 
     match ws_upgrade {
-        Some(ws_upgrade) => {
-            Ok(ws_upgrade.on_upgrade(move |socket| proxy_web3_socket(app, authorization, socket)))
-        }
+        Some(ws_upgrade) => Ok(ws_upgrade.on_upgrade(move |socket| {
+            proxy_web3_socket(app, authorization, None, block_session, socket)
+        })),
         None => {
             // if no websocket upgrade, this is probably a user loading the url with their browser
 
             // TODO: rate limit here? key_is_authorized might be enough
 
             match (
-                &app.config.redirect_public_url,
-                &app.config.redirect_rpc_key_url,
+                &app.config().redirect_public_url,
+                &app.config().redirect_rpc_key_url,
                 authorization.checks.rpc_secret_key_id,
             ) {
                 (None, None, _) => Err(FrontendErrorResponse::StatusCode(
@@ -305,6 +493,8 @@ async fn _websocket_handler_with_key(
 async fn proxy_web3_socket(
     app: Arc<Web3ProxyApp>,
     authorization: Arc<Authorization>,
+    claims: Option<Arc<RpcClaims>>,
+    block_session: Option<Arc<BlockSession>>,
     socket: WebSocket,
 ) {
     // split the websocket so we can read and write concurrently
@@ -313,14 +503,26 @@ async fn proxy_web3_socket(
     // create a channel for our reader and writer can communicate. todo: benchmark different channels
     let (response_sender, response_receiver) = flume::unbounded::<Message>();
 
-    tokio::spawn(write_web3_socket(response_receiver, ws_tx));
-    tokio::spawn(read_web3_socket(app, authorization, ws_rx, response_sender));
+    app.open_websockets.fetch_add(1, atomic::Ordering::AcqRel);
+
+    tokio::spawn(write_web3_socket(app.clone(), response_receiver, ws_tx));
+    tokio::spawn(read_web3_socket(
+        app,
+        authorization,
+        claims,
+        block_session,
+        ws_rx,
+        response_sender,
+    ));
 }
 
 /// websockets support a few more methods than http clients
+#[allow(clippy::too_many_arguments)]
 async fn handle_socket_payload(
     app: Arc<Web3ProxyApp>,
     authorization: &Arc<Authorization>,
+    claims: Option<&RpcClaims>,
+    block_session: Option<&BlockSession>,
     payload: &str,
     response_sender: &flume::Sender<Message>,
     subscription_count: &AtomicUsize,
@@ -345,6 +547,12 @@ async fn handle_socket_payload(
             let response: anyhow::Result<JsonRpcForwardedResponseEnum> = match &json_request.method
                 [..]
             {
+                method if claims.map_or(false, |x| !x.allows_method(method)) => {
+                    Err(anyhow::anyhow!("this jwt does not allow {}", method))
+                }
+                "eth_subscribe" if app.shutdown_phase() >= ShutdownPhase::ClosingWebsockets => Err(
+                    anyhow::anyhow!("server is shutting down. subscribe on another server"),
+                ),
                 "eth_subscribe" => {
                     // TODO: how can we subscribe with proxy_mode?
                     match app
@@ -417,7 +625,7 @@ async fn handle_socket_payload(
                     Ok(response.into())
                 }
                 _ => app
-                    .proxy_web3_rpc(authorization.clone(), json_request.into())
+                    .proxy_web3_rpc(authorization.clone(), json_request.into(), block_session)
                     .await
                     .map_or_else(
                         |err| match err {
@@ -427,7 +635,7 @@ async fn handle_socket_payload(
                                 Err(anyhow::anyhow!("unexpected error! {:?}", err))
                             }
                         },
-                        |(response, _)| Ok(response),
+                        |(response, _, _)| Ok(response),
                     ),
             };
 
@@ -457,6 +665,8 @@ async fn handle_socket_payload(
 async fn read_web3_socket(
     app: Arc<Web3ProxyApp>,
     authorization: Arc<Authorization>,
+    claims: Option<Arc<RpcClaims>>,
+    block_session: Option<Arc<BlockSession>>,
     mut ws_rx: SplitStream<WebSocket>,
     response_sender: flume::Sender<Message>,
 ) {
@@ -466,6 +676,9 @@ async fn read_web3_socket(
 
     let (close_sender, mut close_receiver) = broadcast::channel(1);
 
+    let mut shutdown_phase_receiver = app.shutdown_phase_receiver();
+    let mut subscriptions_ended = false;
+
     loop {
         tokio::select! {
             msg = ws_rx.next() => {
@@ -475,6 +688,8 @@ async fn read_web3_socket(
                     let close_sender = close_sender.clone();
                     let app = app.clone();
                     let authorization = authorization.clone();
+                    let claims = claims.clone();
+                    let block_session = block_session.clone();
                     let response_sender = response_sender.clone();
                     let subscriptions = subscriptions.clone();
                     let subscription_count = subscription_count.clone();
@@ -488,6 +703,8 @@ async fn read_web3_socket(
                                 let (msg, s) = handle_socket_payload(
                                     app.clone(),
                                     &authorization,
+                                    claims.as_deref(),
+                                    block_session.as_deref(),
                                     &payload,
                                     &response_sender,
                                     &subscription_count,
@@ -519,6 +736,8 @@ async fn read_web3_socket(
                                 let (msg, s) = handle_socket_payload(
                                     app.clone(),
                                     &authorization,
+                                    claims.as_deref(),
+                                    block_session.as_deref(),
                                     payload,
                                     &response_sender,
                                     &subscription_count,
@@ -548,16 +767,76 @@ async fn read_web3_socket(
             _ = close_receiver.recv() => {
                 break;
             }
+            _ = shutdown_phase_receiver.changed() => {
+                let phase = *shutdown_phase_receiver.borrow_and_update();
+
+                if phase >= ShutdownPhase::ClosingWebsockets && !subscriptions_ended {
+                    subscriptions_ended = true;
+
+                    end_subscriptions(&subscriptions, &response_sender).await;
+                }
+
+                if phase >= ShutdownPhase::Closed {
+                    let close = Message::Close(Some(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "shutting down".into(),
+                    }));
+
+                    let _ = response_sender.send_async(close).await;
+
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Stop all of a client's subscriptions and tell them so they can resubscribe on another server.
+/// There isn't a standard for this, so the notification looks like an `eth_subscription` with an error instead of a result.
+async fn end_subscriptions(
+    subscriptions: &RwLock<HashMap<String, AbortHandle>>,
+    response_sender: &flume::Sender<Message>,
+) {
+    let mut x = subscriptions.write().await;
+
+    for (subscription_id, handle) in x.drain() {
+        handle.abort();
+
+        // the keys are the json of the subscription ids
+        let subscription_id = serde_json::from_str::<serde_json::Value>(&subscription_id)
+            .unwrap_or_else(|_| json!(subscription_id));
+
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "eth_subscription",
+            "params": {
+                "subscription": subscription_id,
+                "error": {
+                    "code": -32000,
+                    "message": "server is shutting down. subscribe on another server",
+                },
+            },
+        });
+
+        let notification =
+            serde_json::to_string(&notification).expect("to_string should always work here");
+
+        if response_sender
+            .send_async(Message::Text(notification))
+            .await
+            .is_err()
+        {
+            // the client is already gone
+            break;
         }
     }
 }
 
 async fn write_web3_socket(
+    app: Arc<Web3ProxyApp>,
     response_rx: flume::Receiver<Message>,
     mut ws_tx: SplitSink<WebSocket, Message>,
 ) {
-    // TODO: increment counter for open websockets
-
     // TODO: is there any way to make this stream receive.
     while let Ok(msg) = response_rx.recv_async().await {
         // a response is ready
@@ -572,5 +851,38 @@ async fn write_web3_socket(
         };
     }
 
-    // TODO: decrement counter for open websockets
+    app.open_websockets.fetch_sub(1, atomic::Ordering::AcqRel);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quorum_n() {
+        assert_eq!(
+            QuorumQuery { n: None }.proxy_mode(5).unwrap(),
+            ProxyMode::Quorum(3)
+        );
+        assert_eq!(
+            QuorumQuery { n: None }.proxy_mode(2).unwrap(),
+            ProxyMode::Quorum(2)
+        );
+        assert_eq!(
+            QuorumQuery { n: Some(0) }.proxy_mode(5).unwrap(),
+            ProxyMode::Quorum(1)
+        );
+        assert_eq!(
+            QuorumQuery { n: Some(5) }.proxy_mode(5).unwrap(),
+            ProxyMode::Quorum(5)
+        );
+
+        assert!(matches!(
+            QuorumQuery {
+                n: Some(usize::MAX)
+            }
+            .proxy_mode(5),
+            Err(FrontendErrorResponse::BadRequest(_))
+        ));
+    }
 }
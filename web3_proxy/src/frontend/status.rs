@@ -4,7 +4,7 @@
 //! They will eventually move to another port.
 
 use super::{FrontendHealthCache, FrontendResponseCache, FrontendResponseCaches};
-use crate::app::{Web3ProxyApp, APP_USER_AGENT};
+use crate::app::{ShutdownPhase, Web3ProxyApp, APP_USER_AGENT};
 use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
 use axum_macros::debug_handler;
 use serde_json::json;
@@ -16,6 +16,11 @@ pub async fn health(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     Extension(health_cache): Extension<FrontendHealthCache>,
 ) -> impl IntoResponse {
+    if app.shutdown_phase() != ShutdownPhase::Running {
+        // let the load balancer move traffic to other proxies before we stop accepting connections
+        return (StatusCode::SERVICE_UNAVAILABLE, "shutting down");
+    }
+
     let synced = health_cache
         .get_with((), async { app.balanced_rpcs.synced() })
         .await;
@@ -40,7 +45,7 @@ pub async fn status(
             // TODO: what else should we include? uptime, cache hit rates, cpu load, memory used
             let body = json!({
                 "version": APP_USER_AGENT,
-                "chain_id": app.config.chain_id,
+                "chain_id": app.config().chain_id,
                 "balanced_rpcs": app.balanced_rpcs,
                 "private_rpcs": app.private_rpcs,
             });
@@ -0,0 +1,438 @@
+//! Share an account with other users.
+//!
+//! The account's user invites members by address. Each member has a role:
+//!   - collaborators can view stats, keys, and revert logs
+//!   - admins can also create and modify keys
+//!   - owners can also manage billing and members
+//!
+//! Members pass `?user_id=$x` to act on the account instead of their own.
+//! Every change to an account's keys, billing, or members is saved in its `team_trail`.
+use super::errors::{FrontendErrorResponse, FrontendResult};
+use crate::app::Web3ProxyApp;
+use crate::user_queries::get_page_from_params;
+use anyhow::Context;
+use axum::{
+    extract::{Path, Query},
+    headers::{authorization::Bearer, Authorization},
+    response::IntoResponse,
+    Extension, Json, TypedHeader,
+};
+use axum_macros::debug_handler;
+use chrono::Utc;
+use entities::sea_orm_active_enums::Role;
+use entities::{secondary_user, team_trail, user};
+use ethers::prelude::Address;
+use hashbrown::HashMap;
+use migration::sea_orm::{
+    self, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel,
+    PaginatorTrait, QueryFilter, QueryOrder,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+/// `?user_id=$x` to act on another user's account. Leave it out for the caller's own account
+#[derive(Debug, Default, Deserialize)]
+pub struct TeamQuery {
+    pub user_id: Option<u64>,
+}
+
+/// the JSON input to the `members_post` handler.
+/// Inviting an existing member changes their role and description.
+#[derive(Debug, Deserialize)]
+pub struct TeamMemberInvite {
+    user_id: Option<u64>,
+    address: Address,
+    role: Role,
+    description: Option<String>,
+}
+
+/// Does `have` include everything that `need` can do?
+pub fn role_allows(have: &Role, need: &Role) -> bool {
+    fn rank(role: &Role) -> u8 {
+        match role {
+            Role::Owner => 2,
+            Role::Admin => 1,
+            Role::Collaborator => 0,
+        }
+    }
+
+    rank(have) >= rank(need)
+}
+
+/// The role that `member_user_id` has on `user_id`'s account. Invitations that are not accepted yet don't count.
+pub async fn team_role(
+    db_conn: &DatabaseConnection,
+    user_id: u64,
+    member_user_id: u64,
+) -> anyhow::Result<Option<Role>> {
+    let x = secondary_user::Entity::find()
+        .filter(secondary_user::Column::UserId.eq(user_id))
+        .filter(secondary_user::Column::MemberUserId.eq(member_user_id))
+        .filter(secondary_user::Column::AcceptedAt.is_not_null())
+        .one(db_conn)
+        .await
+        .context("fetching team role")?
+        .map(|x| x.role);
+
+    Ok(x)
+}
+
+/// Note that `caller` changed something on `user_id`'s account.
+/// The account's own changes are saved too so that owners can see everything in one place.
+pub async fn save_team_trail(
+    db_conn: &DatabaseConnection,
+    user_id: u64,
+    caller: u64,
+    endpoint: &str,
+    payload: String,
+) -> anyhow::Result<()> {
+    team_trail::ActiveModel {
+        user_id: sea_orm::Set(user_id.into()),
+        caller: sea_orm::Set(caller.into()),
+        endpoint: sea_orm::Set(endpoint.to_string()),
+        payload: sea_orm::Set(payload),
+        ..Default::default()
+    }
+    .save(db_conn)
+    .await
+    .context("saving team trail")?;
+
+    Ok(())
+}
+
+/// `GET /user/members` -- Use a bearer token to get the members of an account and their invitations.
+#[debug_handler]
+pub async fn members_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(query): Query<TeamQuery>,
+) -> FrontendResult {
+    let (_caller, user_id, _semaphore) = app
+        .bearer_is_authorized_for(bearer, query.user_id, Role::Collaborator)
+        .await?;
+
+    let db_replica = app
+        .db_replica()
+        .context("getting db to fetch account's members")?;
+
+    let members = secondary_user::Entity::find()
+        .filter(secondary_user::Column::UserId.eq(user_id))
+        .order_by_asc(secondary_user::Column::Id)
+        .all(db_replica.conn())
+        .await
+        .context("failed loading account's members")?;
+
+    let member_users: HashMap<_, _> = user::Entity::find()
+        .filter(user::Column::Id.is_in(members.iter().map(|x| x.member_user_id)))
+        .all(db_replica.conn())
+        .await
+        .context("failed loading members' users")?
+        .into_iter()
+        .map(|x| (x.id, x))
+        .collect();
+
+    let members: Vec<_> = members
+        .into_iter()
+        .map(|x| {
+            let member_user = member_users.get(&x.member_user_id);
+
+            json!({
+                "member": x,
+                "user": member_user,
+            })
+        })
+        .collect();
+
+    let response_json = json!({
+        "user_id": user_id,
+        "members": members,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `POST /user/members` -- Use a bearer token to invite a user to an account or to change a member's role.
+///
+/// The invited address must have logged in at least once. Invitations do nothing until they are accepted.
+#[debug_handler]
+pub async fn members_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<TeamMemberInvite>,
+) -> FrontendResult {
+    let (caller, user_id, _semaphore) = app
+        .bearer_is_authorized_for(bearer, payload.user_id, Role::Owner)
+        .await?;
+
+    let db_conn = app.db_conn().context("inviting a member requires a db")?;
+
+    let member_user = user::Entity::find()
+        .filter(user::Column::Address.eq(payload.address.as_bytes().to_vec()))
+        .one(&db_conn)
+        .await
+        .context("failed loading invited user")?
+        .ok_or_else(|| {
+            FrontendErrorResponse::BadRequest(
+                "the invited address needs to log in once first".to_string(),
+            )
+        })?;
+
+    if member_user.id == user_id {
+        return Err(FrontendErrorResponse::BadRequest(
+            "users can't be members of their own account".to_string(),
+        ));
+    }
+
+    let existing = secondary_user::Entity::find()
+        .filter(secondary_user::Column::UserId.eq(user_id))
+        .filter(secondary_user::Column::MemberUserId.eq(member_user.id))
+        .one(&db_conn)
+        .await
+        .context("failed loading existing member")?;
+
+    let member = match existing {
+        Some(x) => {
+            let mut x = x.into_active_model();
+
+            x.role = sea_orm::Set(payload.role.clone());
+            x.description = sea_orm::Set(payload.description.clone());
+
+            x.update(&db_conn).await.context("Failed updating member")?
+        }
+        None => secondary_user::ActiveModel {
+            user_id: sea_orm::Set(user_id.into()),
+            member_user_id: sea_orm::Set(member_user.id),
+            role: sea_orm::Set(payload.role.clone()),
+            description: sea_orm::Set(payload.description.clone()),
+            ..Default::default()
+        }
+        .insert(&db_conn)
+        .await
+        .context("Failed saving invitation")?,
+    };
+
+    save_team_trail(
+        &db_conn,
+        user_id,
+        caller.id.into(),
+        "members_post",
+        format!("{:?}", payload),
+    )
+    .await?;
+
+    Ok(Json(member).into_response())
+}
+
+/// `DELETE /user/members/:member_id` -- Use a bearer token to remove a member or cancel their invitation.
+/// `member_id` is the member's user id.
+#[debug_handler]
+pub async fn member_delete(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(member_id): Path<u64>,
+    Query(query): Query<TeamQuery>,
+) -> FrontendResult {
+    let (caller, user_id, _semaphore) = app
+        .bearer_is_authorized_for(bearer, query.user_id, Role::Owner)
+        .await?;
+
+    let db_conn = app.db_conn().context("removing a member requires a db")?;
+
+    let member = secondary_user::Entity::find()
+        .filter(secondary_user::Column::UserId.eq(user_id))
+        .filter(secondary_user::Column::MemberUserId.eq(member_id))
+        .one(&db_conn)
+        .await
+        .context("failed loading member")?
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    secondary_user::Entity::delete_by_id(member.id)
+        .exec(&db_conn)
+        .await
+        .context("Failed deleting member")?;
+
+    save_team_trail(
+        &db_conn,
+        user_id,
+        caller.id.into(),
+        "member_delete",
+        format!("{:?}", member),
+    )
+    .await?;
+
+    Ok(Json(json!({ "deleted": member })).into_response())
+}
+
+/// `GET /user/members/trail` -- Use a bearer token to see what members have changed on an account.
+#[debug_handler]
+pub async fn members_trail_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> FrontendResult {
+    let user_id = params
+        .get("user_id")
+        .map(|x| x.parse::<u64>())
+        .transpose()
+        .context("Parsing user_id param")?;
+
+    let (_caller, user_id, _semaphore) = app
+        .bearer_is_authorized_for(bearer, user_id, Role::Owner)
+        .await?;
+
+    let page = get_page_from_params(&params)?;
+
+    // TODO: page size from config
+    let page_size = 1_000;
+
+    let db_replica = app
+        .db_replica()
+        .context("getting replica db for account's team trail")?;
+
+    let q = team_trail::Entity::find()
+        .filter(team_trail::Column::UserId.eq(user_id))
+        .order_by_desc(team_trail::Column::Id);
+
+    let pages_result = q
+        .clone()
+        .paginate(db_replica.conn(), page_size)
+        .num_items_and_pages()
+        .await?;
+
+    let trail = q
+        .paginate(db_replica.conn(), page_size)
+        .fetch_page(page)
+        .await?;
+
+    let response_json = json!({
+        "user_id": user_id,
+        "page": page,
+        "page_size": page_size,
+        "num_items": pages_result.number_of_items,
+        "num_pages": pages_result.number_of_pages,
+        "team_trail": trail,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `GET /user/invitations` -- Use a bearer token to get the accounts that the user is a member of or is invited to.
+#[debug_handler]
+pub async fn invitations_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> FrontendResult {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = app
+        .db_replica()
+        .context("getting db to fetch user's invitations")?;
+
+    let invitations: Vec<_> = secondary_user::Entity::find()
+        .filter(secondary_user::Column::MemberUserId.eq(user.id))
+        .find_also_related(user::Entity)
+        .order_by_asc(secondary_user::Column::Id)
+        .all(db_replica.conn())
+        .await
+        .context("failed loading user's invitations")?
+        .into_iter()
+        .map(|(invitation, account)| {
+            json!({
+                "invitation": invitation,
+                "account": account,
+            })
+        })
+        .collect();
+
+    let response_json = json!({
+        "user_id": user.id,
+        "invitations": invitations,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `POST /user/invitations/:id` -- Use a bearer token to accept an invitation.
+#[debug_handler]
+pub async fn invitation_accept_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(id): Path<u64>,
+) -> FrontendResult {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app
+        .db_conn()
+        .context("accepting an invitation requires a db")?;
+
+    let invitation = secondary_user::Entity::find_by_id(id)
+        .filter(secondary_user::Column::MemberUserId.eq(user.id))
+        .one(&db_conn)
+        .await
+        .context("failed loading invitation")?
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    let invitation = if invitation.accepted_at.is_some() {
+        invitation
+    } else {
+        let mut x = invitation.into_active_model();
+
+        x.accepted_at = sea_orm::Set(Some(Utc::now()));
+
+        let x = x
+            .update(&db_conn)
+            .await
+            .context("Failed accepting invitation")?;
+
+        save_team_trail(
+            &db_conn,
+            x.user_id.into(),
+            user.id.into(),
+            "invitation_accept_post",
+            format!("{:?}", x),
+        )
+        .await?;
+
+        x
+    };
+
+    Ok(Json(invitation).into_response())
+}
+
+/// `DELETE /user/invitations/:id` -- Use a bearer token to decline an invitation or to leave an account.
+#[debug_handler]
+pub async fn invitation_delete(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(id): Path<u64>,
+) -> FrontendResult {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app
+        .db_conn()
+        .context("declining an invitation requires a db")?;
+
+    let invitation = secondary_user::Entity::find_by_id(id)
+        .filter(secondary_user::Column::MemberUserId.eq(user.id))
+        .one(&db_conn)
+        .await
+        .context("failed loading invitation")?
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    secondary_user::Entity::delete_by_id(invitation.id)
+        .exec(&db_conn)
+        .await
+        .context("Failed deleting invitation")?;
+
+    save_team_trail(
+        &db_conn,
+        invitation.user_id.into(),
+        user.id.into(),
+        "invitation_delete",
+        format!("{:?}", invitation),
+    )
+    .await?;
+
+    Ok(Json(json!({ "deleted": invitation })).into_response())
+}
@@ -1,12 +1,17 @@
 //! Handle registration, logins, and managing account data.
 use super::authorization::{login_is_authorized, RpcSecretKey};
-use super::errors::FrontendResult;
+use super::errors::{FrontendErrorResponse, FrontendResult};
+use super::teams::{save_team_trail, TeamQuery};
 use crate::app::Web3ProxyApp;
+use crate::billing::{get_user_balance, get_user_usage};
+use crate::email::{is_valid_email, verify_email, Notification};
+use crate::jwt::{self, LoginClaims};
+use crate::rpc_keys::{delete_rpc_key, rotate_rpc_key};
 use crate::user_queries::get_page_from_params;
 use crate::user_queries::{
     get_chain_id_from_params, get_query_start_from_params, query_user_stats, StatResponse,
 };
-use crate::user_token::UserBearerToken;
+use crate::user_token::{revoke_logins, UserBearerToken};
 use crate::{PostLogin, PostLoginQuery};
 use anyhow::Context;
 use axum::headers::{Header, Origin, Referer, UserAgent};
@@ -19,9 +24,12 @@ use axum::{
 use axum_client_ip::InsecureClientIp;
 use axum_macros::debug_handler;
 use chrono::{TimeZone, Utc};
-use entities::sea_orm_active_enums::LogLevel;
-use entities::{login, pending_login, revert_log, rpc_key, user};
-use ethers::{prelude::Address, types::Bytes};
+use entities::sea_orm_active_enums::{LogLevel, Role};
+use entities::{deposit, jwt_public_key, login, pending_login, revert_log, rpc_key, user};
+use ethers::{
+    prelude::{Address, TxHash},
+    types::Bytes,
+};
 use hashbrown::HashMap;
 use http::{HeaderValue, StatusCode};
 use ipnet::IpNet;
@@ -32,9 +40,10 @@ use migration::sea_orm::{
     self, ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, PaginatorTrait, QueryFilter,
     QueryOrder, TransactionTrait, TryIntoModel,
 };
+use migration::Condition;
 use serde::Deserialize;
 use serde_json::json;
-use siwe::{Message, VerificationOpts};
+use siwe::Message;
 use std::ops::Add;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -48,7 +57,7 @@ use ulid::Ulid;
 ///   - eip191_hash
 ///   - eip4361 (default)
 ///
-/// Coming soon: eip1271
+/// Smart contract wallets sign the eip4361 message. Their signatures are checked with eip1271 or eip6492.
 ///
 /// This is the initial entrypoint for logging in. Take the response from this endpoint and give it to your user's wallet for singing. POST the response to `/user/login`.
 ///
@@ -87,7 +96,7 @@ pub async fn user_login_get(
         .context("unable to parse address")?;
 
     let login_domain = app
-        .config
+        .config()
         .login_domain
         .clone()
         .unwrap_or_else(|| "llamanodes.com".to_string());
@@ -156,26 +165,21 @@ pub async fn user_login_get(
 }
 
 /// `POST /user/login` - Register or login by posting a signed "siwe" message.
+/// Contract wallet signatures are checked with an `eth_call` to the wallet.
 /// It is recommended to save the returned bearer token in a cookie.
 /// The bearer token can be used to authenticate other requests, such as getting the user's stats or modifying the user's profile.
 #[debug_handler]
 pub async fn user_login_post(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     InsecureClientIp(ip): InsecureClientIp,
+    user_agent: Option<TypedHeader<UserAgent>>,
     Query(query): Query<PostLoginQuery>,
     Json(payload): Json<PostLogin>,
 ) -> FrontendResult {
     login_is_authorized(&app, ip).await?;
 
-    // TODO: this seems too verbose. how can we simply convert a String into a [u8; 65]
-    let their_sig_bytes = Bytes::from_str(&payload.sig).context("parsing sig")?;
-    if their_sig_bytes.len() != 65 {
-        return Err(anyhow::anyhow!("checking signature length").into());
-    }
-    let mut their_sig: [u8; 65] = [0; 65];
-    for x in 0..65 {
-        their_sig[x] = their_sig_bytes[x]
-    }
+    // contract wallet signatures can be any length
+    let their_sig = Bytes::from_str(&payload.sig).context("parsing sig")?;
 
     // we can't trust that they didn't tamper with the message in some way. like some clients return it hex encoded
     // TODO: checking 0x seems fragile, but I think it will be fine. siwe message text shouldn't ever start with 0x
@@ -215,41 +219,25 @@ pub async fn user_login_post(
         .parse()
         .context("parsing siwe message")?;
 
-    // default options are fine. the message includes timestamp and domain and nonce
-    let verify_config = VerificationOpts::default();
+    // contract wallets are checked with an eth_call if the signature doesn't recover to their address
+    if let Err(err) = app.verify_siwe_signature(&our_msg, &their_sig).await {
+        let db_conn = app
+            .db_conn()
+            .context("deleting expired pending logins requires a db")?;
 
-    // Check with both verify and verify_eip191
-    if let Err(err_1) = our_msg
-        .verify(&their_sig, &verify_config)
-        .await
-        .context("verifying signature against our local message")
-    {
-        // verification method 1 failed. try eip191
-        if let Err(err_191) = our_msg
-            .verify_eip191(&their_sig)
-            .context("verifying eip191 signature against our local message")
-        {
-            let db_conn = app
-                .db_conn()
-                .context("deleting expired pending logins requires a db")?;
-
-            // delete ALL expired rows.
-            let now = Utc::now();
-            let delete_result = pending_login::Entity::delete_many()
-                .filter(pending_login::Column::ExpiresAt.lte(now))
-                .exec(&db_conn)
-                .await?;
-
-            // TODO: emit a stat? if this is high something weird might be happening
-            debug!("cleared expired pending_logins: {:?}", delete_result);
-
-            return Err(anyhow::anyhow!(
-                "both the primary and eip191 verification failed: {:#?}; {:#?}",
-                err_1,
-                err_191
-            )
+        // delete ALL expired rows.
+        let now = Utc::now();
+        let delete_result = pending_login::Entity::delete_many()
+            .filter(pending_login::Column::ExpiresAt.lte(now))
+            .exec(&db_conn)
+            .await?;
+
+        // TODO: emit a stat? if this is high something weird might be happening
+        debug!("cleared expired pending_logins: {:?}", delete_result);
+
+        return Err(err
+            .context("verifying signature against our local message")
             .into());
-        }
     }
 
     // TODO: limit columns or load whole user?
@@ -267,7 +255,7 @@ pub async fn user_login_post(
 
             // check the invite code
             // TODO: more advanced invite codes that set different request/minute and concurrency limits
-            if let Some(invite_code) = &app.config.invite_code {
+            if let Some(invite_code) = &app.config().invite_code {
                 if query.invite_code.as_ref() != Some(invite_code) {
                     return Err(anyhow::anyhow!("checking invite_code").into());
                 }
@@ -323,9 +311,13 @@ pub async fn user_login_post(
     // create a bearer token for the user.
     let user_bearer_token = UserBearerToken::default();
 
+    // expire in 4 weeks
+    let now = Utc::now();
+    let expires_at = now.checked_add_signed(chrono::Duration::weeks(4)).unwrap();
+
     // json response with everything in it
     // we could return just the bearer token, but I think they will always request api keys and the user profile
-    let response_json = json!({
+    let mut response_json = json!({
         "rpc_keys": uks
             .into_iter()
             .map(|uk| (uk.id, uk))
@@ -334,21 +326,33 @@ pub async fn user_login_post(
         "user": u,
     });
 
+    if let Some(jwt_secret) = app.config().jwt_secret.as_ref() {
+        let claims = LoginClaims {
+            sub: u.id.to_string(),
+            jti: user_bearer_token.0,
+            iat: now.timestamp(),
+            exp: expires_at.timestamp(),
+        };
+
+        let jwt = jwt::encode_login(&claims, jwt_secret).context("signing login jwt")?;
+
+        response_json["jwt"] = jwt.into();
+    }
+
     let response = (status_code, Json(response_json)).into_response();
 
     // add bearer to the database
 
-    // expire in 4 weeks
-    let expires_at = Utc::now()
-        .checked_add_signed(chrono::Duration::weeks(4))
-        .unwrap();
-
     let user_login = login::ActiveModel {
         id: sea_orm::NotSet,
         bearer_token: sea_orm::Set(user_bearer_token.uuid()),
         user_id: sea_orm::Set(u.id),
         expires_at: sea_orm::Set(expires_at),
         read_only: sea_orm::Set(false),
+        created_at: sea_orm::Set(Some(now)),
+        last_used_at: sea_orm::Set(None),
+        ip: sea_orm::Set(Some(ip.to_string())),
+        user_agent: sea_orm::Set(user_agent.map(|TypedHeader(x)| x.to_string())),
     };
 
     user_login
@@ -373,14 +377,19 @@ pub async fn user_logout_post(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
 ) -> FrontendResult {
-    let user_bearer = UserBearerToken::try_from(bearer)?;
+    // the jwt that was issued with this bearer token shares its login. it stops working too
+    let user_bearer = app.user_bearer_token(&bearer)?;
 
     let db_conn = app.db_conn().context("database needed for user logout")?;
 
-    if let Err(err) = login::Entity::delete_many()
-        .filter(login::Column::BearerToken.eq(user_bearer.uuid()))
-        .exec(&db_conn)
-        .await
+    let mut redis_conn = app.redis_conn().await?;
+
+    if let Err(err) = revoke_logins(
+        &db_conn,
+        redis_conn.as_mut(),
+        login::Column::BearerToken.eq(user_bearer.uuid()),
+    )
+    .await
     {
         debug!("Failed to delete {}: {}", user_bearer.redis_key(), err);
     }
@@ -407,6 +416,88 @@ pub async fn user_logout_post(
     Ok("goodbye".into_response())
 }
 
+/// `GET /user/sessions` -- Use a bearer token to list the user's logins that have not expired.
+///
+/// Bearer tokens are not included. The session that made the request is marked as `current`.
+/// `created_at`, `ip`, and `user_agent` are null for logins from before sessions were tracked.
+#[debug_handler]
+pub async fn user_sessions_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> FrontendResult {
+    let current_bearer = app.user_bearer_token(&bearer)?;
+
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = app
+        .db_replica()
+        .context("getting replica db for user's sessions")?;
+
+    let sessions = login::Entity::find()
+        .filter(login::Column::UserId.eq(user.id))
+        .filter(login::Column::ExpiresAt.gt(Utc::now()))
+        .order_by_desc(login::Column::Id)
+        .all(db_replica.conn())
+        .await
+        .context("failed loading user's sessions")?;
+
+    let current_bearer = current_bearer.uuid();
+
+    let sessions: Vec<_> = sessions
+        .into_iter()
+        .map(|x| {
+            json!({
+                "id": x.id,
+                "created_at": x.created_at,
+                "last_used_at": x.last_used_at,
+                "expires_at": x.expires_at,
+                "ip": x.ip,
+                "user_agent": x.user_agent,
+                "read_only": x.read_only,
+                "current": x.bearer_token == current_bearer,
+            })
+        })
+        .collect();
+
+    let response_json = json!({
+        "user_id": user.id,
+        "sessions": sessions,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `DELETE /user/sessions/:session_id` -- Use a bearer token to revoke one of the user's sessions.
+///
+/// The session's bearer token and jwt stop working on every proxy. Revoking the current session logs it out.
+#[debug_handler]
+pub async fn user_session_delete(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(session_id): Path<u64>,
+) -> FrontendResult {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app.db_conn().context("revoking a session requires a db")?;
+
+    let mut redis_conn = app.redis_conn().await?;
+
+    let revoked = revoke_logins(
+        &db_conn,
+        redis_conn.as_mut(),
+        Condition::all()
+            .add(login::Column::Id.eq(session_id))
+            .add(login::Column::UserId.eq(user.id)),
+    )
+    .await?;
+
+    if revoked == 0 {
+        return Err(FrontendErrorResponse::NotFound);
+    }
+
+    Ok(Json(json!({ "revoked": session_id })).into_response())
+}
+
 /// `GET /user` -- Use a bearer token to get the user's profile.
 ///
 /// - the email address of a user if they opted in to get contacted via email
@@ -429,6 +520,9 @@ pub struct UserPost {
 }
 
 /// `POST /user` -- modify the account connected to the bearer token in the `Authentication` header.
+///
+/// A new email is saved as unverified and sent a link to `GET /user/email/verify/:token`.
+/// Notifications are only sent to verified emails.
 #[debug_handler]
 pub async fn user_post(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
@@ -437,18 +531,27 @@ pub async fn user_post(
 ) -> FrontendResult {
     let (user, _semaphore) = app.bearer_is_authorized(bearer_token).await?;
 
+    let current_email = user.email.clone();
+    let mut new_email = None;
+
     let mut user: user::ActiveModel = user.into();
 
     // update the email address
     if let Some(x) = payload.email {
-        // TODO: only Set if no change
         if x.is_empty() {
             user.email = sea_orm::Set(None);
-        } else {
-            // TODO: do some basic validation
-            // TODO: don't set immediatly, send a confirmation email first
-            // TODO: compare first? or is sea orm smart enough to do that for us?
-            user.email = sea_orm::Set(Some(x));
+            user.email_verified_at = sea_orm::Set(None);
+        } else if current_email.as_deref() != Some(x.as_str()) {
+            if !is_valid_email(&x) {
+                return Err(FrontendErrorResponse::BadRequest(
+                    "invalid email address".to_string(),
+                ));
+            }
+
+            user.email = sea_orm::Set(Some(x.clone()));
+            user.email_verified_at = sea_orm::Set(None);
+
+            new_email = Some(x);
         }
     }
 
@@ -465,66 +568,219 @@ pub async fn user_post(
 
     let user: user::Model = user.try_into().context("Returning updated user")?;
 
+    if let Some(email) = new_email {
+        // the email is saved either way. they can ask for another link with `POST /user/email/verify`
+        if let Err(err) = app.send_email_verification(user.id.into(), &email).await {
+            warn!(
+                "failed sending email verification to user {}. err={:?}",
+                user.id, err
+            );
+        }
+    }
+
     Ok(Json(user).into_response())
 }
 
+/// `POST /user/email/verify` -- Use a bearer token to send another link for confirming the user's email.
+///
+/// Only one link is sent per minute. Older links stop working.
+#[debug_handler]
+pub async fn user_email_verify_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> FrontendResult {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    if app.mailer.is_none() {
+        return Err(FrontendErrorResponse::BadRequest(
+            "email is not configured on this server".to_string(),
+        ));
+    }
+
+    let email = match (user.email, user.email_verified_at) {
+        (None, _) => {
+            return Err(FrontendErrorResponse::BadRequest(
+                "set an email with `POST /user` first".to_string(),
+            ))
+        }
+        (Some(_), Some(_)) => {
+            return Err(FrontendErrorResponse::BadRequest(
+                "email is already verified".to_string(),
+            ))
+        }
+        (Some(x), None) => x,
+    };
+
+    if !app.send_email_verification(user.id.into(), &email).await? {
+        return Err(FrontendErrorResponse::StatusCode(
+            StatusCode::TOO_MANY_REQUESTS,
+            "a verification email was sent less than a minute ago".to_string(),
+            None,
+        ));
+    }
+
+    let response_json = json!({
+        "user_id": user.id,
+        "email": email,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `GET /user/email/verify/:token` -- Confirm the email that the token was sent to.
+///
+/// This is the link in verification emails, so it does not need a bearer token.
+/// Tokens only work once and only for the email the user still has.
+#[debug_handler]
+pub async fn user_email_verify_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    Path(token): Path<Ulid>,
+) -> FrontendResult {
+    // guessing tokens is limited like logins
+    login_is_authorized(&app, ip).await?;
+
+    let db_conn = app.db_conn().context("verifying an email requires a db")?;
+
+    let user = verify_email(&db_conn, token).await?.ok_or_else(|| {
+        FrontendErrorResponse::BadRequest("verification link is invalid or expired".to_string())
+    })?;
+
+    let response_json = json!({
+        "user_id": user.id,
+        "email": user.email,
+        "email_verified_at": user.email_verified_at,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
 /// `GET /user/balance` -- Use a bearer token to get the user's balance and spend.
 ///
 /// - show balance in USD
 /// - show deposits history (currency, amounts, transaction id)
 ///
+/// Pending deposits are listed but are not part of the balance until they have enough confirmations.
+///
+/// Owners of a shared account can view its balance with `?user_id=$x`.
+///
 /// TODO: one key per request? maybe /user/balance/:rpc_key?
-/// TODO: this will change as we add better support for secondary users.
 #[debug_handler]
 pub async fn user_balance_get(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(query): Query<TeamQuery>,
 ) -> FrontendResult {
-    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+    let (_caller, user_id, _semaphore) = app
+        .bearer_is_authorized_for(bearer, query.user_id, Role::Owner)
+        .await?;
+
+    let db_replica = app
+        .db_replica()
+        .context("getting db to fetch user's balance")?;
+
+    let balance = get_user_balance(db_replica.conn(), user_id).await?;
+
+    let usage = get_user_usage(db_replica.conn(), user_id).await?;
 
-    todo!("user_balance_get");
+    let deposits = deposit::Entity::find()
+        .filter(deposit::Column::UserId.eq(user_id))
+        .order_by_desc(deposit::Column::Id)
+        .all(db_replica.conn())
+        .await
+        .context("failed loading user's deposits")?;
+
+    let response_json = json!({
+        "user_id": user_id,
+        "balance": balance,
+        "usage": usage,
+        "deposits": deposits,
+    });
+
+    Ok(Json(response_json).into_response())
 }
 
 /// `POST /user/balance/:txhash` -- Manually process a confirmed txid to update a user's balance.
 ///
 /// We will subscribe to events to watch for any user deposits, but sometimes events can be missed.
 ///
+/// Any payments in the transaction are saved no matter who they are for. Only the account's are returned.
+/// They are credited once they have enough confirmations.
+///
+/// Owners of a shared account can check deposits for it with `?user_id=$x`.
+///
 /// TODO: rate limit by user
 /// TODO: one key per request? maybe /user/balance/:rpc_key?
-/// TODO: this will change as we add better support for secondary users.
 #[debug_handler]
 pub async fn user_balance_post(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(txid): Path<String>,
+    Query(query): Query<TeamQuery>,
 ) -> FrontendResult {
-    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+    let (caller, user_id, _semaphore) = app
+        .bearer_is_authorized_for(bearer, query.user_id, Role::Owner)
+        .await?;
 
-    todo!("user_balance_post");
+    let txid: TxHash = txid
+        .parse()
+        .map_err(|_| FrontendErrorResponse::BadRequest("invalid txid".to_string()))?;
+
+    let deposits: Vec<_> = app
+        .submit_deposit_tx(txid)
+        .await?
+        .into_iter()
+        .filter(|x| x.user_id == user_id)
+        .collect();
+
+    let db_conn = app.db_conn().context("saving team trail requires a db")?;
+
+    save_team_trail(
+        &db_conn,
+        user_id,
+        caller.id.into(),
+        "user_balance_post",
+        format!("{:?}", txid),
+    )
+    .await?;
+
+    let response_json = json!({
+        "user_id": user_id,
+        "deposits": deposits,
+    });
+
+    Ok(Json(response_json).into_response())
 }
 
 /// `GET /user/keys` -- Use a bearer token to get the user's api keys and their settings.
 ///
+/// Members of a shared account can view its keys with `?user_id=$x`.
+///
 /// TODO: one key per request? maybe /user/keys/:rpc_key?
 #[debug_handler]
 pub async fn rpc_keys_get(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(query): Query<TeamQuery>,
 ) -> FrontendResult {
-    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+    let (_caller, user_id, _semaphore) = app
+        .bearer_is_authorized_for(bearer, query.user_id, Role::Collaborator)
+        .await?;
 
     let db_replica = app
         .db_replica()
         .context("getting db to fetch user's keys")?;
 
     let uks = rpc_key::Entity::find()
-        .filter(rpc_key::Column::UserId.eq(user.id))
+        .filter(rpc_key::Column::UserId.eq(user_id))
+        .filter(rpc_key::Column::DeletedAt.is_null())
         .all(db_replica.conn())
         .await
         .context("failed loading user's key")?;
 
     // TODO: stricter type on this?
     let response_json = json!({
-        "user_id": user.id,
+        "user_id": user_id,
         "user_rpc_keys": uks
             .into_iter()
             .map(|uk| (uk.id, uk))
@@ -534,16 +790,97 @@ pub async fn rpc_keys_get(
     Ok(Json(response_json).into_response())
 }
 
-/// `DELETE /user/keys` -- Use a bearer token to delete an existing key.
+/// `DELETE /user/keys/:rpc_key_id` -- Use a bearer token to delete an existing key.
+///
+/// The key stops working on every proxy. It is kept in the database so that its stats are not lost.
 #[debug_handler]
 pub async fn rpc_keys_delete(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+    Query(query): Query<TeamQuery>,
 ) -> FrontendResult {
-    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+    let (caller, user_id, _semaphore) = app
+        .bearer_is_authorized_for(bearer, query.user_id, Role::Admin)
+        .await?;
+
+    let db_conn = app.db_conn().context("deleting a key requires a db")?;
+
+    let uk = rpc_key::Entity::find_by_id(rpc_key_id)
+        .filter(rpc_key::Column::UserId.eq(user_id))
+        .filter(rpc_key::Column::DeletedAt.is_null())
+        .one(&db_conn)
+        .await
+        .context("failed loading user's key")?
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    let (uk, invalidation) = delete_rpc_key(&db_conn, uk).await?;
 
-    // TODO: think about how cascading deletes and billing should work
-    Err(anyhow::anyhow!("work in progress").into())
+    app.invalidate_rpc_keys(invalidation).await?;
+
+    save_team_trail(
+        &db_conn,
+        user_id,
+        caller.id.into(),
+        "rpc_keys_delete",
+        format!("{:?}", rpc_key_id),
+    )
+    .await?;
+
+    Ok(Json(json!({ "deleted": uk })).into_response())
+}
+
+/// `POST /user/keys/:rpc_key_id/rotate` -- Use a bearer token to give a key a new secret.
+///
+/// The old secret keeps working for `rpc_key_rotation_grace_seconds` so that clients can be moved to the new one.
+/// Rotating again before then revokes the old secret immediately.
+/// The account's verified email is told about the rotation.
+#[debug_handler]
+pub async fn rpc_key_rotate_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+    Query(query): Query<TeamQuery>,
+) -> FrontendResult {
+    let (caller, user_id, _semaphore) = app
+        .bearer_is_authorized_for(bearer, query.user_id, Role::Admin)
+        .await?;
+
+    let db_conn = app.db_conn().context("rotating a key requires a db")?;
+
+    let uk = rpc_key::Entity::find_by_id(rpc_key_id)
+        .filter(rpc_key::Column::UserId.eq(user_id))
+        .filter(rpc_key::Column::DeletedAt.is_null())
+        .one(&db_conn)
+        .await
+        .context("failed loading user's key")?
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    let grace_period =
+        chrono::Duration::seconds(app.config().rpc_key_rotation_grace_seconds as i64);
+
+    let (uk, invalidation) = rotate_rpc_key(&db_conn, uk, grace_period).await?;
+
+    app.invalidate_rpc_keys(invalidation).await?;
+
+    app.notify_user(
+        user_id,
+        Notification::RpcKeyRotated {
+            rpc_key_id: uk.id.into(),
+            old_secret_key_expires_at: uk.old_secret_key_expires_at,
+        },
+    );
+
+    save_team_trail(
+        &db_conn,
+        user_id,
+        caller.id.into(),
+        "rpc_key_rotate_post",
+        format!("{:?}", rpc_key_id),
+    )
+    .await?;
+
+    Ok(Json(uk).into_response())
 }
 
 /// the JSON input to the `rpc_keys_management` handler.
@@ -566,23 +903,31 @@ pub struct UserKeyManagement {
 }
 
 /// `POST /user/keys` or `PUT /user/keys` -- Use a bearer token to create or update an existing key.
+///
+/// Admins and owners of a shared account can manage its keys with `?user_id=$x`.
 #[debug_handler]
 pub async fn rpc_keys_management(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(query): Query<TeamQuery>,
     Json(payload): Json<UserKeyManagement>,
 ) -> FrontendResult {
     // TODO: is there a way we can know if this is a PUT or POST? right now we can modify or create keys with either. though that probably doesn't matter
 
-    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+    let (caller, user_id, _semaphore) = app
+        .bearer_is_authorized_for(bearer, query.user_id, Role::Admin)
+        .await?;
+
+    let trail_payload = format!("{:?}", payload);
 
     let db_replica = app.db_replica().context("getting db for user's keys")?;
 
     let mut uk = if let Some(existing_key_id) = payload.key_id {
         // get the key and make sure it belongs to the user
         rpc_key::Entity::find()
-            .filter(rpc_key::Column::UserId.eq(user.id))
+            .filter(rpc_key::Column::UserId.eq(user_id))
             .filter(rpc_key::Column::Id.eq(existing_key_id))
+            .filter(rpc_key::Column::DeletedAt.is_null())
             .one(db_replica.conn())
             .await
             .context("failed loading user's key")?
@@ -598,7 +943,7 @@ pub async fn rpc_keys_management(
             .context("log level must be 'none', 'detailed', or 'aggregated'")?;
 
         rpc_key::ActiveModel {
-            user_id: sea_orm::Set(user.id),
+            user_id: sea_orm::Set(user_id.into()),
             secret_key: sea_orm::Set(secret_key.into()),
             log_level: sea_orm::Set(log_level),
             ..Default::default()
@@ -726,7 +1071,18 @@ pub async fn rpc_keys_management(
     let uk = if uk.is_changed() {
         let db_conn = app.db_conn().context("login requires a db")?;
 
-        uk.save(&db_conn).await.context("Failed saving user key")?
+        let uk = uk.save(&db_conn).await.context("Failed saving user key")?;
+
+        save_team_trail(
+            &db_conn,
+            user_id,
+            caller.id.into(),
+            "rpc_keys_management",
+            trail_payload,
+        )
+        .await?;
+
+        uk
     } else {
         uk
     };
@@ -736,14 +1092,174 @@ pub async fn rpc_keys_management(
     Ok(Json(uk).into_response())
 }
 
+/// the JSON input to the `rpc_key_jwts_post` handler.
+/// `public_key` is a PEM. `algorithm` is the jwt `alg` that it signs with.
+#[derive(Debug, Deserialize)]
+pub struct NewJwtPublicKey {
+    rpc_key_id: u64,
+    algorithm: String,
+    public_key: String,
+    /// jwts must have this `aud` claim
+    audience: Option<String>,
+    /// jwts must have an `iat` claim and expire within this many seconds of it
+    max_lifetime_seconds: Option<u64>,
+    description: Option<String>,
+}
+
+/// `GET /user/keys/jwt` -- Use a bearer token to get the public keys uploaded for the user's api keys.
+#[debug_handler]
+pub async fn rpc_key_jwts_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(query): Query<TeamQuery>,
+) -> FrontendResult {
+    let (_caller, user_id, _semaphore) = app
+        .bearer_is_authorized_for(bearer, query.user_id, Role::Collaborator)
+        .await?;
+
+    let db_replica = app
+        .db_replica()
+        .context("getting db to fetch user's jwt keys")?;
+
+    let jwt_keys = jwt_public_key::Entity::find()
+        .inner_join(rpc_key::Entity)
+        .filter(rpc_key::Column::UserId.eq(user_id))
+        .order_by_asc(jwt_public_key::Column::Id)
+        .all(db_replica.conn())
+        .await
+        .context("failed loading user's jwt keys")?;
+
+    let response_json = json!({
+        "user_id": user_id,
+        "jwt_public_keys": jwt_keys,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `POST /user/keys/jwt` -- Use a bearer token to upload a public key for one of the user's api keys.
+/// Jwts signed by the matching private key, with the returned `key_id` as their `kid`, can be used as a bearer token on `/rpc`.
+#[debug_handler]
+pub async fn rpc_key_jwts_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(query): Query<TeamQuery>,
+    Json(payload): Json<NewJwtPublicKey>,
+) -> FrontendResult {
+    let (caller, user_id, _semaphore) = app
+        .bearer_is_authorized_for(bearer, query.user_id, Role::Admin)
+        .await?;
+
+    let algorithm = jwt::parse_algorithm(&payload.algorithm)
+        .map_err(|err| FrontendErrorResponse::BadRequest(err.to_string()))?;
+
+    // make sure the key is usable now instead of on the first request
+    jwt::parse_public_key(algorithm, &payload.public_key)
+        .map_err(|err| FrontendErrorResponse::BadRequest(format!("{:#}", err)))?;
+
+    let db_conn = app.db_conn().context("uploading a jwt key requires a db")?;
+
+    // make sure the key belongs to the user
+    rpc_key::Entity::find()
+        .filter(rpc_key::Column::UserId.eq(user_id))
+        .filter(rpc_key::Column::Id.eq(payload.rpc_key_id))
+        .filter(rpc_key::Column::DeletedAt.is_null())
+        .one(&db_conn)
+        .await
+        .context("failed loading user's key")?
+        .context("key does not exist or is not controlled by this bearer token")?;
+
+    let key_id = Ulid::new();
+
+    let jwt_key = jwt_public_key::ActiveModel {
+        rpc_key_id: sea_orm::Set(payload.rpc_key_id.into()),
+        key_id: sea_orm::Set(Uuid::from_u128(key_id.into())),
+        algorithm: sea_orm::Set(payload.algorithm),
+        public_key: sea_orm::Set(payload.public_key),
+        audience: sea_orm::Set(payload.audience),
+        max_lifetime_seconds: sea_orm::Set(payload.max_lifetime_seconds.map(Into::into)),
+        description: sea_orm::Set(payload.description),
+        ..Default::default()
+    }
+    .insert(&db_conn)
+    .await
+    .context("Failed saving jwt key")?;
+
+    save_team_trail(
+        &db_conn,
+        user_id,
+        caller.id.into(),
+        "rpc_key_jwts_post",
+        format!("{:?}", jwt_key),
+    )
+    .await?;
+
+    Ok(Json(jwt_key).into_response())
+}
+
+/// `DELETE /user/keys/jwt/:key_id` -- Use a bearer token to delete one of the user's jwt public keys.
+/// Other proxies keep accepting jwts signed by the key until their cache of it expires.
+#[debug_handler]
+pub async fn rpc_key_jwt_delete(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(key_id): Path<String>,
+    Query(query): Query<TeamQuery>,
+) -> FrontendResult {
+    let (caller, user_id, _semaphore) = app
+        .bearer_is_authorized_for(bearer, query.user_id, Role::Admin)
+        .await?;
+
+    let key_id = Ulid::from_string(&key_id)?;
+
+    let db_conn = app.db_conn().context("deleting a jwt key requires a db")?;
+
+    let jwt_key = jwt_public_key::Entity::find()
+        .inner_join(rpc_key::Entity)
+        .filter(rpc_key::Column::UserId.eq(user_id))
+        .filter(jwt_public_key::Column::KeyId.eq(Uuid::from_u128(key_id.into())))
+        .one(&db_conn)
+        .await
+        .context("failed loading user's jwt key")?
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    jwt_public_key::Entity::delete_by_id(jwt_key.id)
+        .exec(&db_conn)
+        .await
+        .context("Failed deleting jwt key")?;
+
+    app.jwt_public_key_cache.invalidate(&key_id).await;
+
+    save_team_trail(
+        &db_conn,
+        user_id,
+        caller.id.into(),
+        "rpc_key_jwt_delete",
+        format!("{:?}", jwt_key),
+    )
+    .await?;
+
+    Ok(Json(json!({ "deleted": jwt_key })).into_response())
+}
+
 /// `GET /user/revert_logs` -- Use a bearer token to get the user's revert logs.
+///
+/// Members of a shared account can view its revert logs with `?user_id=$x`.
 #[debug_handler]
 pub async fn user_revert_logs_get(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
     Query(params): Query<HashMap<String, String>>,
 ) -> FrontendResult {
-    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+    let user_id = params
+        .get("user_id")
+        .map(|x| x.parse::<u64>())
+        .transpose()
+        .context("Parsing user_id param")?;
+
+    let (_caller, user_id, _semaphore) = app
+        .bearer_is_authorized_for(bearer, user_id, Role::Collaborator)
+        .await?;
 
     let chain_id = get_chain_id_from_params(app.as_ref(), &params)?;
     let query_start = get_query_start_from_params(&params)?;
@@ -764,7 +1280,7 @@ pub async fn user_revert_logs_get(
         .context("getting replica db for user's revert logs")?;
 
     let uks = rpc_key::Entity::find()
-        .filter(rpc_key::Column::UserId.eq(user.id))
+        .filter(rpc_key::Column::UserId.eq(user_id))
         .all(db_replica.conn())
         .await
         .context("failed loading user's key")?;
@@ -821,12 +1337,10 @@ pub async fn user_stats_aggregated_get(
 /// `GET /user/stats/detailed` -- Use a bearer token to get the user's key stats such as bandwidth used and methods requested.
 ///
 /// If no bearer is provided, detailed stats for all users will be shown.
-/// View a single user with `?user_id=$x`.
+/// View a single user with `?user_id=$x`. Members of a shared account can view it too.
 /// View a single chain with `?chain_id=$x`.
 ///
 /// Set `$x` to zero to see all.
-///
-/// TODO: this will change as we add better support for secondary users.
 #[debug_handler]
 pub async fn user_stats_detailed_get(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
@@ -1,5 +1,6 @@
+use crate::rpcs::http::HttpClientError;
 use derive_more::From;
-use ethers::prelude::{HttpClientError, ProviderError, WsClientError};
+use ethers::prelude::{ProviderError, WsClientError};
 use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -252,10 +253,10 @@ impl JsonRpcForwardedResponse {
 
                 if let Ok(e) = e {
                     match *e {
-                        HttpClientError::JsonRpcError(e) => {
-                            code = e.code;
-                            message = e.message.clone();
-                            data = e.data;
+                        HttpClientError::JsonRpcError { error, .. } => {
+                            code = error.code;
+                            message = error.message;
+                            data = error.data;
                         }
                         e => {
                             // this is not an rpc error. keep it as an error
@@ -0,0 +1,257 @@
+//! JSON Web Tokens for logins and rpc keys.
+//!
+//! Login tokens are signed with the app's `jwt_secret`. Any proxy with the same secret can check them without redis.
+//! Rpc tokens are signed by the user with a private key. The matching public key is uploaded for one of their rpc keys.
+
+use anyhow::Context;
+use entities::jwt_public_key;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use ulid::Ulid;
+
+/// Claims in the token returned by `/user/login`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LoginClaims {
+    /// the user's id
+    pub sub: String,
+    /// the bearer token that was created at the same time. logging out with this jwt forgets that bearer token
+    pub jti: Ulid,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+/// Claims that a user can put in the tokens they sign for rpc requests
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RpcClaims {
+    pub exp: i64,
+    pub iat: Option<i64>,
+    /// None = allow all methods
+    pub methods: Option<Vec<String>>,
+}
+
+impl RpcClaims {
+    pub fn allows_method(&self, method: &str) -> bool {
+        match &self.methods {
+            None => true,
+            Some(methods) => methods.iter().any(|x| x == method),
+        }
+    }
+}
+
+/// A public key that was uploaded for an rpc key. Parsed once and then cached
+#[derive(Clone)]
+pub struct RpcJwtKey {
+    pub rpc_key_id: u64,
+    pub secret_key: Ulid,
+    pub algorithm: Algorithm,
+    pub decoding_key: DecodingKey,
+    pub audience: Option<String>,
+    pub max_lifetime_seconds: Option<u64>,
+}
+
+/// Only asymmetric algorithms are allowed. We never want to hold a secret that can sign a user's tokens
+pub fn parse_algorithm(algorithm: &str) -> anyhow::Result<Algorithm> {
+    let algorithm = Algorithm::from_str(algorithm).context("unknown jwt algorithm")?;
+
+    match algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => Err(anyhow::anyhow!(
+            "shared secret jwt algorithms are not supported. use RS*, PS*, ES*, or EdDSA"
+        )),
+        x => Ok(x),
+    }
+}
+
+pub fn parse_public_key(algorithm: Algorithm, public_key: &str) -> anyhow::Result<DecodingKey> {
+    let public_key = public_key.as_bytes();
+
+    let decoding_key = match algorithm {
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512 => DecodingKey::from_rsa_pem(public_key),
+        Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(public_key),
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(public_key),
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            return Err(anyhow::anyhow!(
+                "shared secret jwt algorithms are not supported"
+            ))
+        }
+    }
+    .context("parsing public key pem")?;
+
+    Ok(decoding_key)
+}
+
+impl RpcJwtKey {
+    pub fn try_new(model: jwt_public_key::Model, secret_key: Ulid) -> anyhow::Result<Self> {
+        let algorithm = parse_algorithm(&model.algorithm)?;
+
+        let decoding_key = parse_public_key(algorithm, &model.public_key)?;
+
+        Ok(Self {
+            rpc_key_id: model.rpc_key_id.into(),
+            secret_key,
+            algorithm,
+            decoding_key,
+            audience: model.audience,
+            max_lifetime_seconds: model.max_lifetime_seconds.map(Into::into),
+        })
+    }
+
+    /// check the signature and the standard claims
+    pub fn decode(&self, token: &str) -> anyhow::Result<RpcClaims> {
+        let mut validation = Validation::new(self.algorithm);
+
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+            validation.set_required_spec_claims(&["exp", "aud"]);
+        }
+
+        let claims = jsonwebtoken::decode::<RpcClaims>(token, &self.decoding_key, &validation)
+            .context("invalid jwt")?
+            .claims;
+
+        if let Some(max_lifetime_seconds) = self.max_lifetime_seconds {
+            let iat = claims
+                .iat
+                .context("iat is required to check this key's max lifetime")?;
+
+            if claims.exp - iat > max_lifetime_seconds as i64 {
+                return Err(anyhow::anyhow!(
+                    "jwt lifetime is longer than {} seconds",
+                    max_lifetime_seconds
+                ));
+            }
+        }
+
+        Ok(claims)
+    }
+}
+
+/// the `kid` header says which uploaded public key signed the token
+pub fn decode_key_id(token: &str) -> anyhow::Result<Ulid> {
+    let header = jsonwebtoken::decode_header(token).context("invalid jwt header")?;
+
+    let kid = header.kid.context("jwt header needs a kid")?;
+
+    let kid = Ulid::from_string(&kid).context("jwt kid is not a valid key id")?;
+
+    Ok(kid)
+}
+
+pub fn encode_login(claims: &LoginClaims, jwt_secret: &str) -> anyhow::Result<String> {
+    let token = jsonwebtoken::encode(
+        &Header::new(Algorithm::HS256),
+        claims,
+        &EncodingKey::from_secret(jwt_secret.as_bytes()),
+    )?;
+
+    Ok(token)
+}
+
+pub fn decode_login(token: &str, jwt_secret: &str) -> anyhow::Result<LoginClaims> {
+    let claims = jsonwebtoken::decode::<LoginClaims>(
+        token,
+        &DecodingKey::from_secret(jwt_secret.as_bytes()),
+        &Validation::new(Algorithm::HS256),
+    )
+    .context("invalid jwt")?
+    .claims;
+
+    Ok(claims)
+}
+
+/// bearer tokens are ulids. anything with dots in it is a jwt
+pub fn is_jwt(token: &str) -> bool {
+    token.contains('.')
+}
+
+/// jwts for the rpc routes name the public key that signed them. login jwts and bearer tokens don't
+pub fn has_key_id(token: &str) -> bool {
+    is_jwt(token)
+        && jsonwebtoken::decode_header(token)
+            .map(|x| x.kid.is_some())
+            .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use serde_json::json;
+
+    #[test]
+    fn test_login_roundtrip() {
+        let now = Utc::now().timestamp();
+
+        let claims = LoginClaims {
+            sub: "1".to_string(),
+            jti: Ulid::new(),
+            iat: now,
+            exp: now + 60,
+        };
+
+        let token = encode_login(&claims, "some secret that is long enough").unwrap();
+
+        assert!(is_jwt(&token));
+
+        let decoded = decode_login(&token, "some secret that is long enough").unwrap();
+
+        assert_eq!(decoded.sub, claims.sub);
+        assert_eq!(decoded.jti, claims.jti);
+
+        assert!(decode_login(&token, "a different secret").is_err());
+
+        // login jwts aren't for the rpc routes
+        assert!(!has_key_id(&token));
+    }
+
+    #[test]
+    fn test_has_key_id() {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(Ulid::new().to_string());
+
+        let token = jsonwebtoken::encode(
+            &header,
+            &json!({"exp": 0}),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+
+        assert!(has_key_id(&token));
+
+        assert!(!has_key_id(&Ulid::new().to_string()));
+        assert!(!has_key_id("not.a.jwt"));
+    }
+
+    #[test]
+    fn test_no_shared_secrets() {
+        assert!(parse_algorithm("HS256").is_err());
+        assert!(parse_algorithm("ES256").is_ok());
+        assert!(parse_algorithm("EdDSA").is_ok());
+        assert!(parse_algorithm("nope").is_err());
+    }
+
+    #[test]
+    fn test_allows_method() {
+        let all = RpcClaims {
+            exp: 0,
+            iat: None,
+            methods: None,
+        };
+
+        assert!(all.allows_method("eth_call"));
+
+        let some = RpcClaims {
+            exp: 0,
+            iat: None,
+            methods: Some(vec!["eth_blockNumber".to_string()]),
+        };
+
+        assert!(some.allows_method("eth_blockNumber"));
+        assert!(!some.allows_method("eth_call"));
+    }
+}
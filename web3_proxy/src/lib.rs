@@ -2,12 +2,16 @@ pub mod app;
 pub mod app_stats;
 pub mod admin_queries;
 pub mod atomics;
+pub mod billing;
 pub mod block_number;
 pub mod config;
+pub mod email;
 pub mod frontend;
 pub mod jsonrpc;
+pub mod jwt;
 pub mod metrics_frontend;
 pub mod pagerduty;
+pub mod rpc_keys;
 pub mod rpcs;
 pub mod user_queries;
 pub mod user_token;
@@ -0,0 +1,230 @@
+//! Rotate and delete rpc keys.
+//!
+//! Proxies cache what they know about a secret key. Changes are published through redis so that every proxy forgets the old secrets.
+
+use crate::frontend::authorization::RpcSecretKey;
+use anyhow::Context;
+use chrono::{Duration, Utc};
+use entities::unsigned::BigUnsigned;
+use entities::{jwt_public_key, rpc_key};
+use migration::sea_orm::prelude::{DateTimeUtc, Uuid};
+use migration::sea_orm::{
+    self, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel,
+    QueryFilter, QuerySelect,
+};
+use migration::Expr;
+use redis_rate_limiter::redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+/// the redis channel that invalidations are published on. keys work on every chain, so this is shared by all of them
+pub const RPC_KEY_INVALIDATIONS_CHANNEL: &str = "web3_proxy:rpc_key_invalidations";
+
+/// Secret keys and jwt public keys that proxies need to load again
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct RpcKeyInvalidation {
+    /// the proxy that sent the message. it has already forgotten the keys. None if it came from the cli
+    pub sender: Option<Ulid>,
+    pub secret_keys: Vec<Ulid>,
+    /// jwt public keys remember the secret key that they stand in for
+    pub jwt_key_ids: Vec<Ulid>,
+}
+
+impl RpcKeyInvalidation {
+    pub fn is_empty(&self) -> bool {
+        self.secret_keys.is_empty() && self.jwt_key_ids.is_empty()
+    }
+}
+
+fn uuid_to_ulid(x: Uuid) -> Ulid {
+    Ulid::from(x.as_u128())
+}
+
+/// the `kid`s of the jwt public keys uploaded for these rpc keys
+async fn jwt_key_ids(
+    db_conn: &DatabaseConnection,
+    rpc_key_ids: Vec<u64>,
+) -> anyhow::Result<Vec<Ulid>> {
+    let x = jwt_public_key::Entity::find()
+        .filter(jwt_public_key::Column::RpcKeyId.is_in(rpc_key_ids))
+        .all(db_conn)
+        .await
+        .context("fetching jwt public keys")?
+        .into_iter()
+        .map(|x| uuid_to_ulid(x.key_id))
+        .collect();
+
+    Ok(x)
+}
+
+/// Every secret and jwt that the user's keys are cached under.
+/// A new tier changes the limits of all of them.
+pub async fn user_rpc_key_invalidation(
+    db_conn: &DatabaseConnection,
+    user_id: u64,
+) -> anyhow::Result<RpcKeyInvalidation> {
+    let rpc_keys = rpc_key::Entity::find()
+        .filter(rpc_key::Column::UserId.eq(user_id))
+        .all(db_conn)
+        .await
+        .context("fetching the user's rpc keys")?;
+
+    let rpc_key_ids = rpc_keys.iter().map(|x| x.id.0).collect();
+
+    // a rotated key's old secret is cached separately
+    let secret_keys = rpc_keys
+        .into_iter()
+        .flat_map(|x| [Some(x.secret_key), x.old_secret_key])
+        .flatten()
+        .map(uuid_to_ulid)
+        .collect();
+
+    let invalidation = RpcKeyInvalidation {
+        secret_keys,
+        jwt_key_ids: jwt_key_ids(db_conn, rpc_key_ids).await?,
+        ..Default::default()
+    };
+
+    Ok(invalidation)
+}
+
+/// Give the key a new secret. The current secret keeps working for `grace_period`.
+/// A secret that was kept from an earlier rotation stops working now.
+pub async fn rotate_rpc_key(
+    db_conn: &DatabaseConnection,
+    rpc_key: rpc_key::Model,
+    grace_period: Duration,
+) -> anyhow::Result<(rpc_key::Model, RpcKeyInvalidation)> {
+    anyhow::ensure!(
+        rpc_key.deleted_at.is_none(),
+        "deleted keys can't be rotated"
+    );
+
+    let mut invalidation = RpcKeyInvalidation {
+        jwt_key_ids: jwt_key_ids(db_conn, vec![rpc_key.id.into()]).await?,
+        ..Default::default()
+    };
+
+    if let Some(x) = rpc_key.old_secret_key {
+        invalidation.secret_keys.push(uuid_to_ulid(x));
+    }
+
+    let secret_key = rpc_key.secret_key;
+
+    let mut rpc_key = rpc_key.into_active_model();
+
+    rpc_key.secret_key = sea_orm::Set(RpcSecretKey::new().into());
+
+    if grace_period > Duration::zero() {
+        rpc_key.old_secret_key = sea_orm::Set(Some(secret_key));
+        rpc_key.old_secret_key_expires_at = sea_orm::Set(Some(Utc::now() + grace_period));
+    } else {
+        invalidation.secret_keys.push(uuid_to_ulid(secret_key));
+
+        rpc_key.old_secret_key = sea_orm::Set(None);
+        rpc_key.old_secret_key_expires_at = sea_orm::Set(None);
+    }
+
+    let rpc_key = rpc_key
+        .update(db_conn)
+        .await
+        .context("saving rotated rpc key")?;
+
+    Ok((rpc_key, invalidation))
+}
+
+/// Stop the key from working. The row is kept so that its stats still have a key.
+pub async fn delete_rpc_key(
+    db_conn: &DatabaseConnection,
+    rpc_key: rpc_key::Model,
+) -> anyhow::Result<(rpc_key::Model, RpcKeyInvalidation)> {
+    let mut invalidation = RpcKeyInvalidation {
+        jwt_key_ids: jwt_key_ids(db_conn, vec![rpc_key.id.into()]).await?,
+        secret_keys: vec![uuid_to_ulid(rpc_key.secret_key)],
+        ..Default::default()
+    };
+
+    if let Some(x) = rpc_key.old_secret_key {
+        invalidation.secret_keys.push(uuid_to_ulid(x));
+    }
+
+    let mut rpc_key = rpc_key.into_active_model();
+
+    rpc_key.active = sea_orm::Set(false);
+    rpc_key.old_secret_key = sea_orm::Set(None);
+    rpc_key.old_secret_key_expires_at = sea_orm::Set(None);
+    rpc_key.deleted_at = sea_orm::Set(Some(Utc::now()));
+
+    let rpc_key = rpc_key
+        .update(db_conn)
+        .await
+        .context("saving deleted rpc key")?;
+
+    Ok((rpc_key, invalidation))
+}
+
+/// Forget old secrets whose grace period is over.
+/// The database stops accepting them on its own. This is for the proxies that have them cached.
+pub async fn revoke_expired_rpc_keys(
+    db_conn: &DatabaseConnection,
+) -> anyhow::Result<RpcKeyInvalidation> {
+    let now = Utc::now();
+
+    let expired: Vec<(BigUnsigned, Option<Uuid>)> = rpc_key::Entity::find()
+        .select_only()
+        .column(rpc_key::Column::Id)
+        .column(rpc_key::Column::OldSecretKey)
+        .filter(rpc_key::Column::OldSecretKey.is_not_null())
+        .filter(rpc_key::Column::OldSecretKeyExpiresAt.lte(now))
+        .into_tuple()
+        .all(db_conn)
+        .await
+        .context("fetching expired rpc keys")?;
+
+    if expired.is_empty() {
+        return Ok(RpcKeyInvalidation::default());
+    }
+
+    let rpc_key_ids: Vec<u64> = expired.iter().map(|(id, _)| id.0).collect();
+
+    rpc_key::Entity::update_many()
+        .col_expr(
+            rpc_key::Column::OldSecretKey,
+            Expr::value(Option::<Uuid>::None),
+        )
+        .col_expr(
+            rpc_key::Column::OldSecretKeyExpiresAt,
+            Expr::value(Option::<DateTimeUtc>::None),
+        )
+        .filter(rpc_key::Column::Id.is_in(rpc_key_ids.clone()))
+        .filter(rpc_key::Column::OldSecretKeyExpiresAt.lte(now))
+        .exec(db_conn)
+        .await
+        .context("revoking expired rpc keys")?;
+
+    let invalidation = RpcKeyInvalidation {
+        secret_keys: expired
+            .into_iter()
+            .filter_map(|(_, x)| x.map(uuid_to_ulid))
+            .collect(),
+        jwt_key_ids: jwt_key_ids(db_conn, rpc_key_ids).await?,
+        ..Default::default()
+    };
+
+    Ok(invalidation)
+}
+
+/// Tell the proxies that are subscribed to `RPC_KEY_INVALIDATIONS_CHANNEL` to forget these keys
+pub async fn publish_rpc_key_invalidation<C: AsyncCommands>(
+    redis_conn: &mut C,
+    invalidation: &RpcKeyInvalidation,
+) -> anyhow::Result<()> {
+    let message = serde_json::to_string(invalidation)?;
+
+    redis_conn
+        .publish::<_, _, ()>(RPC_KEY_INVALIDATIONS_CHANNEL, message)
+        .await
+        .context("publishing rpc key invalidation")?;
+
+    Ok(())
+}
@@ -0,0 +1,191 @@
+///! Changes that admins can make to running rpcs. None of these are saved to the config file.
+use super::many::Web3Rpcs;
+use super::one::Web3Rpc;
+use anyhow::anyhow;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+use ulid::Ulid;
+
+/// the redis channel that commands are published on. the chain id is appended
+pub const RPC_COMMANDS_CHANNEL: &str = "web3_proxy:rpc_commands";
+
+/// how long a drain waits for active requests before disabling the rpc anyway
+pub const DEFAULT_DRAIN_TIMEOUT_SECONDS: u64 = 300;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Web3RpcAdminState {
+    #[default]
+    Enabled,
+    /// no new requests. switches to Disabled once the active requests finish
+    Draining,
+    /// no requests
+    Disabled,
+}
+
+impl Web3RpcAdminState {
+    /// true if new requests can be sent to the rpc
+    pub fn is_serving(&self) -> bool {
+        matches!(self, Self::Enabled)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Web3RpcCommand {
+    Drain {
+        /// defaults to DEFAULT_DRAIN_TIMEOUT_SECONDS
+        #[serde(default)]
+        timeout_seconds: Option<u64>,
+    },
+    Disable,
+    Enable,
+    SetTier {
+        tier: u64,
+    },
+    SetSoftLimit {
+        soft_limit: u32,
+    },
+    Reconnect,
+}
+
+/// A command published to the other proxies
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Web3RpcCommandMessage {
+    /// the proxy that sent the message. it has already applied the command
+    pub sender: Ulid,
+    pub rpc: String,
+    pub command: Web3RpcCommand,
+}
+
+impl Web3Rpcs {
+    /// Apply an admin's command to one of our rpcs.
+    /// This only changes the rpc in this process. Use `Web3ProxyApp::rpc_admin_command` to change all proxies.
+    pub async fn apply_rpc_command(
+        &self,
+        rpc: &Arc<Web3Rpc>,
+        command: &Web3RpcCommand,
+    ) -> anyhow::Result<()> {
+        info!("{}: {:?}", rpc, command);
+
+        match command {
+            Web3RpcCommand::Drain { timeout_seconds } => {
+                *rpc.admin_state.write() = Web3RpcAdminState::Draining;
+
+                let timeout =
+                    Duration::from_secs(timeout_seconds.unwrap_or(DEFAULT_DRAIN_TIMEOUT_SECONDS));
+
+                let rpc = rpc.clone();
+                tokio::spawn(async move {
+                    let drained = tokio::time::timeout(timeout, async {
+                        while rpc.active_requests.load(atomic::Ordering::Acquire) > 0 {
+                            sleep(Duration::from_millis(100)).await;
+                        }
+                    })
+                    .await
+                    .is_ok();
+
+                    let mut admin_state = rpc.admin_state.write();
+
+                    // an admin might have changed the state while we were waiting
+                    if *admin_state == Web3RpcAdminState::Draining {
+                        if drained {
+                            info!("{} is drained", rpc);
+                        } else {
+                            // long subscriptions and stuck requests would keep it draining forever
+                            warn!(
+                                "{} still has {} active requests after {:?}. disabling it anyway",
+                                rpc,
+                                rpc.active_requests.load(atomic::Ordering::Acquire),
+                                timeout
+                            );
+                        }
+
+                        *admin_state = Web3RpcAdminState::Disabled;
+                    }
+                });
+            }
+            Web3RpcCommand::Disable => {
+                *rpc.admin_state.write() = Web3RpcAdminState::Disabled;
+            }
+            Web3RpcCommand::Enable => {
+                *rpc.admin_state.write() = Web3RpcAdminState::Enabled;
+            }
+            Web3RpcCommand::SetTier { tier } => {
+                rpc.tier.store(*tier, atomic::Ordering::Release);
+            }
+            Web3RpcCommand::SetSoftLimit { soft_limit } => {
+                rpc.soft_limit.store(*soft_limit, atomic::Ordering::Release);
+            }
+            Web3RpcCommand::Reconnect => {
+                if !rpc.reconnect.load(atomic::Ordering::Acquire) {
+                    return Err(anyhow!("{} is not allowed to reconnect", rpc));
+                }
+
+                rpc.force_reconnect.notify_one();
+
+                // reconnecting will send the new head block on its own
+                return Ok(());
+            }
+        }
+
+        if self.watch_consensus_head_sender.is_none() {
+            // this group doesn't track consensus
+            return Ok(());
+        }
+
+        // remove the rpc from the consensus finder and then add it back so that the new settings are used right away
+        let head_block = rpc.head_block.read().clone();
+
+        if let Some(head_block) = head_block {
+            self.block_sender
+                .send_async((None, rpc.clone()))
+                .await
+                .map_err(|_| anyhow!("block_sender closed"))?;
+
+            self.block_sender
+                .send_async((Some(head_block), rpc.clone()))
+                .await
+                .map_err(|_| anyhow!("block_sender closed"))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rpc_command_serde() {
+        let command: Web3RpcCommand =
+            serde_json::from_str(r#"{"action": "set_tier", "tier": 2}"#).unwrap();
+
+        assert_eq!(command, Web3RpcCommand::SetTier { tier: 2 });
+
+        let command: Web3RpcCommand = serde_json::from_str(r#"{"action": "drain"}"#).unwrap();
+
+        assert_eq!(
+            command,
+            Web3RpcCommand::Drain {
+                timeout_seconds: None
+            }
+        );
+
+        let command: Web3RpcCommand =
+            serde_json::from_str(r#"{"action": "drain", "timeout_seconds": 60}"#).unwrap();
+
+        assert_eq!(
+            command,
+            Web3RpcCommand::Drain {
+                timeout_seconds: Some(60)
+            }
+        );
+
+        assert!(serde_json::from_str::<Web3RpcCommand>(r#"{"action": "delete"}"#).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+///! Find how far back a node keeps state.
+use std::future::Future;
+
+/// how many checks in a row need to find the same limit before we change a server's block_data_limit.
+/// load balanced endpoints can give a different answer on every request
+pub const BLOCK_DATA_LIMIT_AGREEMENTS: u32 = 3;
+
+/// the head block keeps moving while we search. don't treat a few blocks of difference as a new limit
+const BLOCK_DATA_LIMIT_SLACK: u64 = 2;
+
+/// Binary search for the oldest block that `has_state` can query.
+///
+/// Returns None if even the head block has no state (the node is probably still syncing).
+/// Errors from `has_state` stop the search. They should only be for things like timeouts and rate limits, not for missing state.
+pub async fn find_oldest_block_with_state<F, Fut>(
+    head_block_num: u64,
+    mut has_state: F,
+) -> anyhow::Result<Option<u64>>
+where
+    F: FnMut(u64) -> Fut,
+    Fut: Future<Output = anyhow::Result<bool>>,
+{
+    if !has_state(head_block_num).await? {
+        return Ok(None);
+    }
+
+    if head_block_num == 0 || has_state(0).await? {
+        return Ok(Some(0));
+    }
+
+    // `oldest_missing` never has state. `oldest_found` always has state
+    let mut oldest_missing = 0;
+    let mut oldest_found = head_block_num;
+
+    while oldest_found - oldest_missing > 1 {
+        let mid = oldest_missing + (oldest_found - oldest_missing) / 2;
+
+        if has_state(mid).await? {
+            oldest_found = mid;
+        } else {
+            oldest_missing = mid;
+        }
+    }
+
+    Ok(Some(oldest_found))
+}
+
+/// convert the result of `find_oldest_block_with_state` into a block_data_limit
+pub fn block_data_limit_from_oldest(head_block_num: u64, oldest: Option<u64>) -> u64 {
+    match oldest {
+        None => 0,
+        // genesis has state. this is an archive node
+        Some(0) => u64::MAX,
+        Some(oldest) => head_block_num.saturating_sub(oldest),
+    }
+}
+
+/// two limits are close enough that changing between them isn't worth it
+pub fn block_data_limits_agree(a: u64, b: u64) -> bool {
+    if a == b {
+        return true;
+    }
+
+    if a == u64::MAX || b == u64::MAX || a == 0 || b == 0 {
+        return false;
+    }
+
+    a.abs_diff(b) <= a.max(b) / 100 + BLOCK_DATA_LIMIT_SLACK
+}
+
+/// Track repeated checks of a server's block_data_limit so that one odd answer doesn't change it
+#[derive(Debug, Default)]
+pub struct BlockDataLimitVotes {
+    candidate: Option<u64>,
+    count: u32,
+}
+
+impl BlockDataLimitVotes {
+    /// a different limit has been seen, but it has not been confirmed yet
+    pub fn is_pending(&self) -> bool {
+        self.candidate.is_some()
+    }
+
+    /// Record a freshly checked limit. Returns the new limit once enough checks in a row agree on it.
+    pub fn vote(&mut self, current: u64, found: u64, required: u32) -> Option<u64> {
+        if block_data_limits_agree(current, found) {
+            // nothing needs to change
+            self.candidate = None;
+            self.count = 0;
+            return None;
+        }
+
+        match self.candidate {
+            Some(candidate) if block_data_limits_agree(candidate, found) => {
+                // keep the smaller limit. it is better to skip a server than to send it a query it can't answer
+                self.candidate = Some(candidate.min(found));
+                self.count += 1;
+            }
+            _ => {
+                self.candidate = Some(found);
+                self.count = 1;
+            }
+        }
+
+        if self.count >= required {
+            self.count = 0;
+            self.candidate.take()
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn search(head_block_num: u64, oldest_with_state: Option<u64>) -> Option<u64> {
+        find_oldest_block_with_state(head_block_num, |x| async move {
+            Ok(oldest_with_state.map(|oldest| x >= oldest).unwrap_or(false))
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_find_oldest_block_with_state() {
+        assert_eq!(search(16_000_000, Some(0)).await, Some(0));
+        assert_eq!(search(16_000_000, None).await, None);
+        assert_eq!(search(16_000_000, Some(15_999_872)).await, Some(15_999_872));
+        assert_eq!(search(16_000_000, Some(16_000_000)).await, Some(16_000_000));
+        assert_eq!(search(16_000_000, Some(1)).await, Some(1));
+        assert_eq!(search(0, Some(0)).await, Some(0));
+
+        assert_eq!(
+            block_data_limit_from_oldest(16_000_000, Some(15_999_872)),
+            128
+        );
+        assert_eq!(block_data_limit_from_oldest(16_000_000, Some(0)), u64::MAX);
+        assert_eq!(block_data_limit_from_oldest(16_000_000, None), 0);
+    }
+
+    #[tokio::test]
+    async fn test_find_oldest_block_with_state_error() {
+        let result = find_oldest_block_with_state(100, |x| async move {
+            if x == 0 {
+                Err(anyhow::anyhow!("timeout"))
+            } else {
+                Ok(true)
+            }
+        })
+        .await;
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_block_data_limit_votes() {
+        let mut votes = BlockDataLimitVotes::default();
+
+        // matching the current limit never changes anything
+        assert_eq!(votes.vote(128, 128, 3), None);
+        assert_eq!(votes.vote(128, 129, 3), None);
+        assert!(!votes.is_pending());
+
+        // a load balancer sends us to a different node once
+        assert_eq!(votes.vote(128, u64::MAX, 3), None);
+        assert!(votes.is_pending());
+        assert_eq!(votes.vote(128, 128, 3), None);
+        assert!(!votes.is_pending());
+
+        // the node finished syncing and is now an archive node
+        assert_eq!(votes.vote(0, u64::MAX, 3), None);
+        assert_eq!(votes.vote(0, u64::MAX, 3), None);
+        assert_eq!(votes.vote(0, u64::MAX, 3), Some(u64::MAX));
+        assert!(!votes.is_pending());
+
+        // disagreeing answers restart the count. agreeing answers keep the smallest
+        assert_eq!(votes.vote(u64::MAX, 90_000, 3), None);
+        assert_eq!(votes.vote(u64::MAX, 128, 3), None);
+        assert_eq!(votes.vote(u64::MAX, 129, 3), None);
+        assert_eq!(votes.vote(u64::MAX, 130, 3), Some(128));
+    }
+}
@@ -338,13 +338,15 @@ impl Web3Rpcs {
         // Geth's subscriptions have the same potential for skipping blocks.
         pending_tx_sender: Option<broadcast::Sender<TxStatus>>,
     ) -> anyhow::Result<()> {
-        let mut connection_heads = ConsensusFinder::new(self.max_block_age, self.max_block_lag);
+        let mut connection_heads = ConsensusFinder::new(self.max_block_age(), self.max_block_lag());
 
         loop {
             match block_receiver.recv_async().await {
                 Ok((new_block, rpc)) => {
                     let rpc_name = rpc.name.clone();
 
+                    connection_heads.update_limits(self.max_block_age(), self.max_block_lag());
+
                     if let Err(err) = self
                         .process_block_from_rpc(
                             authorization,
@@ -34,7 +34,7 @@ impl ConsensusWeb3Rpcs {
     }
 
     pub fn sum_soft_limit(&self) -> u32 {
-        self.rpcs.iter().fold(0, |sum, rpc| sum + rpc.soft_limit)
+        self.rpcs.iter().fold(0, |sum, rpc| sum + rpc.soft_limit())
     }
 
     // TODO: sum_hard_limit?
@@ -192,11 +192,15 @@ impl ConnectionsGroup {
 
         let num_known = self.rpc_to_block.len();
 
-        if num_known < web3_rpcs.min_head_rpcs {
+        // these can change while the app is running. read them once so this check is consistent
+        let min_head_rpcs = web3_rpcs.min_head_rpcs();
+        let min_sum_soft_limit = web3_rpcs.min_sum_soft_limit();
+
+        if num_known < min_head_rpcs {
             return Err(anyhow::anyhow!(
                 "not enough rpcs connected: {}/{}",
                 num_known,
-                web3_rpcs.min_head_rpcs,
+                min_head_rpcs,
             ));
         }
 
@@ -241,11 +245,11 @@ impl ConnectionsGroup {
                         // backups already voted for a head block. don't change it
                     } else {
                         backup_consensus_rpcs.insert(rpc_name);
-                        backup_sum_soft_limit += rpc.soft_limit;
+                        backup_sum_soft_limit += rpc.soft_limit();
                     }
                     if !rpc.backup {
                         primary_consensus_rpcs.insert(rpc_name);
-                        primary_sum_soft_limit += rpc.soft_limit;
+                        primary_sum_soft_limit += rpc.soft_limit();
                     }
                 } else {
                     // i don't think this is an error. i think its just if a reconnect is currently happening
@@ -258,8 +262,8 @@ impl ConnectionsGroup {
                 }
             }
 
-            if primary_sum_soft_limit >= web3_rpcs.min_sum_soft_limit
-                && primary_consensus_rpcs.len() >= web3_rpcs.min_head_rpcs
+            if primary_sum_soft_limit >= min_sum_soft_limit
+                && primary_consensus_rpcs.len() >= min_head_rpcs
             {
                 // we have enough servers with enough requests! yey!
                 primary_rpcs_voted = Some(maybe_head_block.clone());
@@ -268,8 +272,8 @@ impl ConnectionsGroup {
 
             if backup_rpcs_voted.is_none()
                 && backup_consensus_rpcs != primary_consensus_rpcs
-                && backup_sum_soft_limit >= web3_rpcs.min_sum_soft_limit
-                && backup_consensus_rpcs.len() >= web3_rpcs.min_head_rpcs
+                && backup_sum_soft_limit >= min_sum_soft_limit
+                && backup_consensus_rpcs.len() >= min_head_rpcs
             {
                 // if we include backup servers, we have enough servers with high enough limits
                 backup_rpcs_voted = Some(maybe_head_block.clone());
@@ -288,16 +292,15 @@ impl ConnectionsGroup {
                     continue;
                 }
                 Err(err) => {
-                    let soft_limit_percent = (primary_sum_soft_limit as f32
-                        / web3_rpcs.min_sum_soft_limit as f32)
-                        * 100.0;
+                    let soft_limit_percent =
+                        (primary_sum_soft_limit as f32 / min_sum_soft_limit as f32) * 100.0;
 
                     let err_msg = format!("ran out of parents to check. rpcs {}/{}/{}. soft limit: {:.2}% ({}/{}). err: {:#?}",
                         primary_consensus_rpcs.len(),
                         num_known,
-                        web3_rpcs.min_head_rpcs,
+                        min_head_rpcs,
                         primary_sum_soft_limit,
-                        web3_rpcs.min_sum_soft_limit,
+                        min_sum_soft_limit,
                         soft_limit_percent,
                         err,
                     );
@@ -315,21 +318,21 @@ impl ConnectionsGroup {
         // TODO: if consensus_head_rpcs.is_empty, try another method of finding the head block. will need to change the return Err above into breaks.
 
         // we've done all the searching for the heaviest block that we can
-        if (primary_consensus_rpcs.len() < web3_rpcs.min_head_rpcs
-            || primary_sum_soft_limit < web3_rpcs.min_sum_soft_limit)
+        if (primary_consensus_rpcs.len() < min_head_rpcs
+            || primary_sum_soft_limit < min_sum_soft_limit)
             && backup_rpcs_voted.is_none()
         {
             // if we get here, not enough servers are synced. return an error
             let soft_limit_percent =
-                (primary_sum_soft_limit as f32 / web3_rpcs.min_sum_soft_limit as f32) * 100.0;
+                (primary_sum_soft_limit as f32 / min_sum_soft_limit as f32) * 100.0;
 
             return Err(anyhow::anyhow!(
                 "Not enough resources. rpcs {}/{}/{}. soft limit: {:.2}% ({}/{})",
                 primary_consensus_rpcs.len(),
                 num_known,
-                web3_rpcs.min_head_rpcs,
+                min_head_rpcs,
                 primary_sum_soft_limit,
-                web3_rpcs.min_sum_soft_limit,
+                min_sum_soft_limit,
                 soft_limit_percent,
             ));
         }
@@ -390,6 +393,12 @@ impl ConsensusFinder {
         }
     }
 
+    /// the limits can be changed by a new config
+    pub fn update_limits(&mut self, max_block_age: Option<u64>, max_block_lag: Option<U64>) {
+        self.max_block_age = max_block_age;
+        self.max_block_lag = max_block_lag;
+    }
+
     pub fn len(&self) -> usize {
         self.tiers.len()
     }
@@ -412,10 +421,8 @@ impl ConsensusFinder {
     pub fn remove(&mut self, rpc: &Arc<Web3Rpc>) -> Option<Web3ProxyBlock> {
         let mut removed = None;
 
-        for (i, tier_group) in self.tiers.iter_mut().rev() {
-            if i < &rpc.tier {
-                break;
-            }
+        // check every tier. the rpc's tier might have been changed since it was inserted
+        for tier_group in self.tiers.values_mut().rev() {
             let x = tier_group.remove(rpc);
 
             if removed.is_none() && x.is_some() {
@@ -436,8 +443,10 @@ impl ConsensusFinder {
 
         // TODO: error if rpc.tier is not in self.tiers
 
+        let rpc_tier = rpc.tier();
+
         for (i, tier_group) in self.tiers.iter_mut().rev() {
-            if i < &rpc.tier {
+            if i < &rpc_tier {
                 break;
             }
 
@@ -462,6 +471,10 @@ impl ConsensusFinder {
     ) -> anyhow::Result<bool> {
         // add the rpc's block to connection_heads, or remove the rpc from connection_heads
         let changed = match rpc_head_block {
+            Some(_) if !rpc.admin_state().is_serving() => {
+                // an admin took this rpc out of rotation
+                self.remove(&rpc).is_some()
+            }
             Some(mut rpc_head_block) => {
                 // we don't know if its on the heaviest chain yet
                 rpc_head_block = web3_connections
@@ -548,8 +561,43 @@ impl ConsensusFinder {
 
 #[cfg(test)]
 mod test {
-    // #[test]
-    // fn test_simplest_case_consensus_head_connections() {
-    //     todo!();
-    // }
+    use crate::rpcs::mock::{spawn_web3_rpcs, wait_for_head, MockChain, MockNode};
+
+    #[tokio::test]
+    async fn test_simplest_case_consensus_head_connections() {
+        let chain = MockChain::new(999_002);
+
+        chain.mine_n(10);
+
+        let a = MockNode::spawn(&chain).await.unwrap();
+        let b = MockNode::spawn(&chain).await.unwrap();
+        let c = MockNode::spawn(&chain).await.unwrap();
+
+        let (rpcs, mut head_receiver) =
+            spawn_web3_rpcs(&chain, &[("a", &a), ("b", &b), ("c", &c)], 2, 200)
+                .await
+                .unwrap();
+
+        // new blocks are followed
+        let head = chain.mine();
+
+        wait_for_head(&mut head_receiver, &head).await.unwrap();
+
+        // one lagging server doesn't hold back the others
+        c.update_settings(|x| x.lag = 3);
+
+        let head = chain.mine();
+
+        wait_for_head(&mut head_receiver, &head).await.unwrap();
+
+        // reorgs switch to the new chain
+        let head = chain.reorg(2);
+
+        wait_for_head(&mut head_receiver, &head).await.unwrap();
+
+        assert_eq!(rpcs.head_block_hash(), head.hash);
+
+        // the lagging server is not on the new head yet
+        assert_eq!(rpcs.num_synced_rpcs(), 2);
+    }
 }
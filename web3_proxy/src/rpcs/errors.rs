@@ -0,0 +1,327 @@
+///! Classify the errors that backend rpcs send us.
+use super::http::HttpClientError;
+use ethers::providers::{ProviderError, WsClientError};
+use reqwest::StatusCode;
+use std::time::Duration;
+
+/// how long to back off if a rate limit didn't tell us when to retry
+const DEFAULT_RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// how long to back off if a quota is used up and the provider didn't tell us when to retry
+const DEFAULT_QUOTA_BACKOFF: Duration = Duration::from_secs(60);
+
+/// never trust a provider's retry time more than this. a misconfigured header shouldn't take a server out for a day
+pub(super) const MAX_RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(600);
+
+/// The parts of a backend's error that we need for deciding what to do next. Http and Ws errors are very similar, but different types
+#[derive(Debug, Default)]
+pub struct BackendErrorDetails<'a> {
+    pub status: Option<StatusCode>,
+    pub retry_after: Option<Duration>,
+    pub code: Option<i64>,
+    pub message: Option<&'a str>,
+    pub data: Option<&'a serde_json::Value>,
+}
+
+/// Different providers say "slow down" in different ways
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitKind {
+    /// HTTP 429 Too Many Requests. Alchemy also puts 429 in the jsonrpc error code
+    TooManyRequests,
+    /// HTTP 503 Service Unavailable with a Retry-After header
+    Overloaded,
+    /// EIP-1474's -32005 "limit exceeded". Infura includes `backoff_seconds` in the error data
+    LimitExceeded,
+    /// a daily or monthly quota is used up. retrying soon will not help
+    QuotaExceeded,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackendRateLimit {
+    pub kind: RateLimitKind,
+    /// how long the provider asked us to wait (if they told us)
+    pub retry_after: Option<Duration>,
+}
+
+impl<'a> BackendErrorDetails<'a> {
+    pub fn from_provider_error(err: &'a ProviderError) -> Self {
+        match err {
+            ProviderError::JsonRpcClientError(err) => {
+                if let Some(err) = err.downcast_ref::<HttpClientError>() {
+                    match err {
+                        HttpClientError::JsonRpcError {
+                            error,
+                            status,
+                            retry_after,
+                        } => Self {
+                            status: Some(*status),
+                            retry_after: *retry_after,
+                            code: Some(error.code),
+                            message: Some(&error.message),
+                            data: error.data.as_ref(),
+                        },
+                        HttpClientError::Status {
+                            status,
+                            retry_after,
+                            text,
+                        } => Self {
+                            status: Some(*status),
+                            retry_after: *retry_after,
+                            message: Some(text),
+                            ..Default::default()
+                        },
+                        HttpClientError::Reqwest(err) => Self {
+                            status: err.status(),
+                            ..Default::default()
+                        },
+                        HttpClientError::SerdeJson { .. } => Default::default(),
+                    }
+                } else if let Some(WsClientError::JsonRpcError(err)) =
+                    err.downcast_ref::<WsClientError>()
+                {
+                    Self {
+                        code: Some(err.code),
+                        message: Some(&err.message),
+                        data: err.data.as_ref(),
+                        ..Default::default()
+                    }
+                } else {
+                    Default::default()
+                }
+            }
+            ProviderError::HTTPError(err) => Self {
+                status: err.status(),
+                ..Default::default()
+            },
+            _ => Default::default(),
+        }
+    }
+
+    pub fn is_revert(&self) -> bool {
+        self.message
+            .map(|x| x.starts_with("execution reverted"))
+            .unwrap_or(false)
+    }
+
+    /// the node doesn't have the state for the requested block. its block_data_limit might be wrong
+    pub fn is_missing_state(&self) -> bool {
+        let message = match self.message {
+            Some(x) => x.to_lowercase(),
+            None => return false,
+        };
+
+        [
+            "missing trie node",
+            "old data not available due to pruning",
+            "historical state",
+            "world state not available",
+        ]
+        .iter()
+        .any(|x| message.contains(x))
+    }
+
+    /// check the status code, the jsonrpc error code, and then the message for a rate limit.
+    /// we used to check for "limit" or "request" in the message, but that matched far too many normal errors
+    pub fn rate_limit(&self) -> Option<BackendRateLimit> {
+        let message = self.message.unwrap_or_default().to_lowercase();
+
+        let kind = if is_quota_message(&message) {
+            // check quotas first. they come in with all sorts of codes and statuses
+            if self.status == Some(StatusCode::TOO_MANY_REQUESTS)
+                || matches!(self.code, Some(-32005) | Some(429))
+                || message.contains("exceeded")
+            {
+                RateLimitKind::QuotaExceeded
+            } else {
+                return None;
+            }
+        } else if self.status == Some(StatusCode::TOO_MANY_REQUESTS) || self.code == Some(429) {
+            RateLimitKind::TooManyRequests
+        } else if self.status == Some(StatusCode::SERVICE_UNAVAILABLE) && self.retry_after.is_some()
+        {
+            RateLimitKind::Overloaded
+        } else if self.code == Some(-32005) {
+            // -32005 is also used for things like "query returned more than 10000 results"
+            if self.backoff_seconds().is_some()
+                || message.contains("rate")
+                || message.contains("too many requests")
+            {
+                RateLimitKind::LimitExceeded
+            } else {
+                return None;
+            }
+        } else if message.contains("rate limit") || message.contains("too many requests") {
+            // some providers don't use a standard code or status, but they are clear about it in the message
+            RateLimitKind::TooManyRequests
+        } else {
+            return None;
+        };
+
+        let retry_after = self.retry_after.or_else(|| self.backoff_seconds());
+
+        Some(BackendRateLimit { kind, retry_after })
+    }
+
+    /// Infura puts a float of seconds in the error data
+    fn backoff_seconds(&self) -> Option<Duration> {
+        let backoff_seconds = self.data?.get("backoff_seconds")?.as_f64()?;
+
+        if backoff_seconds.is_finite() && backoff_seconds >= 0.0 {
+            Some(Duration::from_secs_f64(backoff_seconds))
+        } else {
+            None
+        }
+    }
+}
+
+fn is_quota_message(message: &str) -> bool {
+    ["daily request count", "monthly", "quota", "capacity limit"]
+        .iter()
+        .any(|x| message.contains(x))
+}
+
+impl BackendRateLimit {
+    /// how long to wait before sending this server another request
+    pub fn backoff(&self) -> Duration {
+        let default = match self.kind {
+            RateLimitKind::QuotaExceeded => DEFAULT_QUOTA_BACKOFF,
+            _ => DEFAULT_RATE_LIMIT_BACKOFF,
+        };
+
+        self.retry_after
+            .unwrap_or(default)
+            .min(MAX_RATE_LIMIT_BACKOFF)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_alchemy_rate_limit() {
+        let details = BackendErrorDetails {
+            status: Some(StatusCode::TOO_MANY_REQUESTS),
+            code: Some(429),
+            message: Some("Your app has exceeded its compute units per second capacity. If you have retries enabled, you can safely ignore this message."),
+            ..Default::default()
+        };
+
+        let rate_limit = details.rate_limit().unwrap();
+
+        assert_eq!(rate_limit.kind, RateLimitKind::TooManyRequests);
+        assert_eq!(rate_limit.backoff(), DEFAULT_RATE_LIMIT_BACKOFF);
+    }
+
+    #[test]
+    fn test_infura_rate_limit() {
+        let data = json!({
+            "see": "https://infura.io/dashboard",
+            "current_rps": 13.333,
+            "allowed_rps": 10.0,
+            "backoff_seconds": 30.0,
+        });
+
+        let details = BackendErrorDetails {
+            code: Some(-32005),
+            message: Some("project ID request rate exceeded"),
+            data: Some(&data),
+            ..Default::default()
+        };
+
+        let rate_limit = details.rate_limit().unwrap();
+
+        assert_eq!(rate_limit.kind, RateLimitKind::LimitExceeded);
+        assert_eq!(rate_limit.backoff(), Duration::from_secs(30));
+
+        let details = BackendErrorDetails {
+            code: Some(-32005),
+            message: Some("daily request count exceeded, request rate limited"),
+            ..Default::default()
+        };
+
+        let rate_limit = details.rate_limit().unwrap();
+
+        assert_eq!(rate_limit.kind, RateLimitKind::QuotaExceeded);
+        assert_eq!(rate_limit.backoff(), DEFAULT_QUOTA_BACKOFF);
+    }
+
+    #[test]
+    fn test_retry_after_is_honored_and_capped() {
+        let details = BackendErrorDetails {
+            status: Some(StatusCode::SERVICE_UNAVAILABLE),
+            retry_after: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+
+        let rate_limit = details.rate_limit().unwrap();
+
+        assert_eq!(rate_limit.kind, RateLimitKind::Overloaded);
+        assert_eq!(rate_limit.backoff(), Duration::from_secs(5));
+
+        let details = BackendErrorDetails {
+            status: Some(StatusCode::TOO_MANY_REQUESTS),
+            retry_after: Some(Duration::from_secs(86_400)),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            details.rate_limit().unwrap().backoff(),
+            MAX_RATE_LIMIT_BACKOFF
+        );
+    }
+
+    #[test]
+    fn test_missing_state() {
+        for message in [
+            "missing trie node 8d3a6f09e8f8b8c4a9a6bd1f0ab6ff3b09c1a4d9f0c4fc5f8f2e5b6e0f1c2d3e (path )",
+            "old data not available due to pruning",
+        ] {
+            let details = BackendErrorDetails {
+                code: Some(-32000),
+                message: Some(message),
+                ..Default::default()
+            };
+
+            assert!(details.is_missing_state(), "{}", message);
+            assert_eq!(details.rate_limit(), None, "{}", message);
+        }
+
+        let details = BackendErrorDetails {
+            code: Some(-32000),
+            message: Some("header not found"),
+            ..Default::default()
+        };
+
+        assert!(!details.is_missing_state());
+    }
+
+    #[test]
+    fn test_not_rate_limits() {
+        // these all matched the old "limit" or "request" substring checks
+        for (code, message) in [
+            (-32005, "query returned more than 10000 results"),
+            (-32000, "gas limit reached"),
+            (-32000, "invalid request"),
+            (-32000, "exceeds block gas limit"),
+            (3, "execution reverted: request failed"),
+        ] {
+            let details = BackendErrorDetails {
+                code: Some(code),
+                message: Some(message),
+                ..Default::default()
+            };
+
+            assert_eq!(details.rate_limit(), None, "{}", message);
+        }
+
+        // a 503 without a retry time is just a broken server
+        let details = BackendErrorDetails {
+            status: Some(StatusCode::SERVICE_UNAVAILABLE),
+            ..Default::default()
+        };
+
+        assert_eq!(details.rate_limit(), None);
+    }
+}
@@ -0,0 +1,273 @@
+///! Send a second copy of a slow request to another server and use whichever answers first.
+use crate::config::HedgeConfig;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// don't trust a server's percentiles until it has answered this many requests
+const MIN_SAMPLES: u64 = 100;
+
+/// start a new window after this many requests so that old latencies age out
+const WINDOW_SAMPLES: u64 = 10_000;
+
+/// latencies are recorded in milliseconds. slower requests are counted as this
+const MAX_MILLIS: u64 = 60_000;
+
+/// every power of two is split into this many buckets. that is about 6% precision, which is plenty for picking a delay
+const SUB_BUCKETS: u64 = 16;
+
+/// enough buckets for MAX_MILLIS
+const NUM_BUCKETS: usize = bucket_index(MAX_MILLIS) + 1;
+
+/// Counts of latencies in log-linear buckets.
+/// Every request records into this, so it is atomics instead of a lock
+#[derive(Debug)]
+struct LatencyWindow {
+    buckets: [AtomicU64; NUM_BUCKETS],
+    len: AtomicU64,
+}
+
+impl Default for LatencyWindow {
+    fn default() -> Self {
+        Self {
+            buckets: [(); NUM_BUCKETS].map(|_| AtomicU64::new(0)),
+            len: AtomicU64::new(0),
+        }
+    }
+}
+
+impl LatencyWindow {
+    fn reset(&self) {
+        for x in self.buckets.iter() {
+            x.store(0, Ordering::Relaxed);
+        }
+
+        self.len.store(0, Ordering::Relaxed);
+    }
+
+    fn value_at_quantile(&self, quantile: f64) -> u64 {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|x| x.load(Ordering::Relaxed))
+            .collect();
+
+        let total: u64 = counts.iter().sum();
+
+        let target = ((quantile * total as f64).ceil() as u64).max(1);
+
+        let mut seen = 0;
+
+        for (i, count) in counts.into_iter().enumerate() {
+            seen += count;
+
+            if seen >= target {
+                return bucket_max(i);
+            }
+        }
+
+        MAX_MILLIS
+    }
+}
+
+/// values below SUB_BUCKETS get their own bucket. bigger values share a bucket with their closest neighbors
+const fn bucket_index(millis: u64) -> usize {
+    if millis < SUB_BUCKETS {
+        return millis as usize;
+    }
+
+    let exponent = 63 - millis.leading_zeros() as u64;
+    let shift = exponent - SUB_BUCKETS.trailing_zeros() as u64;
+    let sub_bucket = (millis >> shift) & (SUB_BUCKETS - 1);
+
+    ((shift + 1) * SUB_BUCKETS + sub_bucket) as usize
+}
+
+/// the largest value that is counted in a bucket
+fn bucket_max(index: usize) -> u64 {
+    let index = index as u64;
+
+    if index < SUB_BUCKETS {
+        return index;
+    }
+
+    let shift = index / SUB_BUCKETS - 1;
+    let sub_bucket = index % SUB_BUCKETS;
+
+    (((SUB_BUCKETS + sub_bucket + 1) << shift) - 1).min(MAX_MILLIS)
+}
+
+/// Recent latencies of a server's successful requests.
+/// Recording is lock free. Percentiles are approximate while requests are being recorded
+#[derive(Debug, Default)]
+pub struct RequestLatency {
+    windows: [LatencyWindow; 2],
+    /// index of the window that is being recorded into. the other is the last full window. it is used until the current one has enough samples
+    current: AtomicUsize,
+}
+
+impl RequestLatency {
+    pub fn record(&self, duration: Duration) {
+        let millis = duration
+            .as_millis()
+            .try_into()
+            .unwrap_or(u64::MAX)
+            .min(MAX_MILLIS);
+
+        let current = self.current.load(Ordering::Acquire);
+
+        let window = &self.windows[current];
+
+        window.buckets[bucket_index(millis)].fetch_add(1, Ordering::Relaxed);
+
+        // only one request sees the window fill up, so only one request swaps them
+        if window.len.fetch_add(1, Ordering::Relaxed) + 1 == WINDOW_SAMPLES {
+            let next = 1 - current;
+
+            self.windows[next].reset();
+
+            self.current.store(next, Ordering::Release);
+        }
+    }
+
+    /// None if there have not been enough requests to know
+    pub fn percentile(&self, quantile: f64) -> Option<Duration> {
+        let current = self.current.load(Ordering::Acquire);
+
+        let window = [&self.windows[current], &self.windows[1 - current]]
+            .into_iter()
+            .find(|x| x.len.load(Ordering::Relaxed) >= MIN_SAMPLES)?;
+
+        Some(Duration::from_millis(window.value_at_quantile(quantile)))
+    }
+}
+
+/// How long to wait for a server before sending a copy of the request to the next best server.
+/// None if this request should not be hedged.
+pub fn hedge_delay(
+    config: &HedgeConfig,
+    method: &str,
+    tier: u64,
+    p95: Option<Duration>,
+) -> Option<Duration> {
+    let method_delay_ms = *config
+        .methods
+        .get(method)
+        .or_else(|| config.methods.get("*"))?;
+
+    let tier_delay_ms = if config.tiers.is_empty() {
+        0
+    } else {
+        *config.tiers.get(&tier.to_string())?
+    };
+
+    // 0 means "use the server's own p95"
+    match (method_delay_ms, tier_delay_ms) {
+        (0, 0) => p95,
+        (0, x) => Some(Duration::from_millis(x)),
+        (x, _) => Some(Duration::from_millis(x)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashbrown::HashMap;
+
+    #[test]
+    fn test_request_latency() {
+        let latency = RequestLatency::default();
+
+        for _ in 0..MIN_SAMPLES - 1 {
+            latency.record(Duration::from_millis(10));
+        }
+
+        // not enough samples yet
+        assert_eq!(latency.percentile(0.95), None);
+
+        for _ in 0..10 {
+            latency.record(Duration::from_millis(1_000));
+        }
+
+        let p95 = latency.percentile(0.95).unwrap();
+        assert!(p95 >= Duration::from_millis(990), "{:?}", p95);
+
+        let p50 = latency.percentile(0.5).unwrap();
+        assert!(p50 <= Duration::from_millis(11), "{:?}", p50);
+
+        // slow requests are capped instead of erroring
+        latency.record(Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn test_latency_buckets() {
+        for millis in 0..=MAX_MILLIS {
+            let index = bucket_index(millis);
+
+            assert!(index < NUM_BUCKETS);
+
+            let max = bucket_max(index);
+
+            assert!(max >= millis, "{} {}", millis, max);
+            assert!(max - millis <= millis / SUB_BUCKETS, "{} {}", millis, max);
+        }
+    }
+
+    #[test]
+    fn test_latency_windows() {
+        let latency = RequestLatency::default();
+
+        for _ in 0..WINDOW_SAMPLES {
+            latency.record(Duration::from_millis(10));
+        }
+
+        // the full window is still used while the new one fills up
+        assert_eq!(latency.percentile(0.5), Some(Duration::from_millis(10)));
+
+        for _ in 0..MIN_SAMPLES {
+            latency.record(Duration::from_millis(100));
+        }
+
+        // then the old latencies age out
+        let p50 = latency.percentile(0.5).unwrap();
+        assert!(p50 >= Duration::from_millis(100), "{:?}", p50);
+    }
+
+    #[test]
+    fn test_hedge_delay() {
+        let p95 = Some(Duration::from_millis(150));
+
+        let config = HedgeConfig {
+            methods: HashMap::from_iter([
+                ("eth_call".to_string(), 0),
+                ("eth_getLogs".to_string(), 2_000),
+            ]),
+            tiers: HashMap::new(),
+        };
+
+        assert_eq!(hedge_delay(&config, "eth_call", 0, p95), p95);
+        assert_eq!(hedge_delay(&config, "eth_call", 0, None), None);
+        assert_eq!(
+            hedge_delay(&config, "eth_getLogs", 0, p95),
+            Some(Duration::from_millis(2_000))
+        );
+        assert_eq!(hedge_delay(&config, "eth_getBalance", 0, p95), None);
+
+        let config = HedgeConfig {
+            methods: HashMap::from_iter([("*".to_string(), 0), ("eth_getLogs".to_string(), 2_000)]),
+            tiers: HashMap::from_iter([("0".to_string(), 0), ("1".to_string(), 500)]),
+        };
+
+        assert_eq!(hedge_delay(&config, "eth_getBalance", 0, p95), p95);
+        assert_eq!(
+            hedge_delay(&config, "eth_getBalance", 1, p95),
+            Some(Duration::from_millis(500))
+        );
+        // method delays win over tier delays
+        assert_eq!(
+            hedge_delay(&config, "eth_getLogs", 1, p95),
+            Some(Duration::from_millis(2_000))
+        );
+        // tiers that are not listed are not hedged
+        assert_eq!(hedge_delay(&config, "eth_getBalance", 2, p95), None);
+    }
+}
@@ -0,0 +1,327 @@
+///! A JSON-RPC over HTTP client that keeps the parts of the http response that ethers throws away.
+use super::errors::MAX_RATE_LIMIT_BACKOFF;
+use crate::jsonrpc::JsonRpcErrorData;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use ethers::providers::{JsonRpcClient, ProviderError};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use url::Url;
+
+/// some providers send a reset time instead of a Retry-After. values larger than this are unix timestamps
+const RESET_IS_TIMESTAMP_AFTER: u64 = 1_000_000_000;
+
+/// ethers' Http client only gives us the body. We need the status code and headers to see rate limits
+pub struct Web3HttpClient {
+    id: AtomicU64,
+    client: reqwest::Client,
+    url: Url,
+}
+
+#[derive(Debug)]
+pub enum HttpClientError {
+    /// the request failed before we got a response
+    Reqwest(reqwest::Error),
+    /// the server sent a jsonrpc error. the status and Retry-After are kept so that rate limits can be detected
+    JsonRpcError {
+        error: JsonRpcErrorData,
+        status: StatusCode,
+        retry_after: Option<Duration>,
+    },
+    /// the server sent a non-success status without a jsonrpc error (like a plain text 429)
+    Status {
+        status: StatusCode,
+        retry_after: Option<Duration>,
+        text: String,
+    },
+    /// the server sent something that is not valid json
+    SerdeJson {
+        err: serde_json::Error,
+        text: String,
+    },
+}
+
+#[derive(Serialize)]
+struct Request<'a, T> {
+    id: u64,
+    jsonrpc: &'a str,
+    method: &'a str,
+    #[serde(skip_serializing_if = "is_zst")]
+    params: T,
+}
+
+/// this matches ethers. `()` params are left out entirely
+fn is_zst<T>(_t: &T) -> bool {
+    std::mem::size_of::<T>() == 0
+}
+
+#[derive(Deserialize)]
+struct Response {
+    // TODO: check the id?
+    result: Option<Box<RawValue>>,
+    error: Option<JsonRpcErrorData>,
+}
+
+impl Web3HttpClient {
+    pub fn new(url: Url, client: reqwest::Client) -> Self {
+        Self {
+            id: AtomicU64::new(1),
+            client,
+            url,
+        }
+    }
+}
+
+impl Clone for Web3HttpClient {
+    fn clone(&self) -> Self {
+        Self::new(self.url.clone(), self.client.clone())
+    }
+}
+
+impl fmt::Debug for Web3HttpClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the url is excluded because it likely includes private information
+        f.debug_struct("Web3HttpClient").finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl JsonRpcClient for Web3HttpClient {
+    type Error = HttpClientError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, HttpClientError>
+    where
+        T: fmt::Debug + Serialize + Send + Sync,
+        R: DeserializeOwned,
+    {
+        let id = self.id.fetch_add(1, Ordering::Relaxed);
+
+        let payload = Request {
+            id,
+            jsonrpc: "2.0",
+            method,
+            params,
+        };
+
+        let response = self
+            .client
+            .post(self.url.as_ref())
+            .json(&payload)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let retry_after = retry_after_from_headers(response.headers(), Utc::now());
+
+        let body = response.bytes().await?;
+
+        let response: Response = match serde_json::from_slice(&body) {
+            Ok(x) => x,
+            Err(err) => {
+                let text = String::from_utf8_lossy(&body).to_string();
+
+                if status.is_success() {
+                    return Err(HttpClientError::SerdeJson { err, text });
+                } else {
+                    return Err(HttpClientError::Status {
+                        status,
+                        retry_after,
+                        text,
+                    });
+                }
+            }
+        };
+
+        if let Some(error) = response.error {
+            return Err(HttpClientError::JsonRpcError {
+                error,
+                status,
+                retry_after,
+            });
+        }
+
+        if !status.is_success() {
+            return Err(HttpClientError::Status {
+                status,
+                retry_after,
+                text: String::from_utf8_lossy(&body).to_string(),
+            });
+        }
+
+        // serde turns a "null" result into None
+        let result = response.result.as_ref().map(|x| x.get()).unwrap_or("null");
+
+        serde_json::from_str(result).map_err(|err| HttpClientError::SerdeJson {
+            err,
+            text: result.to_string(),
+        })
+    }
+}
+
+/// Check `Retry-After` and then some common provider-specific headers for how long we should wait.
+pub fn retry_after_from_headers(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    if let Some(retry_after) = headers.get(RETRY_AFTER).and_then(|x| x.to_str().ok()) {
+        let retry_after = retry_after.trim();
+
+        // Retry-After is either a number of seconds or an http date
+        if let Ok(seconds) = retry_after.parse::<u64>() {
+            return Some(Duration::from_secs(seconds));
+        }
+
+        if let Ok(retry_at) = DateTime::parse_from_rfc2822(retry_after) {
+            let wait = retry_at.with_timezone(&Utc) - now;
+
+            return Some(wait.to_std().unwrap_or_default());
+        }
+    }
+
+    for header_name in ["x-ratelimit-reset", "x-rate-limit-reset", "ratelimit-reset"] {
+        if let Some(reset) = headers
+            .get(header_name)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.trim().parse::<f64>().ok())
+        {
+            if !reset.is_finite() || reset < 0.0 {
+                continue;
+            }
+
+            let reset = if reset as u64 > RESET_IS_TIMESTAMP_AFTER {
+                // this is a unix timestamp
+                reset - now.timestamp() as f64
+            } else {
+                reset
+            };
+
+            // from_secs_f64 panics on values that don't fit in a Duration
+            let reset = reset.clamp(0.0, MAX_RATE_LIMIT_BACKOFF.as_secs_f64());
+
+            return Some(Duration::from_secs_f64(reset));
+        }
+    }
+
+    None
+}
+
+impl fmt::Display for HttpClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Reqwest(err) => write!(f, "{}", err),
+            Self::JsonRpcError { error, status, .. } => {
+                write!(f, "({}) {}: {}", status, error.code, error.message)
+            }
+            Self::Status { status, text, .. } => write!(f, "{}: {}", status, text),
+            Self::SerdeJson { err, text } => {
+                write!(f, "Deserialization Error: {}. Response: {}", err, text)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HttpClientError {}
+
+impl From<reqwest::Error> for HttpClientError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Reqwest(err)
+    }
+}
+
+impl From<HttpClientError> for ProviderError {
+    fn from(err: HttpClientError) -> Self {
+        match err {
+            HttpClientError::Reqwest(err) => ProviderError::HTTPError(err),
+            err => ProviderError::JsonRpcClientError(Box::new(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_retry_after_seconds() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("30"));
+
+        assert_eq!(
+            retry_after_from_headers(&headers, Utc::now()),
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn test_retry_after_date() {
+        let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:10 GMT"),
+        );
+
+        assert_eq!(
+            retry_after_from_headers(&headers, now),
+            Some(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn test_ratelimit_reset() {
+        let now = Utc::now();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("2"));
+
+        assert_eq!(
+            retry_after_from_headers(&headers, now),
+            Some(Duration::from_secs(2))
+        );
+
+        let reset_at = (now.timestamp() + 5).to_string();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-ratelimit-reset",
+            HeaderValue::from_str(&reset_at).unwrap(),
+        );
+
+        assert_eq!(
+            retry_after_from_headers(&headers, now),
+            Some(Duration::from_secs(5))
+        );
+
+        assert_eq!(retry_after_from_headers(&HeaderMap::new(), now), None);
+    }
+
+    #[test]
+    fn test_ratelimit_reset_out_of_range() {
+        let now = Utc::now();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("inf"));
+
+        assert_eq!(retry_after_from_headers(&headers, now), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("NaN"));
+
+        assert_eq!(retry_after_from_headers(&headers, now), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("1e30"));
+
+        assert_eq!(
+            retry_after_from_headers(&headers, now),
+            Some(MAX_RATE_LIMIT_BACKOFF)
+        );
+    }
+}
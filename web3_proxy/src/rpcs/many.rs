@@ -1,15 +1,19 @@
 ///! Load balanced communication with a group of web3 rpc providers
-use super::blockchain::{BlocksByHashCache, Web3ProxyBlock};
+use super::admin::Web3RpcAdminState;
+use super::blockchain::{ArcBlock, BlocksByHashCache, Web3ProxyBlock};
 use super::consensus::ConsensusWeb3Rpcs;
+use super::errors::BackendErrorDetails;
+use super::hedge::hedge_delay;
+use super::normalize::{BackendErrorClass, ClientKind, NormalizedError};
 use super::one::Web3Rpc;
+use super::quorum::{QuorumVotes, QUORUM_NOT_REACHED_CODE};
 use super::request::{OpenRequestHandle, OpenRequestResult, RequestRevertHandler};
 use crate::app::{flatten_handle, AnyhowJoinHandle, Web3ProxyApp};
-use crate::config::{BlockAndRpc, TxHashAndRpc, Web3RpcConfig};
+use crate::config::{AppConfig, BlockAndRpc, HedgeConfig, TxHashAndRpc, Web3RpcConfig};
 use crate::frontend::authorization::{Authorization, RequestMetadata};
 use crate::frontend::rpc_proxy_ws::ProxyMode;
 use crate::jsonrpc::{JsonRpcForwardedResponse, JsonRpcRequest};
 use crate::rpcs::transactions::TxStatus;
-use anyhow::Context;
 use counter::Counter;
 use derive_more::From;
 use ethers::prelude::{ProviderError, TxHash, H256, U64};
@@ -23,13 +27,14 @@ use migration::sea_orm::DatabaseConnection;
 use moka::future::{Cache, ConcurrentCacheExt};
 use ordered_float::OrderedFloat;
 use parking_lot::RwLock;
+use redis_rate_limiter::RedisPool;
 use serde::ser::{SerializeStruct, Serializer};
 use serde::Serialize;
 use serde_json::json;
 use serde_json::value::RawValue;
 use std::cmp::min_by_key;
 use std::collections::BTreeMap;
-use std::sync::atomic::{self, Ordering};
+use std::sync::atomic::{self, AtomicU32, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::{cmp, fmt};
 use thread_fast_rng::rand::seq::SliceRandom;
@@ -37,6 +42,11 @@ use tokio::sync::{broadcast, watch};
 use tokio::task;
 use tokio::time::{interval, sleep, sleep_until, Duration, Instant, MissedTickBehavior};
 
+/// blocks_by_hash is weighed by 1 + the number of transaction hashes in the block
+pub const BLOCKS_BY_HASH_CAPACITY: u64 = 1024 * 1024 * 1024;
+/// all block numbers are the same size. this is a count of entries
+pub const BLOCKS_BY_NUMBER_CAPACITY: u64 = 10_000;
+
 /// A collection of web3 connections. Sends requests either the current best server or all servers.
 #[derive(From)]
 pub struct Web3Rpcs {
@@ -64,13 +74,15 @@ pub struct Web3Rpcs {
     /// blocks on the heaviest chain
     pub(super) blocks_by_number: Cache<U64, H256, hashbrown::hash_map::DefaultHashBuilder>,
     /// the number of rpcs required to agree on consensus for the head block (thundering herd protection)
-    pub(super) min_head_rpcs: usize,
+    pub(super) min_head_rpcs: AtomicUsize,
     /// the soft limit required to agree on consensus for the head block. (thundering herd protection)
-    pub(super) min_sum_soft_limit: u32,
+    pub(super) min_sum_soft_limit: AtomicU32,
     /// how far behind the highest known block height we can be before we stop serving requests
-    pub(super) max_block_lag: Option<U64>,
+    pub(super) max_block_lag: RwLock<Option<U64>>,
     /// how old our consensus head block we can be before we stop serving requests
-    pub(super) max_block_age: Option<u64>,
+    pub(super) max_block_age: RwLock<Option<u64>>,
+    /// send a second copy of slow requests to another server
+    pub(super) hedge: RwLock<Option<HedgeConfig>>,
 }
 
 impl Web3Rpcs {
@@ -84,6 +96,7 @@ impl Web3Rpcs {
         max_block_lag: Option<U64>,
         min_head_rpcs: usize,
         min_sum_soft_limit: u32,
+        hedge: Option<HedgeConfig>,
         pending_transaction_cache: Cache<TxHash, TxStatus, hashbrown::hash_map::DefaultHashBuilder>,
         pending_tx_sender: Option<broadcast::Sender<TxStatus>>,
         watch_consensus_head_sender: Option<watch::Sender<Option<Web3ProxyBlock>>>,
@@ -147,7 +160,7 @@ impl Web3Rpcs {
         // TODO: how can we do the weigher better? need to know actual allocated size
         // TODO: limits from config
         let blocks_by_hash: BlocksByHashCache = Cache::builder()
-            .max_capacity(1024 * 1024 * 1024)
+            .max_capacity(BLOCKS_BY_HASH_CAPACITY)
             .weigher(|_k, v: &Web3ProxyBlock| {
                 1 + v.block.transactions.len().try_into().unwrap_or(u32::MAX)
             })
@@ -158,7 +171,7 @@ impl Web3Rpcs {
         // TODO: limits from config
         let blocks_by_number = Cache::builder()
             .time_to_idle(Duration::from_secs(600))
-            .max_capacity(10_000)
+            .max_capacity(BLOCKS_BY_NUMBER_CAPACITY)
             .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
 
         let (watch_consensus_rpcs_sender, _) = watch::channel(Default::default());
@@ -177,10 +190,11 @@ impl Web3Rpcs {
             pending_tx_id_receiver,
             blocks_by_hash,
             blocks_by_number,
-            min_sum_soft_limit,
-            min_head_rpcs,
-            max_block_age,
-            max_block_lag,
+            min_sum_soft_limit: min_sum_soft_limit.into(),
+            min_head_rpcs: min_head_rpcs.into(),
+            max_block_age: RwLock::new(max_block_age),
+            max_block_lag: RwLock::new(max_block_lag),
+            hedge: RwLock::new(hedge),
         });
 
         let authorization = Arc::new(Authorization::internal(db_conn)?);
@@ -198,18 +212,17 @@ impl Web3Rpcs {
         Ok((connections, handle))
     }
 
-    /// update the rpcs in this group
-    pub async fn apply_server_configs(
-        &self,
-        app: &Web3ProxyApp,
-        rpc_configs: HashMap<String, Web3RpcConfig>,
+    /// safety checks on a group's rpc configs. these don't need any connections
+    pub fn check_server_configs(
+        rpc_configs: &HashMap<String, Web3RpcConfig>,
+        min_head_rpcs: usize,
+        min_sum_soft_limit: u32,
     ) -> anyhow::Result<()> {
-        // safety checks
-        if rpc_configs.len() < app.config.min_synced_rpcs {
+        if rpc_configs.len() < min_head_rpcs {
             return Err(anyhow::anyhow!(
                 "Only {}/{} rpcs! Add more rpcs or reduce min_synced_rpcs.",
                 rpc_configs.len(),
-                app.config.min_synced_rpcs
+                min_head_rpcs
             ));
         }
 
@@ -218,16 +231,72 @@ impl Web3Rpcs {
         let sum_soft_limit = rpc_configs.values().fold(0, |acc, x| acc + x.soft_limit);
 
         // TODO: < is a bit dangerous, we should require a buffer
-        if sum_soft_limit < self.min_sum_soft_limit {
+        if sum_soft_limit < min_sum_soft_limit {
             return Err(anyhow::anyhow!(
                 "Only {}/{} soft limit! Add more rpcs, increase soft limits, or reduce min_sum_soft_limit.",
                 sum_soft_limit,
-                self.min_sum_soft_limit
+                min_sum_soft_limit
             ));
         }
 
-        // turn configs into connections (in parallel)
-        // TODO: move this into a helper function. then we can use it when configs change (will need a remove function too)
+        Ok(())
+    }
+
+    /// change the consensus and hedging settings of a running group
+    pub fn apply_app_config(&self, app_config: &AppConfig) {
+        self.min_head_rpcs
+            .store(app_config.min_synced_rpcs, Ordering::Release);
+        self.min_sum_soft_limit
+            .store(app_config.min_sum_soft_limit, Ordering::Release);
+        *self.max_block_age.write() = app_config.max_block_age;
+        *self.max_block_lag.write() = app_config.max_block_lag;
+        *self.hedge.write() = app_config.hedge.clone();
+    }
+
+    /// connect to the rpcs in a new config. nothing is served by them until they are passed to `swap_rpcs`.
+    /// the configs should have passed `check_server_configs` first
+    pub async fn connect_server_configs(
+        &self,
+        app: &Web3ProxyApp,
+        rpc_configs: HashMap<String, Web3RpcConfig>,
+    ) -> anyhow::Result<Vec<Arc<Web3Rpc>>> {
+        self.connect_rpcs(
+            app.config().chain_id,
+            app.db_conn(),
+            app.http_client.clone(),
+            app.vredis_pool.clone(),
+            rpc_configs,
+        )
+        .await
+    }
+
+    /// connect to the rpcs. an rpc with the same name as one of these is replaced once the new one is synced
+    pub async fn spawn_rpcs(
+        &self,
+        chain_id: u64,
+        db_conn: Option<DatabaseConnection>,
+        http_client: Option<reqwest::Client>,
+        vredis_pool: Option<RedisPool>,
+        rpc_configs: HashMap<String, Web3RpcConfig>,
+    ) -> anyhow::Result<()> {
+        let new_rpcs = self
+            .connect_rpcs(chain_id, db_conn, http_client, vredis_pool, rpc_configs)
+            .await?;
+
+        self.swap_rpcs(new_rpcs).await;
+
+        Ok(())
+    }
+
+    /// turn configs into connections (in parallel). if any of them can't be spawned, none of them are kept
+    async fn connect_rpcs(
+        &self,
+        chain_id: u64,
+        db_conn: Option<DatabaseConnection>,
+        http_client: Option<reqwest::Client>,
+        vredis_pool: Option<RedisPool>,
+        rpc_configs: HashMap<String, Web3RpcConfig>,
+    ) -> anyhow::Result<Vec<Arc<Web3Rpc>>> {
         let mut spawn_handles: FuturesUnordered<_> = rpc_configs
             .into_iter()
             .filter_map(|(server_name, server_config)| {
@@ -236,9 +305,9 @@ impl Web3Rpcs {
                     return None;
                 }
 
-                let db_conn = app.db_conn();
-                let http_client = app.http_client.clone();
-                let vredis_pool = app.vredis_pool.clone();
+                let db_conn = db_conn.clone();
+                let http_client = http_client.clone();
+                let vredis_pool = vredis_pool.clone();
 
                 let block_sender = if self.watch_consensus_head_sender.is_some() {
                     Some(self.block_sender.clone())
@@ -249,7 +318,6 @@ impl Web3Rpcs {
                 let pending_tx_id_sender = Some(self.pending_tx_id_sender.clone());
                 let blocks_by_hash = self.blocks_by_hash.clone();
                 let http_interval_sender = self.http_interval_sender.clone();
-                let chain_id = app.config.chain_id;
 
                 debug!("spawning {}", server_name);
 
@@ -274,26 +342,14 @@ impl Web3Rpcs {
             })
             .collect();
 
+        let mut new_rpcs = vec![];
+
         while let Some(x) = spawn_handles.next().await {
             match x {
                 Ok(Ok((rpc, _handle))) => {
                     // web3 connection worked
-                    let old_rpc = self.by_name.write().insert(rpc.name.clone(), rpc.clone());
-
-                    if let Some(old_rpc) = old_rpc {
-                        if old_rpc.head_block.read().is_some() {
-                            debug!("waiting for new {} to sync", rpc);
-                            // TODO: wait for connection to have a block by watching a channel instead of looping
-                            // TODO: maximum wait time or this could block things for too long
-                            while rpc.head_block.read().is_none() {
-                                sleep(Duration::from_millis(100)).await;
-                            }
-                        }
-
-                        old_rpc.disconnect().await.context("disconnect old rpc")?;
-                    }
-
                     // TODO: what should we do with the new handle? make sure error logs aren't dropped
+                    new_rpcs.push(rpc);
                 }
                 Ok(Err(err)) => {
                     // if we got an error here, the app can continue on
@@ -303,12 +359,53 @@ impl Web3Rpcs {
                 }
                 Err(err) => {
                     // something actually bad happened. exit with an error
+                    Self::disconnect_rpcs(new_rpcs).await;
+
                     return Err(err.into());
                 }
             }
         }
 
-        Ok(())
+        Ok(new_rpcs)
+    }
+
+    /// start serving from rpcs made by `connect_server_configs`
+    pub async fn swap_rpcs(&self, new_rpcs: Vec<Arc<Web3Rpc>>) {
+        for rpc in new_rpcs {
+            // keep the server out of rotation if an admin drained or disabled it
+            // tier and soft limit changes are not kept. the new config wins
+            if let Some(old_rpc) = self.get(&rpc.name) {
+                if !old_rpc.admin_state().is_serving() {
+                    *rpc.admin_state.write() = Web3RpcAdminState::Disabled;
+                }
+            }
+
+            let old_rpc = self.by_name.write().insert(rpc.name.clone(), rpc.clone());
+
+            if let Some(old_rpc) = old_rpc {
+                if old_rpc.head_block.read().is_some() {
+                    debug!("waiting for new {} to sync", rpc);
+                    // TODO: wait for connection to have a block by watching a channel instead of looping
+                    // TODO: maximum wait time or this could block things for too long
+                    while rpc.head_block.read().is_none() {
+                        sleep(Duration::from_millis(100)).await;
+                    }
+                }
+
+                if let Err(err) = old_rpc.disconnect().await {
+                    warn!("failed disconnecting old {}. err={:?}", old_rpc, err);
+                }
+            }
+        }
+    }
+
+    /// throw away rpcs made by `connect_server_configs` that will not be used
+    pub async fn disconnect_rpcs(rpcs: Vec<Arc<Web3Rpc>>) {
+        for rpc in rpcs {
+            if let Err(err) = rpc.disconnect().await {
+                warn!("failed disconnecting unused {}. err={:?}", rpc, err);
+            }
+        }
     }
 
     pub fn get(&self, conn_name: &str) -> Option<Arc<Web3Rpc>> {
@@ -324,7 +421,19 @@ impl Web3Rpcs {
     }
 
     pub fn min_head_rpcs(&self) -> usize {
-        self.min_head_rpcs
+        self.min_head_rpcs.load(Ordering::Acquire)
+    }
+
+    pub fn min_sum_soft_limit(&self) -> u32 {
+        self.min_sum_soft_limit.load(Ordering::Acquire)
+    }
+
+    pub fn max_block_age(&self) -> Option<u64> {
+        *self.max_block_age.read()
+    }
+
+    pub fn max_block_lag(&self) -> Option<U64> {
+        *self.max_block_lag.read()
     }
 
     /// subscribe to blocks and transactions from all the backend rpcs.
@@ -418,13 +527,16 @@ impl Web3Rpcs {
         let responses = active_request_handles
             .into_iter()
             .map(|active_request_handle| async move {
+                let client_kind = active_request_handle.clone_connection().client_kind();
+
                 let result: Result<Box<RawValue>, _> = active_request_handle
                     .request(method, &json!(&params), error_level.into(), None)
                     .await;
-                result
+
+                (client_kind, result)
             })
             .collect::<FuturesUnordered<_>>()
-            .collect::<Vec<Result<Box<RawValue>, ProviderError>>>()
+            .collect::<Vec<(ClientKind, Result<Box<RawValue>, ProviderError>)>>()
             .await;
 
         // TODO: Strings are not great keys, but we can't use RawValue or ProviderError as keys because they don't implement Hash or Eq
@@ -432,14 +544,21 @@ impl Web3Rpcs {
         let mut counts: Counter<String> = Counter::new();
         let mut any_ok_with_json_result = false;
         let mut any_ok_but_maybe_json_error = false;
-        for partial_response in responses {
+        for (client_kind, partial_response) in responses {
             if partial_response.is_ok() {
                 any_ok_with_json_result = true;
             }
 
-            let response =
+            let mut response =
                 JsonRpcForwardedResponse::try_from_response_result(partial_response, id.clone());
 
+            // normalize errors so that the same error from different clients is counted together
+            if let Ok(response) = response.as_mut() {
+                if let Some(error) = response.error.take() {
+                    response.error = Some(NormalizedError::new(client_kind, error).error);
+                }
+            }
+
             // TODO: better key?
             let s = format!("{:?}", response);
 
@@ -540,9 +659,12 @@ impl Web3Rpcs {
                     // need an old block. check all the rpcs. ignore rpcs that are still syncing
                     trace!("old block needed");
 
-                    let min_block_age =
-                        self.max_block_age.map(|x| head_block_age.saturating_sub(x));
-                    let min_sync_num = self.max_block_lag.map(|x| head_block_num.saturating_sub(x));
+                    let min_block_age = self
+                        .max_block_age()
+                        .map(|x| head_block_age.saturating_sub(x));
+                    let min_sync_num = self
+                        .max_block_lag()
+                        .map(|x| head_block_num.saturating_sub(x));
 
                     // TODO: cache this somehow?
                     // TODO: maybe have a helper on synced_connections? that way sum_soft_limits/min_synced_rpcs will be DRY
@@ -556,6 +678,9 @@ impl Web3Rpcs {
                             if skip.contains(x) {
                                 // we've already tried this server or have some other reason to skip it
                                 false
+                            } else if !x.admin_state().is_serving() {
+                                // an admin drained or disabled this server
+                                false
                             } else if max_block_needed
                                 .map(|max_block_needed| !x.has_block_data(max_block_needed))
                                 .unwrap_or(false)
@@ -596,7 +721,7 @@ impl Web3Rpcs {
                                 }
                             }
 
-                            let key = (x.tier, Some(*x_head_num));
+                            let key = (x.tier(), Some(*x_head_num));
 
                             m.entry(key).or_insert_with(Vec::new).push(x);
                         }
@@ -616,6 +741,11 @@ impl Web3Rpcs {
                             trace!("skipping: {}", x);
                             continue;
                         }
+                        if !x.admin_state().is_serving() {
+                            // the consensus rpcs haven't been updated yet
+                            trace!("not serving: {}", x);
+                            continue;
+                        }
                         trace!("not skipped!");
 
                         m.entry(key).or_insert_with(Vec::new).push(x.clone());
@@ -656,7 +786,7 @@ impl Web3Rpcs {
                 // TODO: cached key to save a read lock
                 // TODO: ties to the server with the smallest block_data_limit
                 let best_rpc = min_by_key(rpc_a, rpc_b, |x| {
-                    OrderedFloat(x.head_latency.read().value())
+                    OrderedFloat(x.head_latency.read().value() * x.shadow_health_penalty())
                 });
                 trace!("winner: {}", best_rpc);
 
@@ -777,6 +907,11 @@ impl Web3Rpcs {
                 continue;
             }
 
+            if !rpc.admin_state().is_serving() {
+                debug!("{} was taken out of rotation by an admin. skipping", rpc);
+                continue;
+            }
+
             if let Some(block_needed) = min_block_needed {
                 if !rpc.has_block_data(block_needed) {
                     warn!("{} is missing min_block_needed. skipping", rpc);
@@ -831,7 +966,7 @@ impl Web3Rpcs {
         max_block_needed: Option<&U64>,
     ) -> anyhow::Result<JsonRpcForwardedResponse> {
         let mut skip_rpcs = vec![];
-        let mut method_not_available_response = None;
+        let mut retryable_response = None;
 
         let mut watch_consensus_connections = self.watch_consensus_rpcs_sender.subscribe();
 
@@ -867,21 +1002,37 @@ impl Web3Rpcs {
                     }
 
                     // TODO: get the log percent from the user data
-                    let response_result = active_request_handle
-                        .request(
-                            &request.method,
-                            &json!(request.params),
-                            RequestRevertHandler::Save,
-                            None,
+                    let (rpc, response_result) = self
+                        .request_with_hedge(
+                            authorization,
+                            &request,
+                            request_metadata,
+                            active_request_handle,
+                            &mut skip_rpcs,
+                            min_block_needed,
+                            max_block_needed,
                         )
                         .await;
 
+                    // rate limits should be retried on other servers. the handle already set this server's backoff
+                    if let Some(rate_limit) = response_result
+                        .as_ref()
+                        .err()
+                        .and_then(|err| BackendErrorDetails::from_provider_error(err).rate_limit())
+                    {
+                        warn!(
+                            "rate limited ({:?}) by {}. retrying on another server",
+                            rate_limit.kind, rpc
+                        );
+                        continue;
+                    }
+
                     match JsonRpcForwardedResponse::try_from_response_result(
                         response_result,
                         request.id.clone(),
                     ) {
-                        Ok(response) => {
-                            if let Some(error) = &response.error.as_ref() {
+                        Ok(mut response) => {
+                            if let Some(error) = response.error.take() {
                                 // trace!(?response, "rpc error");
 
                                 if let Some(request_metadata) = request_metadata {
@@ -890,58 +1041,19 @@ impl Web3Rpcs {
                                         .store(true, Ordering::Release);
                                 }
 
-                                // some errors should be retried on other nodes
-                                let error_msg = error.message.as_str();
-
-                                // different providers do different codes. check all of them
-                                // TODO: there's probably more strings to add here
-                                let rate_limit_substrings = ["limit", "exceeded"];
-                                for rate_limit_substr in rate_limit_substrings {
-                                    if error_msg.contains(rate_limit_substr) {
-                                        warn!("rate limited by {}", skip_rpcs.last().unwrap());
-                                        continue;
-                                    }
-                                }
+                                // different clients say the same thing differently. give our users one shape
+                                let normalized = NormalizedError::new(rpc.client_kind(), error);
 
-                                match error.code {
-                                    -32000 => {
-                                        // TODO: regex?
-                                        let retry_prefixes = [
-                                            "header not found",
-                                            "header for hash not found",
-                                            "missing trie node",
-                                            "node not started",
-                                            "RPC timeout",
-                                        ];
-                                        for retry_prefix in retry_prefixes {
-                                            if error_msg.starts_with(retry_prefix) {
-                                                continue;
-                                            }
-                                        }
-                                    }
-                                    -32601 => {
-                                        let error_msg = error.message.as_str();
-
-                                        // sometimes a provider does not support all rpc methods
-                                        // we check other connections rather than returning the error
-                                        // but sometimes the method is something that is actually unsupported,
-                                        // so we save the response here to return it later
-
-                                        // some providers look like this
-                                        if error_msg.starts_with("the method")
-                                            && error_msg.ends_with("is not available")
-                                        {
-                                            method_not_available_response = Some(response);
-                                            continue;
-                                        }
-
-                                        // others look like this (this is the example in the official spec)
-                                        if error_msg == "Method not found" {
-                                            method_not_available_response = Some(response);
-                                            continue;
-                                        }
-                                    }
-                                    _ => {}
+                                response.error = Some(normalized.error);
+
+                                if normalized.class == BackendErrorClass::Retryable {
+                                    // some errors should be retried on other nodes
+                                    // but sometimes every node will give this error (like for an unsupported method),
+                                    // so we save the response here to return it later
+                                    trace!("retryable error from {}. trying another server", rpc);
+
+                                    retryable_response = Some(response);
+                                    continue;
                                 }
                             } else {
                                 // trace!(?response, "rpc success");
@@ -950,10 +1062,6 @@ impl Web3Rpcs {
                             return Ok(response);
                         }
                         Err(err) => {
-                            let rpc = skip_rpcs
-                                .last()
-                                .expect("there must have been a provider if we got an error");
-
                             // TODO: emit a stat. if a server is getting skipped a lot, something is not right
 
                             debug!(
@@ -1005,7 +1113,8 @@ impl Web3Rpcs {
                 .store(true, Ordering::Release);
         }
 
-        if let Some(r) = method_not_available_response {
+        if let Some(r) = retryable_response {
+            // every server that we tried gave an error that might have worked on another server
             // TODO: emit a stat for unsupported methods? it would be best to block them at the proxy instead of at the backend
             return Ok(r);
         }
@@ -1036,6 +1145,126 @@ impl Web3Rpcs {
         ))
     }
 
+    /// Send the request to the server behind `handle`.
+    /// If that server is slower than usual, send a copy to the next best server and use whichever answers first.
+    /// The slower request is cancelled.
+    #[allow(clippy::too_many_arguments)]
+    async fn request_with_hedge(
+        &self,
+        authorization: &Arc<Authorization>,
+        request: &JsonRpcRequest,
+        request_metadata: Option<&Arc<RequestMetadata>>,
+        handle: OpenRequestHandle,
+        skip_rpcs: &mut Vec<Arc<Web3Rpc>>,
+        min_block_needed: Option<&U64>,
+        max_block_needed: Option<&U64>,
+    ) -> (Arc<Web3Rpc>, Result<Box<RawValue>, ProviderError>) {
+        let rpc = handle.clone_connection();
+
+        let params = json!(request.params);
+
+        let delay = self.hedge.read().as_ref().and_then(|hedge| {
+            let p95 = rpc.request_latency.percentile(0.95);
+
+            hedge_delay(hedge, &request.method, rpc.tier(), p95)
+        });
+
+        let first = handle.request(&request.method, &params, RequestRevertHandler::Save, None);
+
+        let delay = match delay {
+            Some(x) => x,
+            None => return (rpc, first.await),
+        };
+
+        tokio::pin!(first);
+
+        tokio::select! {
+            response = &mut first => return (rpc, response),
+            _ = sleep(delay) => {}
+        }
+
+        // the first server is slow. find another one to race it
+        // request_metadata is not passed because not having a second server doesn't mean there are no servers
+        let hedge_handle = match self
+            .best_available_rpc(
+                authorization,
+                None,
+                skip_rpcs,
+                min_block_needed,
+                max_block_needed,
+            )
+            .await
+        {
+            Ok(OpenRequestResult::Handle(x)) => x,
+            _ => {
+                trace!("no server available to hedge {} on {}", request.method, rpc);
+                return (rpc, first.await);
+            }
+        };
+
+        let hedge_rpc = hedge_handle.clone_connection();
+
+        trace!(
+            "{} slower than {:?} on {}. hedging on {}",
+            request.method,
+            delay,
+            rpc,
+            hedge_rpc
+        );
+
+        skip_rpcs.push(hedge_rpc.clone());
+
+        if let Some(request_metadata) = request_metadata {
+            request_metadata
+                .backend_requests
+                .lock()
+                .push(hedge_rpc.clone());
+        }
+
+        let second =
+            hedge_handle.request(&request.method, &params, RequestRevertHandler::Save, None);
+
+        tokio::pin!(second);
+
+        // the first real answer wins. dropping the other future cancels that request
+        // transport errors and rate limits don't count as answers. wait for the other server instead
+        let (winner, response) = tokio::select! {
+            response = &mut first => {
+                if is_backend_answer(&response) {
+                    (rpc, response)
+                } else {
+                    (hedge_rpc, second.await)
+                }
+            }
+            response = &mut second => {
+                if is_backend_answer(&response) {
+                    (hedge_rpc, response)
+                } else {
+                    (rpc, first.await)
+                }
+            }
+        };
+
+        if let Some(request_metadata) = request_metadata {
+            request_metadata
+                .response_from_backup_rpc
+                .store(winner.backup, Ordering::Release);
+
+            // the last backend request is the one that gave the response
+            let mut backend_requests = request_metadata.backend_requests.lock();
+
+            if let Some(i) = backend_requests
+                .iter()
+                .rposition(|x| Arc::ptr_eq(x, &winner))
+            {
+                let x = backend_requests.remove(i);
+                backend_requests.push(x);
+            }
+        }
+
+        (winner, response)
+    }
+
     /// be sure there is a timeout on this or it might loop forever
     #[allow(clippy::too_many_arguments)]
     pub async fn try_send_all_synced_connections(
@@ -1138,6 +1367,230 @@ impl Web3Rpcs {
         }
     }
 
+    /// Send a request to a different server than the one that already answered it. Used for checking responses.
+    /// The server must agree that `block` is canonical. Returns None if no server is able to check.
+    pub async fn try_send_shadow_request(
+        &self,
+        authorization: &Arc<Authorization>,
+        request: &JsonRpcRequest,
+        skip_rpcs: &[Arc<Web3Rpc>],
+        block: &Web3ProxyBlock,
+    ) -> anyhow::Result<Option<(Arc<Web3Rpc>, JsonRpcForwardedResponse)>> {
+        let block_num = block.number();
+
+        let handle = match self
+            .best_available_rpc(
+                authorization,
+                None,
+                skip_rpcs,
+                Some(block_num),
+                Some(block_num),
+            )
+            .await?
+        {
+            OpenRequestResult::Handle(handle) => handle,
+            _ => return Ok(None),
+        };
+
+        let rpc = handle.clone_connection();
+
+        // pin the check to the same block. if this server has a different block at this height, comparing is pointless
+        if !handle_has_block(handle, block_num, block.hash()).await? {
+            trace!(
+                "{} has a different block at {}. skipping check",
+                rpc,
+                block_num
+            );
+            return Ok(None);
+        }
+
+        let handle = rpc
+            .wait_for_request_handle(authorization, Some(Duration::from_secs(1)), None)
+            .await?;
+
+        let response_result = handle
+            .request(
+                &request.method,
+                &json!(request.params),
+                Level::Debug.into(),
+                None,
+            )
+            .await;
+
+        let mut response = JsonRpcForwardedResponse::try_from_response_result(
+            response_result,
+            request.id.clone(),
+        )?;
+
+        if let Some(error) = response.error.take() {
+            response.error = Some(NormalizedError::new(rpc.client_kind(), error).error);
+        }
+
+        Ok(Some((rpc, response)))
+    }
+
+    /// Send the request to `n` synced servers that all have the same block.
+    /// Only return an answer if a majority of them agree on it.
+    pub async fn try_send_quorum_request(
+        &self,
+        authorization: &Arc<Authorization>,
+        request: JsonRpcRequest,
+        request_metadata: Option<&Arc<RequestMetadata>>,
+        min_block_needed: Option<&U64>,
+        max_block_needed: Option<&U64>,
+        n: usize,
+    ) -> anyhow::Result<JsonRpcForwardedResponse> {
+        // pin every server to the same block. otherwise a server that just got a new head would look like it disagrees
+        let (block_num, block_hash) = match min_block_needed {
+            Some(num) => (*num, self.block_hash(authorization, num).await?.0),
+            None => match self.head_block() {
+                Some(head_block) => (*head_block.number(), *head_block.hash()),
+                None => {
+                    if let Some(request_metadata) = request_metadata {
+                        request_metadata.no_servers.fetch_add(1, Ordering::Release);
+                    }
+
+                    return Ok(JsonRpcForwardedResponse::from_str(
+                        "no servers synced",
+                        Some(-32043),
+                        Some(request.id),
+                    ));
+                }
+            },
+        };
+
+        let max_block_needed = max_block_needed.unwrap_or(&block_num).max(&block_num);
+
+        let mut handles = vec![];
+        let mut skip_rpcs = vec![];
+
+        // TODO: wait for RetryAt like try_send_best_consensus_head_connection does?
+        while handles.len() < n {
+            match self
+                .best_available_rpc(
+                    authorization,
+                    request_metadata,
+                    &skip_rpcs,
+                    Some(&block_num),
+                    Some(max_block_needed),
+                )
+                .await?
+            {
+                OpenRequestResult::Handle(handle) => {
+                    skip_rpcs.push(handle.clone_connection());
+                    handles.push(handle);
+                }
+                _ => break,
+            }
+        }
+
+        if handles.len() < n {
+            if let Some(request_metadata) = request_metadata {
+                request_metadata.no_servers.fetch_add(1, Ordering::Release);
+                request_metadata
+                    .error_response
+                    .store(true, Ordering::Release);
+            }
+
+            return Ok(JsonRpcForwardedResponse::from_string(
+                format!(
+                    "only {} of {} servers needed for a quorum are available",
+                    handles.len(),
+                    n
+                ),
+                Some(QUORUM_NOT_REACHED_CODE),
+                Some(request.id),
+            ));
+        }
+
+        if let Some(request_metadata) = request_metadata {
+            // every server that we ask is a backend request that gets charged
+            request_metadata
+                .backend_requests
+                .lock()
+                .extend(skip_rpcs.iter().cloned());
+        }
+
+        let params = json!(request.params);
+
+        let answers = handles.into_iter().map(|handle| {
+            let params = &params;
+            let request = &request;
+
+            async move {
+                let rpc = handle.clone_connection();
+
+                let response = async {
+                    if !handle_has_block(handle, &block_num, &block_hash).await? {
+                        trace!("{} has a different block at {}", rpc, block_num);
+                        return Ok(None);
+                    }
+
+                    let handle = rpc
+                        .wait_for_request_handle(authorization, Some(Duration::from_secs(1)), None)
+                        .await?;
+
+                    let response_result = handle
+                        .request(&request.method, params, RequestRevertHandler::Save, None)
+                        .await;
+
+                    let mut response = JsonRpcForwardedResponse::try_from_response_result(
+                        response_result,
+                        request.id.clone(),
+                    )?;
+
+                    if let Some(error) = response.error.take() {
+                        let normalized = NormalizedError::new(rpc.client_kind(), error);
+
+                        if normalized.class == BackendErrorClass::Retryable {
+                            // a lagging or rate limited server doesn't get a vote
+                            return Ok(None);
+                        }
+
+                        response.error = Some(normalized.error);
+                    }
+
+                    anyhow::Ok(Some(response))
+                }
+                .await;
+
+                (rpc, response)
+            }
+        });
+
+        let mut votes = QuorumVotes::default();
+
+        for (rpc, response) in futures::future::join_all(answers).await {
+            match response {
+                Ok(Some(response)) => votes.vote(rpc.name.clone(), response),
+                Ok(None) => votes.abstain(rpc.name.clone()),
+                Err(err) => {
+                    debug!("quorum request to {} failed. err={:?}", rpc, err);
+                    votes.abstain(rpc.name.clone());
+                }
+            }
+        }
+
+        let response = votes.into_response(n, request.id);
+
+        if let Some(error) = response.error.as_ref() {
+            if error.code == QUORUM_NOT_REACHED_CODE {
+                warn!(
+                    "quorum not reached for {} at {}. {:?}",
+                    request.method, block_num, error.data
+                );
+            }
+
+            if let Some(request_metadata) = request_metadata {
+                request_metadata
+                    .error_response
+                    .store(true, Ordering::Release);
+            }
+        }
+
+        Ok(response)
+    }
+
     pub async fn try_proxy_connection(
         &self,
         authorization: &Arc<Authorization>,
@@ -1157,12 +1610,54 @@ impl Web3Rpcs {
                 )
                 .await
             }
+            ProxyMode::Quorum(n) => {
+                self.try_send_quorum_request(
+                    authorization,
+                    request,
+                    request_metadata,
+                    min_block_needed,
+                    max_block_needed,
+                    n,
+                )
+                .await
+            }
             ProxyMode::Fastest(x) => todo!("Fastest"),
             ProxyMode::Versus => todo!("Versus"),
         }
     }
 }
 
+/// a response that came from the server itself. transport errors and rate limits are not answers
+fn is_backend_answer(response: &Result<Box<RawValue>, ProviderError>) -> bool {
+    match response {
+        Ok(_) => true,
+        Err(err) => {
+            let details = BackendErrorDetails::from_provider_error(err);
+
+            details.code.is_some() && details.rate_limit().is_none()
+        }
+    }
+}
+
+/// check that the server behind `handle` has the given block at the given height
+/// TODO: use EIP-1898 blockHash params for the methods that support them. that would close the race here
+async fn handle_has_block(
+    handle: OpenRequestHandle,
+    block_num: &U64,
+    block_hash: &H256,
+) -> anyhow::Result<bool> {
+    let rpc_block: Option<ArcBlock> = handle
+        .request(
+            "eth_getBlockByNumber",
+            &json!((block_num, false)),
+            Level::Debug.into(),
+            None,
+        )
+        .await?;
+
+    Ok(rpc_block.and_then(|x| x.hash).as_ref() == Some(block_hash))
+}
+
 impl fmt::Debug for Web3Rpcs {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // TODO: the default formatter takes forever to write. this is too quiet though
@@ -1219,7 +1714,7 @@ fn rpc_sync_status_sort_key(x: &Arc<Web3Rpc>) -> (U64, u64, bool, OrderedFloat<f
             .map(|x| *x.number())
             .unwrap_or_default();
 
-    let tier = x.tier;
+    let tier = x.tier();
 
     // TODO: use request latency instead of head latency
     // TODO: have the latency decay automatically
@@ -1236,11 +1731,13 @@ fn rpc_sync_status_sort_key(x: &Arc<Web3Rpc>) -> (U64, u64, bool, OrderedFloat<f
     (reversed_head_block, tier, backup, peak_ewma)
 }
 
+#[cfg(test)]
 mod tests {
     // TODO: why is this allow needed? does tokio::test get in the way somehow?
     #![allow(unused_imports)]
     use super::*;
     use crate::rpcs::consensus::ConsensusFinder;
+    use crate::rpcs::mock::{spawn_web3_rpcs, MockChain, MockNode, MockNodeSettings};
     use crate::rpcs::{blockchain::Web3ProxyBlock, provider::Web3Provider};
     use ethers::types::{Block, U256};
     use log::{trace, LevelFilter};
@@ -1276,37 +1773,37 @@ mod tests {
         let mut rpcs: Vec<_> = [
             Web3Rpc {
                 name: "a".to_string(),
-                tier: 0,
+                tier: 0.into(),
                 head_block: RwLock::new(None),
                 ..Default::default()
             },
             Web3Rpc {
                 name: "b".to_string(),
-                tier: 0,
+                tier: 0.into(),
                 head_block: RwLock::new(blocks.get(1).cloned()),
                 ..Default::default()
             },
             Web3Rpc {
                 name: "c".to_string(),
-                tier: 0,
+                tier: 0.into(),
                 head_block: RwLock::new(blocks.get(2).cloned()),
                 ..Default::default()
             },
             Web3Rpc {
                 name: "d".to_string(),
-                tier: 1,
+                tier: 1.into(),
                 head_block: RwLock::new(None),
                 ..Default::default()
             },
             Web3Rpc {
                 name: "e".to_string(),
-                tier: 1,
+                tier: 1.into(),
                 head_block: RwLock::new(blocks.get(1).cloned()),
                 ..Default::default()
             },
             Web3Rpc {
                 name: "f".to_string(),
-                tier: 1,
+                tier: 1.into(),
                 head_block: RwLock::new(blocks.get(2).cloned()),
                 ..Default::default()
             },
@@ -1362,11 +1859,11 @@ mod tests {
 
         let head_rpc = Web3Rpc {
             name: "synced".to_string(),
-            soft_limit: 1_000,
+            soft_limit: 1_000.into(),
             automatic_block_limit: false,
             backup: false,
             block_data_limit: block_data_limit.into(),
-            tier: 0,
+            tier: 0.into(),
             head_block: RwLock::new(Some(head_block.clone())),
             provider: AsyncRwLock::new(Some(Arc::new(Web3Provider::Mock))),
             ..Default::default()
@@ -1374,11 +1871,11 @@ mod tests {
 
         let lagged_rpc = Web3Rpc {
             name: "lagged".to_string(),
-            soft_limit: 1_000,
+            soft_limit: 1_000.into(),
             automatic_block_limit: false,
             backup: false,
             block_data_limit: block_data_limit.into(),
-            tier: 0,
+            tier: 0.into(),
             head_block: RwLock::new(Some(lagged_block.clone())),
             provider: AsyncRwLock::new(Some(Arc::new(Web3Provider::Mock))),
             ..Default::default()
@@ -1421,11 +1918,12 @@ mod tests {
             blocks_by_number: Cache::builder()
                 .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
             // TODO: test max_block_age?
-            max_block_age: None,
+            max_block_age: RwLock::new(None),
             // TODO: test max_block_lag?
-            max_block_lag: None,
-            min_head_rpcs: 1,
-            min_sum_soft_limit: 1,
+            max_block_lag: RwLock::new(None),
+            min_head_rpcs: 1.into(),
+            min_sum_soft_limit: 1.into(),
+            hedge: RwLock::new(None),
         };
 
         let authorization = Arc::new(Authorization::internal(None).unwrap());
@@ -1565,11 +2063,11 @@ mod tests {
 
         let pruned_rpc = Web3Rpc {
             name: "pruned".to_string(),
-            soft_limit: 3_000,
+            soft_limit: 3_000.into(),
             automatic_block_limit: false,
             backup: false,
             block_data_limit: 64.into(),
-            tier: 1,
+            tier: 1.into(),
             head_block: RwLock::new(Some(head_block.clone())),
             provider: AsyncRwLock::new(Some(Arc::new(Web3Provider::Mock))),
             ..Default::default()
@@ -1577,11 +2075,11 @@ mod tests {
 
         let archive_rpc = Web3Rpc {
             name: "archive".to_string(),
-            soft_limit: 1_000,
+            soft_limit: 1_000.into(),
             automatic_block_limit: false,
             backup: false,
             block_data_limit: u64::MAX.into(),
-            tier: 2,
+            tier: 2.into(),
             head_block: RwLock::new(Some(head_block.clone())),
             provider: AsyncRwLock::new(Some(Arc::new(Web3Provider::Mock))),
             ..Default::default()
@@ -1621,10 +2119,11 @@ mod tests {
                 .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
             blocks_by_number: Cache::builder()
                 .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
-            min_head_rpcs: 1,
-            min_sum_soft_limit: 4_000,
-            max_block_age: None,
-            max_block_lag: None,
+            min_head_rpcs: 1.into(),
+            min_sum_soft_limit: 4_000.into(),
+            max_block_age: RwLock::new(None),
+            max_block_lag: RwLock::new(None),
+            hedge: RwLock::new(None),
         };
 
         let authorization = Arc::new(Authorization::internal(None).unwrap());
@@ -1722,11 +2221,11 @@ mod tests {
 
         let mock_geth = Web3Rpc {
             name: "mock_geth".to_string(),
-            soft_limit: 1_000,
+            soft_limit: 1_000.into(),
             automatic_block_limit: false,
             backup: false,
             block_data_limit: 64.into(),
-            tier: 0,
+            tier: 0.into(),
             head_block: RwLock::new(Some(block_1.clone())),
             provider: AsyncRwLock::new(Some(Arc::new(Web3Provider::Mock))),
             ..Default::default()
@@ -1734,11 +2233,11 @@ mod tests {
 
         let mock_erigon_archive = Web3Rpc {
             name: "mock_erigon_archive".to_string(),
-            soft_limit: 1_000,
+            soft_limit: 1_000.into(),
             automatic_block_limit: false,
             backup: false,
             block_data_limit: u64::MAX.into(),
-            tier: 1,
+            tier: 1.into(),
             head_block: RwLock::new(Some(block_2.clone())),
             provider: AsyncRwLock::new(Some(Arc::new(Web3Provider::Mock))),
             ..Default::default()
@@ -1781,10 +2280,11 @@ mod tests {
                 .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
             blocks_by_number: Cache::builder()
                 .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
-            min_head_rpcs: 1,
-            min_sum_soft_limit: 1_000,
-            max_block_age: None,
-            max_block_lag: None,
+            min_head_rpcs: 1.into(),
+            min_sum_soft_limit: 1_000.into(),
+            max_block_age: RwLock::new(None),
+            max_block_lag: RwLock::new(None),
+            hedge: RwLock::new(None),
         };
 
         let authorization = Arc::new(Authorization::internal(None).unwrap());
@@ -1851,4 +2351,146 @@ mod tests {
             "wrong number of connections"
         )
     }
+
+    #[tokio::test]
+    async fn test_rate_limited_server_is_skipped() {
+        let chain = MockChain::new(999_003);
+
+        chain.mine_n(5);
+
+        let a = MockNode::spawn(&chain).await.unwrap();
+        let b = MockNode::spawn(&chain).await.unwrap();
+
+        let (rpcs, _) = spawn_web3_rpcs(&chain, &[("a", &a), ("b", &b)], 1, 100)
+            .await
+            .unwrap();
+
+        a.update_settings(|x| x.rate_limited = true);
+
+        let a_requests = a.requests();
+
+        let authorization = Arc::new(Authorization::internal(None).unwrap());
+
+        for _ in 0..5 {
+            let request = JsonRpcRequest {
+                jsonrpc: "2.0".to_string(),
+                id: RawValue::from_string("1".to_string()).unwrap(),
+                method: "eth_blockNumber".to_string(),
+                params: None,
+            };
+
+            let response = rpcs
+                .try_send_best_consensus_head_connection(&authorization, request, None, None, None)
+                .await
+                .unwrap();
+
+            assert_eq!(response.result.unwrap().get(), r#""0x5""#);
+        }
+
+        // the first rate limit backs the server off. it isn't tried again
+        assert!(a.requests() <= a_requests + 1);
+    }
+
+    /// Break whichever server gets the request until a request is sent to a broken server first.
+    /// Returns the names of the servers that request was sent to
+    async fn send_until_failover(
+        rpcs: &Web3Rpcs,
+        nodes: &[(&str, &Arc<MockNode>)],
+        method: &str,
+        expected: &str,
+        set_broken: impl Fn(&mut MockNodeSettings, bool),
+    ) -> Vec<String> {
+        let authorization = Arc::new(Authorization::internal(None).unwrap());
+
+        for _ in 0..10 {
+            let request = JsonRpcRequest {
+                jsonrpc: "2.0".to_string(),
+                id: RawValue::from_string("1".to_string()).unwrap(),
+                method: method.to_string(),
+                params: None,
+            };
+
+            let request_metadata = Arc::new(RequestMetadata::new(60, 0).unwrap());
+
+            let response = rpcs
+                .try_send_best_consensus_head_connection(
+                    &authorization,
+                    request,
+                    Some(&request_metadata),
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.result.unwrap().get(), expected);
+
+            let tried: Vec<_> = request_metadata
+                .backend_requests
+                .lock()
+                .iter()
+                .map(|x| x.name.clone())
+                .collect();
+
+            if tried.len() > 1 {
+                return tried;
+            }
+
+            // the servers are sorted by latency, so the same one is probably picked next time
+            for (name, node) in nodes {
+                node.update_settings(|x| set_broken(x, *name == tried[0]));
+            }
+        }
+
+        panic!("no request was sent to a broken server first");
+    }
+
+    #[tokio::test]
+    async fn test_error_fails_over_to_another_server() {
+        let chain = MockChain::new(999_004);
+
+        chain.mine_n(5);
+
+        let a = MockNode::spawn(&chain).await.unwrap();
+        let b = MockNode::spawn(&chain).await.unwrap();
+
+        let nodes = [("a", &a), ("b", &b)];
+
+        let (rpcs, _) = spawn_web3_rpcs(&chain, &nodes, 1, 100).await.unwrap();
+
+        let tried = send_until_failover(&rpcs, &nodes, "eth_call", r#""0x""#, |x, broken| {
+            if broken {
+                x.unsupported_methods.insert("eth_call".to_string());
+            } else {
+                x.unsupported_methods.clear();
+            }
+        })
+        .await;
+
+        assert_eq!(tried.len(), 2);
+        assert_ne!(tried[0], tried[1]);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_request_is_retried() {
+        let chain = MockChain::new(999_005);
+
+        chain.mine_n(5);
+
+        let a = MockNode::spawn(&chain).await.unwrap();
+        let b = MockNode::spawn(&chain).await.unwrap();
+
+        let nodes = [("a", &a), ("b", &b)];
+
+        let (rpcs, _) = spawn_web3_rpcs(&chain, &nodes, 1, 100).await.unwrap();
+
+        let tried =
+            send_until_failover(&rpcs, &nodes, "eth_blockNumber", r#""0x5""#, |x, broken| {
+                x.rate_limited = broken
+            })
+            .await;
+
+        assert_eq!(tried.len(), 2);
+        assert_ne!(tried[0], tried[1]);
+    }
 }
@@ -0,0 +1,747 @@
+///! A scriptable JSON-RPC node for tests. It serves HTTP and websockets on localhost so that the real clients are used.
+///! Tests can mine blocks, reorg, and make a node lag, slow down, rate limit, prune state, or reject methods.
+use super::blockchain::{ArcBlock, Web3ProxyBlock};
+use super::many::Web3Rpcs;
+use crate::config::Web3RpcConfig;
+use anyhow::Context;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::http::header::RETRY_AFTER;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Extension, Json, Router};
+use ethers::prelude::{Block, TxHash, H256, U64};
+use ethers::utils::keccak256;
+use futures::future::{join_all, AbortHandle, Abortable};
+use futures::{SinkExt, StreamExt};
+use hashbrown::{HashMap, HashSet};
+use moka::future::Cache;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout, Duration};
+
+const METHOD_NOT_FOUND_CODE: i64 = -32601;
+const INVALID_PARAMS_CODE: i64 = -32602;
+const SERVER_ERROR_CODE: i64 = -32000;
+const LIMIT_EXCEEDED_CODE: i64 = -32005;
+
+type MockResult = Result<Value, (i64, String)>;
+
+#[derive(Debug, Default)]
+struct MockChainState {
+    /// the canonical chain. index is the block number
+    blocks: Vec<ArcBlock>,
+    /// includes blocks that were removed by reorgs
+    by_hash: HashMap<H256, ArcBlock>,
+    /// used to give every block a different hash
+    mined: u64,
+}
+
+/// Blocks shared by a group of mock nodes
+#[derive(Clone)]
+pub struct MockChain {
+    chain_id: u64,
+    state: Arc<watch::Sender<MockChainState>>,
+}
+
+impl MockChain {
+    /// A chain with only a genesis block
+    pub fn new(chain_id: u64) -> Self {
+        let (state, _) = watch::channel(MockChainState::default());
+
+        let chain = Self {
+            chain_id,
+            state: Arc::new(state),
+        };
+
+        chain.mine();
+
+        chain
+    }
+
+    pub fn chain_id(&self) -> u64 {
+        self.chain_id
+    }
+
+    pub fn head(&self) -> ArcBlock {
+        self.state
+            .borrow()
+            .blocks
+            .last()
+            .cloned()
+            .expect("there is always a genesis block")
+    }
+
+    pub fn head_num(&self) -> u64 {
+        self.state.borrow().blocks.len() as u64 - 1
+    }
+
+    /// Add a block on top of the head
+    pub fn mine(&self) -> ArcBlock {
+        let chain_id = self.chain_id;
+
+        let mut block = None;
+
+        self.state.send_modify(|state| {
+            let new_block = new_block(chain_id, state.mined, state.blocks.last());
+
+            state.mined += 1;
+            state.blocks.push(new_block.clone());
+            state
+                .by_hash
+                .insert(new_block.hash.unwrap(), new_block.clone());
+
+            block = Some(new_block);
+        });
+
+        block.unwrap()
+    }
+
+    /// Add `n` blocks and return the new head. `mine_n(0)` only returns the head
+    pub fn mine_n(&self, n: u64) -> ArcBlock {
+        for _ in 0..n {
+            self.mine();
+        }
+
+        self.head()
+    }
+
+    /// Replace the last `depth` blocks and then mine one more so that the new chain is the longest
+    pub fn reorg(&self, depth: u64) -> ArcBlock {
+        self.state.send_modify(|state| {
+            let keep = state.blocks.len().saturating_sub(depth as usize).max(1);
+
+            state.blocks.truncate(keep);
+        });
+
+        self.mine_n(depth + 1)
+    }
+
+    fn subscribe(&self) -> watch::Receiver<MockChainState> {
+        self.state.subscribe()
+    }
+}
+
+fn new_block(chain_id: u64, mined: u64, parent: Option<&ArcBlock>) -> ArcBlock {
+    let number = parent
+        .map(|x| x.number.unwrap() + 1)
+        .unwrap_or_else(U64::zero);
+
+    let hash = H256::from(keccak256(format!("{}:{}", chain_id, mined)));
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("there should always be time")
+        .as_secs();
+
+    let block: Block<TxHash> = Block {
+        hash: Some(hash),
+        parent_hash: parent.and_then(|x| x.hash).unwrap_or_default(),
+        number: Some(number),
+        timestamp: timestamp.into(),
+        ..Default::default()
+    };
+
+    Arc::new(block)
+}
+
+/// How a mock node misbehaves. The default is a healthy archive node
+#[derive(Clone, Debug, Default)]
+pub struct MockNodeSettings {
+    /// how many blocks behind the chain's head this node is
+    pub lag: u64,
+    /// how long every request takes
+    pub latency: Duration,
+    /// every request gets a rate limit error
+    pub rate_limited: bool,
+    /// how many blocks of state the node keeps. None is an archive node
+    pub archive_depth: Option<u64>,
+    /// these methods get "method not found"
+    pub unsupported_methods: HashSet<String>,
+}
+
+pub struct MockNode {
+    chain: MockChain,
+    settings: watch::Sender<MockNodeSettings>,
+    requests: AtomicU64,
+    next_subscription_id: AtomicU64,
+    pub http_url: String,
+    pub ws_url: String,
+}
+
+impl MockNode {
+    /// Start serving on a random localhost port. The server runs until the test's runtime stops
+    pub async fn spawn(chain: &MockChain) -> anyhow::Result<Arc<Self>> {
+        // the router needs the node and the node needs the port, so bind before building either
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+
+        let addr = listener.local_addr()?;
+
+        let (settings, _) = watch::channel(MockNodeSettings::default());
+
+        let node = Arc::new(Self {
+            chain: chain.clone(),
+            settings,
+            requests: AtomicU64::new(0),
+            next_subscription_id: AtomicU64::new(1),
+            http_url: format!("http://{}", addr),
+            ws_url: format!("ws://{}", addr),
+        });
+
+        let app = Router::new()
+            .route("/", post(http_handler).get(ws_handler))
+            .layer(Extension(node.clone()));
+
+        let server = axum::Server::from_tcp(listener)?.serve(app.into_make_service());
+
+        tokio::spawn(server);
+
+        Ok(node)
+    }
+
+    pub fn settings(&self) -> MockNodeSettings {
+        self.settings.borrow().clone()
+    }
+
+    pub fn update_settings(&self, f: impl FnOnce(&mut MockNodeSettings)) {
+        self.settings.send_modify(f);
+    }
+
+    /// how many requests this node has received
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Acquire)
+    }
+
+    /// A config that uses the websocket. Remove `ws_url` to test polling over http
+    pub fn config(&self) -> Web3RpcConfig {
+        Web3RpcConfig {
+            http_url: Some(self.http_url.clone()),
+            ws_url: Some(self.ws_url.clone()),
+            soft_limit: 100,
+            ..Default::default()
+        }
+    }
+
+    /// the block this node thinks is the head
+    pub fn head(&self) -> ArcBlock {
+        let lag = self.settings.borrow().lag;
+
+        let state = self.chain.state.borrow();
+
+        let num = (state.blocks.len() as u64 - 1).saturating_sub(lag);
+
+        state.blocks[num as usize].clone()
+    }
+
+    async fn handle_request(&self, request: Value) -> Value {
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+
+        let method = request
+            .get("method")
+            .and_then(|x| x.as_str())
+            .unwrap_or_default();
+
+        let params = match request.get("params") {
+            Some(Value::Array(x)) => x.clone(),
+            _ => vec![],
+        };
+
+        self.requests.fetch_add(1, Ordering::AcqRel);
+
+        let settings = self.settings();
+
+        if !settings.latency.is_zero() {
+            sleep(settings.latency).await;
+        }
+
+        let result = if settings.rate_limited {
+            Err((
+                LIMIT_EXCEEDED_CODE,
+                "rate limit exceeded. too many requests".to_string(),
+            ))
+        } else {
+            self.call(&settings, method, &params)
+        };
+
+        jsonrpc_response(id, result)
+    }
+
+    fn call(&self, settings: &MockNodeSettings, method: &str, params: &[Value]) -> MockResult {
+        if settings.unsupported_methods.contains(method) {
+            return Err(method_not_found(method));
+        }
+
+        let head = self.head();
+        let head_num = head.number.unwrap().as_u64();
+
+        match method {
+            "eth_chainId" => Ok(json!(U64::from(self.chain.chain_id))),
+            "net_version" => Ok(json!(self.chain.chain_id.to_string())),
+            "web3_clientVersion" => Ok(json!("MockNode/v1.0.0")),
+            "eth_syncing" => Ok(json!(false)),
+            "eth_blockNumber" => Ok(json!(U64::from(head_num))),
+            "eth_getBlockByNumber" => {
+                let num = block_num_param(params.first(), head_num)?;
+
+                if num > head_num {
+                    return Ok(Value::Null);
+                }
+
+                let block = self.chain.state.borrow().blocks.get(num as usize).cloned();
+
+                Ok(json!(block.as_deref()))
+            }
+            "eth_getBlockByHash" => {
+                let hash: H256 = params
+                    .first()
+                    .and_then(|x| serde_json::from_value(x.clone()).ok())
+                    .ok_or_else(|| invalid_params("block hash"))?;
+
+                let block = self
+                    .chain
+                    .state
+                    .borrow()
+                    .by_hash
+                    .get(&hash)
+                    .filter(|x| x.number.unwrap().as_u64() <= head_num)
+                    .cloned();
+
+                Ok(json!(block.as_deref()))
+            }
+            "eth_getTransactionByHash" | "eth_getTransactionReceipt" => Ok(Value::Null),
+            "eth_getBalance" | "eth_getCode" | "eth_getTransactionCount" | "eth_call" => {
+                self.check_state(settings, params.get(1), head_num)?;
+
+                match method {
+                    "eth_getBalance" | "eth_getTransactionCount" => Ok(json!("0x0")),
+                    _ => Ok(json!("0x")),
+                }
+            }
+            "eth_getStorageAt" => {
+                self.check_state(settings, params.get(2), head_num)?;
+
+                Ok(json!(H256::zero()))
+            }
+            _ => Err(method_not_found(method)),
+        }
+    }
+
+    /// errors like a real node does if the block is in the future or has been pruned
+    fn check_state(
+        &self,
+        settings: &MockNodeSettings,
+        block_param: Option<&Value>,
+        head_num: u64,
+    ) -> Result<(), (i64, String)> {
+        let num = block_num_param(block_param, head_num)?;
+
+        if num > head_num {
+            return Err((SERVER_ERROR_CODE, "header not found".to_string()));
+        }
+
+        if let Some(archive_depth) = settings.archive_depth {
+            if head_num - num > archive_depth {
+                return Err((
+                    SERVER_ERROR_CODE,
+                    format!("missing trie node {:?} (path )", H256::zero()),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn serve_socket(self: Arc<Self>, socket: WebSocket) {
+        let (mut ws_tx, mut ws_rx) = socket.split();
+
+        let (response_sender, response_receiver) = flume::unbounded::<Message>();
+
+        tokio::spawn(async move {
+            while let Ok(msg) = response_receiver.recv_async().await {
+                if ws_tx.send(msg).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let subscriptions = Arc::new(Mutex::new(HashMap::<String, AbortHandle>::new()));
+
+        while let Some(Ok(msg)) = ws_rx.next().await {
+            let payload = match msg {
+                Message::Text(x) => x,
+                Message::Close(_) => break,
+                _ => continue,
+            };
+
+            let request: Value = match serde_json::from_str(&payload) {
+                Ok(x) => x,
+                Err(err) => {
+                    let response = jsonrpc_response(
+                        Value::Null,
+                        Err((INVALID_PARAMS_CODE, format!("invalid json: {}", err))),
+                    );
+
+                    let _ = response_sender.send(Message::Text(response.to_string()));
+                    continue;
+                }
+            };
+
+            // spawn so that slow requests don't block the rest of the socket
+            let node = self.clone();
+            let response_sender = response_sender.clone();
+            let subscriptions = subscriptions.clone();
+
+            tokio::spawn(async move {
+                let response = match request.get("method").and_then(|x| x.as_str()) {
+                    Some("eth_subscribe") if !node.settings().rate_limited => {
+                        node.subscribe(request, &response_sender, &subscriptions)
+                    }
+                    Some("eth_unsubscribe") => {
+                        let id = request.get("id").cloned().unwrap_or(Value::Null);
+
+                        let subscription_id = request
+                            .get("params")
+                            .and_then(|x| x.get(0))
+                            .and_then(|x| x.as_str())
+                            .unwrap_or_default();
+
+                        let found = match subscriptions.lock().remove(subscription_id) {
+                            Some(handle) => {
+                                handle.abort();
+                                true
+                            }
+                            None => false,
+                        };
+
+                        jsonrpc_response(id, Ok(json!(found)))
+                    }
+                    _ => node.handle_request(request).await,
+                };
+
+                let _ = response_sender.send(Message::Text(response.to_string()));
+            });
+        }
+
+        for (_, handle) in subscriptions.lock().drain() {
+            handle.abort();
+        }
+    }
+
+    fn subscribe(
+        self: &Arc<Self>,
+        request: Value,
+        response_sender: &flume::Sender<Message>,
+        subscriptions: &Mutex<HashMap<String, AbortHandle>>,
+    ) -> Value {
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+
+        self.requests.fetch_add(1, Ordering::AcqRel);
+
+        let kind = request
+            .get("params")
+            .and_then(|x| x.get(0))
+            .and_then(|x| x.as_str())
+            .unwrap_or_default()
+            .to_string();
+
+        let subscription_id = format!(
+            "{:#x}",
+            self.next_subscription_id.fetch_add(1, Ordering::AcqRel)
+        );
+
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+
+        subscriptions
+            .lock()
+            .insert(subscription_id.clone(), abort_handle);
+
+        // only new heads are sent. other kinds of subscriptions are accepted, but nothing is ever sent on them
+        if kind == "newHeads" {
+            let f = self
+                .clone()
+                .send_new_heads(subscription_id.clone(), response_sender.clone());
+
+            tokio::spawn(Abortable::new(f, abort_registration));
+        }
+
+        jsonrpc_response(id, Ok(json!(subscription_id)))
+    }
+
+    /// send the head every time it changes. like a real node, the current head is not sent when subscribing
+    async fn send_new_heads(
+        self: Arc<Self>,
+        subscription_id: String,
+        response_sender: flume::Sender<Message>,
+    ) {
+        let mut chain_receiver = self.chain.subscribe();
+        let mut settings_receiver = self.settings.subscribe();
+
+        let mut last_hash = self.head().hash;
+
+        loop {
+            tokio::select! {
+                x = chain_receiver.changed() => if x.is_err() { break },
+                x = settings_receiver.changed() => if x.is_err() { break },
+            }
+
+            let head = self.head();
+
+            if head.hash == last_hash {
+                continue;
+            }
+
+            last_hash = head.hash;
+
+            let notification = json!({
+                "jsonrpc": "2.0",
+                "method": "eth_subscription",
+                "params": {
+                    "subscription": subscription_id,
+                    "result": &*head,
+                },
+            });
+
+            if response_sender
+                .send(Message::Text(notification.to_string()))
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+}
+
+async fn http_handler(
+    Extension(node): Extension<Arc<MockNode>>,
+    Json(payload): Json<Value>,
+) -> Response {
+    if node.settings().rate_limited {
+        node.requests.fetch_add(1, Ordering::AcqRel);
+
+        let id = payload.get("id").cloned().unwrap_or(Value::Null);
+
+        let response = jsonrpc_response(id, Err((429, "too many requests".to_string())));
+
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, "1")],
+            Json(response),
+        )
+            .into_response();
+    }
+
+    match payload {
+        Value::Array(requests) => {
+            let responses = join_all(requests.into_iter().map(|x| node.handle_request(x))).await;
+
+            Json(responses).into_response()
+        }
+        request => Json(node.handle_request(request).await).into_response(),
+    }
+}
+
+async fn ws_handler(
+    Extension(node): Extension<Arc<MockNode>>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| node.serve_socket(socket))
+}
+
+fn jsonrpc_response(id: Value, result: MockResult) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        }),
+    }
+}
+
+fn method_not_found(method: &str) -> (i64, String) {
+    (
+        METHOD_NOT_FOUND_CODE,
+        format!("the method {} does not exist/is not available", method),
+    )
+}
+
+fn invalid_params(name: &str) -> (i64, String) {
+    (INVALID_PARAMS_CODE, format!("invalid {}", name))
+}
+
+/// a missing block param means "latest"
+fn block_num_param(param: Option<&Value>, head_num: u64) -> Result<u64, (i64, String)> {
+    match param {
+        None | Some(Value::Null) => Ok(head_num),
+        Some(Value::String(x)) => match x.as_str() {
+            "latest" | "pending" | "safe" | "finalized" => Ok(head_num),
+            "earliest" => Ok(0),
+            _ => serde_json::from_value::<U64>(json!(x))
+                .map(|x| x.as_u64())
+                .map_err(|_| invalid_params("block number")),
+        },
+        Some(_) => Err(invalid_params("block number")),
+    }
+}
+
+/// Connect a new group of rpcs to some mock nodes. Returns once all of them agree on the chain's head.
+pub async fn spawn_web3_rpcs(
+    chain: &MockChain,
+    nodes: &[(&str, &Arc<MockNode>)],
+    min_head_rpcs: usize,
+    min_sum_soft_limit: u32,
+) -> anyhow::Result<(Arc<Web3Rpcs>, watch::Receiver<Option<Web3ProxyBlock>>)> {
+    let (watch_consensus_head_sender, mut watch_consensus_head_receiver) = watch::channel(None);
+
+    let pending_transaction_cache =
+        Cache::builder().build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+
+    let (rpcs, _handle) = Web3Rpcs::spawn(
+        chain.chain_id(),
+        None,
+        Some(reqwest::Client::new()),
+        None,
+        None,
+        min_head_rpcs,
+        min_sum_soft_limit,
+        None,
+        pending_transaction_cache,
+        None,
+        Some(watch_consensus_head_sender),
+    )
+    .await?;
+
+    let rpc_configs = nodes
+        .iter()
+        .map(|(name, node)| (name.to_string(), node.config()))
+        .collect();
+
+    rpcs.spawn_rpcs(
+        chain.chain_id(),
+        None,
+        Some(reqwest::Client::new()),
+        None,
+        rpc_configs,
+    )
+    .await?;
+
+    wait_for_head(&mut watch_consensus_head_receiver, &chain.head()).await?;
+
+    Ok((rpcs, watch_consensus_head_receiver))
+}
+
+/// Wait for the consensus head to be the given block. Gives up after 10 seconds
+pub async fn wait_for_head(
+    watch_consensus_head_receiver: &mut watch::Receiver<Option<Web3ProxyBlock>>,
+    block: &ArcBlock,
+) -> anyhow::Result<()> {
+    let f = async {
+        loop {
+            if let Some(head) = watch_consensus_head_receiver.borrow_and_update().as_ref() {
+                if Some(*head.hash()) == block.hash {
+                    return Ok::<_, anyhow::Error>(());
+                }
+            }
+
+            watch_consensus_head_receiver.changed().await?;
+        }
+    };
+
+    timeout(Duration::from_secs(10), f)
+        .await
+        .with_context(|| format!("timeout waiting for head {:?}", block.number))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_node_http() {
+        let chain = MockChain::new(999_001);
+
+        chain.mine_n(10);
+
+        let node = MockNode::spawn(&chain).await.unwrap();
+
+        node.update_settings(|x| {
+            x.lag = 2;
+            x.archive_depth = Some(5);
+        });
+
+        let client = reqwest::Client::new();
+
+        let request = |method: &str, params: Value| {
+            client
+                .post(&node.http_url)
+                .json(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }))
+                .send()
+        };
+
+        let response: Value = request("eth_blockNumber", json!([]))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        assert_eq!(response["result"], json!("0x8"));
+
+        let response: Value = request("eth_getCode", json!(["0x00", "0x3"]))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        assert_eq!(response["result"], json!("0x"));
+
+        let response: Value = request("eth_getCode", json!(["0x00", "0x2"]))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        assert!(response["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("missing trie node"));
+
+        node.update_settings(|x| x.rate_limited = true);
+
+        let response = request("eth_blockNumber", json!([])).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        assert_eq!(node.requests(), 4);
+    }
+
+    #[test]
+    fn test_mock_chain_reorg() {
+        let chain = MockChain::new(999_001);
+
+        let old_head = chain.mine_n(5);
+
+        let new_head = chain.reorg(2);
+
+        assert_eq!(new_head.number, Some(6.into()));
+
+        // mining nothing is a no-op
+        assert_eq!(chain.mine_n(0), new_head);
+        assert_eq!(chain.head_num(), 6);
+
+        let state = chain.state.borrow();
+
+        assert_ne!(state.blocks[5].hash, old_head.hash);
+        assert_eq!(state.blocks[4].parent_hash, state.blocks[3].hash.unwrap());
+
+        // orphaned blocks can still be found by hash
+        assert!(state.by_hash.contains_key(&old_head.hash.unwrap()));
+    }
+}
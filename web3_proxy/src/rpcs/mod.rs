@@ -1,8 +1,17 @@
 // TODO: all pub, or export useful things here instead?
+pub mod admin;
+pub mod block_data_limit;
 pub mod blockchain;
 pub mod consensus;
+pub mod errors;
+pub mod hedge;
+pub mod http;
 pub mod many;
+#[cfg(test)]
+pub mod mock;
+pub mod normalize;
 pub mod one;
 pub mod provider;
+pub mod quorum;
 pub mod request;
 pub mod transactions;
@@ -0,0 +1,484 @@
+///! Different node clients say the same thing in different ways. Rewrite their errors into one shape for our users.
+use super::errors::BackendErrorDetails;
+use crate::jsonrpc::JsonRpcErrorData;
+use ethers::abi::{self, ParamType, Token};
+use ethers::types::Bytes;
+use serde::Serialize;
+use serde_json::Value;
+
+/// the selector for solidity's `Error(string)`
+const ERROR_STRING_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+
+/// The node software behind a backend. Detected with `web3_clientVersion`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientKind {
+    /// geth and its forks (like bor)
+    Geth,
+    Erigon,
+    Nethermind,
+    Besu,
+    /// load balanced providers often hide their client version. check every client's errors
+    #[default]
+    Unknown,
+}
+
+impl ClientKind {
+    /// parse a `web3_clientVersion` like "Geth/v1.11.5-stable/linux-amd64/go1.20.2"
+    pub fn from_client_version(client_version: &str) -> Self {
+        let name = client_version
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+
+        match name.as_str() {
+            "geth" | "bor" => Self::Geth,
+            "erigon" => Self::Erigon,
+            "nethermind" => Self::Nethermind,
+            "besu" => Self::Besu,
+            _ => Self::Unknown,
+        }
+    }
+
+    fn is(self, kind: Self) -> bool {
+        self == kind || self == Self::Unknown
+    }
+}
+
+/// What we should do with an error from a backend
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendErrorClass {
+    /// the backend had a problem (lagging, pruned, missing the method, overloaded). another backend might be able to answer
+    Retryable,
+    /// the request was bad. every backend will give the same error
+    UserError,
+    /// the call or transaction reverted. this is a valid answer and the revert data must be kept
+    Revert,
+}
+
+#[derive(Clone, Debug)]
+pub struct NormalizedError {
+    pub class: BackendErrorClass,
+    pub error: JsonRpcErrorData,
+}
+
+impl NormalizedError {
+    /// Classify an error from a backend and rewrite it into the shape that geth uses
+    pub fn new(client: ClientKind, error: JsonRpcErrorData) -> Self {
+        let message = error.message.to_lowercase();
+
+        if let Some(revert) = normalize_revert(client, &error, &message) {
+            return Self {
+                class: BackendErrorClass::Revert,
+                error: revert,
+            };
+        }
+
+        if let Some(retryable) = normalize_retryable(&error, &message) {
+            return Self {
+                class: BackendErrorClass::Retryable,
+                error: retryable,
+            };
+        }
+
+        Self {
+            class: BackendErrorClass::UserError,
+            error: normalize_user_error(client, error, &message),
+        }
+    }
+}
+
+fn normalize_revert(
+    client: ClientKind,
+    error: &JsonRpcErrorData,
+    message: &str,
+) -> Option<JsonRpcErrorData> {
+    // geth and erigon: "execution reverted" or "execution reverted: reason". besu capitalizes it
+    // geth uses code 3 when there is revert data and -32000 when there is not
+    let (reason, data) = if message.starts_with("execution reverted") || error.code == 3 {
+        let reason = error
+            .message
+            .split_once(": ")
+            .map(|(_, reason)| reason.to_string());
+
+        (reason, error.data.as_ref())
+    } else if client.is(ClientKind::Nethermind)
+        && message.starts_with("vm execution error")
+        && error
+            .data
+            .as_ref()
+            .and_then(|x| x.as_str())
+            .map(|x| x.to_lowercase().starts_with("revert"))
+            .unwrap_or(false)
+    {
+        // nethermind: "VM execution error." with data like "Reverted 0x08c379a0..."
+        (None, error.data.as_ref())
+    } else {
+        return None;
+    };
+
+    let revert_data = data.and_then(revert_data_from_value);
+
+    // clients that don't put the reason in the message still send the data. decode it so everyone gets the same message
+    let reason = reason.or_else(|| revert_data.as_ref().and_then(decode_revert_reason));
+
+    let message = match reason {
+        Some(reason) => format!("execution reverted: {}", reason),
+        None => "execution reverted".to_string(),
+    };
+
+    // if we couldn't find hex in the data, keep whatever the client sent rather than lose it
+    let data = match revert_data {
+        Some(revert_data) => Some(Value::String(revert_data.to_string())),
+        None => data.cloned(),
+    };
+
+    Some(JsonRpcErrorData {
+        code: 3,
+        message,
+        data,
+    })
+}
+
+/// find the hex revert data in the different shapes clients send it in
+fn revert_data_from_value(data: &Value) -> Option<Bytes> {
+    match data {
+        // nethermind prefixes it with "Reverted "
+        Value::String(x) => x
+            .split_whitespace()
+            .find(|x| x.starts_with("0x"))
+            .and_then(|x| x.parse().ok()),
+        // some providers nest it
+        Value::Object(x) => x.get("data").and_then(revert_data_from_value),
+        _ => None,
+    }
+}
+
+fn decode_revert_reason(revert_data: &Bytes) -> Option<String> {
+    let revert_data = revert_data.as_ref();
+
+    if revert_data.len() < 4 || revert_data[..4] != ERROR_STRING_SELECTOR {
+        return None;
+    }
+
+    match abi::decode(&[ParamType::String], &revert_data[4..])
+        .ok()?
+        .pop()?
+    {
+        Token::String(reason) => Some(reason),
+        _ => None,
+    }
+}
+
+fn normalize_retryable(error: &JsonRpcErrorData, message: &str) -> Option<JsonRpcErrorData> {
+    let details = BackendErrorDetails {
+        code: Some(error.code),
+        message: Some(&error.message),
+        data: error.data.as_ref(),
+        ..Default::default()
+    };
+
+    let (code, message) = if details.is_missing_state() {
+        (-32000, "missing trie node".to_string())
+    } else if [
+        "header not found",
+        "header for hash not found",
+        "unknown block",
+        "block not found",
+    ]
+    .iter()
+    .any(|x| message.starts_with(x))
+        || (message.starts_with("block ") && message.ends_with("could not be found"))
+    {
+        // the backend is probably lagging behind the others
+        (-32000, "header not found".to_string())
+    } else if error.code == -32601
+        || (message.starts_with("the method") && message.ends_with("is not available"))
+    {
+        // sometimes a provider does not support all rpc methods. if none of them do, the user gets this error
+        (-32601, "Method not found".to_string())
+    } else if details.rate_limit().is_some()
+        || ["node not started", "rpc timeout", "request timed out"]
+            .iter()
+            .any(|x| message.starts_with(x))
+    {
+        (error.code, error.message.clone())
+    } else {
+        return None;
+    };
+
+    Some(rename(error, code, message))
+}
+
+fn normalize_user_error(
+    client: ClientKind,
+    error: JsonRpcErrorData,
+    message: &str,
+) -> JsonRpcErrorData {
+    // nethermind and besu have their own names for the transaction pool's errors. use geth's
+    let mut renames: Vec<(&str, &str)> = vec![];
+
+    if client.is(ClientKind::Nethermind) {
+        renames.extend([
+            ("oldnonce", "nonce too low"),
+            (
+                "insufficientfunds",
+                "insufficient funds for gas * price + value",
+            ),
+            ("alreadyknown", "already known"),
+            ("feetoolow", "transaction underpriced"),
+        ]);
+    }
+
+    if client.is(ClientKind::Besu) {
+        renames.extend([
+            ("nonce too low", "nonce too low"),
+            (
+                "upfront cost exceeds account balance",
+                "insufficient funds for gas * price + value",
+            ),
+            ("known transaction", "already known"),
+            (
+                "transaction replacement price too low",
+                "replacement transaction underpriced",
+            ),
+        ]);
+    }
+
+    for (prefix, new_message) in renames {
+        if message.starts_with(prefix) {
+            return rename(&error, -32000, new_message.to_string());
+        }
+    }
+
+    error
+}
+
+/// Give an error our message. The client's message often has details (like the current nonce) that ours doesn't,
+/// so it is kept in `data` along with whatever data the client sent
+fn rename(error: &JsonRpcErrorData, code: i64, message: String) -> JsonRpcErrorData {
+    if message == error.message {
+        return JsonRpcErrorData {
+            code,
+            message,
+            data: error.data.clone(),
+        };
+    }
+
+    let mut upstream = serde_json::Map::new();
+
+    upstream.insert(
+        "upstream_message".to_string(),
+        Value::String(error.message.clone()),
+    );
+
+    if let Some(data) = &error.data {
+        upstream.insert("upstream_data".to_string(), data.clone());
+    }
+
+    JsonRpcErrorData {
+        code,
+        message,
+        data: Some(Value::Object(upstream)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// abi encoded `Error("not enough tokens")`
+    const REVERT_DATA: &str = "0x08c379a0000000000000000000000000000000000000000000000000000000000000002000000000000000000000000000000000000000000000000000000000000000116e6f7420656e6f75676820746f6b656e73000000000000000000000000000000";
+
+    fn error(code: i64, message: &str, data: Option<Value>) -> JsonRpcErrorData {
+        JsonRpcErrorData {
+            code,
+            message: message.to_string(),
+            data,
+        }
+    }
+
+    #[test]
+    fn test_client_kind() {
+        assert_eq!(
+            ClientKind::from_client_version("Geth/v1.11.5-stable-a38f4108/linux-amd64/go1.20.2"),
+            ClientKind::Geth
+        );
+        assert_eq!(
+            ClientKind::from_client_version("bor/v0.3.5/linux-amd64/go1.19.7"),
+            ClientKind::Geth
+        );
+        assert_eq!(
+            ClientKind::from_client_version("erigon/2.42.0/linux-amd64/go1.20.2"),
+            ClientKind::Erigon
+        );
+        assert_eq!(
+            ClientKind::from_client_version("Nethermind/v1.17.3+da35a5ab/linux-x64/dotnet7.0.3"),
+            ClientKind::Nethermind
+        );
+        assert_eq!(
+            ClientKind::from_client_version("besu/v23.1.2/linux-x86_64/openjdk-java-17"),
+            ClientKind::Besu
+        );
+        assert_eq!(
+            ClientKind::from_client_version("anvil/v0.1.0"),
+            ClientKind::Unknown
+        );
+    }
+
+    #[test]
+    fn test_reverts_look_the_same() {
+        let geth = error(
+            3,
+            "execution reverted: not enough tokens",
+            Some(json!(REVERT_DATA)),
+        );
+        let besu = error(-32000, "Execution reverted", Some(json!(REVERT_DATA)));
+        let nethermind = error(
+            -32015,
+            "VM execution error.",
+            Some(json!(format!("Reverted {}", REVERT_DATA))),
+        );
+
+        for (client, err) in [
+            (ClientKind::Geth, geth),
+            (ClientKind::Besu, besu),
+            (ClientKind::Nethermind, nethermind),
+        ] {
+            let normalized = NormalizedError::new(client, err);
+
+            assert_eq!(normalized.class, BackendErrorClass::Revert);
+            assert_eq!(normalized.error.code, 3);
+            assert_eq!(
+                normalized.error.message,
+                "execution reverted: not enough tokens"
+            );
+            assert_eq!(normalized.error.data, Some(json!(REVERT_DATA)));
+        }
+    }
+
+    #[test]
+    fn test_revert_data_is_kept() {
+        // custom errors can't be decoded, but the data still needs to get to the user
+        let normalized = NormalizedError::new(
+            ClientKind::Erigon,
+            error(3, "execution reverted", Some(json!("0x12345678"))),
+        );
+
+        assert_eq!(normalized.class, BackendErrorClass::Revert);
+        assert_eq!(normalized.error.message, "execution reverted");
+        assert_eq!(normalized.error.data, Some(json!("0x12345678")));
+
+        // data that isn't hex is passed through as is
+        let normalized = NormalizedError::new(
+            ClientKind::Unknown,
+            error(
+                -32000,
+                "execution reverted",
+                Some(json!({"reason": "nope"})),
+            ),
+        );
+
+        assert_eq!(normalized.class, BackendErrorClass::Revert);
+        assert_eq!(normalized.error.data, Some(json!({"reason": "nope"})));
+    }
+
+    #[test]
+    fn test_retryable() {
+        for (client, err) in [
+            (ClientKind::Geth, error(-32000, "header not found", None)),
+            (
+                ClientKind::Nethermind,
+                error(-32001, "Block 16000000 could not be found", None),
+            ),
+            (ClientKind::Besu, error(-32000, "Block not found", None)),
+        ] {
+            let normalized = NormalizedError::new(client, err);
+
+            assert_eq!(normalized.class, BackendErrorClass::Retryable);
+            assert_eq!(normalized.error.code, -32000);
+            assert_eq!(normalized.error.message, "header not found");
+        }
+
+        // an error that already has our message is not wrapped
+        let normalized =
+            NormalizedError::new(ClientKind::Geth, error(-32000, "header not found", None));
+        assert_eq!(normalized.error.data, None);
+
+        let normalized = NormalizedError::new(
+            ClientKind::Erigon,
+            error(-32000, "old data not available due to pruning", None),
+        );
+        assert_eq!(normalized.class, BackendErrorClass::Retryable);
+        assert_eq!(normalized.error.message, "missing trie node");
+
+        let normalized = NormalizedError::new(
+            ClientKind::Geth,
+            error(
+                -32601,
+                "the method debug_traceTransaction does not exist/is not available",
+                None,
+            ),
+        );
+        assert_eq!(normalized.class, BackendErrorClass::Retryable);
+        assert_eq!(normalized.error.message, "Method not found");
+    }
+
+    #[test]
+    fn test_user_errors() {
+        let normalized = NormalizedError::new(
+            ClientKind::Nethermind,
+            error(
+                -32010,
+                "OldNonce, Current nonce: 5, nonce of rejected tx: 3",
+                None,
+            ),
+        );
+        assert_eq!(normalized.class, BackendErrorClass::UserError);
+        assert_eq!(normalized.error.code, -32000);
+        assert_eq!(normalized.error.message, "nonce too low");
+        // the client's message has the nonces. don't lose them
+        assert_eq!(
+            normalized.error.data,
+            Some(json!({
+                "upstream_message": "OldNonce, Current nonce: 5, nonce of rejected tx: 3"
+            }))
+        );
+
+        let normalized = NormalizedError::new(
+            ClientKind::Besu,
+            error(-32000, "Upfront cost exceeds account balance", None),
+        );
+        assert_eq!(
+            normalized.error.message,
+            "insufficient funds for gas * price + value"
+        );
+
+        let normalized = NormalizedError::new(
+            ClientKind::Besu,
+            error(-32000, "Known transaction", Some(json!("0x1234"))),
+        );
+        assert_eq!(normalized.error.message, "already known");
+        assert_eq!(
+            normalized.error.data,
+            Some(json!({
+                "upstream_message": "Known transaction",
+                "upstream_data": "0x1234",
+            }))
+        );
+
+        // a geth message that another client's rename would match is left alone
+        let normalized = NormalizedError::new(
+            ClientKind::Geth,
+            error(-32602, "invalid argument 0: hex string has length 3", None),
+        );
+        assert_eq!(normalized.class, BackendErrorClass::UserError);
+        assert_eq!(normalized.error.code, -32602);
+        assert_eq!(
+            normalized.error.message,
+            "invalid argument 0: hex string has length 3"
+        );
+    }
+}
@@ -1,5 +1,13 @@
 ///! Rate-limited communication with a web3 provider.
+use super::admin::Web3RpcAdminState;
+use super::block_data_limit::{
+    block_data_limit_from_oldest, find_oldest_block_with_state, BlockDataLimitVotes,
+    BLOCK_DATA_LIMIT_AGREEMENTS,
+};
 use super::blockchain::{ArcBlock, BlocksByHashCache, Web3ProxyBlock};
+use super::errors::BackendErrorDetails;
+use super::hedge::RequestLatency;
+use super::normalize::ClientKind;
 use super::provider::Web3Provider;
 use super::request::{OpenRequestHandle, OpenRequestResult};
 use crate::app::{flatten_handle, AnyhowJoinHandle};
@@ -22,11 +30,11 @@ use serde_json::json;
 use std::cmp::min;
 use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::atomic::{self, AtomicBool, AtomicU32, AtomicU64, AtomicUsize};
 use std::{cmp::Ordering, sync::Arc};
 use thread_fast_rng::rand::Rng;
 use thread_fast_rng::thread_fast_rng;
-use tokio::sync::{broadcast, oneshot, watch, RwLock as AsyncRwLock};
+use tokio::sync::{broadcast, oneshot, watch, Notify, RwLock as AsyncRwLock};
 use tokio::time::{sleep, sleep_until, timeout, Duration, Instant};
 
 pub struct Latency {
@@ -92,6 +100,23 @@ impl Latency {
     }
 }
 
+/// Sets `block_data_limit_checking` until it is dropped. The check can be cancelled at any await
+struct BlockDataLimitChecking<'a>(&'a AtomicBool);
+
+impl<'a> BlockDataLimitChecking<'a> {
+    fn new(checking: &'a AtomicBool) -> Self {
+        checking.store(true, atomic::Ordering::Release);
+
+        Self(checking)
+    }
+}
+
+impl Drop for BlockDataLimitChecking<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, atomic::Ordering::Release);
+    }
+}
+
 /// An active connection to a Web3 RPC server like geth or erigon.
 #[derive(Default)]
 pub struct Web3Rpc {
@@ -107,33 +132,51 @@ pub struct Web3Rpc {
     /// this provider is only used for new heads subscriptions
     /// TODO: watch channel instead of a lock
     pub(super) provider: AsyncRwLock<Option<Arc<Web3Provider>>>,
-    /// keep track of hard limits
+    /// keep track of hard limits. set by our own rate limiter and by rate limit errors from the server
     pub(super) hard_limit_until: Option<watch::Sender<Instant>>,
     /// rate limits are stored in a central redis so that multiple proxies can share their rate limits
     /// We do not use the deferred rate limiter because going over limits would cause errors
     pub(super) hard_limit: Option<RedisRateLimiter>,
-    /// used for load balancing to the least loaded server
-    pub(super) soft_limit: u32,
+    /// used for load balancing to the least loaded server. admins can change this at runtime
+    pub(super) soft_limit: AtomicU32,
     /// use web3 queries to find the block data limit for archive/pruned nodes
     pub(super) automatic_block_limit: bool,
     /// only use this rpc if everything else is lagging too far. this allows us to ignore fast but very low limit rpcs
     pub backup: bool,
     /// TODO: have an enum for this so that "no limit" prints pretty?
     pub(super) block_data_limit: AtomicU64,
-    /// Lower tiers are higher priority when sending requests
-    pub(super) tier: u64,
+    /// how often to check the block data limit again. None only checks when connecting
+    pub(super) block_data_limit_check_interval: Option<Duration>,
+    /// wakes up the block data limit checks early. used when a request fails because of missing state
+    pub(super) block_data_limit_recheck: Notify,
+    /// set while the block data limit is being searched for. those queries are expected to fail
+    pub(super) block_data_limit_checking: AtomicBool,
+    /// Lower tiers are higher priority when sending requests. admins can change this at runtime
+    pub(super) tier: AtomicU64,
+    /// admins can drain or disable an rpc without changing the config
+    pub(super) admin_state: RwLock<Web3RpcAdminState>,
+    /// the server's `web3_clientVersion`. used to understand its errors
+    pub(super) client_version: RwLock<Option<String>>,
     /// TODO: change this to a watch channel so that http providers can subscribe and take action on change.
     pub(super) head_block: RwLock<Option<Web3ProxyBlock>>,
     /// Track head block latency
     pub(super) head_latency: RwLock<Latency>,
-    // /// Track request latency
-    // /// TODO: refactor this. this lock kills perf. for now just use head_latency
-    // pub(super) request_latency: RwLock<Latency>,
+    /// Track successful request latency. Used to decide when to hedge a request
+    /// TODO: this lock is on every request. watch the perf
+    pub(super) request_latency: RequestLatency,
     /// Track total requests served
     /// TODO: maybe move this to graphana
     pub(super) total_requests: AtomicUsize,
     pub(super) active_requests: AtomicUsize,
+    /// how many of this server's responses have been checked against another server
+    pub(super) shadow_checks: AtomicU64,
+    /// how many of those checks did not match
+    pub(super) shadow_mismatches: AtomicU64,
+    /// a moving average of recent mismatches (0.0 is all matches). only updated if shadow checks should affect health
+    pub(super) shadow_mismatch_score: RwLock<f64>,
     pub(super) reconnect: AtomicBool,
+    /// wakes up the subscriptions so that they reconnect. used by admins
+    pub(super) force_reconnect: Notify,
     /// this is only inside an Option so that the "Default" derive works. it will always be set.
     pub(super) disconnect_watch: Option<watch::Sender<bool>>,
     pub(super) created_at: Option<Instant>,
@@ -197,16 +240,17 @@ impl Web3Rpc {
         let automatic_block_limit =
             (block_data_limit.load(atomic::Ordering::Acquire) == 0) && block_sender.is_some();
 
-        // track hard limit until on backup servers (which might surprise us with rate limit changes)
-        // and track on servers that have a configured hard limit
-        let hard_limit_until = if backup || hard_limit.is_some() {
-            let (sender, _) = watch::channel(Instant::now());
-
-            Some(sender)
-        } else {
+        let block_data_limit_check_interval = if config.block_data_limit_check_seconds == 0 {
             None
+        } else {
+            Some(Duration::from_secs(config.block_data_limit_check_seconds))
         };
 
+        // track hard limit until on all servers. any of them might surprise us with rate limit changes
+        // this is only inside an Option so that the "Default" derive works
+        let (hard_limit_until, _) = watch::channel(Instant::now());
+        let hard_limit_until = Some(hard_limit_until);
+
         if config.ws_url.is_none() && config.http_url.is_none() {
             if let Some(url) = config.url {
                 if url.starts_with("ws") {
@@ -235,12 +279,13 @@ impl Web3Rpc {
             http_url: config.http_url,
             hard_limit,
             hard_limit_until,
-            soft_limit: config.soft_limit,
+            soft_limit: config.soft_limit.into(),
             automatic_block_limit,
             backup,
             block_data_limit,
+            block_data_limit_check_interval,
             reconnect,
-            tier: config.tier,
+            tier: config.tier.into(),
             disconnect_watch: Some(disconnect_sender),
             created_at: Some(created_at),
             ..Default::default()
@@ -295,88 +340,240 @@ impl Web3Rpc {
             return Ok(None);
         }
 
-        // TODO: check eth_syncing. if it is not false, return Ok(None)
+        let limit = self
+            .find_block_data_limit(authorization, unlocked_provider)
+            .await?;
 
-        let mut limit = None;
+        if limit == 0 {
+            warn!("{} is unable to serve requests", self);
+        }
 
-        // TODO: binary search between 90k and max?
-        // TODO: start at 0 or 1?
-        for block_data_limit in [0, 32, 64, 128, 256, 512, 1024, 90_000, u64::MAX] {
-            let handle = self
-                .wait_for_request_handle(authorization, None, unlocked_provider.clone())
-                .await?;
+        self.block_data_limit
+            .store(limit, atomic::Ordering::Release);
 
-            let head_block_num_future = handle.request::<Option<()>, U256>(
-                "eth_blockNumber",
-                &None,
-                // error here are expected, so keep the level low
-                Level::Debug.into(),
-                unlocked_provider.clone(),
-            );
+        if limit == u64::MAX {
+            info!("block data limit on {}: archive", self);
+        } else {
+            info!("block data limit on {}: {}", self, limit);
+        }
 
-            let head_block_num = timeout(Duration::from_secs(5), head_block_num_future)
-                .await
-                .context("timeout fetching eth_blockNumber")?
-                .context("provider error")?;
+        Ok(Some(limit))
+    }
 
-            let maybe_archive_block = head_block_num.saturating_sub((block_data_limit).into());
+    /// query the server for how many blocks of state it keeps. this does not save the limit
+    async fn find_block_data_limit(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        unlocked_provider: Option<Arc<Web3Provider>>,
+    ) -> anyhow::Result<u64> {
+        // TODO: check eth_syncing. if it is not false, return Ok(0)
 
-            trace!(
-                "checking maybe_archive_block on {}: {}",
-                self,
-                maybe_archive_block
-            );
+        let handle = self
+            .wait_for_request_handle(authorization, None, unlocked_provider.clone())
+            .await?;
 
-            // TODO: wait for the handle BEFORE we check the current block number. it might be delayed too!
-            // TODO: what should the request be?
-            let handle = self
-                .wait_for_request_handle(authorization, None, unlocked_provider.clone())
-                .await?;
+        let head_block_num_future = handle.request::<Option<()>, U256>(
+            "eth_blockNumber",
+            &None,
+            // error here are expected, so keep the level low
+            Level::Debug.into(),
+            unlocked_provider.clone(),
+        );
 
-            let archive_result: Result<Bytes, _> = handle
-                .request(
-                    "eth_getCode",
-                    &json!((
-                        "0xdead00000000000000000000000000000000beef",
-                        maybe_archive_block,
-                    )),
-                    // error here are expected, so keep the level low
-                    Level::Trace.into(),
-                    unlocked_provider.clone(),
-                )
-                .await;
+        let head_block_num = timeout(Duration::from_secs(5), head_block_num_future)
+            .await
+            .context("timeout fetching eth_blockNumber")?
+            .context("provider error")?
+            .as_u64();
 
-            trace!(
-                "archive_result on {} for {} ({}): {:?}",
-                self,
-                block_data_limit,
-                maybe_archive_block,
-                archive_result
-            );
+        // our own queries for old blocks will fail with missing state. don't let them trigger another check
+        let checking = BlockDataLimitChecking::new(&self.block_data_limit_checking);
 
-            if archive_result.is_err() {
-                break;
-            }
+        let rpc = self;
+        let oldest = find_oldest_block_with_state(head_block_num, move |block_num| {
+            rpc.block_has_state(authorization, unlocked_provider.clone(), block_num)
+        })
+        .await;
+
+        drop(checking);
+
+        let oldest = oldest?;
 
-            limit = Some(block_data_limit);
+        trace!(
+            "oldest block with state on {} at {}: {:?}",
+            self,
+            head_block_num,
+            oldest
+        );
+
+        Ok(block_data_limit_from_oldest(head_block_num, oldest))
+    }
+
+    /// errors are only for problems with the request. a server that doesn't have the block is Ok(false)
+    async fn block_has_state(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        unlocked_provider: Option<Arc<Web3Provider>>,
+        block_num: u64,
+    ) -> anyhow::Result<bool> {
+        let handle = self
+            .wait_for_request_handle(authorization, None, unlocked_provider.clone())
+            .await?;
+
+        // TODO: what should the request be?
+        let params = json!((
+            "0xdead00000000000000000000000000000000beef",
+            U64::from(block_num),
+        ));
+
+        let archive_result_future = handle.request::<_, Bytes>(
+            "eth_getCode",
+            &params,
+            // error here are expected, so keep the level low
+            Level::Trace.into(),
+            unlocked_provider,
+        );
+
+        let archive_result = timeout(Duration::from_secs(5), archive_result_future)
+            .await
+            .context("timeout fetching eth_getCode")?;
+
+        trace!(
+            "archive_result on {} for {}: {:?}",
+            self,
+            block_num,
+            archive_result
+        );
+
+        match archive_result {
+            Ok(_) => Ok(true),
+            Err(err) => {
+                let error_details = BackendErrorDetails::from_provider_error(&err);
+
+                // a jsonrpc error means the server answered, but doesn't have the state
+                // anything else (like a rate limit or a dropped connection) means we don't actually know
+                if error_details.code.is_none() || error_details.rate_limit().is_some() {
+                    Err(err).context("unable to check for state")
+                } else {
+                    Ok(false)
+                }
+            }
         }
+    }
+
+    /// Check the block data limit again every `block_data_limit_check_interval` (or sooner if a request saw missing state).
+    /// Nodes that were syncing when we connected and load balanced endpoints both need this.
+    async fn block_data_limit_loop(
+        self: Arc<Self>,
+        authorization: Arc<Authorization>,
+        interval: Duration,
+    ) -> anyhow::Result<()> {
+        // checks are a lot of queries. don't let a flood of errors cause a flood of checks
+        let min_delay = interval.min(Duration::from_secs(10));
+
+        let mut votes = BlockDataLimitVotes::default();
+
+        // the limit was checked when we connected
+        let mut since_last_check = Duration::ZERO;
+
+        loop {
+            if !votes.is_pending() {
+                // nothing to confirm. wait for the next check or for a request to fail with missing state
+                tokio::select! {
+                    _ = sleep(interval.saturating_sub(since_last_check)) => {}
+                    _ = self.block_data_limit_recheck.notified() => {
+                        debug!("missing state on {}. checking block data limit", self);
+                    }
+                    _ = self.wait_for_disconnect() => break,
+                }
+            }
+
+            match self.find_block_data_limit(&authorization, None).await {
+                Ok(found) => {
+                    let current = self.block_data_limit.load(atomic::Ordering::Acquire);
+
+                    if let Some(new_limit) = votes.vote(current, found, BLOCK_DATA_LIMIT_AGREEMENTS)
+                    {
+                        if new_limit == u64::MAX {
+                            info!(
+                                "block data limit on {} changed: {} -> archive",
+                                self, current
+                            );
+                        } else {
+                            info!(
+                                "block data limit on {} changed: {} -> {}",
+                                self, current, new_limit
+                            );
+                        }
+
+                        self.block_data_limit
+                            .store(new_limit, atomic::Ordering::Release);
+                    } else if votes.is_pending() {
+                        debug!(
+                            "block data limit on {} might have changed: {} -> {}",
+                            self, current, found
+                        );
+                    }
+                }
+                Err(err) => {
+                    debug!("failed checking block data limit on {}. {:?}", self, err);
+                }
+            }
 
-        if let Some(limit) = limit {
-            if limit == 0 {
-                warn!("{} is unable to serve requests", self);
+            tokio::select! {
+                _ = sleep(min_delay) => {}
+                _ = self.wait_for_disconnect() => break,
             }
 
-            self.block_data_limit
-                .store(limit, atomic::Ordering::Release);
+            since_last_check = min_delay;
         }
 
-        if limit == Some(u64::MAX) {
-            info!("block data limit on {}: archive", self);
-        } else {
-            info!("block data limit on {}: {:?}", self, limit);
+        debug!("block data limit checks for {} exited", self);
+
+        Ok(())
+    }
+
+    /// record the result of checking one of this server's responses against another server.
+    /// we don't know which of the two servers was wrong, so both get the mismatch
+    pub fn record_shadow_check(&self, matched: bool, affects_health: bool) {
+        self.shadow_checks.fetch_add(1, atomic::Ordering::Relaxed);
+
+        if !matched {
+            self.shadow_mismatches
+                .fetch_add(1, atomic::Ordering::Relaxed);
         }
 
-        Ok(limit)
+        if affects_health {
+            // TODO: what weight? this forgets a mismatch after about 25 matching checks
+            let mut score = self.shadow_mismatch_score.write();
+
+            *score = *score * 0.9 + if matched { 0.0 } else { 0.1 };
+        }
+    }
+
+    /// multiply latency by this when picking a server. servers that recently gave mismatched responses look up to 10x slower
+    pub fn shadow_health_penalty(&self) -> f64 {
+        1.0 + *self.shadow_mismatch_score.read() * 9.0
+    }
+
+    pub fn client_kind(&self) -> ClientKind {
+        self.client_version
+            .read()
+            .as_deref()
+            .map(ClientKind::from_client_version)
+            .unwrap_or_default()
+    }
+
+    pub fn tier(&self) -> u64 {
+        self.tier.load(atomic::Ordering::Acquire)
+    }
+
+    pub fn soft_limit(&self) -> u32 {
+        self.soft_limit.load(atomic::Ordering::Acquire)
+    }
+
+    pub fn admin_state(&self) -> Web3RpcAdminState {
+        *self.admin_state.read()
     }
 
     /// TODO: this might be too simple. different nodes can prune differently. its possible we will have a block range
@@ -564,6 +761,37 @@ impl Web3Rpc {
                 }
             }
 
+            // check the server's client so that we can understand its errors
+            // some providers hide this, so errors here are not fatal
+            let found_client_version: anyhow::Result<String> = match self
+                .wait_for_request_handle(&authorization, None, unlocked_provider.clone())
+                .await
+            {
+                Ok(handle) => handle
+                    .request(
+                        "web3_clientVersion",
+                        &json!(Option::None::<()>),
+                        Level::Trace.into(),
+                        unlocked_provider.clone(),
+                    )
+                    .await
+                    .map_err(Into::into),
+                Err(err) => Err(err),
+            };
+
+            match found_client_version {
+                Ok(found_client_version) => {
+                    debug!("client version on {}: {}", self, found_client_version);
+
+                    *self.client_version.write() = Some(found_client_version);
+                }
+                Err(err) => {
+                    debug!("unable to get client version from {}. err={:?}", self, err);
+
+                    *self.client_version.write() = None;
+                }
+            }
+
             self.check_block_data_limit(&authorization, unlocked_provider.clone())
                 .await?;
 
@@ -701,6 +929,9 @@ impl Web3Rpc {
             RequestRevertHandler::ErrorLevel
         };
 
+        // kept outside of the reconnect loop so that reconnecting doesn't leave old checks running
+        let mut block_data_limit_handle: Option<AnyhowJoinHandle<()>> = None;
+
         loop {
             let mut futures = vec![];
 
@@ -838,6 +1069,20 @@ impl Web3Rpc {
                 ready_rx.await?;
             }
 
+            if self.automatic_block_limit {
+                if let Some(interval) = self.block_data_limit_check_interval {
+                    if let Some(old_handle) = block_data_limit_handle.take() {
+                        old_handle.abort();
+                    }
+
+                    let f = self
+                        .clone()
+                        .block_data_limit_loop(authorization.clone(), interval);
+
+                    block_data_limit_handle = Some(tokio::spawn(f));
+                }
+            }
+
             if let Some(block_sender) = &block_sender {
                 let f = self.clone().subscribe_new_heads(
                     authorization.clone(),
@@ -857,6 +1102,22 @@ impl Web3Rpc {
                 futures.push(flatten_handle(tokio::spawn(f)));
             }
 
+            {
+                // erroring here makes the subscriptions reconnect
+                // TODO: the other futures keep running until they error on their own. abort them instead
+                let rpc = self.clone();
+                let f = async move {
+                    tokio::select! {
+                        _ = rpc.force_reconnect.notified() => {
+                            Err(anyhow!("reconnect requested by an admin"))
+                        }
+                        _ = rpc.wait_for_disconnect() => Ok(()),
+                    }
+                };
+
+                futures.push(flatten_handle(tokio::spawn(f)));
+            }
+
             match try_join_all(futures).await {
                 Ok(_) => {
                     // futures all exited without error. break instead of restarting subscriptions
@@ -885,6 +1146,10 @@ impl Web3Rpc {
             }
         }
 
+        if let Some(handle) = block_data_limit_handle {
+            handle.abort();
+        }
+
         info!("all subscriptions on {} completed", self);
 
         Ok(())
@@ -1063,7 +1328,9 @@ impl Web3Rpc {
                 debug!("new_heads subscription to {} ended", self);
             }
             #[cfg(test)]
-            Web3Provider::Mock => unimplemented!(),
+            Web3Provider::Mock => {
+                self.wait_for_disconnect().await?;
+            }
         }
 
         // clear the head block. this might not be needed, but it won't hurt
@@ -1317,9 +1584,7 @@ impl Hash for Web3Rpc {
         self.ws_url.hash(state);
         self.automatic_block_limit.hash(state);
         self.backup.hash(state);
-        // TODO: including soft_limit might need to change if we change them to be dynamic
-        self.soft_limit.hash(state);
-        self.tier.hash(state);
+        // soft_limit and tier are not included because admins can change them at runtime
         self.created_at.hash(state);
     }
 }
@@ -1350,7 +1615,7 @@ impl Serialize for Web3Rpc {
         S: Serializer,
     {
         // 3 is the number of fields in the struct.
-        let mut state = serializer.serialize_struct("Web3Rpc", 10)?;
+        let mut state = serializer.serialize_struct("Web3Rpc", 15)?;
 
         // the url is excluded because it likely includes private information. just show the name that we use in keys
         state.serialize_field("name", &self.name)?;
@@ -1368,20 +1633,44 @@ impl Serialize for Web3Rpc {
             }
         }
 
-        state.serialize_field("tier", &self.tier)?;
+        state.serialize_field("tier", &self.tier())?;
 
-        state.serialize_field("soft_limit", &self.soft_limit)?;
+        state.serialize_field("client_version", &*self.client_version.read())?;
+
+        state.serialize_field("soft_limit", &self.soft_limit())?;
+
+        state.serialize_field("admin_state", &self.admin_state())?;
+
+        state.serialize_field(
+            "active_requests",
+            &self.active_requests.load(atomic::Ordering::Relaxed),
+        )?;
 
         // TODO: maybe this is too much data. serialize less?
         state.serialize_field("head_block", &*self.head_block.read())?;
 
         state.serialize_field("head_latency", &self.head_latency.read().value())?;
 
+        state.serialize_field(
+            "request_latency_p95_ms",
+            &self.request_latency.percentile(0.95).map(|x| x.as_millis()),
+        )?;
+
         state.serialize_field(
             "total_requests",
             &self.total_requests.load(atomic::Ordering::Relaxed),
         )?;
 
+        state.serialize_field(
+            "shadow_checks",
+            &self.shadow_checks.load(atomic::Ordering::Relaxed),
+        )?;
+
+        state.serialize_field(
+            "shadow_mismatches",
+            &self.shadow_mismatches.load(atomic::Ordering::Relaxed),
+        )?;
+
         state.end()
     }
 }
@@ -1439,11 +1728,11 @@ mod tests {
         let x = Web3Rpc {
             name: "name".to_string(),
             ws_url: Some("ws://example.com".to_string()),
-            soft_limit: 1_000,
+            soft_limit: 1_000.into(),
             automatic_block_limit: false,
             backup: false,
             block_data_limit: block_data_limit.into(),
-            tier: 0,
+            tier: 0.into(),
             head_block: RwLock::new(Some(head_block.clone())),
             ..Default::default()
         };
@@ -1476,11 +1765,11 @@ mod tests {
 
         let x = Web3Rpc {
             name: "name".to_string(),
-            soft_limit: 1_000,
+            soft_limit: 1_000.into(),
             automatic_block_limit: false,
             backup: false,
             block_data_limit: block_data_limit.into(),
-            tier: 0,
+            tier: 0.into(),
             head_block: RwLock::new(Some(head_block.clone())),
             ..Default::default()
         };
@@ -1494,6 +1783,22 @@ mod tests {
         assert!(!x.has_block_data(&(head_block.number() + 1000)));
     }
 
+    #[tokio::test]
+    async fn test_cancelled_block_data_limit_check() {
+        let checking = AtomicBool::new(false);
+
+        let check = async {
+            let _checking = BlockDataLimitChecking::new(&checking);
+
+            sleep(Duration::from_secs(60)).await;
+        };
+
+        // the check is dropped part way through
+        let _ = timeout(Duration::from_millis(10), check).await;
+
+        assert!(!checking.load(atomic::Ordering::Acquire));
+    }
+
     /*
     // TODO: think about how to bring the concept of a "lagged" node back
     #[test]
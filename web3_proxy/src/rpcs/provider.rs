@@ -1,9 +1,10 @@
+use super::http::Web3HttpClient;
 use anyhow::Context;
 use derive_more::From;
 use std::time::Duration;
 
 // TODO: our own structs for these that handle streaming large responses
-type EthersHttpProvider = ethers::providers::Provider<ethers::providers::Http>;
+type EthersHttpProvider = ethers::providers::Provider<Web3HttpClient>;
 type EthersWsProvider = ethers::providers::Provider<ethers::providers::Ws>;
 
 /// Use HTTP and WS providers.
@@ -53,7 +54,8 @@ impl Web3Provider {
 
             let http_client = http_client.context("no http_client")?;
 
-            let provider = ethers::providers::Http::new_with_client(url, http_client);
+            // our own client so that we can see http status codes and Retry-After headers
+            let provider = Web3HttpClient::new(url, http_client);
 
             // TODO: dry this up (needs https://github.com/gakonst/ethers-rs/issues/592)
             // TODO: i don't think this interval matters for our uses, but we should probably set it to like `block time / 2`
@@ -0,0 +1,153 @@
+///! Compare the answers from multiple servers and only return one that most of them agree on.
+use crate::jsonrpc::{JsonRpcErrorData, JsonRpcForwardedResponse};
+use serde::Serialize;
+use serde_json::json;
+use serde_json::value::RawValue;
+
+/// the error code returned when the servers don't agree
+pub const QUORUM_NOT_REACHED_CODE: i64 = -32097;
+
+/// Servers that all gave the same answer
+#[derive(Debug, Serialize)]
+pub struct QuorumGroup {
+    pub rpcs: Vec<String>,
+    #[serde(skip)]
+    pub response: JsonRpcForwardedResponse,
+}
+
+/// The answers from all of the servers in a quorum request
+#[derive(Debug, Default)]
+pub struct QuorumVotes {
+    groups: Vec<(serde_json::Value, QuorumGroup)>,
+    /// servers that didn't give a useful answer (timeouts, lagging, rate limits)
+    abstained: Vec<String>,
+}
+
+impl QuorumVotes {
+    pub fn vote(&mut self, rpc: String, response: JsonRpcForwardedResponse) {
+        let key = vote_key(&response);
+
+        match self.groups.iter_mut().find(|(x, _)| *x == key) {
+            Some((_, group)) => group.rpcs.push(rpc),
+            None => self.groups.push((
+                key,
+                QuorumGroup {
+                    rpcs: vec![rpc],
+                    response,
+                },
+            )),
+        }
+    }
+
+    pub fn abstain(&mut self, rpc: String) {
+        self.abstained.push(rpc);
+    }
+
+    /// Return the response that more than half of the `n` servers agree on.
+    /// Otherwise return an error that lists how each server answered.
+    pub fn into_response(self, n: usize, id: Box<RawValue>) -> JsonRpcForwardedResponse {
+        let Self { groups, abstained } = self;
+
+        let mut groups: Vec<_> = groups.into_iter().map(|(_, group)| group).collect();
+
+        // largest group first
+        groups.sort_by(|a, b| b.rpcs.len().cmp(&a.rpcs.len()));
+
+        if let Some(group) = groups.first() {
+            if group.rpcs.len() > n / 2 {
+                let mut response = groups.swap_remove(0).response;
+                response.id = id;
+                return response;
+            }
+        }
+
+        // TODO: include a short summary of each answer? they can be very large
+        JsonRpcForwardedResponse {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result: None,
+            error: Some(JsonRpcErrorData {
+                code: QUORUM_NOT_REACHED_CODE,
+                message: format!("quorum of {} not reached", n / 2 + 1),
+                data: Some(json!({
+                    "groups": groups,
+                    "abstained": abstained,
+                })),
+            }),
+        }
+    }
+}
+
+/// Parse the answer so that whitespace and key order don't matter.
+/// Errors are compared by code, message, and data
+fn vote_key(response: &JsonRpcForwardedResponse) -> serde_json::Value {
+    if let Some(error) = response.error.as_ref() {
+        return json!({ "error": error });
+    }
+
+    let result = response
+        .result
+        .as_ref()
+        .map(|x| serde_json::from_str(x.get()).unwrap_or_else(|_| json!(x.get())))
+        .unwrap_or_default();
+
+    json!({ "result": result })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(result: serde_json::Value) -> JsonRpcForwardedResponse {
+        JsonRpcForwardedResponse::from_value(result, Default::default())
+    }
+
+    #[test]
+    fn test_quorum_votes() {
+        let mut votes = QuorumVotes::default();
+
+        votes.vote("a".to_string(), ok(json!({"a": 1, "b": 2})));
+        votes.vote("b".to_string(), ok(json!({"b": 2, "a": 1})));
+        votes.vote("c".to_string(), ok(json!({"a": 1, "b": 3})));
+
+        let response = votes.into_response(3, Default::default());
+
+        assert!(response.error.is_none());
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(response.result.unwrap().get()).unwrap(),
+            json!({"a": 1, "b": 2})
+        );
+    }
+
+    #[test]
+    fn test_quorum_not_reached() {
+        let mut votes = QuorumVotes::default();
+
+        votes.vote("a".to_string(), ok(json!("0x1")));
+        votes.vote("b".to_string(), ok(json!("0x2")));
+        votes.abstain("c".to_string());
+
+        let response = votes.into_response(3, Default::default());
+
+        let error = response.error.unwrap();
+
+        assert_eq!(error.code, QUORUM_NOT_REACHED_CODE);
+        assert_eq!(
+            error.data,
+            Some(json!({
+                "groups": [{"rpcs": ["a"]}, {"rpcs": ["b"]}],
+                "abstained": ["c"],
+            }))
+        );
+
+        // an abstaining server counts against the quorum
+        let mut votes = QuorumVotes::default();
+
+        votes.vote("a".to_string(), ok(json!("0x1")));
+        votes.abstain("b".to_string());
+
+        let response = votes.into_response(2, Default::default());
+
+        assert!(response.error.is_some());
+    }
+}
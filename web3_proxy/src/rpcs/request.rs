@@ -1,3 +1,4 @@
+use super::errors::{BackendErrorDetails, BackendRateLimit};
 use super::one::Web3Rpc;
 use super::provider::Web3Provider;
 use crate::frontend::authorization::Authorization;
@@ -5,7 +6,7 @@ use anyhow::Context;
 use chrono::Utc;
 use entities::revert_log;
 use entities::sea_orm_active_enums::Method;
-use ethers::providers::{HttpClientError, ProviderError, WsClientError};
+use ethers::providers::ProviderError;
 use ethers::types::{Address, Bytes};
 use log::{debug, error, trace, warn, Level};
 use migration::sea_orm::{self, ActiveEnum, ActiveModelTrait};
@@ -120,6 +121,28 @@ impl Authorization {
     }
 }
 
+/// Counts an in-flight request on a server until it is dropped
+struct ActiveRequestGuard<'a> {
+    rpc: &'a Web3Rpc,
+}
+
+impl<'a> ActiveRequestGuard<'a> {
+    fn new(rpc: &'a Web3Rpc) -> Self {
+        rpc.active_requests
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+        Self { rpc }
+    }
+}
+
+impl Drop for ActiveRequestGuard<'_> {
+    fn drop(&mut self) {
+        self.rpc
+            .active_requests
+            .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
 impl OpenRequestHandle {
     pub async fn new(authorization: Arc<Authorization>, conn: Arc<Web3Rpc>) -> Self {
         Self {
@@ -182,16 +205,18 @@ impl OpenRequestHandle {
             .total_requests
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
-        self.rpc
-            .active_requests
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        // the request future might be dropped before it finishes (like the slower copy of a hedged request)
+        let _active_request = ActiveRequestGuard::new(&self.rpc);
 
-        // let latency = Instant::now();
+        let start = Instant::now();
 
         // TODO: replace ethers-rs providers with our own that supports streaming the responses
         let response = match provider.as_ref() {
             #[cfg(test)]
-            Web3Provider::Mock => unimplemented!(),
+            Web3Provider::Mock => Err(ProviderError::CustomError(
+                "Web3Provider::Mock can't send requests. use rpcs::mock::MockNode instead"
+                    .to_string(),
+            )),
             Web3Provider::Ws(p) => p.request(method, params).await,
             Web3Provider::Http(p) | Web3Provider::Both(p, _) => {
                 // TODO: i keep hearing that http is faster. but ws has always been better for me. investigate more with actual benchmarks
@@ -199,12 +224,10 @@ impl OpenRequestHandle {
             }
         };
 
-        // note. we intentionally do not record this latency now. we do NOT want to measure errors
-        // let latency = latency.elapsed();
-
-        self.rpc
-            .active_requests
-            .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+        // we do NOT want to measure errors. they are often much faster than real responses
+        if response.is_ok() {
+            self.rpc.request_latency.record(start.elapsed());
+        }
 
         // // TODO: i think ethers already has trace logging (and does it much more fancy)
         // trace!(
@@ -252,73 +275,66 @@ impl OpenRequestHandle {
 
             enum ResponseTypes {
                 Revert,
-                RateLimit,
+                RateLimit(BackendRateLimit),
                 Ok,
             }
 
-            // check for "execution reverted" here
-            let response_type = if let ProviderError::JsonRpcClientError(err) = err {
-                // Http and Ws errors are very similar, but different types
-                let msg = match &*provider {
-                    #[cfg(test)]
-                    Web3Provider::Mock => unimplemented!(),
-                    Web3Provider::Both(_, _) => {
-                        if let Some(HttpClientError::JsonRpcError(err)) =
-                            err.downcast_ref::<HttpClientError>()
-                        {
-                            Some(&err.message)
-                        } else if let Some(WsClientError::JsonRpcError(err)) =
-                            err.downcast_ref::<WsClientError>()
-                        {
-                            Some(&err.message)
-                        } else {
-                            None
-                        }
-                    }
-                    Web3Provider::Http(_) => {
-                        if let Some(HttpClientError::JsonRpcError(err)) =
-                            err.downcast_ref::<HttpClientError>()
-                        {
-                            Some(&err.message)
-                        } else {
-                            None
-                        }
-                    }
-                    Web3Provider::Ws(_) => {
-                        if let Some(WsClientError::JsonRpcError(err)) =
-                            err.downcast_ref::<WsClientError>()
-                        {
-                            Some(&err.message)
-                        } else {
-                            None
-                        }
-                    }
-                };
-
-                if let Some(msg) = msg {
-                    if msg.starts_with("execution reverted") {
-                        trace!("revert from {}", self.rpc);
-                        ResponseTypes::Revert
-                    } else if msg.contains("limit") || msg.contains("request") {
-                        trace!("rate limit from {}", self.rpc);
-                        ResponseTypes::RateLimit
-                    } else {
-                        ResponseTypes::Ok
-                    }
-                } else {
-                    ResponseTypes::Ok
-                }
+            // check for "execution reverted" and rate limits here
+            let error_details = BackendErrorDetails::from_provider_error(err);
+
+            let response_type = if error_details.is_revert() {
+                trace!("revert from {}", self.rpc);
+                ResponseTypes::Revert
+            } else if let Some(rate_limit) = error_details.rate_limit() {
+                trace!("rate limit from {}: {:?}", self.rpc, rate_limit);
+                ResponseTypes::RateLimit(rate_limit)
             } else {
                 ResponseTypes::Ok
             };
 
-            if matches!(response_type, ResponseTypes::RateLimit) {
+            if error_details.is_missing_state()
+                && self.rpc.automatic_block_limit
+                && !self
+                    .rpc
+                    .block_data_limit_checking
+                    .load(std::sync::atomic::Ordering::Acquire)
+            {
+                // this server might have pruned more than we thought
+                debug!("missing state on {}. method={}", self.rpc, method);
+                self.rpc.block_data_limit_recheck.notify_one();
+            }
+
+            if let ResponseTypes::RateLimit(rate_limit) = &response_type {
                 if let Some(hard_limit_until) = self.rpc.hard_limit_until.as_ref() {
-                    let retry_at = Instant::now() + Duration::from_secs(1);
+                    let backoff = rate_limit.backoff();
 
-                    trace!("retry {} at: {:?}", self.rpc, retry_at);
+                    let retry_at = Instant::now() + backoff;
 
-                    hard_limit_until.send_replace(retry_at);
+                    if self.rpc.backup {
+                        debug!(
+                            "rate limited ({:?}) by {}. retry in {}ms",
+                            rate_limit.kind,
+                            self.rpc,
+                            backoff.as_millis()
+                        );
+                    } else {
+                        warn!(
+                            "rate limited ({:?}) by {}. retry in {}ms",
+                            rate_limit.kind,
+                            self.rpc,
+                            backoff.as_millis()
+                        );
+                    }
+
+                    // never move the retry time earlier. another request might have gotten a longer backoff
+                    hard_limit_until.send_if_modified(|x| {
+                        if retry_at > *x {
+                            *x = retry_at;
+                            true
+                        } else {
+                            false
+                        }
+                    });
                 }
             }
 
@@ -1,6 +1,6 @@
-use crate::app::DatabaseReplica;
+use crate::app::{DatabaseReplica, Web3ProxyApp};
 use crate::frontend::errors::FrontendErrorResponse;
-use crate::{app::Web3ProxyApp, user_token::UserBearerToken};
+use crate::frontend::teams;
 use anyhow::Context;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
@@ -9,13 +9,14 @@ use axum::{
     TypedHeader,
 };
 use chrono::{NaiveDateTime, Utc};
+use entities::sea_orm_active_enums::Role;
 use entities::{login, rpc_accounting, rpc_key};
 use hashbrown::HashMap;
 use http::StatusCode;
 use log::{debug, warn};
 use migration::sea_orm::{
-    ColumnTrait, DatabaseConnection, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder,
-    QuerySelect, Select,
+    ColumnTrait, ConnectionTrait, DatabaseConnection, DbBackend, EntityTrait, PaginatorTrait,
+    QueryFilter, QueryOrder, QuerySelect, Select,
 };
 use migration::{Condition, Expr, SimpleExpr};
 use redis_rate_limiter::redis;
@@ -27,18 +28,21 @@ use serde_json::json;
 /// 0 means all users.
 /// This authenticates that the bearer is allowed to view this user_id's stats
 pub async fn get_user_id_from_params(
+    app: &Web3ProxyApp,
     redis_conn: &mut RedisConnection,
     db_conn: &DatabaseConnection,
     db_replica: &DatabaseReplica,
     // this is a long type. should we strip it down?
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
     params: &HashMap<String, String>,
+    // members with at least this role can query a shared account. None only allows the bearer's own account
+    team_role: Option<Role>,
 ) -> Result<u64, FrontendErrorResponse> {
     debug!("bearer and params are: {:?} {:?}", bearer, params);
     match (bearer, params.get("user_id")) {
         (Some(TypedHeader(Authorization(bearer))), Some(user_id)) => {
             // check for the bearer cache key
-            let user_bearer_token = UserBearerToken::try_from(bearer)?;
+            let user_bearer_token = app.user_bearer_token(&bearer)?;
 
             let user_redis_key = user_bearer_token.redis_key();
 
@@ -75,7 +79,7 @@ pub async fn get_user_id_from_params(
 
                     save_to_redis = true;
 
-                    user_login.user_id
+                    user_login.user_id.into()
                 }
                 Ok(x) => {
                     // TODO: push cache ttl further in the future?
@@ -85,23 +89,28 @@ pub async fn get_user_id_from_params(
 
             let user_id: u64 = user_id.parse().context("Parsing user_id param")?;
 
-            if bearer_user_id != user_id {
-                return Err(FrontendErrorResponse::AccessDenied);
-            }
-
             if save_to_redis {
                 // TODO: how long? we store in database for 4 weeks
                 const ONE_DAY: usize = 60 * 60 * 24;
 
                 if let Err(err) = redis_conn
-                    .set_ex::<_, _, ()>(user_redis_key, user_id, ONE_DAY)
+                    .set_ex::<_, _, ()>(user_redis_key, bearer_user_id, ONE_DAY)
                     .await
                 {
                     warn!("Unable to save user bearer token to redis: {}", err)
                 }
             }
 
-            Ok(bearer_user_id)
+            if bearer_user_id != user_id {
+                let team_role = team_role.ok_or(FrontendErrorResponse::AccessDenied)?;
+
+                match teams::team_role(db_replica.conn(), user_id, bearer_user_id).await? {
+                    Some(x) if teams::role_allows(&x, &team_role) => {}
+                    _ => return Err(FrontendErrorResponse::AccessDenied),
+                }
+            }
+
+            Ok(user_id)
         }
         (_, None) => {
             // they have a bearer token. we don't care about it on public pages
@@ -148,7 +157,7 @@ pub fn get_chain_id_from_params(
     params: &HashMap<String, String>,
 ) -> anyhow::Result<u64> {
     params.get("chain_id").map_or_else(
-        || Ok(app.config.chain_id),
+        || Ok(app.config().chain_id),
         |c| {
             let c = c.parse()?;
 
@@ -219,6 +228,7 @@ pub fn get_query_window_seconds_from_params(
 }
 
 pub fn filter_query_window_seconds(
+    db_backend: DbBackend,
     query_window_seconds: u64,
     response: &mut HashMap<&str, serde_json::Value>,
     q: Select<rpc_accounting::Entity>,
@@ -232,10 +242,18 @@ pub fn filter_query_window_seconds(
 
     // TODO: is there a better way to do this? how can we get "period_datetime" into this with types?
     // TODO: how can we get the first window to start at query_start_timestamp
-    let expr = Expr::cust_with_values(
-        "FLOOR(UNIX_TIMESTAMP(rpc_accounting.period_datetime) / ?) * ?",
-        [query_window_seconds, query_window_seconds],
-    );
+    let expr = match db_backend {
+        DbBackend::MySql => "FLOOR(UNIX_TIMESTAMP(rpc_accounting.period_datetime) / ?) * ?",
+        DbBackend::Postgres => {
+            "CAST(FLOOR(EXTRACT(EPOCH FROM rpc_accounting.period_datetime) / ?) * ? AS BIGINT)"
+        }
+        // integer division already floors
+        DbBackend::Sqlite => {
+            "CAST(strftime('%s', rpc_accounting.period_datetime) AS INTEGER) / ? * ?"
+        }
+    };
+
+    let expr = Expr::cust_with_values(expr, [query_window_seconds, query_window_seconds]);
 
     response.insert(
         "query_window_seconds",
@@ -251,6 +269,12 @@ pub fn filter_query_window_seconds(
     Ok(q)
 }
 
+/// The number of `rpc_accounting` rows that were error responses.
+/// mysql and sqlite can sum bools, but postgres can't
+pub fn sum_error_responses() -> SimpleExpr {
+    Expr::cust("SUM(CASE WHEN rpc_accounting.error_response THEN 1 ELSE 0 END)")
+}
+
 pub enum StatResponse {
     Aggregated,
     Detailed,
@@ -273,8 +297,16 @@ pub async fn query_user_stats<'a>(
         .context("query_user_stats needs a redis")?;
 
     // get the user id first. if it is 0, we should use a cache on the app
-    let user_id =
-        get_user_id_from_params(&mut redis_conn, &db_conn, &db_replica, bearer, params).await?;
+    let user_id = get_user_id_from_params(
+        app,
+        &mut redis_conn,
+        &db_conn,
+        &db_replica,
+        bearer,
+        params,
+        Some(Role::Collaborator),
+    )
+    .await?;
     // get the query window seconds now so that we can pick a cache with a good TTL
     // TODO: for now though, just do one cache. its easier
     let query_window_seconds = get_query_window_seconds_from_params(params)?;
@@ -349,10 +381,7 @@ pub async fn query_user_stats<'a>(
             rpc_accounting::Column::SumResponseBytes.sum(),
             "total_response_bytes",
         )
-        .column_as(
-            rpc_accounting::Column::ErrorResponse.sum(),
-            "total_error_responses",
-        )
+        .column_as(sum_error_responses(), "total_error_responses")
         .column_as(
             rpc_accounting::Column::SumResponseMillis.sum(),
             "total_response_millis",
@@ -373,7 +402,12 @@ pub async fn query_user_stats<'a>(
     }
 
     // TODO: have q be &mut?
-    q = filter_query_window_seconds(query_window_seconds, &mut response_body, q)?;
+    q = filter_query_window_seconds(
+        db_replica.conn().get_database_backend(),
+        query_window_seconds,
+        &mut response_body,
+        q,
+    )?;
 
     // aggregate stats after query_start
     // TODO: maximum query_start of 90 days ago?
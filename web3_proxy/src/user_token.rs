@@ -1,7 +1,13 @@
 use std::str::FromStr;
 
+use anyhow::Context;
 use axum::headers::authorization::Bearer;
+use entities::unsigned::BigUnsigned;
+use entities::login;
 use migration::sea_orm::prelude::Uuid;
+use migration::sea_orm::sea_query::IntoCondition;
+use migration::sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QuerySelect};
+use redis_rate_limiter::redis::AsyncCommands;
 use serde::Serialize;
 use ulid::Ulid;
 
@@ -57,3 +63,47 @@ impl TryFrom<Bearer> for UserBearerToken {
         Ok(UserBearerToken(u))
     }
 }
+
+/// Delete the logins that match `filter` and forget the user ids that were cached for their bearer tokens.
+/// Returns how many logins were revoked.
+pub async fn revoke_logins<C: AsyncCommands>(
+    db_conn: &DatabaseConnection,
+    redis_conn: Option<&mut C>,
+    filter: impl IntoCondition,
+) -> anyhow::Result<u64> {
+    let logins: Vec<(BigUnsigned, Uuid)> = login::Entity::find()
+        .select_only()
+        .column(login::Column::Id)
+        .column(login::Column::BearerToken)
+        .filter(filter)
+        .into_tuple()
+        .all(db_conn)
+        .await
+        .context("fetching logins to revoke")?;
+
+    if logins.is_empty() {
+        return Ok(0);
+    }
+
+    let (login_ids, bearer_tokens): (Vec<_>, Vec<_>) = logins.into_iter().unzip();
+
+    let delete_result = login::Entity::delete_many()
+        .filter(login::Column::Id.is_in(login_ids))
+        .exec(db_conn)
+        .await
+        .context("deleting logins")?;
+
+    if let Some(redis_conn) = redis_conn {
+        let redis_keys: Vec<String> = bearer_tokens
+            .into_iter()
+            .map(|x| UserBearerToken(Ulid::from(x.as_u128())).redis_key())
+            .collect();
+
+        redis_conn
+            .del::<_, ()>(redis_keys)
+            .await
+            .context("forgetting revoked bearer tokens")?;
+    }
+
+    Ok(delete_result.rows_affected)
+}